@@ -4,10 +4,27 @@ use ripemd::Ripemd160;
 use secp256k1::PublicKey;
 use sha2::{Digest, Sha256};
 use std::error::Error;
+use std::sync::OnceLock;
 
-const VERSION: u8 = 0;
+/// Version byte of mainnet addresses
+pub const MAINNET_VERSION: u8 = 0;
+/// Version byte of testnet addresses, so they can't be confused with mainnet addresses
+pub const TESTNET_VERSION: u8 = 111;
 
-#[derive(Debug)]
+static VERSION: OnceLock<u8> = OnceLock::new();
+
+/// Sets the version byte used to create and validate addresses. Must be called before any
+/// address is first used, and can only be set once.
+pub fn set_address_version(version: u8) -> Result<(), u8> {
+    VERSION.set(version)
+}
+
+/// Returns the configured address version byte, or MAINNET_VERSION if none was set
+pub fn address_version() -> u8 {
+    *VERSION.get_or_init(|| MAINNET_VERSION)
+}
+
+#[derive(Debug, Clone)]
 pub struct Address {
     pub_key_hash: [u8; 20],
     version: u8,
@@ -16,8 +33,14 @@ pub struct Address {
 
 impl Address {
     /// Create a new Address instance. Provided address must be a string slice of a base58 encoded 25 byte address.
-    /// Bytes should take the format: `[[0 version], [1-21 pub key hash], [21-24 checksum]]`
+    /// Bytes should take the format: `[[0 version], [1-21 pub key hash], [21-24 checksum]]`.
+    /// Addresses with a version other than the configured address version are rejected.
     pub fn new_from_str(addr: &str) -> Result<Self, Box<dyn Error>> {
+        Address::new_from_str_with_version(addr, address_version())
+    }
+
+    /// Decodes the address, rejecting it if its version is not the expected version
+    fn new_from_str_with_version(addr: &str, expected_version: u8) -> Result<Self, Box<dyn Error>> {
         let decoded_addr = addr.from_base58().map_err(|e| {
             format!(
                 "[Address::new_from_str] ERROR: Failed to decode address: {:?}",
@@ -31,6 +54,13 @@ impl Address {
 
         // Extract version byte (first byte)
         let version = decoded_addr[0];
+        if version != expected_version {
+            return Err(format!(
+                "[Address::new_from_str] ERROR: Unexpected address version {}, expected {}",
+                version, expected_version
+            )
+            .into());
+        }
 
         // Extract public key hash (next 20 bytes)
         let pub_key_hash: [u8; 20] = decoded_addr[1..21].try_into()?; // The public key hash is 20 bytes
@@ -51,12 +81,17 @@ impl Address {
     }
 
     pub fn new_from_key(pub_key: PublicKey) -> Self {
-        let pub_key_hash = hash_pub_key(&pub_key);
-        let checksum = Address::calculate_checksum(VERSION, &pub_key_hash);
+        Address::new_from_pub_key_hash(hash_pub_key(&pub_key))
+    }
+
+    /// Creates the address locking outputs to the given pub key hash, such as a tx output's
+    pub fn new_from_pub_key_hash(pub_key_hash: [u8; 20]) -> Self {
+        let version = address_version();
+        let checksum = Address::calculate_checksum(version, &pub_key_hash);
 
         Address {
             pub_key_hash,
-            version: VERSION,
+            version,
             checksum,
         }
     }
@@ -73,13 +108,13 @@ impl Address {
 
         // Hash the version & pub key hash together
         let mut hasher = Sha256::new();
-        hasher.update(&[version]);
+        hasher.update([version]);
         hasher.update(pub_key_hash);
         let hash1 = hasher.finalize();
 
         // Hash an extra time to improve security, to avoid length extension attacks.
         let mut hasher = Sha256::new();
-        hasher.update(&hash1);
+        hasher.update(hash1);
         let hash2 = hasher.finalize();
 
         let mut checksum = [0u8; 4];
@@ -100,11 +135,53 @@ impl Address {
     }
 }
 
-/// Hashes a public key using SHA-256 followed by RIPEMD-160
+/// Hashes a public key using SHA-256 followed by RIPEMD-160. The key is always hashed in its
+/// compressed form, regardless of the form it was parsed from
 pub fn hash_pub_key(pub_key: &PublicKey) -> [u8; 20] {
-    let sha256_hash = Sha256::digest(&pub_key.serialize());
-    let ripemd160_hash = Ripemd160::digest(&sha256_hash);
-    ripemd160_hash
-        .try_into()
-        .expect("[Address::hash_pub_key] ERROR: Hash should be 20 bytes")
+    let sha256_hash = Sha256::digest(pub_key.serialize());
+    Ripemd160::digest(sha256_hash).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes an address with the given version, as a node on that network would
+    fn encode(version: u8, pub_key_hash: [u8; 20]) -> String {
+        Address {
+            pub_key_hash,
+            version,
+            checksum: Address::calculate_checksum(version, &pub_key_hash),
+        }
+        .get_full_address()
+    }
+
+    #[test]
+    fn accepts_mainnet_address() {
+        let addr = encode(MAINNET_VERSION, [7u8; 20]);
+
+        let parsed = Address::new_from_str(&addr).unwrap();
+        assert_eq!(parsed.pub_key_hash(), &[7u8; 20]);
+        assert_eq!(parsed.get_full_address(), addr);
+    }
+
+    #[test]
+    fn rejects_address_of_another_version() {
+        let addr = encode(TESTNET_VERSION, [7u8; 20]);
+
+        let err = Address::new_from_str(&addr).unwrap_err().to_string();
+        assert!(err.contains("Unexpected address version 111"), "{}", err);
+    }
+
+    #[test]
+    fn accepts_testnet_address_on_testnet() {
+        let addr = encode(TESTNET_VERSION, [7u8; 20]);
+
+        let parsed = Address::new_from_str_with_version(&addr, TESTNET_VERSION).unwrap();
+        assert_eq!(parsed.pub_key_hash(), &[7u8; 20]);
+        assert_eq!(parsed.get_full_address(), addr);
+        // Mainnet addresses are rejected in turn
+        let mainnet_addr = encode(MAINNET_VERSION, [7u8; 20]);
+        assert!(Address::new_from_str_with_version(&mainnet_addr, TESTNET_VERSION).is_err());
+    }
 }
@@ -1,2 +1,5 @@
 // TODO: come up with a better seeding solution
 pub const SEED_API_NODE: &str = "http://localhost:3000";
+
+/// Outputs below this value are considered dust and are rejected, as they bloat the utxo set
+pub const MIN_OUTPUT_VALUE: u32 = 10;
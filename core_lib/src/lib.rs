@@ -1,5 +1,6 @@
 pub mod address;
 pub mod constants;
+pub mod merkle;
 pub mod req_types;
 pub mod tx;
 pub mod wallet;
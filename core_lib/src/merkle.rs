@@ -0,0 +1,31 @@
+use sha2::{Digest, Sha256};
+
+/// A Merkle proof holds the sibling hashes along the path from a leaf to the root of a Merkle tree.
+/// Each sibling hash is paired with a flag that is true when the sibling is the left node of its pair.
+pub type MerkleProof = Vec<(bool, [u8; 32])>;
+
+/// Verifies that a tx hash is included in a Merkle tree with the given root, using a proof
+/// generated by the tree. This allows light clients to verify tx inclusion without the full block.
+pub fn verify_merkle_proof(
+    tx_hash: &[u8; 32],
+    proof: &[(bool, [u8; 32])],
+    root: &[u8; 32],
+) -> bool {
+    // Leaf nodes store the hash of their data
+    let mut curr_hash: [u8; 32] = Sha256::digest(tx_hash).into();
+
+    // Rebuild each parent hash up to the root, concatenating the pair in the correct order
+    for (sibling_is_left, sibling_hash) in proof {
+        let mut hasher = Sha256::new();
+        if *sibling_is_left {
+            hasher.update(sibling_hash);
+            hasher.update(curr_hash);
+        } else {
+            hasher.update(curr_hash);
+            hasher.update(sibling_hash);
+        }
+        curr_hash = hasher.finalize().into();
+    }
+
+    curr_hash == *root
+}
@@ -1,15 +1,23 @@
 use hex::decode;
-use secp256k1::{PublicKey, ecdsa::Signature};
+use secp256k1::{
+    PublicKey,
+    constants::{PUBLIC_KEY_SIZE, UNCOMPRESSED_PUBLIC_KEY_SIZE},
+    ecdsa::Signature,
+};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, error::Error};
 
-use crate::tx::{Tx, TxInput, TxOutput, UTXOSet};
+use crate::address::Address;
+use crate::tx::{Tx, TxInput, TxOutput, UTXOSet, empty_signature};
 
 #[derive(Serialize, Deserialize)]
 pub struct TxJson {
     pub id: String, // Hex-encoded
     pub inputs: Vec<TxInputJson>,
     pub outputs: Vec<TxOutputJson>,
+    // Serialized size in bytes. Informational only, so clients may omit it when sending a tx
+    #[serde(default)]
+    pub size: usize,
 }
 
 impl TxJson {
@@ -43,13 +51,13 @@ impl TxJson {
 
     pub fn from_tx(tx: &Tx) -> Result<Self, Box<dyn Error>> {
         Ok(Self {
-            id: hex::encode(&tx.id),
+            id: hex::encode(tx.id),
             inputs: tx
                 .inputs
                 .iter()
                 .map(|input| {
                     Ok(TxInputJson {
-                        prev_tx_id: hex::encode(&input.prev_tx_id),
+                        prev_tx_id: hex::encode(input.prev_tx_id),
                         out: input.out,
                         signature: encode_sig(&input.signature)?,
                         pub_key: encode_pubkey(&input.pub_key)?,
@@ -62,10 +70,11 @@ impl TxJson {
                 .map(|output| {
                     Ok(TxOutputJson {
                         value: output.value,
-                        pub_key_hash: hex::encode(&output.pub_key_hash),
+                        pub_key_hash: hex::encode(output.pub_key_hash),
                     })
                 })
                 .collect::<Result<Vec<TxOutputJson>, Box<dyn Error>>>()?,
+            size: tx.size(),
         })
     }
 }
@@ -77,12 +86,33 @@ fn decode_hex<T: TryFrom<Vec<u8>>>(hex: &str) -> Result<T, Box<dyn Error>> {
 }
 
 fn decode_sig(sig: &str) -> Result<Signature, Box<dyn Error>> {
+    // Unsigned tx skeletons leave the signature empty until they are signed
+    if sig.is_empty() {
+        return Ok(empty_signature());
+    }
     Signature::from_der(&decode(sig)?)
         .map_err(|_| "[tx_json::decode_sig] ERROR: Invalid signature".into())
 }
 
+/// Decodes a pub key given in either compressed or uncompressed form. Keys are always
+/// re-encoded and hashed in compressed form, so both forms resolve to the same address
 fn decode_pubkey(pubkey: &str) -> Result<PublicKey, Box<dyn Error>> {
-    PublicKey::from_slice(&decode(pubkey)?)
+    let bytes = decode(pubkey).map_err(|e| {
+        format!(
+            "[tx_json::decode_pub_key] ERROR: Public key is not valid hex: {}",
+            e
+        )
+    })?;
+    if bytes.len() != PUBLIC_KEY_SIZE && bytes.len() != UNCOMPRESSED_PUBLIC_KEY_SIZE {
+        return Err(format!(
+            "[tx_json::decode_pub_key] ERROR: Public key must be {} bytes compressed or {} bytes uncompressed, got {} bytes",
+            PUBLIC_KEY_SIZE,
+            UNCOMPRESSED_PUBLIC_KEY_SIZE,
+            bytes.len()
+        )
+        .into());
+    }
+    PublicKey::from_slice(&bytes)
         .map_err(|_| "[tx_json::decode_pub_key] ERROR: Invalid public key".into())
 }
 
@@ -122,7 +152,7 @@ pub fn convert_utxoset_to_json(utxoset: &UTXOSet) -> UTXOSetJson {
                         *idx,
                         TxOutputJson {
                             value: txo.value,
-                            pub_key_hash: hex::encode(&txo.pub_key_hash),
+                            pub_key_hash: hex::encode(txo.pub_key_hash),
                         },
                     )
                 })
@@ -167,8 +197,146 @@ pub struct GetUTXORes {
     pub utxos: UTXOSetJson,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetBalanceRes {
+    pub address: String,
+    /// Sum of the address's confirmed utxos
+    pub confirmed: u64,
+    /// Value of confirmed utxos already spent by pending txs in the mempool
+    pub pending_outgoing: u64,
+    /// Value paid to the address by pending txs in the mempool
+    pub pending_incoming: u64,
+}
+
+/// GetBalancesRes maps each requested address to its balance
+pub type GetBalancesRes = HashMap<String, GetBalanceRes>;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OutpointJson {
+    pub tx_id: String, // Hex-encoded
+    pub out_idx: u32,
+    pub value: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetAllUTXORes {
+    pub address: String,
+    pub utxos: Vec<OutpointJson>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UTXOJson {
     pub value: u32,
     pub pub_key_hash: String, // This is hex-encoded
 }
+
+/// TxFeeRes is the fee a tx pays, resolved from the values of the utxos it spends
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TxFeeRes {
+    pub fee: u32,
+    /// Fee per byte of the serialized tx
+    pub fee_rate: f64,
+    pub size: usize,
+}
+
+/// SendRawTxReq submits a signed tx in its raw serialized form
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SendRawTxReq {
+    pub raw: String, // Hex-encoded bincode tx
+}
+
+impl SendRawTxReq {
+    pub fn to_tx(&self) -> Result<Tx, Box<dyn Error>> {
+        bincode::deserialize(&decode(&self.raw)?)
+            .map_err(|e| format!("[send_raw_tx::to_tx] ERROR: Invalid raw tx: {}", e).into())
+    }
+}
+
+/// VerifyMessageReq asks whether a message was signed by the owner of an address
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerifyMessageReq {
+    pub address: String,
+    pub message: String,
+    pub pub_key: String,   // Hex-encoded
+    pub signature: String, // Hex-encoded DER signature
+}
+
+impl VerifyMessageReq {
+    /// Decodes the address, pub key and signature of the request
+    pub fn decode(&self) -> Result<(Address, PublicKey, Signature), Box<dyn Error>> {
+        Ok((
+            Address::new_from_str(&self.address)?,
+            decode_pubkey(&self.pub_key)?,
+            decode_sig(&self.signature)?,
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerifyMessageRes {
+    /// True if the pub key belongs to the address and signed the message
+    pub valid: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{address::hash_pub_key, wallet::Wallet};
+
+    /// Returns a tx signed by the wallet, spending a single output it owns
+    fn signed_tx(wallet: &Wallet) -> Tx {
+        let tx_out = TxOutput {
+            value: 100,
+            pub_key_hash: *wallet.get_wallet_address().pub_key_hash(),
+        };
+        let utxos = HashMap::from([([1u8; 32], HashMap::from([(0, tx_out)]))]);
+        let to = Wallet::new().get_wallet_address();
+        Tx::new(wallet, &to, 50, 0, utxos).unwrap()
+    }
+
+    #[test]
+    fn uncompressed_pub_key_decodes_to_same_tx() {
+        let wallet = Wallet::new();
+        let signed = signed_tx(&wallet);
+        let mut uncompressed = TxJson::from_tx(&signed).unwrap();
+        uncompressed.inputs[0].pub_key = hex::encode(wallet.pub_key().serialize_uncompressed());
+
+        // Both forms hash to the wallet's address, so the tx and its id are unchanged
+        let tx = uncompressed.to_tx().unwrap();
+        assert_eq!(tx.inputs[0].pub_key, *wallet.pub_key());
+        assert_eq!(
+            hash_pub_key(&tx.inputs[0].pub_key),
+            *wallet.get_wallet_address().pub_key_hash()
+        );
+        assert_eq!(tx.hash().unwrap(), signed.hash().unwrap());
+        assert_eq!(tx.id, signed.id);
+
+        // Re-encoding gives back the compressed form
+        let json = TxJson::from_tx(&tx).unwrap();
+        assert_eq!(
+            json.inputs[0].pub_key,
+            hex::encode(wallet.pub_key().serialize())
+        );
+    }
+
+    #[test]
+    fn malformed_pub_key_errors_describe_the_key() {
+        let wallet = Wallet::new();
+        let key = wallet.pub_key().serialize_uncompressed();
+        let signed = signed_tx(&wallet);
+        let error = |pub_key: String| {
+            let mut json = TxJson::from_tx(&signed).unwrap();
+            json.inputs[0].pub_key = pub_key;
+            json.to_tx().err().unwrap().to_string()
+        };
+
+        assert!(error("zz".repeat(33)).contains("Public key is not valid hex"));
+        assert!(error(hex::encode(&key[..40])).contains(
+            "Public key must be 33 bytes compressed or 65 bytes uncompressed, got 40 bytes"
+        ));
+        // A key of the right length must still be a point on the curve
+        let mut off_curve = key;
+        off_curve[64] ^= 1;
+        assert!(error(hex::encode(off_curve)).contains("Invalid public key"));
+    }
+}
@@ -3,14 +3,35 @@ use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::sync::OnceLock;
 use std::{error::Error, fmt::Debug};
 
 use crate::address::Address;
+use crate::constants::MIN_OUTPUT_VALUE;
 use crate::wallet::Wallet;
 
 pub type TxOutMap = HashMap<u32, TxOutput>;
 pub type UTXOSet = HashMap<[u8; 32], TxOutMap>;
 
+/// Network id of mainnet. Bound into every tx signature so signed txs can't be replayed on
+/// another network
+pub const MAINNET_NETWORK_ID: [u8; 4] = [0xd0, 0xc0, 0x1e, 0x01];
+/// Network id of testnet
+pub const TESTNET_NETWORK_ID: [u8; 4] = [0xd0, 0xc0, 0x1e, 0x7e];
+
+static NETWORK_ID: OnceLock<[u8; 4]> = OnceLock::new();
+
+/// Sets the network id used when signing txs. Must be called before any tx is first signed,
+/// and can only be set once.
+pub fn set_network_id(network_id: [u8; 4]) -> Result<(), [u8; 4]> {
+    NETWORK_ID.set(network_id)
+}
+
+/// Returns the configured network id, or MAINNET_NETWORK_ID if none was set
+pub fn network_id() -> [u8; 4] {
+    *NETWORK_ID.get_or_init(|| MAINNET_NETWORK_ID)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Tx {
     pub id: [u8; 32], // ID of the transaction
@@ -31,6 +52,11 @@ impl Tx {
         Ok(hash.into()) // Convert to [u8; 32]
     }
 
+    /// Returns the size of the tx in bytes, as serialized for storage and the network
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).expect("[Tx::size] ERROR: Failed to size tx") as usize
+    }
+
     /// Returns a copy of the given Tx without input pub keys and signatures.
     /// This ensures standardization when signing and validating - so that the tx
     /// has the same format when on either side of the tx.
@@ -59,6 +85,17 @@ impl Tx {
         }
     }
 
+    /// Returns the digest signed by each input - the hash of the trimmed tx, prefixed with the
+    /// network id. Binding the network id means a signature is only valid on one network.
+    pub fn sig_hash(&self, network_id: &[u8; 4]) -> Result<[u8; 32], Box<dyn Error>> {
+        let trimmed_hash = self.trimmed_copy().hash()?;
+        Ok(Sha256::new()
+            .chain_update(network_id)
+            .chain_update(trimmed_hash)
+            .finalize()
+            .into())
+    }
+
     /// Checks if this is the coinbase tx
     pub fn is_coinbase(&self) -> bool {
         self.inputs.len() == 1
@@ -72,16 +109,12 @@ impl Tx {
             return Ok(()); // Coinbase txs don't need to be signed
         }
         let secp = Secp256k1::new();
-        let tx_copy_base = self.trimmed_copy();
+
+        // The signed digest excludes pub keys and signatures, so it is the same for every input
+        let msg = Message::from_digest(self.sig_hash(&network_id())?);
 
         // Loop through inputs from original tx so we can append a signature.
         for input in &mut self.inputs {
-            // Build a copy for hashing that does not include the pubkey or signature
-            let mut tx_copy: Tx = tx_copy_base.trimmed_copy();
-
-            // Set the ID to the hash of the tx. When we verify, this will be used for pubkey comparison
-            tx_copy.id = tx_copy.hash()?;
-            let msg = Message::from_digest(tx_copy.id);
             let sig = secp.sign_ecdsa(&msg, priv_key);
 
             // Set the sig of the original input
@@ -93,16 +126,56 @@ impl Tx {
         Ok(())
     }
 
-    /// Create a new tx
+    /// Create a new tx. The given fee is reserved from the inputs and left unclaimed
+    /// by the outputs, so that it can be collected by the miner in the coinbase tx.
+    /// Change below the dust limit is not returned to the sender and is added to the fee.
     pub fn new(
         from_wallet: &Wallet,
         to_address: &Address,
         value: u32,
+        fee: u32,
+        spendable_txos: UTXOSet,
+    ) -> Result<Tx, Box<dyn Error>> {
+        Self::new_multi(
+            from_wallet,
+            &[(to_address.clone(), value)],
+            fee,
+            spendable_txos,
+        )
+    }
+
+    /// Create a new tx paying each of the given recipients, with a single change output
+    /// returned to the sender. Fees and dust change are handled the same as in `Tx::new`.
+    pub fn new_multi(
+        from_wallet: &Wallet,
+        recipients: &[(Address, u32)],
+        fee: u32,
         spendable_txos: UTXOSet,
     ) -> Result<Tx, Box<dyn Error>> {
         let mut inputs: Vec<TxInput> = Vec::new();
         let mut outputs: Vec<TxOutput> = Vec::new();
-        let mut sum = 0;
+        let mut sum: u32 = 0;
+
+        if recipients.is_empty() {
+            return Err("[Tx::new_multi] ERROR: At least one recipient is required".into());
+        }
+
+        let mut total = fee;
+        for (_, value) in recipients {
+            if *value == 0 {
+                return Err("[Tx::new_multi] ERROR: Value must be greater than zero".into());
+            }
+            if *value < MIN_OUTPUT_VALUE {
+                return Err(format!(
+                    "[Tx::new_multi] ERROR: Value must be at least {} to not be considered dust",
+                    MIN_OUTPUT_VALUE
+                )
+                .into());
+            }
+            total = total
+                .checked_add(*value)
+                .ok_or("[Tx::new_multi] ERROR: Values and fee overflow")?;
+        }
 
         // Create a new input from each spendable txo contributing to the sum
         for (tx_id, txo_map) in spendable_txos {
@@ -113,20 +186,30 @@ impl Tx {
                     empty_signature(),
                     *from_wallet.pub_key(),
                 ));
-                sum += txo.value;
+                sum = sum
+                    .checked_add(txo.value)
+                    .ok_or("[Tx::new_multi] ERROR: Input values overflow")?;
             }
         }
 
-        // Create a new output of the to address receiving the value
-        outputs.push(TxOutput {
-            value,
-            pub_key_hash: *to_address.pub_key_hash(),
-        });
+        if sum < total {
+            return Err("[Tx::new_multi] ERROR: Inputs do not cover the values and fee".into());
+        }
 
-        // Any leftover sum should be retained by the sender
-        if sum > value {
+        // Create a new output for each recipient receiving their value
+        for (to_address, value) in recipients {
             outputs.push(TxOutput {
-                value: sum - value,
+                value: *value,
+                pub_key_hash: *to_address.pub_key_hash(),
+            });
+        }
+
+        // Any leftover sum after the fee should be retained by the sender.
+        // No change output is created if the change would be dust
+        let change = sum - total;
+        if change >= MIN_OUTPUT_VALUE {
+            outputs.push(TxOutput {
+                value: change,
                 pub_key_hash: *from_wallet.get_wallet_address().pub_key_hash(),
             });
         }
@@ -146,7 +229,7 @@ impl Tx {
 
 /** Inputs and Outputs **/
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TxOutput {
     pub value: u32, // Value of output tokens in the tx. Outputs cannot be split
     pub pub_key_hash: [u8; 20], // Recipient pub key (Sha256 + Ripemd160). Locks the output so it can only be included in a future input by the output author.
@@ -186,6 +269,102 @@ fn empty_priv_key() -> SecretKey {
     SecretKey::from_slice(&[1u8; 32]).unwrap()
 }
 
-fn empty_signature() -> Signature {
+pub(crate) fn empty_signature() -> Signature {
     Signature::from_compact(&[0u8; 64]).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::req_types::TxJson;
+
+    /// Returns a utxo set holding a single output of the given value, locked to the wallet
+    fn utxos_of(wallet: &Wallet, value: u32) -> UTXOSet {
+        let tx_out = TxOutput {
+            value,
+            pub_key_hash: *wallet.get_wallet_address().pub_key_hash(),
+        };
+        HashMap::from([([1u8; 32], HashMap::from([(0, tx_out)]))])
+    }
+
+    fn output_values(tx: &Tx) -> Vec<u32> {
+        tx.outputs.iter().map(|tx_out| tx_out.value).collect()
+    }
+
+    #[test]
+    fn new_reserves_fee_from_change() {
+        let from = Wallet::new();
+        let to = Wallet::new().get_wallet_address();
+
+        let tx = Tx::new(&from, &to, 50, 5, utxos_of(&from, 100)).unwrap();
+
+        assert_eq!(output_values(&tx), vec![50, 45]);
+        assert_eq!(
+            tx.outputs[1].pub_key_hash,
+            *from.get_wallet_address().pub_key_hash()
+        );
+    }
+
+    #[test]
+    fn new_rejects_zero_value() {
+        let from = Wallet::new();
+        let to = Wallet::new().get_wallet_address();
+
+        let err = Tx::new(&from, &to, 0, 5, utxos_of(&from, 100)).unwrap_err();
+        assert!(err.to_string().contains("Value must be greater than zero"));
+    }
+
+    #[test]
+    fn new_adds_dust_change_to_fee() {
+        let from = Wallet::new();
+        let to = Wallet::new().get_wallet_address();
+
+        let exact = Tx::new(&from, &to, 90, 10, utxos_of(&from, 100)).unwrap();
+        let dust = Tx::new(&from, &to, 90, 5, utxos_of(&from, 100)).unwrap();
+
+        assert_eq!(output_values(&exact), vec![90]);
+        assert_eq!(output_values(&dust), vec![90]);
+        assert!(Tx::new(&from, &to, 90, 11, utxos_of(&from, 100)).is_err());
+    }
+
+    #[test]
+    fn new_rejects_dust_value() {
+        let from = Wallet::new();
+        let to = Wallet::new().get_wallet_address();
+
+        assert!(Tx::new(&from, &to, MIN_OUTPUT_VALUE - 1, 0, utxos_of(&from, 100)).is_err());
+        assert!(Tx::new(&from, &to, MIN_OUTPUT_VALUE, 0, utxos_of(&from, 100)).is_ok());
+    }
+
+    #[test]
+    fn new_multi_pays_each_recipient_and_change() {
+        let from = Wallet::new();
+        let recipients: Vec<(Address, u32)> = [20, 30, 15]
+            .into_iter()
+            .map(|value| (Wallet::new().get_wallet_address(), value))
+            .collect();
+
+        let tx = Tx::new_multi(&from, &recipients, 5, utxos_of(&from, 100)).unwrap();
+
+        assert_eq!(output_values(&tx), vec![20, 30, 15, 30]);
+        for ((to, _), tx_out) in recipients.iter().zip(&tx.outputs) {
+            assert_eq!(tx_out.pub_key_hash, *to.pub_key_hash());
+        }
+        assert_eq!(
+            tx.outputs[3].pub_key_hash,
+            *from.get_wallet_address().pub_key_hash()
+        );
+        assert!(Tx::new_multi(&from, &recipients, 36, utxos_of(&from, 100)).is_err());
+        assert!(Tx::new_multi(&from, &[], 5, utxos_of(&from, 100)).is_err());
+    }
+
+    #[test]
+    fn size_is_serialized_length() {
+        let wallet = Wallet::new();
+        let to = Wallet::new().get_wallet_address();
+        let tx = Tx::new(&wallet, &to, 50, 5, utxos_of(&wallet, 100)).unwrap();
+
+        assert_eq!(tx.size(), bincode::serialize(&tx).unwrap().len());
+        assert_eq!(TxJson::from_tx(&tx).unwrap().size, tx.size());
+    }
+}
@@ -1,10 +1,21 @@
 use std::str::FromStr;
 
-use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bip39::Mnemonic;
+use secp256k1::ecdsa::Signature;
+use secp256k1::rand::RngCore;
+use secp256k1::{Message, PublicKey, Scalar, Secp256k1, SecretKey};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::error::Error;
 
 use crate::address::Address;
+use crate::tx::{Tx, empty_signature};
+
+/// Prefixed to signed messages before hashing, so a signed message can never double as a tx
+/// signature
+const SIGNED_MESSAGE_PREFIX: &[u8] = b"dcoin signed message:\n";
+/// Prefixed to the parent key when hashing the tweak of a derived child key
+const CHILD_KEY_PREFIX: &[u8] = b"dcoin child key:\n";
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct Wallet {
@@ -14,6 +25,8 @@ pub struct Wallet {
 
 impl Wallet {
     /// Create new wallet - Creates new pub key and private key
+    // A randomly generated key pair isn't a meaningful default, so `Default` isn't implemented
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         let secp = Secp256k1::new();
         let (private_key, public_key) = secp.generate_keypair(&mut secp256k1::rand::thread_rng());
@@ -24,6 +37,71 @@ impl Wallet {
         }
     }
 
+    /// Create a new wallet from a randomly generated 12 word BIP39 mnemonic. The mnemonic phrase
+    /// is returned alongside the wallet so that it can be backed up and used for recovery.
+    pub fn generate_mnemonic() -> (Self, String) {
+        // 16 bytes of entropy produces a 12 word mnemonic
+        let mut entropy = [0u8; 16];
+        secp256k1::rand::thread_rng().fill_bytes(&mut entropy);
+        let mnemonic = Mnemonic::from_entropy(&entropy)
+            .expect("[Wallet::generate_mnemonic] ERROR: Entropy should be a valid length");
+        let phrase = mnemonic.to_string();
+
+        let wallet = Self::from_mnemonic(&phrase)
+            .expect("[Wallet::generate_mnemonic] ERROR: Generated mnemonic should be valid");
+        (wallet, phrase)
+    }
+
+    /// Recover a wallet from a 12 or 24 word BIP39 mnemonic phrase. The same phrase
+    /// always derives the same keys.
+    pub fn from_mnemonic(phrase: &str) -> Result<Self, Box<dyn Error>> {
+        let mnemonic = Mnemonic::parse(phrase)
+            .map_err(|e| format!("[Wallet::from_mnemonic] ERROR: Invalid mnemonic: {}", e))?;
+
+        // The private key is taken from the first 32 bytes of the 64 byte BIP39 seed
+        let seed = mnemonic.to_seed("");
+        let private_key = SecretKey::from_slice(&seed[..32]).map_err(|e| {
+            format!(
+                "[Wallet::from_mnemonic] ERROR: Failed to derive private key: {}",
+                e
+            )
+        })?;
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &private_key);
+
+        Ok(Wallet {
+            private_key,
+            public_key,
+        })
+    }
+
+    /// Derives the child wallet at the given index. Like BIP32 hardened derivation, the child key
+    /// is the parent key tweaked by a hash of the parent key and index, so the same wallet
+    /// always derives the same children and they can only be derived with the private key
+    pub fn derive_child(&self, index: u32) -> Result<Self, Box<dyn Error>> {
+        let mut hasher = Sha256::new();
+        hasher.update(CHILD_KEY_PREFIX);
+        hasher.update(self.private_key.secret_bytes());
+        hasher.update(index.to_be_bytes());
+        let tweak = Scalar::from_be_bytes(hasher.finalize().into()).map_err(|e| {
+            format!(
+                "[Wallet::derive_child] ERROR: Invalid tweak for index {}: {}",
+                index, e
+            )
+        })?;
+        let private_key = self.private_key.add_tweak(&tweak).map_err(|e| {
+            format!(
+                "[Wallet::derive_child] ERROR: Failed to derive child {}: {}",
+                index, e
+            )
+        })?;
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &private_key);
+
+        Ok(Wallet {
+            private_key,
+            public_key,
+        })
+    }
+
     /// Gets the full wallet address from a given wallet using the public key
     pub fn get_wallet_address(&self) -> Address {
         Address::new_from_key(self.public_key)
@@ -37,9 +115,37 @@ impl Wallet {
         &self.private_key
     }
 
+    /// Signs every input of the given tx with this wallet's key. Any existing signatures and pub
+    /// keys are replaced, and the tx id is recomputed, so an unsigned tx skeleton built elsewhere
+    /// can be signed without access to the utxo set.
+    pub fn sign_tx(&self, tx: &mut Tx) -> Result<(), Box<dyn Error>> {
+        if tx.is_coinbase() {
+            return Err("[Wallet::sign_tx] ERROR: Coinbase txs cannot be signed".into());
+        }
+        for input in &mut tx.inputs {
+            input.signature = empty_signature();
+            input.pub_key = self.public_key;
+        }
+        tx.id = tx.hash()?;
+        tx.sign(&self.private_key)
+    }
+
+    /// Signs an arbitrary message with this wallet's key. Together with the pub key, the signature
+    /// proves ownership of the wallet's address without spending from it
+    pub fn sign_message(&self, msg: &[u8]) -> Signature {
+        Secp256k1::new().sign_ecdsa(&message_digest(msg), &self.private_key)
+    }
+
+    /// Builds a wallet from hex-encoded keys. Fails if the pub key doesn't belong to the private key
     pub fn from_keys(pub_key: String, priv_key: String) -> Result<Self, Box<dyn Error>> {
-        let private_key = SecretKey::from_str(&priv_key)?;
-        let public_key = PublicKey::from_str(&pub_key)?;
+        let private_key = SecretKey::from_str(&priv_key)
+            .map_err(|e| format!("[Wallet::from_keys] ERROR: Invalid private key: {}", e))?;
+        let public_key = PublicKey::from_str(&pub_key)
+            .map_err(|e| format!("[Wallet::from_keys] ERROR: Invalid public key: {}", e))?;
+
+        if PublicKey::from_secret_key(&Secp256k1::new(), &private_key) != public_key {
+            return Err("[Wallet::from_keys] ERROR: Public key does not match private key".into());
+        }
 
         Ok(Wallet {
             private_key,
@@ -47,3 +153,161 @@ impl Wallet {
         })
     }
 }
+
+/// Returns true if the signature over the message was made by the given pub key's private key
+pub fn verify_message(pub_key: &PublicKey, msg: &[u8], sig: &Signature) -> bool {
+    Secp256k1::new()
+        .verify_ecdsa(&message_digest(msg), sig, pub_key)
+        .is_ok()
+}
+
+/// Returns the digest signed for a message - the sha256 hash of the prefixed message
+fn message_digest(msg: &[u8]) -> Message {
+    let digest = Sha256::new()
+        .chain_update(SIGNED_MESSAGE_PREFIX)
+        .chain_update(msg)
+        .finalize();
+    Message::from_digest(digest.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        req_types::TxJson,
+        tx::{TxOutput, network_id},
+    };
+
+    const PHRASE_12: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn same_mnemonic_gives_same_address() {
+        let first = Wallet::from_mnemonic(PHRASE_12).unwrap();
+        let second = Wallet::from_mnemonic(PHRASE_12).unwrap();
+        assert_eq!(
+            first.get_wallet_address().get_full_address(),
+            second.get_wallet_address().get_full_address()
+        );
+
+        let phrase_24 = format!("{} art", ["abandon"; 23].join(" "));
+        let third = Wallet::from_mnemonic(&phrase_24).unwrap();
+        assert_ne!(
+            first.get_wallet_address().get_full_address(),
+            third.get_wallet_address().get_full_address()
+        );
+    }
+
+    #[test]
+    fn generated_mnemonic_recovers_wallet() {
+        let (wallet, phrase) = Wallet::generate_mnemonic();
+        assert_eq!(phrase.split_whitespace().count(), 12);
+
+        let recovered = Wallet::from_mnemonic(&phrase).unwrap();
+        assert_eq!(
+            wallet.get_wallet_address().get_full_address(),
+            recovered.get_wallet_address().get_full_address()
+        );
+        assert_eq!(wallet.private_key(), recovered.private_key());
+    }
+
+    #[test]
+    fn from_mnemonic_rejects_invalid_phrase() {
+        // The last word fails the checksum
+        let bad_checksum = PHRASE_12.replace("about", "abandon");
+        assert!(Wallet::from_mnemonic(&bad_checksum).is_err());
+        assert!(Wallet::from_mnemonic("not a mnemonic").is_err());
+    }
+
+    #[test]
+    fn sign_tx_signs_every_input_of_a_skeleton() {
+        let wallet = Wallet::new();
+        let pub_key_hash = *wallet.get_wallet_address().pub_key_hash();
+        let utxos = HashMap::from([
+            (
+                [1u8; 32],
+                HashMap::from([(
+                    0,
+                    TxOutput {
+                        value: 40,
+                        pub_key_hash,
+                    },
+                )]),
+            ),
+            (
+                [2u8; 32],
+                HashMap::from([(
+                    3,
+                    TxOutput {
+                        value: 40,
+                        pub_key_hash,
+                    },
+                )]),
+            ),
+        ]);
+        // The skeleton is built by another party, who doesn't hold the wallet's key
+        let builder = Wallet::new();
+        let to = Wallet::new().get_wallet_address();
+        let skeleton = Tx::new(&builder, &to, 70, 0, utxos).unwrap();
+        let mut tx = TxJson::from_tx(&skeleton).unwrap().to_tx().unwrap();
+        wallet.sign_tx(&mut tx).unwrap();
+
+        assert_eq!(tx.inputs.len(), 2);
+        let msg = Message::from_digest(tx.sig_hash(&network_id()).unwrap());
+        for input in &tx.inputs {
+            assert_eq!(input.pub_key, *wallet.pub_key());
+            assert!(
+                Secp256k1::new()
+                    .verify_ecdsa(&msg, &input.signature, &input.pub_key)
+                    .is_ok()
+            );
+        }
+        // The id commits to the signing wallet's pub keys
+        assert_ne!(tx.id, skeleton.id);
+    }
+
+    #[test]
+    fn derived_children_are_deterministic_from_seed() {
+        let address = |wallet: &Wallet| wallet.get_wallet_address().get_full_address();
+        let children = |wallet: &Wallet| -> Vec<String> {
+            (0..3)
+                .map(|index| address(&wallet.derive_child(index).unwrap()))
+                .collect()
+        };
+
+        // Recovering the seed recovers every derived address
+        let first = children(&Wallet::from_mnemonic(PHRASE_12).unwrap());
+        let second = children(&Wallet::from_mnemonic(PHRASE_12).unwrap());
+        assert_eq!(first, second);
+
+        let root = Wallet::from_mnemonic(PHRASE_12).unwrap();
+        let mut distinct = first.clone();
+        distinct.push(address(&root));
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), 4);
+        assert_ne!(children(&Wallet::new()), first);
+    }
+
+    #[test]
+    fn signed_message_verifies_only_for_signer() {
+        let wallet = Wallet::new();
+        let msg = b"I own this address";
+        let sig = wallet.sign_message(msg);
+
+        assert!(verify_message(wallet.pub_key(), msg, &sig));
+        assert!(!verify_message(
+            wallet.pub_key(),
+            b"I own another address",
+            &sig
+        ));
+        let other = Wallet::new();
+        assert!(!verify_message(other.pub_key(), msg, &sig));
+        assert!(!verify_message(
+            wallet.pub_key(),
+            msg,
+            &other.sign_message(msg)
+        ));
+    }
+}
@@ -1,45 +1,131 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
-    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
     time::{SystemTime, UNIX_EPOCH},
-    u32,
 };
 
 use crate::{
     blockchain::{
-        chain::{get_chain_height, get_last_block},
+        chain::{get_block_by_height, get_chain_height, get_last_block},
         merkle::MerkleTree,
-        transaction::tx::{coinbase_tx, TxVerify, COINBASE_REWARD},
+        params::network_params,
+        transaction::{
+            tx::{block_reward, coinbase_tx, get_total_fees, get_tx_fee, TxVerify},
+            utxo::UTXOSet,
+        },
     },
-    cli::db::{self, get_block, get_last_hash},
+    cli::db::{self, get_block, get_last_hash, MAX_ORPHAN_CHAIN_AGE},
 };
 use core_lib::{address::Address, tx::Tx};
 use hex;
+use log::{debug, info};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 pub type OrphanBlocks = HashMap<[u8; 32], Block>;
 
+/// MAX_BLOCK_TX_BYTES is the max combined serialized size of all txs within a block, including the coinbase tx
+pub const MAX_BLOCK_TX_BYTES: usize = 1024 * 1024;
+/// MAX_BLOCK_BYTES is the max serialized size of a whole block. Headers are small and fixed in
+/// size, so this leaves ample room for one on top of MAX_BLOCK_TX_BYTES
+pub const MAX_BLOCK_BYTES: usize = MAX_BLOCK_TX_BYTES + 1024;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
     pub txs: Vec<Tx>,
     pub prev_hash: [u8; 32],
+    /// Merkle root of the block's txs. Committed to by the block hash, and kept when the txs
+    /// are discarded by pruning
+    pub merkle_root: [u8; 32],
+    pub hash: [u8; 32],
+    pub nonce: u32,
+    /// Extends the nonce search space. Incremented each time the nonce space is exhausted
+    pub extranonce: u32,
+    pub height: u32,
+    pub timestamp: u64,
+}
+
+/// BlockHeader holds the fields of a block that its hash commits to, along with the hash itself.
+/// Headers are small and fixed in size, so the proof of work and linkage of a chain can be
+/// checked before its txs are downloaded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub prev_hash: [u8; 32],
+    pub merkle_root: [u8; 32],
     pub hash: [u8; 32],
     pub nonce: u32,
+    pub extranonce: u32,
     pub height: u32,
     pub timestamp: u64,
+    /// Number of leading zero bits the hash must have. Blocks don't store it, since it is fixed
+    /// per network, so it isn't committed to by the hash and must match the network's
+    pub difficulty: usize,
+}
+
+impl BlockHeader {
+    /// Hash the header into a single SHA256 hash. This is the hash of the whole block
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.prev_hash);
+        hasher.update(self.merkle_root);
+        // Use little-endian for consitency
+        hasher.update(self.nonce.to_le_bytes());
+        hasher.update(self.extranonce.to_le_bytes());
+        hasher.update(self.height.to_le_bytes());
+        hasher.update(self.timestamp.to_le_bytes());
+
+        hasher.finalize().into()
+    }
+
+    /// Returns true if the stored hash matches the header, and the header's difficulty is the
+    /// network's and met by the hash
+    pub fn verify_pow(&self) -> bool {
+        let hash = self.hash();
+        self.difficulty == network_params().difficulty
+            && hash_meets_target(&hash, &target_for(self.difficulty))
+            && hash == self.hash
+    }
+
+    /// Returns the work proven by the header - the expected number of hashes needed to meet its
+    /// target. Each leading zero bit required of the hash doubles the work
+    pub fn work(&self) -> u128 {
+        1u128
+            .checked_shl(self.difficulty as u32)
+            .unwrap_or(u128::MAX)
+    }
+
+    /// Returns true if this header directly follows the given parent - linking to its hash,
+    /// incrementing its height by one and having a later timestamp
+    pub fn extends(&self, parent: &BlockHeader) -> bool {
+        self.prev_hash == parent.hash
+            && self.height == parent.height + 1
+            && self.timestamp > parent.timestamp
+    }
+
+    /// Returns true if the header's timestamp is further ahead of local time than the network
+    /// allows
+    pub fn is_too_far_in_future(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.timestamp > now.saturating_add(network_params().max_time_drift)
+    }
 }
 
 impl Block {
     /// Create the genesis block from a coinbase transaction
     pub fn genesis(addr: &Address) -> Result<Self, Box<dyn Error>> {
-        let cbtx = coinbase_tx(addr)?;
+        let txs = vec![coinbase_tx(addr, 0, 0)?];
         Ok(Block {
             hash: [0u8; 32], // Initialize as empty
-            txs: vec![cbtx],
+            merkle_root: merkle_tree_of(&txs)?.root.hash,
+            txs,
             prev_hash: [0u8; 32],
             nonce: 0,
+            extranonce: 0,
             height: 0,
             timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
@@ -48,134 +134,235 @@ impl Block {
         })
     }
 
+    /// Returns the size of the block in bytes, as serialized for storage and the network
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).expect("[Block::size] ERROR: Failed to size block") as usize
+    }
+
     pub fn is_genesis(&self) -> bool {
         self.prev_hash == [0u8; 32] && self.height == 0
     }
 
+    /// Returns true if the block's tx bodies have been pruned, leaving only its header. Every
+    /// other block holds at least its coinbase tx
+    pub fn is_pruned(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// Returns a header-only copy of the block. The txs are discarded, and their merkle root
+    /// is kept so the block hash still verifies
+    pub fn header_only(&self) -> Result<Self, Box<dyn Error>> {
+        Ok(Block {
+            txs: Vec::new(),
+            prev_hash: self.prev_hash,
+            merkle_root: self.merkle_root,
+            hash: self.hash,
+            nonce: self.nonce,
+            extranonce: self.extranonce,
+            height: self.height,
+            timestamp: self.timestamp,
+        })
+    }
+
+    /// Returns the block's header, without its txs
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            prev_hash: self.prev_hash,
+            merkle_root: self.merkle_root,
+            hash: self.hash,
+            nonce: self.nonce,
+            extranonce: self.extranonce,
+            height: self.height,
+            timestamp: self.timestamp,
+            difficulty: network_params().difficulty,
+        }
+    }
+
+    /// Returns the work proven by the block - the expected number of hashes needed to meet its
+    /// target
+    pub fn work(&self) -> u128 {
+        self.header().work()
+    }
+
+    /// Verifies the block's proof of work and hash, and that it doesn't conflict with a
+    /// checkpoint. Only the header is checked, so pruned blocks can be verified too
+    pub fn verify_header(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.header().verify_pow()
+            && network_params().matches_checkpoint(self.height, &self.hash))
+    }
+
     /// Create and mine a new block
     pub fn new(reward_addr: &Address) -> Result<Self, Box<dyn Error>> {
-        let cbtx = coinbase_tx(reward_addr)?;
         let prev_block = get_last_block()?;
-        let txs: Vec<Tx> = db::get_mempool().values().cloned().collect();
+        let height = prev_block.height + 1;
+
+        let (txs, fees) = select_next_block_txs(reward_addr, height)?;
+
+        // The miner collects the fees of all included txs on top of the block reward
+        let cbtx = coinbase_tx(reward_addr, fees, height)?;
         let mut all_txs = Vec::with_capacity(txs.len() + 1);
         all_txs.push(cbtx); // Add coinbase first
         all_txs.extend_from_slice(&txs); // Add the rest of the transactions
 
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("[Block::new] ERROR: Failed to create timestamp")
+            .as_secs();
+
         Ok(Block {
             hash: [0u8; 32], // Initialize as empty
+            merkle_root: merkle_tree_of(&all_txs)?.root.hash,
             txs: all_txs,
             prev_hash: prev_block.hash,
             nonce: 0,
-            height: prev_block.height + 1,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("[Block::new] ERROR: Failed to create timestamp")
-                .as_secs(),
+            extranonce: 0,
+            height,
+            // Timestamps must always move forward from the parent block
+            timestamp: now.max(prev_block.timestamp + 1),
         })
     }
 
-    /// Mines a designated block using proof of work
-    pub fn mine(&mut self) -> Result<(), Box<dyn Error>> {
-        let target = get_target_difficulty();
-        let mut nonce: u32 = 0;
-        let mut hash: [u8; 32] = [0; 32];
-        let max = u32::MAX;
-
-        println!("Validating block...");
+    /// Mines a designated block using proof of work, until its hash meets the given target.
+    /// Mining stops early with an error if the cancel flag is set, e.g. when a competing block
+    /// is committed at the same height.
+    pub fn mine(&mut self, target: &[u8; 32], cancel: &AtomicBool) -> Result<(), Box<dyn Error>> {
+        debug!("Validating block...");
         for tx in &self.txs {
             tx.verify()
                 .map_err(|e| format!("[block::mine] ERROR: Cannot mine block - {:?}", e))?;
         }
-        println!("Validation successful!");
-        println!("Mining block:");
-        while nonce < max {
-            self.nonce = nonce;
-            hash = self.hash()?;
-
-            // Print hash repeating over same line
-            let hex_str = hex::encode(&hash);
-            print!("\r{}", hex_str);
-            std::io::stdout().flush().unwrap();
-
-            // If hash is less than target, it meets our PoW criteria
-            if hash < target {
-                break;
-            } else {
-                // Increasing our nonce changes the block so the next hash will be different
-                nonce += 1
-            }
-        }
-        // Leave an empty line after the hash is found
-        println!();
+        debug!("Validation successful!");
+        info!("Mining block at height {}...", self.height);
+        self.search_pow(target, u32::MAX, cancel)
+    }
 
-        // TODO: investigate removing the following 2 lines
-        self.hash = hash;
-        self.nonce = nonce;
-        println!("Hash found: {}", hex::encode(&hash));
-        println!("Nonce: {}", nonce);
+    /// Searches nonces up to `max_nonce` for a hash meeting the target, rolling the extranonce
+    /// each time they run out
+    fn search_pow(
+        &mut self,
+        target: &[u8; 32],
+        max_nonce: u32,
+        cancel: &AtomicBool,
+    ) -> Result<(), Box<dyn Error>> {
+        loop {
+            for nonce in 0..=max_nonce {
+                if cancel.load(Ordering::Relaxed) {
+                    return Err("[block::mine] ERROR: Mining cancelled".into());
+                }
+                // Changing our nonce changes the block so the next hash will be different
+                self.nonce = nonce;
+                let hash = self.hash()?;
 
-        // Prepare block for db
-        let block_hash = self.hash()?;
-        // Store block ref and last hash
-        db::put_block(self);
-        db::put_last_hash(&block_hash);
-        Ok(())
+                // If hash is less than target, it meets our PoW criteria
+                if hash_meets_target(&hash, target) {
+                    self.hash = hash;
+                    info!(
+                        "Block mined with hash {}, nonce {} and extranonce {}",
+                        hex::encode(hash),
+                        nonce,
+                        self.extranonce
+                    );
+                    return Ok(());
+                }
+            }
+            // The nonce space is exhausted, so roll the extranonce to search a new set of hashes
+            self.extranonce = self
+                .extranonce
+                .checked_add(1)
+                .ok_or("[block::mine] ERROR: Extranonce space exhausted")?;
+            debug!(
+                "Nonce space exhausted, rolling extranonce to {}",
+                self.extranonce
+            );
+        }
     }
 
     /// Hash the block into a single SHA256 hash
     pub fn hash(&self) -> Result<[u8; 32], Box<dyn Error>> {
-        let mut hasher = Sha256::new();
-        hasher.update(self.prev_hash);
-        hasher.update(self.hash_txs()?);
-        // Use little-endian for consitency
-        hasher.update(self.nonce.to_le_bytes());
-        hasher.update(self.height.to_le_bytes());
-        hasher.update(self.timestamp.to_le_bytes());
+        Ok(self.header().hash())
+    }
 
-        let result = hasher.finalize();
-        Ok(result.into())
+    /// Builds a Merkle tree from the hashes of the block's transactions
+    pub fn merkle_tree(&self) -> Result<MerkleTree, Box<dyn Error>> {
+        merkle_tree_of(&self.txs)
     }
 
-    /// Using a Merkle tree, derive the hash of a root block's transactions
-    fn hash_txs(&self) -> Result<[u8; 32], Box<dyn Error>> {
-        let tx_hashes: Result<Vec<Vec<u8>>, Box<dyn Error>> = self
+    /// Returns true if the stored merkle root matches the block's txs. The block hash only
+    /// commits to the stored root, so this catches txs altered after the block was mined
+    fn has_valid_merkle_root(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.merkle_tree()?.root.hash == self.merkle_root)
+    }
+
+    /// Verifies every tx of the block. Verification only reads the utxo set, so txs are checked
+    /// in parallel. Errors are converted to strings, since boxed errors can't be sent between
+    /// threads
+    fn verify_txs(&self) -> Result<bool, String> {
+        let results: Vec<Result<bool, String>> = self
             .txs
-            .iter()
-            .map(|tx| tx.hash().map(|h| h.to_vec()))
+            .par_iter()
+            .map(|tx| tx.verify().map_err(|e| e.to_string()))
             .collect();
-
-        let tx_hashes = tx_hashes?;
-
-        let tree = MerkleTree::new(tx_hashes);
-
-        Ok(tree.root.hash)
+        for res in results {
+            if !res? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 
     pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
-        if self.txs.is_empty() {
+        // Pruned blocks are only ever stored locally, and must never be accepted from peers
+        if self.is_pruned() || get_txs_size(&self.txs)? > MAX_BLOCK_TX_BYTES {
             return Ok(false);
         }
 
-        // Verify txs
-        for tx in &self.txs {
-            if !tx.verify()? {
-                return Ok(false);
-            }
+        if self.is_too_far_in_future()
+            || !self.has_single_coinbase()
+            || !self.has_valid_coinbase_output()
+            || !self.has_valid_merkle_root()?
+        {
+            return Ok(false);
+        }
+
+        // Txs are verified against the utxo set independently, so we must ensure
+        // no two txs in the block spend the same output
+        if self.contains_double_spend() {
+            return Ok(false);
         }
 
-        // Verify coinbase tx
+        if !self.verify_txs()? {
+            return Ok(false);
+        }
+
+        // Verify coinbase tx. The coinbase must claim exactly the height's block reward plus the fees
+        // left unclaimed by the rest of the block's txs
         let coinbase = &self.txs[0];
-        if !coinbase.is_coinbase() || coinbase.outputs[0].value != COINBASE_REWARD {
+        let fees = match get_total_fees(&self.txs[1..]) {
+            Ok(f) => f,
+            Err(_) => return Ok(false),
+        };
+        if Some(coinbase.outputs[0].value) != block_reward(self.height).checked_add(fees) {
             return Ok(false);
         }
 
-        // Verify PoW
-        let target = get_target_difficulty();
-        let hash = self.hash()?;
-        if hash >= target || hash != self.hash {
+        // Verify PoW, and ensure the block doesn't conflict with a checkpoint
+        if !self.verify_header()? {
             return Ok(false);
         }
 
+        // Ensure this block correctly extends its parent. Blocks with an unknown parent
+        // fail verification here, and may be considered as orphans instead
+        if !self.is_genesis() {
+            match get_block(&self.prev_hash)? {
+                Some(parent) => {
+                    if !self.extends(&parent) {
+                        return Ok(false);
+                    }
+                }
+                None => return Ok(false),
+            }
+        }
+
         // Ensure this block is a continuation of the chain
         match get_last_hash() {
             Ok(lh) => {
@@ -191,88 +378,787 @@ impl Block {
                 }
             }
         };
-        return Ok(true);
+        Ok(true)
+    }
+
+    /// Verifies the block's txs against the given utxo set, which must hold the chain state as
+    /// of the block's parent. Unlike `verify`, this doesn't read the db, so blocks already
+    /// applied to the chain can be re-verified by replaying the chain from genesis
+    pub fn verify_txs_against(&self, utxos: &UTXOSet) -> Result<bool, Box<dyn Error>> {
+        if self.txs.is_empty()
+            || get_txs_size(&self.txs)? > MAX_BLOCK_TX_BYTES
+            || !self.has_single_coinbase()
+            || !self.has_valid_coinbase_output()
+            || !self.has_valid_merkle_root()?
+            || self.contains_double_spend()
+        {
+            return Ok(false);
+        }
+
+        let mut fees: u64 = 0;
+        for tx in &self.txs[1..] {
+            if !tx.verify_against(utxos)? {
+                return Ok(false);
+            }
+            // Verification ensures every input exists and covers the outputs
+            let input_sum: u64 = tx
+                .inputs
+                .iter()
+                .filter_map(|input| utxos.get(&input.prev_tx_id)?.get(&input.out))
+                .map(|tx_out| u64::from(tx_out.value))
+                .sum();
+            let output_sum: u64 = tx.outputs.iter().map(|o| u64::from(o.value)).sum();
+            fees += input_sum - output_sum;
+        }
+
+        let coinbase = &self.txs[0];
+        Ok(u64::from(coinbase.outputs[0].value) == u64::from(block_reward(self.height)) + fees)
+    }
+
+    /// Returns true if this block directly follows the given parent - linking to its hash,
+    /// incrementing its height by one and having a later timestamp
+    pub fn extends(&self, parent: &Block) -> bool {
+        self.header().extends(&parent.header())
+    }
+
+    /// Returns true if the block's timestamp is further ahead of local time than the network
+    /// allows. Kept apart from `extends`, which only orders the block after its parent
+    pub fn is_too_far_in_future(&self) -> bool {
+        self.header().is_too_far_in_future()
+    }
+
+    /// Returns true if the block's first tx is a coinbase and no other tx is. Extra coinbase txs
+    /// would otherwise pass verification, letting a miner mint more than the block reward
+    fn has_single_coinbase(&self) -> bool {
+        self.txs.first().is_some_and(|tx| tx.is_coinbase())
+            && !self.txs[1..].iter().any(|tx| tx.is_coinbase())
+    }
+
+    /// Returns true if the block's coinbase pays out through exactly one output, to a plausible
+    /// address. Only the first output is checked against the reward, so extra outputs could
+    /// otherwise mint coins. An all-zero pub key hash belongs to no real key, and would burn
+    /// the reward
+    fn has_valid_coinbase_output(&self) -> bool {
+        self.txs
+            .first()
+            .is_some_and(|tx| tx.outputs.len() == 1 && tx.outputs[0].pub_key_hash != [0u8; 20])
+    }
+
+    /// Returns true if any output is spent by more than one input across the block's txs
+    fn contains_double_spend(&self) -> bool {
+        let mut spent_outs: HashSet<([u8; 32], u32)> = HashSet::new();
+        for tx in self.txs.iter().filter(|tx| !tx.is_coinbase()) {
+            for input in &tx.inputs {
+                if !spent_outs.insert((input.prev_tx_id, input.out)) {
+                    return true;
+                }
+            }
+        }
+        false
     }
 
     /// Verifies a block without checking tx validity. Txs will be checked
     /// if/when the orphan is added to the chain.
     pub fn verify_orphan(&self) -> Result<bool, Box<dyn Error>> {
-        if self.txs.is_empty() {
+        // Pruned blocks are only ever stored locally, and must never be accepted from peers
+        if self.is_pruned() || get_txs_size(&self.txs)? > MAX_BLOCK_TX_BYTES {
+            return Ok(false);
+        }
+
+        if self.is_too_far_in_future()
+            || !self.has_single_coinbase()
+            || !self.has_valid_coinbase_output()
+            || !self.has_valid_merkle_root()?
+        {
             return Ok(false);
         }
 
-        // Verify coinbase tx
+        // Verify coinbase tx. Fees can't be resolved until the orphan's inputs are known,
+        // so only ensure the coinbase claims at least the block reward
         let coinbase = &self.txs[0];
-        if !coinbase.is_coinbase() || coinbase.outputs[0].value != COINBASE_REWARD {
+        if coinbase.outputs[0].value < block_reward(self.height) {
             return Ok(false);
         }
 
-        // Verify PoW
-        let target = get_target_difficulty();
-        let hash = self.hash()?;
-        if hash >= target || hash != self.hash {
+        // Verify PoW, and ensure the block doesn't conflict with a checkpoint
+        if !self.verify_header()? {
             return Ok(false);
         }
 
-        // Ensure this block is not from an invalid height
+        // Forks may win with fewer blocks if they prove more work, so blocks below the tip are
+        // kept unless they are too far behind it to ever be considered, or are already stored
         if let Ok(h) = get_chain_height() {
-            if self.height <= h {
+            if self.height.saturating_add(MAX_ORPHAN_CHAIN_AGE) < h {
+                return Ok(false);
+            }
+        }
+        if get_block(&self.hash)?.is_some() {
+            return Ok(false);
+        }
+
+        // If the parent is known (the orphan is on a fork), it must be correctly extended
+        if let Some(parent) = get_block(&self.prev_hash)? {
+            if !self.extends(&parent) {
                 return Ok(false);
             }
         }
 
-        return Ok(true);
+        Ok(true)
+    }
+}
+
+/// Selects the mempool txs a block at the given height would include, along with the sum of
+/// their fees. Space is reserved for the block's coinbase, so this matches what `Block::new`
+/// includes
+pub fn select_next_block_txs(
+    reward_addr: &Address,
+    height: u32,
+) -> Result<(Vec<Tx>, u32), Box<dyn Error>> {
+    // The coinbase size doesn't depend on the fees it collects, so we can reserve
+    // space for it before selecting txs from the mempool
+    let cbtx_size = coinbase_tx(reward_addr, 0, height)?.size();
+    select_mempool_txs(MAX_BLOCK_TX_BYTES.saturating_sub(cbtx_size))
+}
+
+/// Greedily selects txs from the mempool, highest fee per byte first, until no more txs fit within the
+/// given number of bytes. Returns the selected txs along with the sum of their fees.
+///
+/// Txs that don't fit are left in the mempool to be included in a future block
+fn select_mempool_txs(max_bytes: usize) -> Result<(Vec<Tx>, u32), Box<dyn Error>> {
+    let mut candidates: Vec<(u32, usize, Tx)> = Vec::new();
+    for tx in db::get_mempool().into_values().map(|entry| entry.tx) {
+        // Txs whose fee can't be resolved spend unknown outputs and can't be mined
+        if let Ok(fee) = get_tx_fee(&tx) {
+            let tx_size = tx.size();
+            candidates.push((fee, tx_size, tx));
+        }
+    }
+    // Highest fee per byte first, compared by cross-multiplying to avoid float rounding. Ties
+    // are broken by tx id so miners with the same mempool build the same block.
+    candidates.sort_by(|(fee_a, size_a, tx_a), (fee_b, size_b, tx_b)| {
+        let rate_a = *fee_a as u128 * *size_b as u128;
+        let rate_b = *fee_b as u128 * *size_a as u128;
+        rate_b.cmp(&rate_a).then_with(|| tx_a.id.cmp(&tx_b.id))
+    });
+
+    let mut selected: Vec<Tx> = Vec::new();
+    let mut used_bytes: usize = 0;
+    let mut fees: u32 = 0;
+    for (fee, tx_size, tx) in candidates {
+        if used_bytes + tx_size > max_bytes {
+            // Smaller txs may still fit in the remaining space
+            continue;
+        }
+        used_bytes += tx_size;
+        fees = fees
+            .checked_add(fee)
+            .ok_or("[block::select_mempool_txs] ERROR: Fee overflow")?;
+        selected.push(tx);
+    }
+
+    Ok((selected, fees))
+}
+
+/// Builds a Merkle tree from the hashes of the given txs
+fn merkle_tree_of(txs: &[Tx]) -> Result<MerkleTree, Box<dyn Error>> {
+    let tx_hashes: Result<Vec<Vec<u8>>, Box<dyn Error>> =
+        txs.iter().map(|tx| tx.hash().map(|h| h.to_vec())).collect();
+
+    Ok(MerkleTree::new(tx_hashes?))
+}
+
+/// Returns the combined serialized size of the given txs in bytes
+pub fn get_txs_size(txs: &[Tx]) -> Result<usize, Box<dyn Error>> {
+    Ok(txs.iter().map(Tx::size).sum())
+}
+
+/// Returns true if the hash is below the target, with both read as big-endian 256-bit integers.
+/// Bytes are compared from the most significant down, so the first differing byte decides
+pub fn hash_meets_target(hash: &[u8; 32], target: &[u8; 32]) -> bool {
+    for (hash_byte, target_byte) in hash.iter().zip(target) {
+        if hash_byte != target_byte {
+            return hash_byte < target_byte;
+        }
     }
+    // A hash equal to the target is not below it
+    false
+}
+
+// Difficulty is fixed per network, but can be made dynamic in future
+pub fn get_target_difficulty() -> [u8; 32] {
+    target_for(network_params().difficulty)
 }
 
-// Difficulty can be made dynamic in future
-const DIFFICULTY: usize = 16;
-fn get_target_difficulty() -> [u8; 32] {
+/// Returns the target requiring the given number of leading zero bits
+fn target_for(difficulty: usize) -> [u8; 32] {
     let mut target = [0u8; 32];
 
     // This PoW algorithm shifts 1 by (256 - Difficulty) to get a target that has zeroes for the first *Difficulty bits
     // When mining, we will hash while changing the nonce until a hash is found that is less
     // than the target - meaning it has the first n bits set to 0
-    let byte_index = DIFFICULTY / 8;
-    let bit_index = DIFFICULTY % 8;
+    let byte_index = difficulty / 8;
+    let bit_index = difficulty % 8;
 
     target[byte_index] = 1 << (7 - bit_index);
     target
 }
 
-pub fn get_blocks_since_height(height: u32) -> Result<Vec<Block>, Box<dyn Error>> {
-    let mut current_block = if let Ok(b) = get_last_block() {
-        b
-    } else {
-        return Err(
-            "[block::get_blocks_since_height] ERROR: Could not find blocks since last height"
-                .into(),
-        );
-    };
+/// Returns the hashes of the main chain blocks from the given height up to the tip, ordered from
+/// highest to lowest. Hashes are read from the height index, so pruned blocks are included
+pub fn get_block_hashes_from_height(from_height: u32) -> Result<Vec<[u8; 32]>, Box<dyn Error>> {
+    let tip_height = get_chain_height().map_err(|_| {
+        "[block::get_block_hashes_from_height] ERROR: Could not find blocks since last height"
+    })?;
+    let mut res = Vec::new();
+    for height in (from_height..=tip_height).rev() {
+        let hash = db::get_block_hash_at_height(height)?.ok_or_else(|| {
+            format!(
+                "[block::get_block_hashes_from_height] ERROR: Could not find block at height {}",
+                height
+            )
+        })?;
+        res.push(hash);
+    }
+    Ok(res)
+}
+
+/// Returns the headers of the main chain blocks from `from_height` up to `to_height`, ordered
+/// from lowest to highest. Heights above the chain tip are ignored. Pruned blocks keep their
+/// headers, so unlike `get_blocks_in_range` the whole chain can be served
+pub fn get_headers_in_range(
+    from_height: u32,
+    to_height: u32,
+) -> Result<Vec<BlockHeader>, Box<dyn Error>> {
+    let mut res: Vec<BlockHeader> = Vec::new();
+    for height in from_height..=to_height.min(get_chain_height()?) {
+        let block = get_block_by_height(height)?.ok_or_else(|| {
+            format!(
+                "[block::get_headers_in_range] ERROR: Could not find block at height {}",
+                height
+            )
+        })?;
+        res.push(block.header());
+    }
+    Ok(res)
+}
 
+/// Returns the main chain blocks from `from_height` up to `to_height`, ordered from lowest to
+/// highest. Heights above the chain tip are ignored, so fewer blocks may be returned than
+/// requested. Errors if any of the blocks have been pruned.
+pub fn get_blocks_in_range(from_height: u32, to_height: u32) -> Result<Vec<Block>, Box<dyn Error>> {
     let mut res: Vec<Block> = Vec::new();
-    let mut block_height = current_block.height;
-    // Trace back blocks until we reach the block height matching the height we have requested
-    // which would be the last height our requesting node has. If we are requesting with height 0,
-    // The genesis block is included in the request
-    while height < block_height || height == 0 {
-        res.push(current_block.clone());
+    for height in from_height..=to_height.min(get_chain_height()?) {
+        let block = get_block_by_height(height)?.ok_or_else(|| {
+            format!(
+                "[block::get_blocks_in_range] ERROR: Could not find block at height {}",
+                height
+            )
+        })?;
+        // Pruned blocks can't be verified by the requester without their txs
+        if block.is_pruned() {
+            return Err(format!(
+                "[block::get_blocks_in_range] ERROR: Block at height {} has been pruned",
+                height
+            )
+            .into());
+        }
+        res.push(block);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use core_lib::{constants::MIN_OUTPUT_VALUE, tx::TxOutput, wallet::Wallet};
+
+    use super::*;
+    use crate::{
+        blockchain::{
+            chain::{commit_block, CommitStatus},
+            transaction::{mempool::add_tx_to_mempool, utxo::find_spendable_utxos},
+        },
+        test_utils::{add_block, block_on, new_chain, next_block, pay, remine, setup},
+    };
+
+    #[test]
+    fn verify_rejects_txs_altered_after_mining() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let mut block = next_block(&wallet.get_wallet_address(), vec![]);
+        assert!(block.verify().unwrap());
+
+        // Redirect the reward without updating the merkle root. The hash only commits to the
+        // root, so the proof of work still holds
+        block.txs[0].outputs[0].pub_key_hash = *Wallet::new().get_wallet_address().pub_key_hash();
+        assert!(block.verify_header().unwrap());
+        assert!(!block.verify().unwrap());
+    }
 
-        if current_block.is_genesis() {
-            break;
+    #[test]
+    fn hash_meets_target_compares_as_256_bit_integers() {
+        // Builds a 256 bit value from the given big-endian bytes, placed at the given offset
+        let value = |offset: usize, bytes: &[u8]| {
+            let mut value = [0u8; 32];
+            value[offset..offset + bytes.len()].copy_from_slice(bytes);
+            value
+        };
+        let target = value(1, &[0x01]);
+
+        // One below the target borrows across every lower byte
+        assert!(hash_meets_target(&value(2, &[0xff; 30]), &target));
+        assert!(!hash_meets_target(&target, &target));
+        assert!(!hash_meets_target(&value(1, &[0x01, 0, 0, 0x01]), &target));
+        // A high byte outweighs every byte below it
+        assert!(!hash_meets_target(
+            &value(0, &[0x01]),
+            &value(1, &[0xff; 31])
+        ));
+        assert!(hash_meets_target(&value(31, &[0xfe]), &value(31, &[0xff])));
+        assert!(!hash_meets_target(&[0xff; 32], &[0xff; 32]));
+        assert!(!hash_meets_target(&[0u8; 32], &[0u8; 32]));
+
+        // Agrees with a numeric comparison for hashes either side of targets around byte boundaries
+        let halves = |value: &[u8; 32]| {
+            (
+                u128::from_be_bytes(value[..16].try_into().unwrap()),
+                u128::from_be_bytes(value[16..].try_into().unwrap()),
+            )
+        };
+        for difficulty in [1, 7, 8, 9, 15, 16, 17, 127, 128, 129] {
+            let target = target_for(difficulty);
+            let (hi, lo) = halves(&target);
+            for (hash_hi, hash_lo) in [
+                (hi, lo.wrapping_sub(1)),
+                (hi.wrapping_sub(1), u128::MAX),
+                (hi, lo.wrapping_add(1)),
+                (hi.wrapping_add(1), 0),
+            ] {
+                let mut hash = [0u8; 32];
+                hash[..16].copy_from_slice(&hash_hi.to_be_bytes());
+                hash[16..].copy_from_slice(&hash_lo.to_be_bytes());
+                assert_eq!(
+                    hash_meets_target(&hash, &target),
+                    halves(&hash) < (hi, lo),
+                    "difficulty {}",
+                    difficulty
+                );
+            }
         }
+    }
 
-        current_block = get_block(&current_block.prev_hash)
-            .map_err(|e| {
-                format!(
-                    "[block::get_blocks_since_height] ERROR: Failed to fetch previous block {}",
-                    e
-                )
-            })?
-            .ok_or_else(|| "[block::get_blocks_since_height] ERROR: Last block not found")?;
+    #[test]
+    fn header_pow_matches_block_pow() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let mut block = next_block(&wallet.get_wallet_address(), vec![]);
+        let block_pow = |block: &Block| {
+            let hash = block.hash().unwrap();
+            hash_meets_target(&hash, &get_target_difficulty()) && hash == block.hash
+        };
+        assert!(block_pow(&block));
+        assert!(block.header().verify_pow());
 
-        block_height = current_block.height;
+        block.nonce = block.nonce.wrapping_add(1);
+        assert!(!block_pow(&block));
+        assert!(!block.header().verify_pow());
     }
 
-    Ok(res)
+    #[test]
+    fn mining_rolls_extranonce_when_nonces_run_out() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let mut block = next_block(&wallet.get_wallet_address(), vec![]);
+
+        // With only 256 nonces per extranonce, a 16 bit target is unlikely to be met before
+        // the extranonce is rolled
+        let target = target_for(16);
+        block.nonce = 0;
+        block.extranonce = 0;
+        while block.extranonce == 0 {
+            block.timestamp += 1;
+            block
+                .search_pow(&target, 255, &AtomicBool::new(false))
+                .unwrap();
+        }
+
+        assert!(block.nonce <= 255);
+        assert!(hash_meets_target(&block.hash, &target));
+        assert_eq!(block.hash().unwrap(), block.hash);
+        let mut header = block.header();
+        header.extranonce -= 1;
+        assert_ne!(header.hash(), block.hash);
+    }
+
+    #[test]
+    fn verify_requires_coinbase_to_claim_fees() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = Wallet::new().get_wallet_address();
+        let tx = pay(&wallet, &miner, 50, 5);
+        let mut block = next_block(&miner, vec![tx]);
+        assert_eq!(
+            block.txs[0].outputs[0].value,
+            block_reward(block.height) + 5
+        );
+        assert!(block.verify().unwrap());
+
+        block.txs[0] = coinbase_tx(&miner, 0, block.height).unwrap();
+        remine(&mut block);
+        assert!(!block.verify().unwrap());
+    }
+
+    #[test]
+    fn block_assembly_selects_highest_fee_txs_that_fit() {
+        let _guard = setup();
+        let to = new_chain().get_wallet_address();
+        let senders: Vec<Wallet> = (0..3).map(|_| Wallet::new()).collect();
+        for sender in &senders {
+            add_block(&sender.get_wallet_address(), vec![]);
+        }
+        let txs: Vec<Tx> = senders
+            .iter()
+            .zip([5, 15, 10])
+            .map(|(sender, fee)| pay(sender, &to, 50, fee))
+            .collect();
+        for tx in &txs {
+            add_tx_to_mempool(tx).unwrap();
+        }
+
+        // Leave room for only two of the three txs
+        let max_bytes = txs.iter().map(Tx::size).max().unwrap() * 2;
+        let (selected, fees) = select_mempool_txs(max_bytes).unwrap();
+
+        let selected_ids: Vec<[u8; 32]> = selected.iter().map(|tx| tx.id).collect();
+        assert_eq!(selected_ids, vec![txs[1].id, txs[2].id]);
+        assert_eq!(fees, 25);
+        assert_eq!(db::get_mempool().len(), 3);
+    }
+
+    #[test]
+    fn verify_rejects_oversized_block() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let mut block = next_block(&miner, vec![]);
+        let tx_out = block.txs[0].outputs[0];
+        block.txs.push(Tx {
+            id: [1u8; 32],
+            inputs: vec![],
+            outputs: vec![tx_out; MAX_BLOCK_TX_BYTES / 24 + 1],
+        });
+        remine(&mut block);
+
+        assert!(get_txs_size(&block.txs).unwrap() > MAX_BLOCK_TX_BYTES);
+        assert!(!block.verify().unwrap());
+    }
+
+    #[test]
+    fn verify_rejects_txs_spending_the_same_output() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+        let first = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 0);
+        let second = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 0);
+        assert!(next_block(&miner, vec![first.clone()]).verify().unwrap());
+        assert!(next_block(&miner, vec![second.clone()]).verify().unwrap());
+
+        let block = next_block(&miner, vec![first, second]);
+        assert!(!block.verify().unwrap());
+    }
+
+    #[test]
+    fn verify_rejects_block_with_bad_height() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let mut block = next_block(&miner, vec![]);
+        block.height += 1;
+        block.txs[0] = coinbase_tx(&miner, 0, block.height).unwrap();
+        remine(&mut block);
+
+        assert!(!block.verify().unwrap());
+    }
+
+    #[test]
+    fn verify_rejects_block_older_than_parent() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let parent = get_last_block().unwrap();
+        let mut block = next_block(&miner, vec![]);
+        block.timestamp = parent.timestamp;
+        remine(&mut block);
+
+        assert!(!block.verify().unwrap());
+    }
+
+    #[test]
+    fn block_with_unknown_parent_is_orphaned() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let parent = next_block(&miner, vec![]);
+        let child = block_on(&parent, &miner, vec![]);
+
+        assert!(!child.verify().unwrap());
+        assert!(matches!(
+            commit_block(&child).unwrap(),
+            CommitStatus::Orphaned
+        ));
+    }
+
+    #[test]
+    fn mining_stops_when_cancelled() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let mut block = next_block(&wallet.get_wallet_address(), vec![]);
+
+        let cancel = AtomicBool::new(true);
+        assert!(block.mine(&get_target_difficulty(), &cancel).is_err());
+
+        // No hash meets an all zero target, so only the cancel flag can stop the search
+        let cancel = AtomicBool::new(false);
+        let started = std::time::Instant::now();
+        let res = std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                cancel.store(true, Ordering::Relaxed);
+            });
+            block.search_pow(&[0u8; 32], u32::MAX, &cancel)
+        });
+
+        assert!(res.unwrap_err().to_string().contains("Mining cancelled"));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn block_assembly_order_is_by_fee_rate_then_id() {
+        let _guard = setup();
+        let to = new_chain().get_wallet_address();
+        let senders: Vec<Wallet> = (0..3).map(|_| Wallet::new()).collect();
+        for sender in &senders {
+            add_block(&sender.get_wallet_address(), vec![]);
+        }
+        let txs: Vec<Tx> = senders
+            .iter()
+            .zip([5, 20, 5])
+            .map(|(sender, fee)| pay(sender, &to, 50, fee))
+            .collect();
+        for tx in &txs {
+            add_tx_to_mempool(tx).unwrap();
+        }
+
+        // Each tx pays at least the fee rate of the next, with equal rates ordered by id. The
+        // same mempool always gives the same order
+        let fees = HashMap::from([(txs[0].id, 5u64), (txs[1].id, 20), (txs[2].id, 5)]);
+        let block_ids = || -> Vec<[u8; 32]> {
+            let block = Block::new(&to).unwrap();
+            block.txs[1..].iter().map(|tx| tx.id).collect()
+        };
+        let ids = block_ids();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[0], txs[1].id);
+        let size = |id: &[u8; 32]| txs.iter().find(|tx| tx.id == *id).unwrap().size() as u64;
+        for pair in ids.windows(2) {
+            let rate_a = fees[&pair[0]] * size(&pair[1]);
+            let rate_b = fees[&pair[1]] * size(&pair[0]);
+            assert!(rate_a > rate_b || (rate_a == rate_b && pair[0] < pair[1]));
+        }
+        assert_eq!(block_ids(), ids);
+
+        // With room for a single tx, only the high fee tx is included
+        let (selected, fees) = select_mempool_txs(txs[1].size()).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].id, txs[1].id);
+        assert_eq!(fees, 20);
+    }
+    /// Verifies the block's txs one at a time, as blocks were verified before txs were checked
+    /// in parallel
+    fn verify_txs_sequentially(block: &Block) -> Result<bool, String> {
+        for tx in &block.txs {
+            if !tx.verify().map_err(|e| e.to_string())? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns a block of many txs, each spending its own output of an earlier block
+    fn block_of_many_txs() -> Block {
+        let wallet = new_chain();
+        let addr = wallet.get_wallet_address();
+        let recipients = vec![(addr.clone(), MIN_OUTPUT_VALUE); 10];
+        let utxos = find_spendable_utxos(addr.pub_key_hash(), 100).unwrap();
+        let split = Tx::new_multi(&wallet, &recipients, 0, utxos).unwrap();
+        add_block(&addr, vec![split.clone()]);
+
+        let to = Wallet::new().get_wallet_address();
+        let txs = (0..recipients.len() as u32)
+            .map(|out_idx| {
+                let tx_out = split.outputs[out_idx as usize];
+                let utxos = HashMap::from([(split.id, HashMap::from([(out_idx, tx_out)]))]);
+                Tx::new(&wallet, &to, MIN_OUTPUT_VALUE, 0, utxos).unwrap()
+            })
+            .collect();
+        next_block(&addr, txs)
+    }
+
+    #[test]
+    fn parallel_tx_verification_matches_sequential() {
+        let _guard = setup();
+        let mut block = block_of_many_txs();
+        assert_eq!(block.verify_txs(), Ok(true));
+        assert_eq!(block.verify_txs(), verify_txs_sequentially(&block));
+
+        // A single invalid tx fails the block either way
+        block.txs[7].outputs[0].value += 1;
+        assert_eq!(block.verify_txs(), Ok(false));
+        assert_eq!(block.verify_txs(), verify_txs_sequentially(&block));
+    }
+
+    /// Compares the time taken to verify a block's txs in parallel and sequentially. Run with
+    /// `cargo test --release parallel_tx_verification_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn parallel_tx_verification_benchmark() {
+        let _guard = setup();
+        let block = block_of_many_txs();
+        let rounds = 100;
+
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            assert_eq!(block.verify_txs(), Ok(true));
+        }
+        let parallel = start.elapsed();
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            assert_eq!(verify_txs_sequentially(&block), Ok(true));
+        }
+        let sequential = start.elapsed();
+
+        println!(
+            "Verified {} txs {} times: parallel {:?}, sequential {:?}",
+            block.txs.len(),
+            rounds,
+            parallel,
+            sequential
+        );
+    }
+
+    #[test]
+    fn block_timestamped_far_in_future_is_rejected() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let max_drift = network_params().max_time_drift;
+
+        // Blocks may run ahead of local time by up to the allowed drift
+        let mut block = next_block(&wallet.get_wallet_address(), vec![]);
+        block.timestamp = now + max_drift - 60;
+        remine(&mut block);
+        assert!(!block.is_too_far_in_future());
+        assert!(block.verify().unwrap());
+
+        block.timestamp = now + max_drift + 60 * 60;
+        remine(&mut block);
+        assert!(block.is_too_far_in_future());
+        assert!(!block.verify().unwrap());
+        assert!(!block.verify_orphan().unwrap());
+    }
+
+    #[test]
+    fn block_with_extra_coinbase_is_rejected() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+        let tx = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 0);
+        let mut block = next_block(&miner, vec![tx]);
+        assert!(block.verify().unwrap());
+
+        // A second coinbase would mint another block reward
+        let mut two_coinbases = block.clone();
+        let extra = coinbase_tx(&Wallet::new().get_wallet_address(), 0, block.height).unwrap();
+        two_coinbases.txs.push(extra);
+        remine(&mut two_coinbases);
+        assert!(!two_coinbases.verify().unwrap());
+        assert!(!two_coinbases.verify_orphan().unwrap());
+
+        // The only coinbase must come first
+        block.txs.swap(0, 1);
+        remine(&mut block);
+        assert!(!block.verify().unwrap());
+    }
+
+    #[test]
+    fn coinbase_with_extra_output_is_rejected() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let mut block = next_block(&wallet.get_wallet_address(), vec![]);
+        assert!(block.verify().unwrap());
+
+        // The first output still pays exactly the reward, so only the output count gives it away
+        let coinbase = &mut block.txs[0];
+        coinbase.outputs.push(TxOutput {
+            value: 50,
+            pub_key_hash: *Wallet::new().get_wallet_address().pub_key_hash(),
+        });
+        coinbase.id = coinbase.hash().unwrap();
+        remine(&mut block);
+        assert!(!block.verify().unwrap());
+        assert!(!block.verify_orphan().unwrap());
+    }
+
+    #[test]
+    fn coinbase_paying_zero_pub_key_hash_is_rejected() {
+        let _guard = setup();
+        new_chain();
+
+        // No key hashes to all zeros, so the reward would be burned
+        let burn = Address::new_from_pub_key_hash([0u8; 20]);
+        let block = next_block(&burn, vec![]);
+        assert!(!block.verify().unwrap());
+        assert!(!block.verify_orphan().unwrap());
+    }
+
+    #[test]
+    fn next_block_preview_matches_new_block() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+
+        // Fund several wallets, each then paying a different fee
+        let senders: Vec<Wallet> = (0..3).map(|_| Wallet::new()).collect();
+        let recipients: Vec<_> = senders
+            .iter()
+            .map(|sender| (sender.get_wallet_address(), 30))
+            .collect();
+        let utxos = find_spendable_utxos(miner.pub_key_hash(), 90).unwrap();
+        add_block(
+            &miner,
+            vec![Tx::new_multi(&wallet, &recipients, 0, utxos).unwrap()],
+        );
+        for (fee, sender) in senders.iter().enumerate() {
+            let tx = pay(sender, &miner, 10, fee as u32 * 2);
+            add_tx_to_mempool(&tx).unwrap();
+        }
+        // A tx spending an unknown output has no fee to rank it by, so neither includes it
+        let unknown_output = TxOutput {
+            value: 50,
+            pub_key_hash: *miner.pub_key_hash(),
+        };
+        let utxos = HashMap::from([([7u8; 32], HashMap::from([(0, unknown_output)]))]);
+        db::put_mempool(&Tx::new(&wallet, &miner, 40, 10, utxos).unwrap());
+
+        // The preview reserves coinbase space for any address, as the CLI command does
+        let height = get_chain_height().unwrap() + 1;
+        let (preview, fees) =
+            select_next_block_txs(&Wallet::new().get_wallet_address(), height).unwrap();
+        let block = Block::new(&miner).unwrap();
+
+        let preview_ids: Vec<[u8; 32]> = preview.iter().map(|tx| tx.id).collect();
+        let block_ids: Vec<[u8; 32]> = block.txs[1..].iter().map(|tx| tx.id).collect();
+        assert_eq!(preview_ids, block_ids);
+        assert_eq!(preview_ids.len(), senders.len());
+        assert_eq!(fees, 6);
+        assert_eq!(block.txs[0].outputs[0].value, block_reward(height) + fees);
+    }
 }
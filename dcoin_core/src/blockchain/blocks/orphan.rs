@@ -2,14 +2,17 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::sync::{Arc, Mutex};
 
-use crate::blockchain::chain::{commit_block, get_last_block, get_tx_from_chain};
+use crate::blockchain::chain::{
+    chain_work, commit_block, get_block_with_tx_from, get_last_block, lock_commits,
+};
+use crate::blockchain::params::network_params;
 use crate::cli::db::{
-    delete_block, delete_utxo, get_all_block_hashes, get_block, get_last_hash, get_orphaned_blocks,
-    put_block, put_last_hash, put_mempool, put_utxo, remove_from_orphan_blocks,
-    remove_txs_from_mempool, MAX_ORPHAN_CHAIN_AGE,
+    get_all_block_hashes, get_block, get_last_hash, get_orphaned_blocks, put_mempool,
+    remove_from_orphan_blocks, ChainBatch, MAX_ORPHAN_CHAIN_AGE,
 };
-use core_lib::tx::TxOutput;
+use core_lib::tx::{TxInput, TxOutput};
 use lazy_static::lazy_static;
+use log::{error, info, warn};
 
 use super::block::Block;
 
@@ -21,8 +24,11 @@ use super::block::Block;
 /// ChainSnapshot defines the chain state before a rollback operation so that the chain can be restored if operations fail
 struct ChainSnapshot {
     last_hash: [u8; 32],
+    /// Utxo changes in the order they were committed. Undone in reverse on restore
     utxo_changes: Vec<UtxoChange>,
     removed_blocks: Vec<Block>,
+    /// Blocks applied to the chain since the snapshot was taken
+    applied_blocks: Vec<Block>,
 }
 
 /// Records a change made to the utxo set for use in rollback operations
@@ -30,11 +36,11 @@ enum UtxoChange {
     Added {
         tx_id: [u8; 32],
         out_idx: u32,
-        utxo: TxOutput,
     },
     Removed {
         tx_id: [u8; 32],
         out_idx: u32,
+        utxo: TxOutput,
     },
 }
 
@@ -80,6 +86,7 @@ impl ChainManager {
             last_hash: get_last_hash()?,
             utxo_changes: Vec::new(),
             removed_blocks: Vec::new(),
+            applied_blocks: Vec::new(),
         };
 
         let mut curr_block = get_last_block()?;
@@ -101,42 +108,56 @@ impl ChainManager {
 
     // Restore chain to previous state if rollback fails
     pub fn restore_snapshot(&mut self) -> Result<(), Box<dyn Error>> {
-        if let Some(snapshot) = &self.snapshot {
-            // Restore last hash
-            put_last_hash(&snapshot.last_hash);
-
-            // Restore UTXOs
-            for change in &snapshot.utxo_changes {
-                match change {
-                    UtxoChange::Added {
-                        tx_id,
-                        out_idx,
-                        utxo,
-                    } => {
-                        put_utxo(tx_id, *out_idx, utxo)?;
-                    }
-                    UtxoChange::Removed { tx_id, out_idx } => {
-                        delete_utxo(tx_id, *out_idx)?;
-                    }
+        let Some(snapshot) = self.snapshot.take() else {
+            return Err("[ChainManager] ERROR: No snapshot available to restore".into());
+        };
+        let mut batch = ChainBatch::new();
+
+        // Undo UTXO changes, newest first, so outputs created and spent during the
+        // operation end up as they were
+        for change in snapshot.utxo_changes.iter().rev() {
+            match change {
+                UtxoChange::Added { tx_id, out_idx } => {
+                    batch.delete_utxo(tx_id, *out_idx)?;
+                }
+                UtxoChange::Removed {
+                    tx_id,
+                    out_idx,
+                    utxo,
+                } => {
+                    batch.put_utxo(tx_id, *out_idx, utxo)?;
                 }
             }
+        }
 
-            // Re-add removed blocks if any
-            for block in snapshot.removed_blocks.iter().rev() {
-                put_block(block);
-            }
+        // Drop blocks applied from the orphan chain and re-add removed blocks. Later writes
+        // win, so removed blocks are re-indexed at heights the orphan chain also used
+        for block in &snapshot.applied_blocks {
+            batch.delete_block(&block.hash);
+            batch.delete_height_index(block.height);
+            batch.delete_tx_index(block);
+        }
+        for block in snapshot.removed_blocks.iter().rev() {
+            batch.put_block(block)?;
+            batch.put_height_index(block.height, &block.hash);
+            batch.put_tx_index(block);
+        }
 
-            self.snapshot = None;
-            Ok(())
-        } else {
-            Err("[ChainManager] ERROR: No snapshot available to restore".into())
+        // Restore last hash
+        batch.put_last_hash(&snapshot.last_hash);
+        batch.commit()
+    }
+
+    // Record UTXO changes for possible restore. Changes must only be recorded once committed
+    fn record_utxo_changes(&mut self, changes: Vec<UtxoChange>) {
+        if let Some(snapshot) = &mut self.snapshot {
+            snapshot.utxo_changes.extend(changes);
         }
     }
 
-    // Record UTXO changes for possible restore
-    pub fn record_utxo_change(&mut self, change: UtxoChange) {
+    fn record_applied_block(&mut self, block: &Block) {
         if let Some(snapshot) = &mut self.snapshot {
-            snapshot.utxo_changes.push(change);
+            snapshot.applied_blocks.push(block.clone());
         }
     }
 }
@@ -151,7 +172,7 @@ pub fn check_for_valid_orphan_blocks() -> Result<(), Box<dyn Error>> {
     let last_hash = get_last_hash()?;
     for (_, block) in orphan_map.iter() {
         if block.prev_hash == last_hash {
-            println!("Valid orphan block found! Attempting to commit...");
+            info!("Valid orphan block found! Attempting to commit...");
             commit_block(&block.clone())?;
         }
     }
@@ -179,15 +200,22 @@ pub fn check_orphans_for_longest_chain() -> Result<(), Box<dyn Error>> {
             let orphan_chain_height = base_block.height as usize + orphan_chain.len();
             let last_chain_block = get_last_block()?;
 
-            if orphan_chain_height > last_chain_block.height as usize {
-                // Found longer chain - attempt adoption with safety measures
+            // The chain with the most cumulative work wins, which isn't necessarily the longest
+            let orphan_chain_work = orphan_chain
+                .iter()
+                .fold(chain_work(&base_block)?, |work, block| {
+                    work.saturating_add(block.work())
+                });
+            if orphan_chain_work > chain_work(&last_chain_block)? {
+                // Found a chain with more work - attempt adoption with safety measures
                 if let Err(e) = adopt_orphan_chain(&base_block, &orphan_chain, &mut manager) {
-                    println!("Failed to adopt orphan chain: {}", e);
+                    warn!("Failed to adopt orphan chain: {}", e);
                     // Ensure chain is unlocked even if adoption fails
                     let _ = manager.unlock_chain();
                 }
             } else {
-                let height_diff = last_chain_block.height as usize - orphan_chain_height;
+                let height_diff =
+                    (last_chain_block.height as usize).saturating_sub(orphan_chain_height);
 
                 // Remove orphan chain if it's too old
                 if height_diff > MAX_ORPHAN_CHAIN_AGE as usize {
@@ -231,7 +259,8 @@ fn adopt_orphan_chain(
     orphan_chain: &[Block],
     manager: &mut ChainManager,
 ) -> Result<(), Box<dyn Error>> {
-    // Lock chain during the entire operation
+    // Lock chain during the entire operation. The tip moves, so no block is committed meanwhile
+    let _commit_guard = lock_commits();
     manager.lock_chain()?;
 
     // Create restore point before changes
@@ -239,8 +268,8 @@ fn adopt_orphan_chain(
 
     // Rollback to the base block
     if let Err(e) = rollback_chain_to_block(base_block.hash, manager) {
-        println!(
-            "[orphan::adopt_orphan_chain] ERROR: Failed to rollback chain: {}",
+        error!(
+            "[orphan::adopt_orphan_chain] Failed to rollback chain: {}",
             e
         );
         // Restore previous state
@@ -252,8 +281,8 @@ fn adopt_orphan_chain(
     // Apply orphan blocks one by one with validation
     for orphan_block in orphan_chain {
         if let Err(e) = validate_and_apply_block(orphan_block, manager) {
-            println!(
-                "[orphan::adopt_orphan_chain] ERROR: Failed to apply orphan block: {}",
+            error!(
+                "[orphan::adopt_orphan_chain] Failed to apply orphan block: {}",
                 e
             );
             // Restore previous state
@@ -275,7 +304,13 @@ fn validate_and_apply_block(
     block: &Block,
     manager: &mut ChainManager,
 ) -> Result<(), Box<dyn Error>> {
-    block.verify()?;
+    if !block.verify()? {
+        return Err(format!(
+            "[orphan::validate_and_apply_block] ERROR: Block {} failed verification",
+            hex::encode(block.hash)
+        )
+        .into());
+    }
 
     // Apply block to the chain
     apply_block_to_chain(block, manager)?;
@@ -290,85 +325,145 @@ fn rollback_chain_to_block(
     let mut curr_block = get_last_block()?;
 
     // Verify the target block exists
-    get_block(&target_hash)?.ok_or_else(|| {
+    let target_block = get_block(&target_hash)?.ok_or_else(|| {
         "[orphan::rollback_chain_to_block] ERROR: Failed to get target block for rollback"
             .to_string()
     })?;
 
-    // Track affected UTXOs during rollback for potential recovery
-    loop {
-        for tx in &curr_block.txs {
+    // Checkpointed blocks are final, so reorgs may only roll back to the latest checkpoint
+    if let Some(checkpoint) =
+        network_params().checkpoint_blocking_rollback(curr_block.height, target_block.height)
+    {
+        return Err(format!(
+            "[orphan::rollback_chain_to_block] ERROR: Rollback to height {} would pass checkpoint at height {}",
+            target_block.height, checkpoint.height
+        )
+        .into());
+    }
+
+    // Undo blocks from the tip down to the target. Each block is undone in its own batch, so
+    // the chain is left consistent - with the parent as the tip - after every step
+    while curr_block.hash != target_hash {
+        let mut batch = ChainBatch::new();
+        let mut changes = Vec::new();
+
+        // Txs are undone in reverse, in case an output was spent within the same block
+        for tx in curr_block.txs.iter().rev() {
             // Remove UTXOs created by this transaction
-            for (i, _) in tx.outputs.iter().enumerate() {
-                delete_utxo(&tx.id, i as u32)?;
-                manager.record_utxo_change(UtxoChange::Removed {
+            for (i, output) in tx.outputs.iter().enumerate() {
+                batch.delete_utxo(&tx.id, i as u32)?;
+                changes.push(UtxoChange::Removed {
                     tx_id: tx.id,
                     out_idx: i as u32,
+                    utxo: *output,
                 });
             }
 
+            // Coinbase txs consume no existing UTXOs
+            if tx.is_coinbase() {
+                continue;
+            }
+
             // Restore UTXOs consumed by this transaction
             for input in &tx.inputs {
-                let prev_tx = get_tx_from_chain(input.prev_tx_id)?;
-                let tx_out = prev_tx.outputs[input.out as usize].clone();
-                put_utxo(&input.prev_tx_id, input.out, &tx_out)?;
-                manager.record_utxo_change(UtxoChange::Added {
+                let tx_out = get_spent_output(&curr_block, input)?;
+                batch.put_utxo(&input.prev_tx_id, input.out, &tx_out)?;
+                changes.push(UtxoChange::Added {
                     tx_id: input.prev_tx_id,
                     out_idx: input.out,
-                    utxo: tx_out,
                 });
             }
-
-            // Return transaction to mempool
-            put_mempool(tx);
         }
 
-        // Break if we've reached the target block
-        if curr_block.prev_hash == target_hash {
-            break;
+        batch.delete_block(&curr_block.hash);
+        batch.delete_height_index(curr_block.height);
+        batch.delete_tx_index(&curr_block);
+        batch.put_last_hash(&curr_block.prev_hash);
+        batch.commit()?;
+        manager.record_utxo_changes(changes);
+
+        // Return transactions to the mempool
+        for tx in curr_block.txs.iter().filter(|tx| !tx.is_coinbase()) {
+            put_mempool(tx);
         }
 
-        // Delete current block and move to previous block
-        delete_block(&curr_block.hash);
         curr_block = get_block(&curr_block.prev_hash)?
             .ok_or_else(|| "[orphan::rollback_chain_to_block] ERROR: Failed to get previous block during rollback".to_string())?;
     }
 
-    // Update the chain tip
-    put_last_hash(&target_hash);
-
     Ok(())
 }
 
+/// Returns the output spent by an input of the given block. The spent output is either
+/// created earlier in the same block or by one of the block's ancestors, so the lookup
+/// doesn't depend on the current chain tip.
+fn get_spent_output(block: &Block, input: &TxInput) -> Result<TxOutput, Box<dyn Error>> {
+    let prev_tx = match block.txs.iter().find(|tx| tx.id == input.prev_tx_id) {
+        Some(tx) => tx.clone(),
+        None => get_block_with_tx_from(block.prev_hash, input.prev_tx_id)?
+            .and_then(|b| b.txs.into_iter().find(|tx| tx.id == input.prev_tx_id))
+            .ok_or("[orphan::get_spent_output] ERROR: Could not find spent tx in chain")?,
+    };
+    prev_tx
+        .outputs
+        .get(input.out as usize)
+        .cloned()
+        .ok_or_else(|| "[orphan::get_spent_output] ERROR: Spent output does not exist".into())
+}
+
 // Apply a block to the chain with proper UTXO management
 fn apply_block_to_chain(block: &Block, manager: &mut ChainManager) -> Result<(), Box<dyn Error>> {
+    // The block is applied in a single batch, so a failure part way through leaves the
+    // chain state as it was before the block
+    let mut batch = ChainBatch::new();
+    let mut changes = Vec::new();
+
     // Process all transactions in the block
     for tx in &block.txs {
-        // Remove inputs from UTXO set
-        for input in &tx.inputs {
-            delete_utxo(&input.prev_tx_id, input.out)?;
-            manager.record_utxo_change(UtxoChange::Added {
-                tx_id: input.prev_tx_id,
-                out_idx: input.out,
-                utxo: get_tx_from_chain(input.prev_tx_id)?.outputs[input.out as usize].clone(),
-            });
+        // Remove inputs from UTXO set. Coinbase inputs don't reference a UTXO
+        if !tx.is_coinbase() {
+            for input in &tx.inputs {
+                let utxo = batch
+                    .get_utxo(&input.prev_tx_id, input.out)?
+                    .ok_or_else(|| {
+                        format!(
+                            "[orphan::apply_block_to_chain] ERROR: Input spends missing UTXO {}:{}",
+                            hex::encode(input.prev_tx_id),
+                            input.out
+                        )
+                    })?;
+                batch.delete_utxo(&input.prev_tx_id, input.out)?;
+                changes.push(UtxoChange::Removed {
+                    tx_id: input.prev_tx_id,
+                    out_idx: input.out,
+                    utxo,
+                });
+            }
         }
 
         // Add outputs to UTXO set
         for (i, output) in tx.outputs.iter().enumerate() {
-            put_utxo(&tx.id, i as u32, output)?;
-            manager.record_utxo_change(UtxoChange::Removed {
+            batch.put_utxo(&tx.id, i as u32, output)?;
+            changes.push(UtxoChange::Added {
                 tx_id: tx.id,
                 out_idx: i as u32,
             });
         }
-
-        // Remove from mempool if it was there
-        remove_txs_from_mempool(vec![tx.id]);
     }
 
-    put_block(block);
-    put_last_hash(&block.hash);
+    // Remove the block's txs from the mempool if they were there
+    let tx_ids: Vec<[u8; 32]> = block.txs.iter().map(|tx| tx.id).collect();
+    batch.remove_txs_from_mempool(&tx_ids)?;
+
+    batch.put_block(block)?;
+    batch.put_chain_work(&block.hash, chain_work(block)?);
+    batch.put_last_hash(&block.hash);
+    batch.put_height_index(block.height, &block.hash);
+    batch.put_tx_index(block);
+    batch.commit()?;
+
+    manager.record_utxo_changes(changes);
+    manager.record_applied_block(block);
     Ok(())
 }
 
@@ -376,3 +471,94 @@ fn prune_orphan_chain(orphan_chain: &[Block]) {
     let orphan_hashes: Vec<[u8; 32]> = orphan_chain.iter().map(|b| b.hash).collect();
     remove_from_orphan_blocks(orphan_hashes);
 }
+
+#[cfg(test)]
+mod tests {
+    use core_lib::wallet::Wallet;
+
+    use super::*;
+    use crate::{
+        blockchain::{
+            chain::{get_block_by_height, validate_chain, CommitStatus},
+            transaction::utxo::get_all_utxos,
+        },
+        cli::db::{get_tx_block_hash, put_orphan_block},
+        test_utils::{add_block, block_on, new_chain, pay, remine, setup},
+    };
+
+    #[test]
+    fn shorter_chain_with_more_work_wins() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let genesis = get_last_block().unwrap();
+        let genesis_work = chain_work(&genesis).unwrap();
+        let main_chain: Vec<Block> = (0..3).map(|_| add_block(&miner, vec![])).collect();
+
+        // Difficulty is fixed per network, so the main chain is given the stored work of blocks
+        // mined at a quarter of the difficulty of the fork's
+        let mut batch = ChainBatch::new();
+        for (i, block) in main_chain.iter().enumerate() {
+            let work = genesis_work + (i as u128 + 1) * block.work() / 4;
+            batch.put_chain_work(&block.hash, work);
+        }
+        batch.commit().unwrap();
+
+        let fork_miner = Wallet::new().get_wallet_address();
+        let fork_1 = block_on(&genesis, &fork_miner, vec![]);
+        let fork_2 = block_on(&fork_1, &fork_miner, vec![]);
+        for block in [&fork_1, &fork_2] {
+            assert!(matches!(
+                commit_block(block).unwrap(),
+                CommitStatus::Orphaned
+            ));
+        }
+        check_orphans_for_longest_chain().unwrap();
+
+        assert_eq!(get_last_hash().unwrap(), fork_2.hash);
+        assert_eq!(get_last_block().unwrap().height, 2);
+        assert!(get_orphaned_blocks().is_empty());
+    }
+
+    #[test]
+    fn failed_reorg_restores_prior_chain_state() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+        let genesis = get_last_block().unwrap();
+        let main_tip = add_block(
+            &miner,
+            vec![pay(&wallet, &Wallet::new().get_wallet_address(), 50, 5)],
+        );
+        let utxos = get_all_utxos().unwrap();
+
+        // The fork spends the same genesis output differently, then fails on its third block
+        let fork_miner = Wallet::new().get_wallet_address();
+        let fork_1 = block_on(
+            &genesis,
+            &fork_miner,
+            vec![pay(&wallet, &Wallet::new().get_wallet_address(), 30, 5)],
+        );
+        let fork_2 = block_on(&fork_1, &fork_miner, vec![]);
+        let mut fork_3 = block_on(&fork_2, &fork_miner, vec![]);
+        fork_3.txs[0].outputs[0].value += 1;
+        remine(&mut fork_3);
+        for block in [&fork_1, &fork_2, &fork_3] {
+            put_orphan_block(block);
+        }
+        check_orphans_for_longest_chain().unwrap();
+
+        assert_eq!(get_last_hash().unwrap(), main_tip.hash);
+        assert_eq!(get_all_utxos().unwrap(), utxos);
+        assert_eq!(
+            get_block_by_height(1).unwrap().map(|block| block.hash),
+            Some(main_tip.hash)
+        );
+        for tx in &main_tip.txs {
+            assert_eq!(get_tx_block_hash(&tx.id).unwrap(), Some(main_tip.hash));
+        }
+        for block in [&fork_1, &fork_2] {
+            assert!(get_block(&block.hash).unwrap().is_none());
+        }
+        assert!(validate_chain().unwrap().is_none());
+    }
+}
@@ -1,21 +1,60 @@
-use core_lib::{address::Address, tx::Tx};
+use core_lib::{address::Address, tx::TxOutput};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{atomic::AtomicBool, Mutex, MutexGuard},
+};
+use tokio::sync::broadcast;
 
-use super::blocks::block::Block;
+use super::blocks::block::{get_target_difficulty, Block};
+use super::params::network_params;
 use crate::{
     blockchain::{
         blocks::orphan::{check_for_valid_orphan_blocks, check_orphans_for_longest_chain},
-        transaction::{mempool::update_mempool, utxo::update_utxos},
+        transaction::{
+            mempool::{promote_orphan_txs, update_mempool},
+            utxo::{apply_block_to_set, get_all_utxos, update_utxos, UTXOSet},
+        },
     },
     cli::db::{
-        self, blockchain_exists, delete_all_blocks, delete_all_orphan_blocks, delete_all_utxos,
-        delete_last_hash, delete_mempool, get_block, get_last_hash, put_block, put_last_hash,
-        put_orphan_block, remove_from_orphan_blocks,
+        self, blockchain_exists, delete_all_blocks, delete_all_chain_work,
+        delete_all_orphan_blocks, delete_all_orphan_txs, delete_all_utxos, delete_height_index,
+        delete_last_hash, delete_mempool, delete_pruned_height, delete_tx_index, get_block,
+        get_last_hash, get_pruned_height, put_orphan_block, remove_from_orphan_blocks, ChainBatch,
     },
 };
 use hex;
 
+/// NewBlockEvent is broadcast to subscribers whenever a new block is added to the chain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewBlockEvent {
+    pub height: u32,
+    pub hash: String,
+}
+
+// Global new block event channel. Subscribers that fall too far behind will miss events
+lazy_static! {
+    static ref NEW_BLOCK_EVENTS: broadcast::Sender<NewBlockEvent> = broadcast::channel(100).0;
+}
+
+/// Subscribe to events for all blocks added to the chain from this point on
+pub fn subscribe_new_blocks() -> broadcast::Receiver<NewBlockEvent> {
+    NEW_BLOCK_EVENTS.subscribe()
+}
+
+/// Notify all subscribers that a new block has been added to the chain
+pub fn notify_new_block(block: &Block) {
+    // Sending only fails when there are no subscribers, which is fine
+    let _ = NEW_BLOCK_EVENTS.send(NewBlockEvent {
+        height: block.height,
+        hash: hex::encode(block.hash),
+    });
+}
+
 /// Initializes the blockchain, and fails if a blockchain already exists
 pub fn create_blockchain(addr: &Address) -> Result<(), Box<dyn Error>> {
     if blockchain_exists() {
@@ -23,8 +62,16 @@ pub fn create_blockchain(addr: &Address) -> Result<(), Box<dyn Error>> {
     }
 
     let mut genesis_block = Block::genesis(addr)?;
-    genesis_block.mine()?;
-    Ok(())
+    genesis_block.mine(&get_target_difficulty(), &AtomicBool::new(false))?;
+
+    let mut batch = ChainBatch::new();
+    update_utxos(&genesis_block, &mut batch)?;
+    batch.put_block(&genesis_block)?;
+    batch.put_chain_work(&genesis_block.hash, genesis_block.work());
+    batch.put_last_hash(&genesis_block.hash);
+    batch.put_height_index(genesis_block.height, &genesis_block.hash);
+    batch.put_tx_index(&genesis_block);
+    batch.commit()
 }
 
 /// Clears the existing chain. Retains the node id
@@ -32,8 +79,13 @@ pub fn clear_blockchain() {
     delete_all_blocks();
     delete_all_utxos();
     delete_all_orphan_blocks();
+    delete_all_orphan_txs();
     delete_mempool();
     delete_last_hash();
+    delete_pruned_height();
+    delete_all_chain_work();
+    delete_height_index();
+    delete_tx_index();
 }
 
 pub fn get_last_block() -> Result<Block, Box<dyn Error>> {
@@ -45,7 +97,7 @@ pub fn get_last_block() -> Result<Block, Box<dyn Error>> {
                 e
             )
         })?
-        .ok_or_else(|| "[block::get_last_block] ERROR: Last block not found")?;
+        .ok_or("[block::get_last_block] ERROR: Last block not found")?;
 
     Ok(block)
 }
@@ -55,15 +107,53 @@ pub fn get_chain_height() -> Result<u32, Box<dyn Error>> {
     Ok(lb.height)
 }
 
+/// Returns the cumulative work of the chain ending at the given block. The stored work of the
+/// nearest ancestor is used where available, so the block itself need not be stored yet
+pub fn chain_work(block: &Block) -> Result<u128, Box<dyn Error>> {
+    let mut work: u128 = 0;
+    let mut current_block = block.clone();
+    loop {
+        if let Some(stored) = db::get_chain_work(&current_block.hash)? {
+            return Ok(work.saturating_add(stored));
+        }
+        work = work.saturating_add(current_block.work());
+        if current_block.is_genesis() {
+            return Ok(work);
+        }
+        current_block = get_block(&current_block.prev_hash)?.ok_or_else(|| {
+            format!(
+                "[chain::chain_work] ERROR: Could not find previous block {}",
+                hex::encode(current_block.prev_hash)
+            )
+        })?;
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BlockJson {
     height: u32,
     hash: String,
     prev_hash: String,
+    merkle_root: String,
     timestamp: u64,
     nonce: u32,
+    extranonce: u32,
+    // Number of leading zero bits required of the block hash
+    difficulty: usize,
+    // Serialized size in bytes
+    size: usize,
+    // Address paid by the coinbase, and the value it claimed. Unknown once the block is pruned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    miner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reward: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     txs: Option<Vec<TxJson>>,
+    // Chain depth of the block, only included when serving a single block
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confirmations: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_tip: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -71,12 +161,18 @@ struct TxJson {
     id: String,
     inputs: Vec<TxInputJson>,
     outputs: Vec<TxOutputJson>,
+    size: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct TxInputJson {
     prev_tx_id: String,
     out: u32,
+    // Value and address of the spent output, only included when inputs are resolved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -85,30 +181,45 @@ struct TxOutputJson {
     pub_key_hash: String,
 }
 
-pub fn get_blockchain_json(include_txs: bool) -> Result<Vec<BlockJson>, Box<dyn Error>> {
-    let mut blocks = Vec::new();
-    let mut current_block = get_last_block()?;
-
-    loop {
-        let block_json = BlockJson {
-            height: current_block.height,
-            hash: hex::encode(&current_block.hash),
-            prev_hash: hex::encode(&current_block.prev_hash),
-            timestamp: current_block.timestamp,
-            nonce: current_block.nonce,
-            txs: if include_txs {
+impl BlockJson {
+    /// Builds the JSON representation of a block, optionally including its txs
+    pub fn from_block(block: &Block, include_txs: bool) -> Self {
+        let coinbase_output = block
+            .txs
+            .first()
+            .filter(|tx| tx.is_coinbase())
+            .and_then(|tx| tx.outputs.first());
+        BlockJson {
+            height: block.height,
+            hash: hex::encode(block.hash),
+            prev_hash: hex::encode(block.prev_hash),
+            merkle_root: hex::encode(block.merkle_root),
+            timestamp: block.timestamp,
+            nonce: block.nonce,
+            extranonce: block.extranonce,
+            // Difficulty is currently fixed per network, so every block was mined against the same target
+            difficulty: network_params().difficulty,
+            size: block.size(),
+            miner: coinbase_output.map(|output| {
+                Address::new_from_pub_key_hash(output.pub_key_hash).get_full_address()
+            }),
+            reward: coinbase_output.map(|output| output.value),
+            // Pruned blocks no longer hold their txs
+            txs: if include_txs && !block.is_pruned() {
                 Some(
-                    current_block
+                    block
                         .txs
                         .iter()
                         .map(|tx| TxJson {
-                            id: hex::encode(&tx.id),
+                            id: hex::encode(tx.id),
                             inputs: tx
                                 .inputs
                                 .iter()
                                 .map(|input| TxInputJson {
-                                    prev_tx_id: hex::encode(&input.prev_tx_id),
+                                    prev_tx_id: hex::encode(input.prev_tx_id),
                                     out: input.out,
+                                    value: None,
+                                    address: None,
                                 })
                                 .collect(),
                             outputs: tx
@@ -116,17 +227,106 @@ pub fn get_blockchain_json(include_txs: bool) -> Result<Vec<BlockJson>, Box<dyn
                                 .iter()
                                 .map(|output| TxOutputJson {
                                     value: output.value,
-                                    pub_key_hash: hex::encode(&output.pub_key_hash),
+                                    pub_key_hash: hex::encode(output.pub_key_hash),
                                 })
                                 .collect(),
+                            size: tx.size(),
                         })
                         .collect(),
                 )
             } else {
                 None
             },
+            confirmations: None,
+            is_tip: None,
+        }
+    }
+
+    /// Adds the block's number of confirmations and whether it is the chain tip. Blocks that
+    /// aren't part of the main chain have no confirmations
+    pub fn with_confirmations(mut self, block: &Block) -> Result<Self, Box<dyn Error>> {
+        let tip = get_last_block()?;
+        let in_main_chain =
+            get_block_by_height(block.height)?.is_some_and(|main| main.hash == block.hash);
+        // The tip may move between reads, so the depth saturates rather than underflowing
+        self.confirmations = Some(if in_main_chain {
+            (tip.height + 1).saturating_sub(block.height)
+        } else {
+            0
+        });
+        self.is_tip = Some(block.hash == tip.hash);
+        Ok(self)
+    }
+
+    /// Adds the value and address of the output spent by each input of the block's txs. Has no
+    /// effect unless txs are included
+    pub fn with_resolved_inputs(mut self, block: &Block) -> Result<Self, Box<dyn Error>> {
+        self.resolve_inputs(block, &mut HashMap::new())?;
+        Ok(self)
+    }
+
+    /// Resolves the outputs spent by the block's txs. Spent outputs not found in the given index
+    /// are searched for in the chain from the block's parent back, and added to the index. Inputs
+    /// whose outputs can't be found, such as those in the pruned part of the chain, are left as is
+    fn resolve_inputs(
+        &mut self,
+        block: &Block,
+        outputs: &mut HashMap<[u8; 32], Vec<TxOutput>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(txs) = self.txs.as_mut() else {
+            return Ok(());
         };
 
+        // Txs may spend outputs created earlier in the same block
+        for tx in &block.txs {
+            outputs.insert(tx.id, tx.outputs.clone());
+        }
+
+        for (tx_json, tx) in txs.iter_mut().zip(&block.txs) {
+            if tx.is_coinbase() {
+                continue;
+            }
+            for (input_json, input) in tx_json.inputs.iter_mut().zip(&tx.inputs) {
+                if !outputs.contains_key(&input.prev_tx_id) {
+                    let Some(prev_block) =
+                        get_block_with_tx_from(block.prev_hash, input.prev_tx_id)?
+                    else {
+                        continue;
+                    };
+                    for prev_tx in prev_block.txs {
+                        outputs.insert(prev_tx.id, prev_tx.outputs);
+                    }
+                }
+                if let Some(prev_out) = outputs
+                    .get(&input.prev_tx_id)
+                    .and_then(|outs| outs.get(input.out as usize))
+                {
+                    input_json.value = Some(prev_out.value);
+                    input_json.address = Some(
+                        Address::new_from_pub_key_hash(prev_out.pub_key_hash).get_full_address(),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the main chain as JSON, from the last block back to genesis. If `resolve_inputs` is
+/// set, the inputs of included txs carry the value and address of the output they spend
+pub fn get_blockchain_json(
+    include_txs: bool,
+    resolve_inputs: bool,
+) -> Result<Vec<BlockJson>, Box<dyn Error>> {
+    let mut blocks = Vec::new();
+    let mut current_block = get_last_block()?;
+    let mut outputs = HashMap::new();
+
+    loop {
+        let mut block_json = BlockJson::from_block(&current_block, include_txs);
+        if resolve_inputs {
+            block_json.resolve_inputs(&current_block, &mut outputs)?;
+        }
         blocks.push(block_json);
 
         if current_block.is_genesis() {
@@ -140,58 +340,313 @@ pub fn get_blockchain_json(include_txs: bool) -> Result<Vec<BlockJson>, Box<dyn
                     e
                 )
             })?
-            .ok_or_else(|| "[chain::get_blockchain_json] ERROR: Last block not found")?;
+            .ok_or("[chain::get_blockchain_json] ERROR: Last block not found")?;
     }
 
     Ok(blocks)
 }
 
-pub fn get_tx_from_chain(tx_id: [u8; 32]) -> Result<Tx, Box<dyn Error>> {
-    let last_hash = db::get_last_hash()?;
-    let mut current_block = db::get_block(&last_hash)?.ok_or_else(|| {
+/// ChainPage is a window of main chain blocks, ordered from highest to lowest
+#[derive(Serialize, Debug)]
+pub struct ChainPage {
+    pub blocks: Vec<BlockJson>,
+    /// Height to request the next page from. None once the genesis block has been returned
+    pub next_from_height: Option<u32>,
+}
+
+/// Returns up to `limit` main chain blocks, walking back from the block at `from_height`, or from
+/// the last block if no height is given. Returns None if there is no block at the given height.
+/// If `resolve_inputs` is set, the inputs of included txs carry the value and address they spend
+pub fn get_chain_page(
+    from_height: Option<u32>,
+    limit: usize,
+    include_txs: bool,
+    resolve_inputs: bool,
+) -> Result<Option<ChainPage>, Box<dyn Error>> {
+    let mut current_block = match from_height {
+        Some(height) => match get_block_by_height(height)? {
+            Some(block) => block,
+            None => return Ok(None),
+        },
+        None => get_last_block()?,
+    };
+
+    let mut blocks = Vec::new();
+    let mut outputs = HashMap::new();
+    loop {
+        let mut block_json = BlockJson::from_block(&current_block, include_txs);
+        if resolve_inputs {
+            block_json.resolve_inputs(&current_block, &mut outputs)?;
+        }
+        blocks.push(block_json);
+
+        if current_block.is_genesis() {
+            return Ok(Some(ChainPage {
+                blocks,
+                next_from_height: None,
+            }));
+        }
+        if blocks.len() >= limit {
+            break;
+        }
+
+        current_block = get_block(&current_block.prev_hash)?.ok_or_else(|| {
+            format!(
+                "[chain::get_chain_page] ERROR: Could not find previous block {}",
+                hex::encode(current_block.prev_hash)
+            )
+        })?;
+    }
+
+    Ok(Some(ChainPage {
+        blocks,
+        next_from_height: Some(current_block.height - 1),
+    }))
+}
+
+/// Returns the block at the given height on the main chain, looked up in the height index
+pub fn get_block_by_height(height: u32) -> Result<Option<Block>, Box<dyn Error>> {
+    let Some(block_hash) = db::get_block_hash_at_height(height)? else {
+        return Ok(None);
+    };
+    let block = get_block(&block_hash)?.ok_or_else(|| {
         format!(
-            "[chain::find_tx_in_chain] ERROR: Could not find block from last hash {:?}",
-            last_hash
+            "[chain::get_block_by_height] ERROR: Could not find indexed block {}",
+            hex::encode(block_hash)
         )
     })?;
+    Ok(Some(block))
+}
+
+/// Returns the main chain block containing the given tx, if any, looked up in the tx index.
+/// Txs of pruned blocks are no longer indexed
+pub fn get_block_with_tx(tx_id: [u8; 32]) -> Result<Option<Block>, Box<dyn Error>> {
+    let Some(block_hash) = db::get_tx_block_hash(&tx_id)? else {
+        return Ok(None);
+    };
+    let block = get_block(&block_hash)?.ok_or_else(|| {
+        format!(
+            "[chain::get_block_with_tx] ERROR: Could not find indexed block {}",
+            hex::encode(block_hash)
+        )
+    })?;
+
+    // Guard against an entry left by a block that has since been replaced on the main chain
+    if db::get_block_hash_at_height(block.height)? != Some(block_hash) {
+        return Ok(None);
+    }
+    Ok(Some(block))
+}
+
+/// Returns the block containing the given tx, if any, among the block with the given hash and
+/// its ancestors. Useful when the chain tip is being changed, such as during a rollback.
+///
+/// Main chain blocks are looked up in the tx index. Otherwise the chain is walked back, stopping
+/// at the first pruned block, since the txs of older blocks are no longer stored
+pub fn get_block_with_tx_from(
+    start_hash: [u8; 32],
+    tx_id: [u8; 32],
+) -> Result<Option<Block>, Box<dyn Error>> {
+    let mut current_block = db::get_block(&start_hash)?.ok_or_else(|| {
+        format!(
+            "[chain::get_block_with_tx_from] ERROR: Could not find start block {:?}",
+            start_hash
+        )
+    })?;
+
+    if db::get_block_hash_at_height(current_block.height)? == Some(start_hash) {
+        let start_height = current_block.height;
+        return Ok(get_block_with_tx(tx_id)?.filter(|block| block.height <= start_height));
+    }
 
     loop {
-        for tx in &current_block.txs {
-            if tx.id == tx_id {
-                return Ok(tx.clone());
-            }
+        if current_block.txs.iter().any(|tx| tx.id == tx_id) {
+            return Ok(Some(current_block));
         }
-        // Break if we have reached the first block
-        if current_block.is_genesis() {
+        // Break if we have reached the first block, or the pruned part of the chain
+        if current_block.is_genesis() || current_block.is_pruned() {
             break;
         }
         // Otherwise, get the next block
         current_block = db::get_block(&current_block.prev_hash)?.ok_or_else(|| {
             format!(
-                "[chain::find_tx_in_chain] ERROR: Could not find next block {:?}",
+                "[chain::get_block_with_tx_from] ERROR: Could not find next block {:?}",
                 current_block.prev_hash
             )
         })?;
     }
 
-    Err("[chain::find_tx_in_chain] ERROR: Could not find tx in chain".into())
+    Ok(None)
+}
+
+/// ChainFault describes an inconsistency found while validating the stored chain
+#[derive(Debug)]
+pub struct ChainFault {
+    pub height: u32,
+    pub hash: [u8; 32],
+    pub reason: String,
+}
+
+impl ChainFault {
+    fn new(block: &Block, reason: &str) -> Self {
+        ChainFault {
+            height: block.height,
+            hash: block.hash,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Re-verifies the stored main chain end to end. Returns the lowest inconsistency found, or
+/// None if the chain is valid.
+///
+/// The chain is walked back from the last block to genesis, then replayed forward. Each block's
+/// proof of work, height, prev_hash linkage and stored chain work are checked, and its txs are
+/// verified against a utxo set rebuilt in memory. Finally the rebuilt utxo set is compared to the one in the db.
+/// Pruned blocks no longer hold their txs, so only headers are checked on pruned chains.
+pub fn validate_chain() -> Result<Option<ChainFault>, Box<dyn Error>> {
+    // Only hashes are kept during the walk back, so the chain doesn't have to fit in memory
+    let mut current_block = get_last_block()?;
+    let mut hashes = vec![current_block.hash];
+    while !current_block.is_genesis() {
+        let Some(parent) = get_block(&current_block.prev_hash)? else {
+            return Ok(Some(ChainFault::new(
+                &current_block,
+                "parent block is missing",
+            )));
+        };
+        // Heights must strictly decrease, otherwise the walk may never reach genesis
+        if parent.height >= current_block.height {
+            return Ok(Some(ChainFault::new(
+                &current_block,
+                "height does not follow its parent",
+            )));
+        }
+        hashes.push(parent.hash);
+        current_block = parent;
+    }
+
+    let check_txs = get_pruned_height().is_none();
+    let mut utxos: UTXOSet = HashMap::new();
+    let mut work: u128 = 0;
+    let mut parent: Option<Block> = None;
+    for hash in hashes.iter().rev() {
+        let block = get_block(hash)?.ok_or_else(|| {
+            format!(
+                "[chain::validate_chain] ERROR: Could not find block {}",
+                hex::encode(hash)
+            )
+        })?;
+
+        if block.hash != *hash {
+            return Ok(Some(ChainFault::new(
+                &block,
+                "stored under a different hash",
+            )));
+        }
+        if !block.verify_header()? {
+            return Ok(Some(ChainFault::new(
+                &block,
+                "invalid proof of work, hash or checkpoint",
+            )));
+        }
+        let linked = match &parent {
+            Some(parent) => block.extends(parent),
+            None => block.is_genesis(),
+        };
+        if !linked {
+            return Ok(Some(ChainFault::new(
+                &block,
+                "prev_hash, height or timestamp does not follow its parent",
+            )));
+        }
+
+        work = work.saturating_add(block.work());
+        if db::get_chain_work(&block.hash)?.is_some_and(|stored| stored != work) {
+            return Ok(Some(ChainFault::new(
+                &block,
+                "stored chain work does not match the chain",
+            )));
+        }
+
+        if check_txs {
+            if !block.verify_txs_against(&utxos)? {
+                return Ok(Some(ChainFault::new(&block, "contains invalid txs")));
+            }
+            apply_block_to_set(&mut utxos, &block);
+        }
+        parent = Some(block);
+    }
+
+    if check_txs && utxos != get_all_utxos()? {
+        let tip = parent.ok_or("[chain::validate_chain] ERROR: Chain is empty")?;
+        return Ok(Some(ChainFault::new(
+            &tip,
+            "utxo set in the db does not match the chain",
+        )));
+    }
+
+    Ok(None)
+}
+
+/// CommitStatus is the outcome of committing a block
+#[derive(Debug, PartialEq)]
+pub enum CommitStatus {
+    /// The block was added to the chain
+    Committed,
+    /// The block can't be added yet and was stored as an orphan
+    Orphaned,
+    /// The block is invalid and was discarded
+    Rejected,
+}
+
+/// Held from a block's checks against the chain tip through its write, so that concurrent
+/// commits, such as a mined block and one received from a peer, can't both build on one tip
+static COMMIT_LOCK: Mutex<()> = Mutex::new(());
+
+pub(crate) fn lock_commits() -> MutexGuard<'static, ()> {
+    // The lock guards no data, so a panic while it was held can't leave anything inconsistent
+    COMMIT_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Commits a block to the chain, or stores it as an orphan if it can't be added yet
+pub fn commit_block(block: &Block) -> Result<CommitStatus, Box<dyn Error>> {
+    let guard = lock_commits();
+    let status = write_block(block)?;
+    drop(guard);
+    finish_commit(block, status)
 }
 
-pub fn commit_block(block: &Block) -> Result<(), Box<dyn Error>> {
+/// Commits a block mined by this node. Returns None, leaving the chain untouched, if the tip
+/// changed while the block was being mined. The tip is checked under the commit lock, so no
+/// other block can be committed between the check and the write
+pub fn commit_mined_block(block: &Block) -> Result<Option<CommitStatus>, Box<dyn Error>> {
+    let guard = lock_commits();
+    if get_last_hash()? != block.prev_hash {
+        return Ok(None);
+    }
+    let status = write_block(block)?;
+    drop(guard);
+    finish_commit(block, status).map(Some)
+}
+
+/// Verifies the block and writes it to the chain, or stores it as an orphan. Must be called
+/// with the commit lock held
+fn write_block(block: &Block) -> Result<CommitStatus, Box<dyn Error>> {
     match block.verify() {
         Ok(v) => {
             if !v {
-                println!("Verification failed for given block!");
-                println!("Checking if block is a valid orphan block...");
+                warn!("Verification failed for given block!");
+                debug!("Checking if block is a valid orphan block...");
                 match block.verify_orphan() {
                     Ok(v) => {
                         if !v {
-                            println!("Block is not a valid orphan block and will be discarded");
-                            return Ok(());
+                            warn!("Block is not a valid orphan block and will be discarded");
+                            return Ok(CommitStatus::Rejected);
                         }
-                        put_orphan_block(&block);
-                        println!("Block is a valid orphan and has been persisted for future consideration");
-                        return Ok(());
+                        put_orphan_block(block);
+                        info!("Block is a valid orphan and has been persisted for future consideration");
+                        return Ok(CommitStatus::Orphaned);
                     }
                     Err(e) => {
                         return Err(
@@ -210,40 +665,494 @@ pub fn commit_block(block: &Block) -> Result<(), Box<dyn Error>> {
         }
     }
 
-    // TODO: Should send a signal to cancel mining
-    if let Err(e) = update_utxos(&block) {
+    // All chain state changes for the block are written in a single batch, so a failure
+    // can't leave the block stored with its utxos only partially applied
+    let mut batch = ChainBatch::new();
+    if let Err(e) = update_utxos(block, &mut batch) {
         return Err(format!(
-            "[miner::handle_mine] ERROR: Failed to update utxos: {:?}",
+            "[chain::commit_block] ERROR: Failed to update utxos: {:?}",
             e
         )
         .into());
     };
 
-    if let Err(e) = update_mempool(&block) {
+    if let Err(e) = update_mempool(block, &mut batch) {
         return Err(format!(
-            "[miner::handle_mine] ERROR: Failed to update mempool: {:?}",
+            "[chain::commit_block] ERROR: Failed to update mempool: {:?}",
             e
         )
         .into());
     };
 
-    put_block(&block);
-    remove_from_orphan_blocks(vec![block.hash]);
+    batch.put_block(block)?;
+    batch.put_chain_work(&block.hash, chain_work(block)?);
 
-    let current_height = if let Ok(h) = get_chain_height() {
-        h
-    } else {
-        // Chain is empty, therefore set curr height to 0
-        0
-    };
+    // If the chain is empty, the current height is 0
+    let current_height = get_chain_height().unwrap_or_default();
     if block.height >= current_height {
-        put_last_hash(&block.hash);
+        batch.put_last_hash(&block.hash);
+        batch.put_height_index(block.height, &block.hash);
+        batch.put_tx_index(block);
+    }
+    batch.commit()?;
+    Ok(CommitStatus::Committed)
+}
+
+/// Runs the follow up work of a committed block. Its children may be waiting in the orphan
+/// pools, and the chain may need pruning. Other statuses are returned unchanged
+fn finish_commit(block: &Block, status: CommitStatus) -> Result<CommitStatus, Box<dyn Error>> {
+    if status != CommitStatus::Committed {
+        return Ok(status);
     }
 
+    remove_from_orphan_blocks(vec![block.hash]);
+
+    notify_new_block(block);
+
     // Check if new block allows other orphaned blocks to be committed
     check_for_valid_orphan_blocks()?;
     check_orphans_for_longest_chain()?;
 
-    println!("Block was successfully committed to the blockchain");
+    // The block's outputs may be the missing inputs of orphan txs
+    let promoted = promote_orphan_txs();
+    if promoted > 0 {
+        info!("Promoted {} orphan txs to the mempool", promoted);
+    }
+
+    // Pruning failures leave the chain intact, so they are retried on the next block
+    if let Err(e) = prune_blocks() {
+        warn!("Failed to prune blocks: {}", e);
+    }
+
+    info!("Block was successfully committed to the blockchain");
+    Ok(status)
+}
+
+static PRUNE_DEPTH: OnceCell<u32> = OnceCell::new();
+
+/// Enables pruning, discarding the txs of blocks buried deeper than the given depth. Must be
+/// called before blocks are committed, and can only be set once.
+pub fn set_prune_depth(depth: u32) -> Result<(), u32> {
+    PRUNE_DEPTH.set(depth)
+}
+
+/// Discards the txs of main chain blocks that are buried deeper than the prune depth and at or
+/// below the latest checkpoint, keeping only their headers. Reorgs can't roll back past the
+/// latest checkpoint, so the txs of these blocks are never needed to undo them.
+///
+/// Does nothing if pruning is disabled or no checkpoint has been reached.
+pub fn prune_blocks() -> Result<(), Box<dyn Error>> {
+    let Some(depth) = PRUNE_DEPTH.get() else {
+        return Ok(());
+    };
+    let tip = get_last_block()?;
+    let Some(checkpoint) = network_params().last_checkpoint(tip.height) else {
+        return Ok(());
+    };
+    let Some(prune_height) = tip.height.checked_sub(*depth) else {
+        return Ok(());
+    };
+    prune_blocks_to(prune_height.min(checkpoint.height))
+}
+
+/// Discards the txs of main chain blocks up to and including the given height, walking back
+/// until reaching blocks pruned previously
+fn prune_blocks_to(prune_height: u32) -> Result<(), Box<dyn Error>> {
+    if get_pruned_height().is_some_and(|h| h >= prune_height) {
+        return Ok(());
+    }
+
+    let Some(mut current_block) = get_block_by_height(prune_height)? else {
+        return Ok(());
+    };
+
+    let mut batch = ChainBatch::new();
+    let mut pruned = 0;
+    loop {
+        if current_block.is_pruned() {
+            break;
+        }
+        batch.delete_tx_index(&current_block);
+        batch.put_block(&current_block.header_only()?)?;
+        pruned += 1;
+
+        if current_block.is_genesis() {
+            break;
+        }
+        current_block = get_block(&current_block.prev_hash)?.ok_or_else(|| {
+            format!(
+                "[chain::prune_blocks] ERROR: Could not find previous block {}",
+                hex::encode(current_block.prev_hash)
+            )
+        })?;
+    }
+    batch.put_pruned_height(prune_height);
+    batch.commit()?;
+
+    info!(
+        "Pruned txs of {} blocks up to height {}",
+        pruned, prune_height
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use core_lib::wallet::Wallet;
+
+    use super::*;
+    use crate::{
+        blockchain::{
+            blocks::block::{get_block_hashes_from_height, get_blocks_in_range},
+            transaction::{mempool::add_tx_to_mempool, tx::block_reward},
+        },
+        cli::db::{get_mempool, get_tx_block_hash, get_utxo},
+        test_utils::{add_block, block_on, new_chain, next_block, pay, setup},
+    };
+
+    #[test]
+    fn pruning_keeps_headers_and_chain_still_verifies() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        for _ in 0..4 {
+            add_block(&miner, vec![]);
+        }
+        let unpruned = get_block_by_height(2).unwrap().unwrap();
+
+        prune_blocks_to(2).unwrap();
+
+        let pruned = get_block(&unpruned.hash).unwrap().unwrap();
+        assert!(pruned.is_pruned());
+        assert_eq!(pruned.header(), unpruned.header());
+        assert!(pruned.verify_header().unwrap());
+        assert!(get_tx_block_hash(&unpruned.txs[0].id).unwrap().is_none());
+        assert!(get_block_by_height(0).unwrap().unwrap().is_pruned());
+        assert!(!get_block_by_height(3).unwrap().unwrap().is_pruned());
+        assert_eq!(get_pruned_height(), Some(2));
+        assert!(validate_chain().unwrap().is_none());
+    }
+
+    #[test]
+    fn pruned_chain_still_answers_chainsync() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        for _ in 0..4 {
+            add_block(&miner, vec![]);
+        }
+        let expected: Vec<[u8; 32]> = (0..=4)
+            .rev()
+            .map(|h| get_block_by_height(h).unwrap().unwrap().hash)
+            .collect();
+
+        prune_blocks_to(2).unwrap();
+
+        assert_eq!(get_block_hashes_from_height(0).unwrap(), expected);
+        assert_eq!(get_block_hashes_from_height(2).unwrap(), expected[..3]);
+        // Full blocks are still refused below the prune point
+        assert!(get_blocks_in_range(0, 4).is_err());
+        assert_eq!(get_blocks_in_range(3, 4).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn block_json_includes_difficulty() {
+        let _guard = setup();
+        let block = add_block(&new_chain().get_wallet_address(), vec![]);
+
+        let json = serde_json::to_value(BlockJson::from_block(&block, false)).unwrap();
+
+        assert_eq!(json["difficulty"], network_params().difficulty);
+        assert_eq!(json["height"], 1);
+    }
+    #[test]
+    fn failed_commit_writes_nothing() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+        let genesis = get_last_block().unwrap();
+        let tx = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 5);
+        add_tx_to_mempool(&tx).unwrap();
+        let block = next_block(&miner, vec![tx.clone()]);
+
+        // A corrupt chain work entry for the parent fails the commit only after the block's
+        // utxo and mempool changes have been staged
+        let genesis_work = db::get_chain_work(&genesis.hash).unwrap().unwrap();
+        db::ROCKS_DB
+            .put_cf(db::work_cf(), genesis.hash, b"corrupt")
+            .unwrap();
+        assert!(commit_block(&block).is_err());
+        db::ROCKS_DB
+            .put_cf(db::work_cf(), genesis.hash, genesis_work.to_le_bytes())
+            .unwrap();
+
+        // The chain is left exactly as it was before the block
+        assert_eq!(get_last_block().unwrap().hash, genesis.hash);
+        assert!(get_block(&block.hash).unwrap().is_none());
+        assert!(get_block_by_height(1).unwrap().is_none());
+        assert!(get_tx_block_hash(&tx.id).unwrap().is_none());
+        let spent = &tx.inputs[0];
+        assert!(get_utxo(&spent.prev_tx_id, spent.out).unwrap().is_some());
+        assert!(get_utxo(&tx.id, 0).unwrap().is_none());
+        assert!(get_mempool().contains_key(&tx.id));
+
+        // The same block commits once the entry is repaired
+        assert!(matches!(
+            commit_block(&block).unwrap(),
+            CommitStatus::Committed
+        ));
+    }
+    #[test]
+    fn validate_chain_reports_tampered_block_height() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+        add_block(&miner, vec![]);
+        let tx = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 5);
+        let target = add_block(&miner, vec![tx]);
+        add_block(&miner, vec![]);
+        assert!(validate_chain().unwrap().is_none());
+
+        // Overwrite the stored block with one paying more than its tx was signed for
+        let mut tampered = target.clone();
+        tampered.txs[1].outputs[0].value += 1;
+        let mut batch = ChainBatch::new();
+        batch.put_block(&tampered).unwrap();
+        batch.commit().unwrap();
+
+        let fault = validate_chain()
+            .unwrap()
+            .expect("tampered block was not detected");
+        assert_eq!(fault.height, 2);
+        assert_eq!(fault.hash, target.hash);
+        assert_eq!(fault.reason, "contains invalid txs");
+    }
+
+    #[test]
+    fn block_json_reports_serialized_sizes() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let tx = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 5);
+        let block = add_block(&wallet.get_wallet_address(), vec![tx]);
+        assert_eq!(block.size(), bincode::serialize(&block).unwrap().len());
+
+        let json = BlockJson::from_block(&block, true);
+        assert_eq!(json.size, block.size());
+        let txs = json.txs.unwrap();
+        for (tx_json, tx) in txs.iter().zip(&block.txs) {
+            assert_eq!(tx_json.size, bincode::serialize(tx).unwrap().len());
+        }
+    }
+
+    #[test]
+    fn block_json_reports_confirmations() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        for _ in 0..4 {
+            add_block(&miner, vec![]);
+        }
+        let depth = |height| {
+            let block = get_block_by_height(height).unwrap().unwrap();
+            let json = BlockJson::from_block(&block, false)
+                .with_confirmations(&block)
+                .unwrap();
+            (json.confirmations, json.is_tip)
+        };
+
+        assert_eq!(depth(4), (Some(1), Some(true)));
+        assert_eq!(depth(0), (Some(5), Some(false)));
+    }
+
+    /// Asserts the height index holds exactly the blocks found walking back from the tip
+    fn assert_height_index_matches_chain() {
+        let mut block = get_last_block().unwrap();
+        assert!(db::get_block_hash_at_height(block.height + 1)
+            .unwrap()
+            .is_none());
+        loop {
+            assert_eq!(
+                db::get_block_hash_at_height(block.height).unwrap(),
+                Some(block.hash)
+            );
+            if block.is_genesis() {
+                break;
+            }
+            block = get_block(&block.prev_hash).unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn height_index_follows_commits_and_reorgs() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let genesis = get_last_block().unwrap();
+        for _ in 0..2 {
+            add_block(&miner, vec![]);
+        }
+        assert_height_index_matches_chain();
+
+        // A longer fork from genesis replaces every indexed block above it
+        let fork_miner = Wallet::new().get_wallet_address();
+        let mut fork = vec![genesis];
+        for _ in 0..3 {
+            fork.push(block_on(fork.last().unwrap(), &fork_miner, vec![]));
+        }
+        for block in &fork[1..] {
+            commit_block(block).unwrap();
+        }
+        check_orphans_for_longest_chain().unwrap();
+        assert_eq!(get_last_hash().unwrap(), fork[3].hash);
+        assert_height_index_matches_chain();
+        for block in &fork {
+            assert_eq!(
+                get_block_by_height(block.height).unwrap().unwrap().hash,
+                block.hash
+            );
+        }
+    }
+
+    #[test]
+    fn block_json_reports_miner_and_reward() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = Wallet::new().get_wallet_address();
+        let tx = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 5);
+        let block = add_block(&miner, vec![tx]);
+
+        // Reported even when the txs themselves are left out
+        let json = BlockJson::from_block(&block, false);
+        assert!(json.txs.is_none());
+        assert_eq!(json.miner, Some(miner.get_full_address()));
+        assert_eq!(json.reward, Some(block_reward(block.height) + 5));
+    }
+
+    #[test]
+    fn resolved_inputs_match_spent_outputs() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = Wallet::new().get_wallet_address();
+        let spender = Wallet::new();
+        add_block(
+            &miner,
+            vec![pay(&wallet, &spender.get_wallet_address(), 50, 0)],
+        );
+        add_block(&miner, vec![pay(&spender, &miner, 30, 0)]);
+
+        // Each non-coinbase input, from the tip back, as (value, address)
+        let inputs = |resolve_inputs| -> Vec<(Option<u32>, Option<String>)> {
+            get_blockchain_json(true, resolve_inputs)
+                .unwrap()
+                .into_iter()
+                .flat_map(|block| block.txs.unwrap().into_iter().skip(1))
+                .flat_map(|tx| tx.inputs)
+                .map(|input| (input.value, input.address))
+                .collect()
+        };
+
+        // The spender's input resolves to the output paid to it a block earlier, and the first
+        // tx's input to the genesis reward
+        assert_eq!(
+            inputs(true),
+            vec![
+                (
+                    Some(50),
+                    Some(spender.get_wallet_address().get_full_address())
+                ),
+                (
+                    Some(100),
+                    Some(wallet.get_wallet_address().get_full_address())
+                ),
+            ]
+        );
+        assert_eq!(inputs(false), vec![(None, None), (None, None)]);
+    }
+
+    /// Returns the block containing the given tx by walking back from the tip, as lookups did
+    /// before the tx index
+    fn scan_for_tx(tx_id: [u8; 32]) -> Option<Block> {
+        let mut block = get_last_block().unwrap();
+        loop {
+            if block.txs.iter().any(|tx| tx.id == tx_id) {
+                return Some(block);
+            }
+            if block.is_genesis() {
+                return None;
+            }
+            block = get_block(&block.prev_hash).unwrap().unwrap();
+        }
+    }
+
+    /// Asserts the tx index finds the same block as a scan of the chain for each given tx
+    fn assert_tx_index_matches_scan(tx_ids: &[[u8; 32]]) {
+        for tx_id in tx_ids {
+            let indexed = get_block_with_tx(*tx_id).unwrap().map(|block| block.hash);
+            assert_eq!(indexed, scan_for_tx(*tx_id).map(|block| block.hash));
+        }
+    }
+
+    #[test]
+    fn tx_index_matches_chain_scan() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+        let genesis = get_last_block().unwrap();
+        let mut tx_ids: Vec<[u8; 32]> = genesis.txs.iter().map(|tx| tx.id).collect();
+        for value in [20, 30] {
+            let tx = pay(&wallet, &Wallet::new().get_wallet_address(), value, 0);
+            let block = add_block(&miner, vec![tx]);
+            tx_ids.extend(block.txs.iter().map(|tx| tx.id));
+        }
+        assert_tx_index_matches_scan(&tx_ids);
+        assert!(tx_ids.iter().all(|id| scan_for_tx(*id).is_some()));
+
+        // Txs of blocks replaced by a longer fork are no longer found
+        let fork_miner = Wallet::new().get_wallet_address();
+        let mut fork = vec![genesis];
+        for _ in 0..3 {
+            fork.push(block_on(fork.last().unwrap(), &fork_miner, vec![]));
+        }
+        for block in &fork[1..] {
+            commit_block(block).unwrap();
+        }
+        check_orphans_for_longest_chain().unwrap();
+        assert_eq!(get_last_hash().unwrap(), fork[3].hash);
+        tx_ids.extend(
+            fork.iter()
+                .flat_map(|block| block.txs.iter().map(|tx| tx.id)),
+        );
+        assert_tx_index_matches_scan(&tx_ids);
+        assert!(get_block_with_tx(tx_ids[1]).unwrap().is_none());
+    }
+
+    /// Compares the time taken to find txs through the tx index and by scanning the chain. Run
+    /// with `cargo test --release tx_index_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn tx_index_benchmark() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        for _ in 0..200 {
+            add_block(&miner, vec![]);
+        }
+        // Coinbase txs of the oldest blocks take the longest to scan for
+        let tx_ids: Vec<[u8; 32]> = (0..20)
+            .map(|height| get_block_by_height(height).unwrap().unwrap().txs[0].id)
+            .collect();
+
+        let start = std::time::Instant::now();
+        for tx_id in &tx_ids {
+            assert!(get_block_with_tx(*tx_id).unwrap().is_some());
+        }
+        let indexed = start.elapsed();
+        let start = std::time::Instant::now();
+        for tx_id in &tx_ids {
+            assert!(scan_for_tx(*tx_id).is_some());
+        }
+        let scanned = start.elapsed();
+
+        println!(
+            "Found {} txs in a chain of 201 blocks: indexed {:?}, scanned {:?}",
+            tx_ids.len(),
+            indexed,
+            scanned
+        );
+    }
+}
@@ -0,0 +1,27 @@
+use thiserror::Error;
+
+/// ChainError represents failures in chain, tx and utxo handling that callers need to
+/// tell apart, e.g. to respond with the right HTTP status
+#[derive(Debug, Error)]
+pub enum ChainError {
+    #[error("insufficient funds: {available} available, {required} required")]
+    InsufficientFunds { available: u64, required: u32 },
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("invalid tx: {0}")]
+    InvalidTx(String),
+    #[error("db error: {0}")]
+    Db(String),
+}
+
+impl From<rocksdb::Error> for ChainError {
+    fn from(e: rocksdb::Error) -> Self {
+        ChainError::Db(e.to_string())
+    }
+}
+
+impl From<bincode::Error> for ChainError {
+    fn from(e: bincode::Error) -> Self {
+        ChainError::Db(format!("failed to deserialize stored data: {}", e))
+    }
+}
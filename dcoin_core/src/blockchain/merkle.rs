@@ -1,3 +1,4 @@
+use core_lib::merkle::MerkleProof;
 use sha2::{Digest, Sha256};
 
 #[derive(Debug, Clone)]
@@ -35,6 +36,26 @@ impl MerkleNode {
             hash: data_hash.into(),
         }
     }
+
+    /// Searches the subtree for the given leaf hash. If found, the sibling hashes along the path
+    /// are appended to the proof from the leaf upwards and true is returned.
+    fn build_proof(&self, leaf_hash: &[u8; 32], proof: &mut MerkleProof) -> bool {
+        match (&self.left, &self.right) {
+            (Some(left), Some(right)) => {
+                if left.build_proof(leaf_hash, proof) {
+                    proof.push((false, right.hash));
+                    true
+                } else if right.build_proof(leaf_hash, proof) {
+                    proof.push((true, left.hash));
+                    true
+                } else {
+                    false
+                }
+            }
+            // Leaf nodes have no children
+            _ => self.hash == *leaf_hash,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -63,7 +84,7 @@ impl MerkleTree {
         while nodes.len() > 1 {
             // If there is an odd number of nodes, duplicate the last node to make it even
             // Every node must have a pair to compute parent nodes
-            if nodes.len() % 2 != 0 {
+            if !nodes.len().is_multiple_of(2) {
                 let last_node = nodes.last().unwrap().clone();
                 nodes.push(last_node);
             }
@@ -92,4 +113,67 @@ impl MerkleTree {
             root: nodes.remove(0),
         }
     }
+
+    /// Generates a proof that the given tx hash is included in the tree. The proof contains the
+    /// sibling hashes from the leaf to the root, and can be checked with `verify_merkle_proof`.
+    ///
+    /// Since odd nodes are duplicated when building the tree, a duplicated node's sibling is itself.
+    pub fn generate_proof(&self, tx_hash: &[u8; 32]) -> Option<MerkleProof> {
+        let leaf_hash: [u8; 32] = Sha256::digest(tx_hash).into();
+        let mut proof: MerkleProof = Vec::new();
+        if self.root.build_proof(&leaf_hash, &mut proof) {
+            Some(proof)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core_lib::merkle::verify_merkle_proof;
+
+    use super::*;
+
+    fn tree_of(tx_hashes: &[[u8; 32]]) -> MerkleTree {
+        MerkleTree::new(tx_hashes.iter().map(|hash| hash.to_vec()).collect())
+    }
+
+    #[test]
+    fn proves_inclusion_of_every_tx() {
+        for count in [1u8, 2, 5, 8] {
+            let tx_hashes: Vec<[u8; 32]> = (0..count).map(|i| [i; 32]).collect();
+            let tree = tree_of(&tx_hashes);
+            for tx_hash in &tx_hashes {
+                let proof = tree.generate_proof(tx_hash).unwrap();
+                assert!(verify_merkle_proof(tx_hash, &proof, &tree.root.hash));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_proof() {
+        let tx_hashes: Vec<[u8; 32]> = (0..5u8).map(|i| [i; 32]).collect();
+        let tree = tree_of(&tx_hashes);
+        let proof = tree.generate_proof(&tx_hashes[2]).unwrap();
+
+        let mut tampered_hash = proof.clone();
+        tampered_hash[0].1[0] ^= 1;
+        assert!(!verify_merkle_proof(
+            &tx_hashes[2],
+            &tampered_hash,
+            &tree.root.hash
+        ));
+
+        let mut tampered_side = proof.clone();
+        tampered_side[0].0 = !tampered_side[0].0;
+        assert!(!verify_merkle_proof(
+            &tx_hashes[2],
+            &tampered_side,
+            &tree.root.hash
+        ));
+
+        assert!(!verify_merkle_proof(&[9u8; 32], &proof, &tree.root.hash));
+        assert!(tree.generate_proof(&[9u8; 32]).is_none());
+    }
 }
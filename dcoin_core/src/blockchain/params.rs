@@ -0,0 +1,212 @@
+use clap::ValueEnum;
+use core_lib::address::{set_address_version, MAINNET_VERSION, TESTNET_VERSION};
+use core_lib::tx::{set_network_id, MAINNET_NETWORK_ID, TESTNET_NETWORK_ID};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::str::FromStr;
+
+/// Network selects which dcoin network a node participates in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+}
+
+/// NetworkParams are the consensus and networking parameters that differ between networks
+#[derive(Debug, Clone)]
+pub struct NetworkParams {
+    pub network: Network,
+    /// Unique network identifier. Prefixed to all gossip topics so nodes on different
+    /// networks never exchange messages, and bound into tx signatures to prevent replays
+    pub magic: [u8; 4],
+    /// Number of leading zero bits required of a block hash
+    pub difficulty: usize,
+    /// Version byte of addresses on this network
+    pub address_version: u8,
+    /// Known block hashes the chain must pass through. Reorgs can't roll back past the latest
+    /// checkpoint, and blocks conflicting with a checkpoint are rejected
+    pub checkpoints: Vec<Checkpoint>,
+    /// Max seconds a block timestamp may be ahead of local time. Later blocks are rejected, so
+    /// timestamps can't be pushed forward to manipulate difficulty
+    pub max_time_drift: u64,
+    /// Number of blocks after which the block reward halves
+    pub halving_interval: u32,
+}
+
+/// DEFAULT_MAX_TIME_DRIFT_SECS is how far ahead of local time block timestamps may be by default
+pub const DEFAULT_MAX_TIME_DRIFT_SECS: u64 = 2 * 60 * 60;
+
+/// Checkpoint pins the hash of the block at a given height
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub height: u32,
+    pub hash: [u8; 32],
+}
+
+impl FromStr for Checkpoint {
+    type Err = String;
+
+    /// Parses a checkpoint in the format HEIGHT:HASH, with the hash hex-encoded
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (height, hash) = s
+            .split_once(':')
+            .ok_or("checkpoint must be in the format HEIGHT:HASH")?;
+        let height = height
+            .parse::<u32>()
+            .map_err(|e| format!("invalid checkpoint height: {}", e))?;
+        let hash = hex::decode(hash)
+            .map_err(|e| format!("invalid checkpoint hash: {}", e))?
+            .try_into()
+            .map_err(|_| "checkpoint hash must be 32 bytes")?;
+        Ok(Checkpoint { height, hash })
+    }
+}
+
+/// Hardcoded checkpoints for each network. Each node currently mines its own genesis block, so
+/// none are pinned yet - additional checkpoints can be passed on the command line
+const MAINNET_CHECKPOINTS: &[Checkpoint] = &[];
+const TESTNET_CHECKPOINTS: &[Checkpoint] = &[];
+
+impl Network {
+    pub fn params(&self) -> NetworkParams {
+        match self {
+            Network::Mainnet => NetworkParams {
+                network: *self,
+                magic: MAINNET_NETWORK_ID,
+                difficulty: 16,
+                address_version: MAINNET_VERSION,
+                checkpoints: MAINNET_CHECKPOINTS.to_vec(),
+                max_time_drift: DEFAULT_MAX_TIME_DRIFT_SECS,
+                halving_interval: 210_000,
+            },
+            Network::Testnet => NetworkParams {
+                network: *self,
+                magic: TESTNET_NETWORK_ID,
+                difficulty: 12,
+                address_version: TESTNET_VERSION,
+                checkpoints: TESTNET_CHECKPOINTS.to_vec(),
+                max_time_drift: DEFAULT_MAX_TIME_DRIFT_SECS,
+                halving_interval: 1_000,
+            },
+        }
+    }
+}
+
+impl NetworkParams {
+    /// Returns the hex-encoded network magic, used as the gossip topic prefix
+    pub fn magic_hex(&self) -> String {
+        hex::encode(self.magic)
+    }
+
+    /// Returns false if there is a checkpoint at the given height with a different hash
+    pub fn matches_checkpoint(&self, height: u32, hash: &[u8; 32]) -> bool {
+        self.checkpoints
+            .iter()
+            .all(|cp| cp.height != height || cp.hash == *hash)
+    }
+
+    /// Returns the highest checkpoint at or below the given height
+    pub fn last_checkpoint(&self, height: u32) -> Option<&Checkpoint> {
+        self.checkpoints
+            .iter()
+            .filter(|cp| cp.height <= height)
+            .max_by_key(|cp| cp.height)
+    }
+
+    /// Returns the checkpoint a rollback from the tip height down to the target height would
+    /// pass, if any. Only the latest checkpoint at or below the tip matters, as the chain
+    /// already passes through all earlier ones
+    pub fn checkpoint_blocking_rollback(
+        &self,
+        tip_height: u32,
+        target_height: u32,
+    ) -> Option<&Checkpoint> {
+        self.last_checkpoint(tip_height)
+            .filter(|cp| target_height < cp.height)
+    }
+}
+
+static NETWORK_PARAMS: OnceCell<NetworkParams> = OnceCell::new();
+
+/// Sets the params of the network the node runs on, including the address version and network
+/// id used by core_lib. Must be called before the chain, p2p network, any address or any tx
+/// signature is first used, and can only be set once.
+pub fn set_network_params(params: NetworkParams) -> Result<(), NetworkParams> {
+    if set_address_version(params.address_version).is_err() || set_network_id(params.magic).is_err()
+    {
+        return Err(params);
+    }
+    NETWORK_PARAMS.set(params)
+}
+
+/// Returns the params of the configured network, or mainnet if none was set
+pub fn network_params() -> &'static NetworkParams {
+    NETWORK_PARAMS.get_or_init(|| Network::Mainnet.params())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params_with_checkpoints(heights: &[u32]) -> NetworkParams {
+        let mut params = Network::Testnet.params();
+        params.checkpoints = heights
+            .iter()
+            .map(|&height| Checkpoint {
+                height,
+                hash: [height as u8; 32],
+            })
+            .collect();
+        params
+    }
+
+    #[test]
+    fn rollback_cannot_pass_latest_checkpoint() {
+        let params = params_with_checkpoints(&[10, 20]);
+
+        // Rolling back to the checkpointed block itself or above it is allowed
+        assert_eq!(params.checkpoint_blocking_rollback(25, 20), None);
+        assert_eq!(params.checkpoint_blocking_rollback(25, 22), None);
+        // Rolling back past it isn't, even if an earlier checkpoint isn't passed
+        assert_eq!(
+            params
+                .checkpoint_blocking_rollback(25, 15)
+                .map(|cp| cp.height),
+            Some(20)
+        );
+        // Checkpoints above the tip haven't been reached yet, so only the earlier one applies
+        assert_eq!(
+            params
+                .checkpoint_blocking_rollback(15, 5)
+                .map(|cp| cp.height),
+            Some(10)
+        );
+        assert_eq!(params.checkpoint_blocking_rollback(9, 0), None);
+    }
+
+    #[test]
+    fn block_conflicting_with_checkpoint_is_rejected() {
+        let params = params_with_checkpoints(&[10]);
+
+        assert!(params.matches_checkpoint(10, &[10u8; 32]));
+        assert!(!params.matches_checkpoint(10, &[11u8; 32]));
+        // Heights without a checkpoint accept any hash
+        assert!(params.matches_checkpoint(11, &[11u8; 32]));
+    }
+
+    #[test]
+    fn checkpoint_parses_height_and_hash() {
+        let hash = hex::encode([3u8; 32]);
+        assert_eq!(
+            format!("7:{}", hash).parse::<Checkpoint>(),
+            Ok(Checkpoint {
+                height: 7,
+                hash: [3u8; 32]
+            })
+        );
+        assert!("7".parse::<Checkpoint>().is_err());
+        assert!(format!("x:{}", hash).parse::<Checkpoint>().is_err());
+        assert!("7:abcd".parse::<Checkpoint>().is_err());
+    }
+}
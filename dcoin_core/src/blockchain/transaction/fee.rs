@@ -0,0 +1,190 @@
+use core_lib::tx::{Tx, TxOutput};
+use serde::Serialize;
+use std::{collections::HashMap, error::Error};
+
+use crate::{
+    blockchain::chain::{get_block_with_tx_from, get_last_block},
+    cli::db::get_block,
+};
+
+/// DEFAULT_FEE_ESTIMATE_BLOCKS is how many recent blocks are sampled when none are requested
+pub const DEFAULT_FEE_ESTIMATE_BLOCKS: u32 = 6;
+/// MAX_FEE_ESTIMATE_BLOCKS caps the blocks sampled by a single estimate
+pub const MAX_FEE_ESTIMATE_BLOCKS: u32 = 100;
+/// Fee per byte suggested when there is too little history to estimate from
+pub const DEFAULT_FEE_RATE: f64 = 0.05;
+/// Minimum number of recent txs needed before their fee rates are trusted over the default
+const MIN_FEE_SAMPLES: usize = 5;
+
+/// FeeEstimate suggests fees per byte, taken from the fee rates paid by txs in recent blocks
+#[derive(Serialize, Debug)]
+pub struct FeeEstimate {
+    /// Number of blocks that were sampled
+    pub blocks: u32,
+    /// Number of txs the estimate is based on
+    pub samples: usize,
+    /// 25th percentile fee rate, for txs that can wait a while to be mined
+    pub low: f64,
+    /// Median fee rate
+    pub medium: f64,
+    /// 75th percentile fee rate, for txs that should be mined quickly
+    pub high: f64,
+}
+
+/// Estimates fee rates from the non-coinbase txs of the last `blocks` main chain blocks. Falls
+/// back to DEFAULT_FEE_RATE if there are too few txs to estimate from.
+pub fn estimate_fee(blocks: u32) -> Result<FeeEstimate, Box<dyn Error>> {
+    // Gather the sampled blocks, stopping early at genesis or the pruned part of the chain
+    let mut sampled = Vec::new();
+    let mut current_block = get_last_block()?;
+    while sampled.len() < blocks as usize && !current_block.is_pruned() {
+        let is_genesis = current_block.is_genesis();
+        let prev_hash = current_block.prev_hash;
+        sampled.push(current_block);
+        if is_genesis {
+            break;
+        }
+        current_block = get_block(&prev_hash)?.ok_or_else(|| {
+            format!(
+                "[fee::estimate_fee] ERROR: Could not find previous block {}",
+                hex::encode(prev_hash)
+            )
+        })?;
+    }
+
+    // Txs often spend outputs created in the sampled blocks, so index those before
+    // falling back to searching the chain
+    let mut outputs: HashMap<[u8; 32], Vec<TxOutput>> = HashMap::new();
+    for block in &sampled {
+        for tx in &block.txs {
+            outputs.insert(tx.id, tx.outputs.clone());
+        }
+    }
+
+    let mut rates = Vec::new();
+    for block in &sampled {
+        for tx in block.txs.iter().filter(|tx| !tx.is_coinbase()) {
+            if let Some(rate) = fee_rate(tx, block.prev_hash, &mut outputs)? {
+                rates.push(rate);
+            }
+        }
+    }
+
+    if rates.len() < MIN_FEE_SAMPLES {
+        return Ok(FeeEstimate {
+            blocks: sampled.len() as u32,
+            samples: rates.len(),
+            low: DEFAULT_FEE_RATE,
+            medium: DEFAULT_FEE_RATE,
+            high: DEFAULT_FEE_RATE,
+        });
+    }
+
+    rates.sort_by(|a, b| a.total_cmp(b));
+    Ok(FeeEstimate {
+        blocks: sampled.len() as u32,
+        samples: rates.len(),
+        low: percentile(&rates, 25),
+        medium: percentile(&rates, 50),
+        high: percentile(&rates, 75),
+    })
+}
+
+/// Returns the fee per byte paid by a confirmed tx. Spent outputs not found in the given index
+/// are searched for in the chain from `prev_hash` back, and added to the index. Returns None if
+/// a spent output can't be found, such as when it is in the pruned part of the chain.
+fn fee_rate(
+    tx: &Tx,
+    prev_hash: [u8; 32],
+    outputs: &mut HashMap<[u8; 32], Vec<TxOutput>>,
+) -> Result<Option<f64>, Box<dyn Error>> {
+    let mut input_sum: u64 = 0;
+    for input in &tx.inputs {
+        if !outputs.contains_key(&input.prev_tx_id) {
+            let Some(block) = get_block_with_tx_from(prev_hash, input.prev_tx_id)? else {
+                return Ok(None);
+            };
+            for prev_tx in block.txs {
+                outputs.insert(prev_tx.id, prev_tx.outputs);
+            }
+        }
+        let Some(prev_out) = outputs
+            .get(&input.prev_tx_id)
+            .and_then(|outs| outs.get(input.out as usize))
+        else {
+            return Ok(None);
+        };
+        input_sum += u64::from(prev_out.value);
+    }
+
+    let output_sum: u64 = tx.outputs.iter().map(|o| u64::from(o.value)).sum();
+    Ok(Some(
+        input_sum.saturating_sub(output_sum) as f64 / tx.size() as f64,
+    ))
+}
+
+/// Returns the value at the given percentile of sorted, non-empty values
+fn percentile(sorted: &[f64], pct: usize) -> f64 {
+    sorted[(sorted.len() - 1) * pct / 100]
+}
+
+#[cfg(test)]
+mod tests {
+    use core_lib::wallet::Wallet;
+
+    use super::*;
+    use crate::{
+        blockchain::transaction::utxo::find_spendable_utxos,
+        test_utils::{add_block, new_chain, setup},
+    };
+
+    #[test]
+    fn estimate_reflects_recent_fee_rates() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+
+        // Only the genesis block exists, so there is no history to estimate from
+        let estimate = estimate_fee(DEFAULT_FEE_ESTIMATE_BLOCKS).unwrap();
+        assert_eq!((estimate.blocks, estimate.samples), (1, 0));
+        assert_eq!(estimate.medium, DEFAULT_FEE_RATE);
+
+        // Fund several wallets without a fee, then have each pay the same fee
+        let senders: Vec<Wallet> = (0..6).map(|_| Wallet::new()).collect();
+        let recipients: Vec<_> = senders
+            .iter()
+            .map(|sender| (sender.get_wallet_address(), 15))
+            .collect();
+        let utxos = find_spendable_utxos(miner.pub_key_hash(), 90).unwrap();
+        add_block(
+            &miner,
+            vec![Tx::new_multi(&wallet, &recipients, 0, utxos).unwrap()],
+        );
+        let txs: Vec<Tx> = senders
+            .iter()
+            .map(|sender| {
+                let utxos =
+                    find_spendable_utxos(sender.get_wallet_address().pub_key_hash(), 15).unwrap();
+                Tx::new(sender, &miner, 10, 5, utxos).unwrap()
+            })
+            .collect();
+        let rates: Vec<f64> = txs
+            .iter()
+            .map(|tx| 5.0 / bincode::serialized_size(tx).unwrap() as f64)
+            .collect();
+        let min_rate = rates.iter().copied().fold(f64::MAX, f64::min);
+        let max_rate = rates.iter().copied().fold(0.0, f64::max);
+        add_block(&miner, txs);
+
+        let estimate = estimate_fee(1).unwrap();
+        assert_eq!((estimate.blocks, estimate.samples), (1, 6));
+        for rate in [estimate.low, estimate.medium, estimate.high] {
+            assert!(min_rate <= rate && rate <= max_rate, "{}", rate);
+        }
+
+        // Sampling more blocks adds the fee-free funding tx, which the median is robust to
+        let estimate = estimate_fee(DEFAULT_FEE_ESTIMATE_BLOCKS).unwrap();
+        assert_eq!((estimate.blocks, estimate.samples), (3, 7));
+        assert!(min_rate <= estimate.medium && estimate.medium <= max_rate);
+    }
+}
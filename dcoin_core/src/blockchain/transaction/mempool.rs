@@ -1,79 +1,446 @@
-use core_lib::tx::Tx;
+use core_lib::{constants::MIN_OUTPUT_VALUE, tx::Tx};
+use log::{debug, info, warn};
 
 use crate::{
-    blockchain::blocks::block::Block,
-    cli::db::{self, get_mempool},
+    blockchain::{
+        blocks::block::Block,
+        error::ChainError,
+        transaction::tx::{has_dust_output, TxVerify},
+    },
+    cli::db::{self, get_mempool, get_mempool_spends, ChainBatch},
 };
 
-use std::{collections::HashMap, error::Error};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-pub type Mempool = HashMap<[u8; 32], Tx>;
+pub type Mempool = HashMap<[u8; 32], MempoolEntry>;
+/// MempoolSpends maps each outpoint spent by a mempool tx to the id of the spending tx
+pub type MempoolSpends = HashMap<([u8; 32], u32), [u8; 32]>;
+/// OrphanTxs are txs spending outputs missing from the utxo set, waiting for a block to
+/// provide them
+pub type OrphanTxs = HashMap<[u8; 32], MempoolEntry>;
 
-/// Returns a bool representing if the output exists in any txs stored in the mempool
-pub fn mempool_contains_txo(tx_id: [u8; 32], out_idx: u32) -> bool {
-    let mempool = get_mempool();
-    for (_, tx) in mempool {
-        for tx_in in tx.inputs {
-            if tx_in.prev_tx_id == tx_id && tx_in.out == out_idx {
-                return true;
-            }
+/// DEFAULT_MEMPOOL_TTL_SECS is how long a tx may stay in the mempool without being mined
+pub const DEFAULT_MEMPOOL_TTL_SECS: u64 = 72 * 60 * 60;
+/// How often the mempool is swept for expired txs
+const MEMPOOL_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// MempoolEntry is a pending tx along with the time it was added to the mempool
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Tx,
+    /// Unix timestamp in seconds of when the tx entered the mempool
+    pub added_at: u64,
+}
+
+impl MempoolEntry {
+    pub fn new(tx: Tx) -> Self {
+        MempoolEntry {
+            tx,
+            added_at: now_secs(),
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Builds the index of outpoints spent by the given mempool's txs
+pub fn index_mempool_spends(mempool: &Mempool) -> MempoolSpends {
+    let mut spends = HashMap::new();
+    for (tx_id, entry) in mempool {
+        for tx_in in &entry.tx.inputs {
+            spends.insert((tx_in.prev_tx_id, tx_in.out), *tx_id);
         }
     }
-    return false;
+    spends
+}
+
+/// Returns a bool representing if the output is spent by any tx stored in the mempool
+pub fn mempool_contains_txo(tx_id: [u8; 32], out_idx: u32) -> bool {
+    get_mempool_spends().contains_key(&(tx_id, out_idx))
 }
 
 /// Returns the tx from the mempool if found
 pub fn get_tx_from_mempool(tx_id: [u8; 32]) -> Option<Tx> {
     let mempool = get_mempool();
-    mempool.get(&tx_id).cloned()
+    mempool.get(&tx_id).map(|entry| entry.tx.clone())
 }
 
 /// Check if the mempool contains a given tx
 pub fn mempool_contains_tx(tx_id: [u8; 32]) -> bool {
     let mempool = get_mempool();
-    match mempool.get(&tx_id) {
-        Some(_) => true,
-        None => false,
-    }
+    mempool.contains_key(&tx_id)
 }
 
-pub fn add_tx_to_mempool(tx: &Tx) -> Result<(), Box<dyn Error>> {
-    for tx_input in &tx.inputs {
-        if mempool_contains_txo(tx_input.prev_tx_id, tx_input.out) {
-            return Err(
-                "[mempool::add_tx_to_mempool] ERROR: tx contains outputs spent in mempool".into(),
-            );
-        }
+/// Adds a tx to the mempool. Every input must reference an existing utxo that isn't already
+/// spent by another tx in the mempool.
+pub fn add_tx_to_mempool(tx: &Tx) -> Result<(), ChainError> {
+    if has_dust_output(tx) {
+        return Err(ChainError::InvalidTx(format!(
+            "tx contains outputs below the dust limit of {}",
+            MIN_OUTPUT_VALUE
+        )));
     }
 
-    db::put_mempool(&tx);
-    Ok(())
+    // Check and insert under the mempool lock, so two txs spending the same output can't both
+    // pass the check when added concurrently
+    db::modify_mempool(|mempool, spends| {
+        for tx_input in &tx.inputs {
+            // Spent outputs are removed from the utxo set, so this also rejects double spends of confirmed txs
+            let utxo = db::get_utxo(&tx_input.prev_tx_id, tx_input.out)
+                .map_err(|e| ChainError::Db(e.to_string()))?;
+            if utxo.is_none() {
+                return Err(ChainError::InvalidTx(format!(
+                    "tx spends missing or already spent output {}:{}",
+                    hex::encode(tx_input.prev_tx_id),
+                    tx_input.out
+                )));
+            }
+            if let Some(conflict_id) = spends.get(&(tx_input.prev_tx_id, tx_input.out)) {
+                return Err(ChainError::InvalidTx(format!(
+                    "tx spends output {}:{} already spent by mempool tx {}",
+                    hex::encode(tx_input.prev_tx_id),
+                    tx_input.out,
+                    hex::encode(conflict_id)
+                )));
+            }
+        }
+
+        mempool.insert(tx.id, MempoolEntry::new(tx.clone()));
+        Ok(())
+    })
 }
 
-/// Update mempool with a new block
-pub fn update_mempool(block: &Block) -> Result<(), Box<dyn Error>> {
+/// Removes the mempool txs confirmed by a new block, along with the txs conflicting with it.
+/// Conflicting txs spend an output the block spends with a different tx. They can never be
+/// mined, and neither can any mempool txs spending their outputs, so those are evicted too.
+pub fn update_mempool(block: &Block, batch: &mut ChainBatch) -> Result<(), Box<dyn Error>> {
     let mempool = get_mempool();
+    let spends = get_mempool_spends();
 
-    // Use mempool id/out hashmap for faster lookup
-    let mut input_map: HashMap<([u8; 32], u32), [u8; 32]> = HashMap::new();
-    for (mem_tx_id, mem_tx) in &mempool {
-        for input in &mem_tx.inputs {
-            input_map.insert((input.prev_tx_id, input.out), *mem_tx_id);
+    let block_tx_ids: HashSet<[u8; 32]> = block.txs.iter().map(|tx| tx.id).collect();
+    let mut tx_ids_to_remove: HashSet<[u8; 32]> = HashSet::new();
+    let mut conflicts = Vec::new();
+    for block_tx in &block.txs {
+        if block_tx.is_coinbase() {
+            continue;
+        }
+        for input in &block_tx.inputs {
+            if let Some(mem_tx_id) = spends.get(&(input.prev_tx_id, input.out)) {
+                if tx_ids_to_remove.insert(*mem_tx_id) && !block_tx_ids.contains(mem_tx_id) {
+                    conflicts.push(*mem_tx_id);
+                }
+            }
         }
     }
 
-    // Track all mempool txs that got spent in the block
-    let mut tx_ids_to_remove = Vec::new();
-    for block_tx in &block.txs {
-        if !block_tx.is_coinbase() {
-            for input in &block_tx.inputs {
-                if let Some(mem_tx_id) = input_map.get(&(input.prev_tx_id, input.out)) {
-                    tx_ids_to_remove.push(*mem_tx_id);
+    // Evict the descendants of conflicting txs, since the outputs they spend will never exist
+    while let Some(conflict_id) = conflicts.pop() {
+        let Some(entry) = mempool.get(&conflict_id) else {
+            continue;
+        };
+        for out_idx in 0..entry.tx.outputs.len() as u32 {
+            if let Some(child_id) = spends.get(&(conflict_id, out_idx)) {
+                if tx_ids_to_remove.insert(*child_id) {
+                    conflicts.push(*child_id);
                 }
             }
         }
     }
 
-    db::remove_txs_from_mempool(tx_ids_to_remove);
-    Ok(())
+    let tx_ids_to_remove: Vec<[u8; 32]> = tx_ids_to_remove.into_iter().collect();
+    batch.remove_txs_from_mempool(&tx_ids_to_remove)
+}
+
+/// Returns true if any input of the tx spends an output missing from the utxo set. The
+/// output may not have been confirmed yet, or may already be spent.
+pub fn has_missing_inputs(tx: &Tx) -> Result<bool, ChainError> {
+    for tx_input in &tx.inputs {
+        let utxo = db::get_utxo(&tx_input.prev_tx_id, tx_input.out)
+            .map_err(|e| ChainError::Db(e.to_string()))?;
+        if utxo.is_none() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Holds a tx with missing inputs in the orphan tx pool, until a block provides its inputs.
+/// Orphans can't be verified yet, so they are only verified once promoted.
+pub fn add_orphan_tx(tx: &Tx) {
+    db::put_orphan_tx(tx);
+}
+
+/// Re-evaluates the orphan tx pool against the utxo set. Orphans whose inputs are now all known
+/// are verified and moved to the mempool, and invalid orphans are dropped. Returns the number of
+/// txs promoted.
+pub fn promote_orphan_txs() -> usize {
+    let mut resolved = Vec::new();
+    let mut promoted = 0;
+    for (tx_id, entry) in db::get_orphan_txs() {
+        match has_missing_inputs(&entry.tx) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                warn!("Failed to check orphan tx inputs: {}", e);
+                continue;
+            }
+        }
+        resolved.push(tx_id);
+
+        if !matches!(entry.tx.verify(), Ok(true)) {
+            debug!("Dropping invalid orphan tx {}", hex::encode(tx_id));
+            continue;
+        }
+        match add_tx_to_mempool(&entry.tx) {
+            Ok(()) => promoted += 1,
+            Err(e) => debug!("Dropping orphan tx {}: {}", hex::encode(tx_id), e),
+        }
+    }
+
+    if !resolved.is_empty() {
+        db::remove_orphan_txs(&resolved);
+    }
+    promoted
+}
+
+/// Removes all txs that have been in the mempool or orphan tx pool for longer than the given
+/// ttl. Returns the number of txs removed.
+pub fn expire_mempool_txs(ttl: Duration) -> usize {
+    let cutoff = now_secs().saturating_sub(ttl.as_secs());
+    let expired: Vec<[u8; 32]> = get_mempool()
+        .into_iter()
+        .filter(|(_, entry)| entry.added_at < cutoff)
+        .map(|(tx_id, _)| tx_id)
+        .collect();
+    let expired_orphans: Vec<[u8; 32]> = db::get_orphan_txs()
+        .into_iter()
+        .filter(|(_, entry)| entry.added_at < cutoff)
+        .map(|(tx_id, _)| tx_id)
+        .collect();
+
+    let count = expired.len() + expired_orphans.len();
+    if !expired.is_empty() {
+        db::remove_txs_from_mempool(expired);
+    }
+    if !expired_orphans.is_empty() {
+        db::remove_orphan_txs(&expired_orphans);
+    }
+    count
+}
+
+/// Periodically evicts txs that have been pending for longer than the given ttl, so that txs
+/// which can never be mined don't accumulate in the mempool
+pub async fn start_mempool_expiry(ttl: Duration) {
+    let mut interval = tokio::time::interval(MEMPOOL_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let removed = expire_mempool_txs(ttl);
+        if removed > 0 {
+            info!("Removed {} expired txs from the mempool", removed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core_lib::{tx::TxOutput, wallet::Wallet};
+
+    use super::*;
+    use crate::{
+        blockchain::transaction::utxo::find_spendable_utxos,
+        test_utils::{add_block, new_chain, pay, setup},
+    };
+
+    /// Asserts the tx is refused entry to the mempool for the given reason
+    fn assert_rejected(tx: &Tx, reason: &str) {
+        match add_tx_to_mempool(tx) {
+            Err(ChainError::InvalidTx(msg)) => assert!(msg.contains(reason), "{}", msg),
+            res => panic!("expected tx to be rejected, got {:?}", res.map(|_| ())),
+        }
+        assert!(!get_mempool().contains_key(&tx.id));
+    }
+
+    #[test]
+    fn rejects_tx_spending_missing_utxo() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let to = Wallet::new().get_wallet_address();
+        let fictional = TxOutput {
+            value: 100,
+            pub_key_hash: *wallet.get_wallet_address().pub_key_hash(),
+        };
+        let utxos = HashMap::from([([7u8; 32], HashMap::from([(0, fictional)]))]);
+        let tx = Tx::new(&wallet, &to, 50, 5, utxos).unwrap();
+
+        assert_rejected(&tx, "missing or already spent");
+    }
+
+    #[test]
+    fn rejects_tx_spending_spent_utxo() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+        let utxos = find_spendable_utxos(miner.pub_key_hash(), 55).unwrap();
+        let to = Wallet::new().get_wallet_address();
+        let spent = Tx::new(&wallet, &to, 50, 5, utxos.clone()).unwrap();
+
+        // Spending an output already spent by a mempool tx
+        add_tx_to_mempool(&spent).unwrap();
+        let conflict = Tx::new(
+            &wallet,
+            &Wallet::new().get_wallet_address(),
+            50,
+            5,
+            utxos.clone(),
+        )
+        .unwrap();
+        assert_rejected(&conflict, "already spent by mempool tx");
+
+        // Spending an output already spent by a confirmed tx
+        add_block(&miner, vec![spent]);
+        assert_rejected(&conflict, "missing or already spent");
+    }
+
+    #[test]
+    fn expiry_removes_only_txs_older_than_ttl() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let to = Wallet::new().get_wallet_address();
+        let tx_spending = |prev_tx_id: [u8; 32]| {
+            let output = TxOutput {
+                value: 100,
+                pub_key_hash: *wallet.get_wallet_address().pub_key_hash(),
+            };
+            let utxos = HashMap::from([(prev_tx_id, HashMap::from([(0, output)]))]);
+            Tx::new(&wallet, &to, 50, 5, utxos).unwrap()
+        };
+        let stale = tx_spending([1u8; 32]);
+        let fresh = tx_spending([2u8; 32]);
+        db::put_mempool(&stale);
+        db::put_mempool(&fresh);
+
+        let ttl = Duration::from_secs(DEFAULT_MEMPOOL_TTL_SECS);
+        db::modify_mempool(|mempool, _| {
+            mempool.get_mut(&stale.id).unwrap().added_at -= ttl.as_secs() + 1;
+            Ok::<_, ChainError>(())
+        })
+        .unwrap();
+
+        assert_eq!(expire_mempool_txs(ttl), 1);
+        let mempool = get_mempool();
+        assert!(!mempool.contains_key(&stale.id));
+        assert!(mempool.contains_key(&fresh.id));
+    }
+
+    #[test]
+    fn concurrent_adds_all_persist() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+
+        // Split the genesis output between several wallets, so each can send a tx of its own
+        let senders: Vec<Wallet> = (0..8).map(|_| Wallet::new()).collect();
+        let recipients: Vec<_> = senders
+            .iter()
+            .map(|sender| (sender.get_wallet_address(), 12))
+            .collect();
+        let utxos = find_spendable_utxos(miner.pub_key_hash(), 96).unwrap();
+        let split = Tx::new_multi(&wallet, &recipients, 0, utxos).unwrap();
+        add_block(&miner, vec![split]);
+
+        let txs: Vec<Tx> = senders
+            .iter()
+            .map(|sender| {
+                let utxos =
+                    find_spendable_utxos(sender.get_wallet_address().pub_key_hash(), 12).unwrap();
+                Tx::new(sender, &miner, 12, 0, utxos).unwrap()
+            })
+            .collect();
+        std::thread::scope(|scope| {
+            for tx in &txs {
+                scope.spawn(|| add_tx_to_mempool(tx).unwrap());
+            }
+        });
+
+        let mempool = get_mempool();
+        assert_eq!(mempool.len(), txs.len());
+        assert!(txs.iter().all(|tx| mempool.contains_key(&tx.id)));
+    }
+
+    #[test]
+    fn block_evicts_conflicting_txs_and_their_children() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let other = Wallet::new();
+        add_block(&other.get_wallet_address(), vec![]);
+
+        let genesis_utxos =
+            find_spendable_utxos(wallet.get_wallet_address().pub_key_hash(), 50).unwrap();
+        let to = Wallet::new();
+        let conflicting = Tx::new(
+            &wallet,
+            &to.get_wallet_address(),
+            50,
+            0,
+            genesis_utxos.clone(),
+        )
+        .unwrap();
+        add_tx_to_mempool(&conflicting).unwrap();
+        // A child of the conflicting tx, spending its output before it is confirmed
+        let parent_out =
+            HashMap::from([(conflicting.id, HashMap::from([(0, conflicting.outputs[0])]))]);
+        let child = Tx::new(&to, &wallet.get_wallet_address(), 40, 0, parent_out).unwrap();
+        db::put_mempool(&child);
+        let utxos = find_spendable_utxos(other.get_wallet_address().pub_key_hash(), 20).unwrap();
+        let unrelated = Tx::new(&other, &to.get_wallet_address(), 20, 0, utxos).unwrap();
+        add_tx_to_mempool(&unrelated).unwrap();
+
+        // The block spends the same genesis output as the conflicting tx
+        let confirmed = Tx::new(
+            &wallet,
+            &Wallet::new().get_wallet_address(),
+            60,
+            0,
+            genesis_utxos,
+        )
+        .unwrap();
+        add_block(&wallet.get_wallet_address(), vec![confirmed]);
+
+        let mempool = get_mempool();
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains_key(&unrelated.id));
+        let spends = get_mempool_spends();
+        assert_eq!(spends.len(), unrelated.inputs.len());
+        assert!(spends.values().all(|tx_id| *tx_id == unrelated.id));
+    }
+
+    #[test]
+    fn orphan_tx_is_promoted_once_parent_is_mined() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let to = Wallet::new();
+        let parent = pay(&wallet, &to.get_wallet_address(), 50, 0);
+        let parent_out = HashMap::from([(parent.id, HashMap::from([(0, parent.outputs[0])]))]);
+        let orphan = Tx::new(&to, &wallet.get_wallet_address(), 40, 0, parent_out).unwrap();
+
+        assert!(has_missing_inputs(&orphan).unwrap());
+        add_orphan_tx(&orphan);
+        // Nothing provides the orphan's input yet, so it stays in the pool
+        assert_eq!(promote_orphan_txs(), 0);
+        assert!(db::get_orphan_txs().contains_key(&orphan.id));
+
+        add_block(&wallet.get_wallet_address(), vec![parent]);
+        assert!(get_mempool().contains_key(&orphan.id));
+        assert!(db::get_orphan_txs().is_empty());
+    }
 }
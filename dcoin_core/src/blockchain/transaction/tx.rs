@@ -1,69 +1,184 @@
 use core_lib::address::{hash_pub_key, Address};
+use core_lib::constants::MIN_OUTPUT_VALUE;
 use core_lib::tx::{Tx, TxInput, TxOutput};
-use secp256k1::rand::RngCore;
-use secp256k1::{rand, Message, PublicKey, Secp256k1, SecretKey};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use std::error::Error;
 
+use super::utxo::UTXOSet;
+use crate::blockchain::params::network_params;
 use crate::cli::db::get_utxo;
 
 /** Constants **/
-pub const COINBASE_REWARD: u32 = 100;
+/// Reward of the first blocks, before any halving
+pub const INITIAL_BLOCK_REWARD: u32 = 100;
 pub trait TxVerify {
     fn verify(&self) -> Result<bool, Box<dyn std::error::Error>>;
+    /// Same as `verify`, but resolves spent outputs from the given utxo set instead of the db
+    fn verify_against(&self, utxos: &UTXOSet) -> Result<bool, Box<dyn Error>>;
 }
 
 impl TxVerify for Tx {
     fn verify(&self) -> Result<bool, Box<dyn Error>> {
-        // Coinbase txs do not need standard verification
-        if self.is_coinbase() {
-            return Ok(true);
+        verify_tx_with(self, get_utxo)
+    }
+
+    fn verify_against(&self, utxos: &UTXOSet) -> Result<bool, Box<dyn Error>> {
+        verify_tx_with(self, |tx_id, out_idx| {
+            Ok(utxos
+                .get(tx_id)
+                .and_then(|txo_map| txo_map.get(&out_idx))
+                .cloned())
+        })
+    }
+}
+
+/// Verifies a tx, looking up the outputs it spends with the given function
+fn verify_tx_with<F>(tx: &Tx, get_spent_output: F) -> Result<bool, Box<dyn Error>>
+where
+    F: Fn(&[u8; 32], u32) -> Result<Option<TxOutput>, Box<dyn Error>>,
+{
+    // Coinbase txs do not need standard verification
+    if tx.is_coinbase() {
+        return Ok(true);
+    }
+
+    // Txs without inputs or outputs move no value, so would only waste block space
+    if tx.inputs.is_empty() || tx.outputs.is_empty() {
+        return Ok(false);
+    }
+
+    // Dust outputs are never valid
+    if has_dust_output(tx) {
+        return Ok(false);
+    }
+
+    // Inputs are checked independently, so an output listed twice would be counted twice
+    if has_duplicate_input(tx) {
+        return Ok(false);
+    }
+
+    // Signatures must commit to this node's network id, so txs signed for another network fail
+    let msg = Message::from_digest(tx.sig_hash(&network_params().magic)?);
+
+    let mut input_sum: u64 = 0;
+    for input in &tx.inputs {
+        // Verify that the prev output pub key hash matches the pub key of the input
+        let prev_tx_out = if let Some(out) = get_spent_output(&input.prev_tx_id, input.out)? {
+            out
+        } else {
+            return Ok(false);
+        };
+
+        // Recompute the pub key hash from the input's public key
+        let computed_pub_key_hash = hash_pub_key(&input.pub_key);
+
+        // Check if the computed pub key hash matches the expected one
+        if computed_pub_key_hash != prev_tx_out.pub_key_hash {
+            return Ok(false);
         }
+        input_sum += u64::from(prev_tx_out.value);
 
-        for input in &self.inputs {
-            let mut tx_copy = self.trimmed_copy();
-
-            // Verify that the prev output pub key hash matches the pub key of the input
-            let prev_tx_out = if let Some(tx) = get_utxo(&input.prev_tx_id, input.out)? {
-                tx
-            } else {
-                return Ok(false);
-            };
-
-            // Recompute the pub key hash from the input's public key
-            let computed_pub_key_hash = hash_pub_key(&input.pub_key);
-
-            // Check if the computed pub key hash matches the expected one
-            if computed_pub_key_hash != prev_tx_out.pub_key_hash {
-                return Ok(false);
-            }
-
-            // Recompute the tx id from the trimmed copy. If the id differs from
-            // the signed tx id, the signature verification will fail
-            tx_copy.id = tx_copy.hash()?;
-
-            // Verify the signature was created by signing the tx is with the given pub key
-            let msg = Message::from_digest(tx_copy.id);
-            if Secp256k1::new()
-                .verify_ecdsa(&msg, &input.signature, &input.pub_key)
-                .is_err()
-            {
-                return Ok(false);
-            }
+        // Verify the signature was created by signing the tx with the given pub key
+        if Secp256k1::new()
+            .verify_ecdsa(&msg, &input.signature, &input.pub_key)
+            .is_err()
+        {
+            return Ok(false);
         }
-        Ok(true)
     }
+
+    // Outputs can't create more value than the inputs provide
+    let output_sum: u64 = tx.outputs.iter().map(|o| u64::from(o.value)).sum();
+    if output_sum > input_sum {
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Returns true if any of the tx outputs are below the dust limit
+pub fn has_dust_output(tx: &Tx) -> bool {
+    tx.outputs.iter().any(|o| o.value < MIN_OUTPUT_VALUE)
+}
+
+/// Returns true if the tx spends the same output more than once
+fn has_duplicate_input(tx: &Tx) -> bool {
+    let mut outpoints: HashSet<([u8; 32], u32)> = HashSet::new();
+    !tx.inputs
+        .iter()
+        .all(|input| outpoints.insert((input.prev_tx_id, input.out)))
+}
+
+/// Returns the fee of a tx - the difference between the value of its inputs and outputs.
+/// Input values are resolved from the utxo set, so the tx must spend existing utxos.
+pub fn get_tx_fee(tx: &Tx) -> Result<u32, Box<dyn Error>> {
+    // Coinbase txs create new value and therefore pay no fee
+    if tx.is_coinbase() {
+        return Ok(0);
+    }
+
+    let mut input_sum: u32 = 0;
+    for input in &tx.inputs {
+        let prev_tx_out = get_utxo(&input.prev_tx_id, input.out)?.ok_or_else(|| {
+            format!(
+                "[tx::get_tx_fee] ERROR: Could not find utxo for input {}:{}",
+                hex::encode(input.prev_tx_id),
+                input.out
+            )
+        })?;
+        input_sum = input_sum
+            .checked_add(prev_tx_out.value)
+            .ok_or("[tx::get_tx_fee] ERROR: Input value overflow")?;
+    }
+
+    let mut output_sum: u32 = 0;
+    for output in &tx.outputs {
+        output_sum = output_sum
+            .checked_add(output.value)
+            .ok_or("[tx::get_tx_fee] ERROR: Output value overflow")?;
+    }
+
+    input_sum
+        .checked_sub(output_sum)
+        .ok_or_else(|| "[tx::get_tx_fee] ERROR: Tx outputs exceed inputs".into())
+}
+
+/// Returns the sum of the fees of all given txs
+pub fn get_total_fees(txs: &[Tx]) -> Result<u32, Box<dyn Error>> {
+    let mut total: u32 = 0;
+    for tx in txs {
+        total = total
+            .checked_add(get_tx_fee(tx)?)
+            .ok_or("[tx::get_total_fees] ERROR: Fee overflow")?;
+    }
+    Ok(total)
 }
 
-/// Create the coinbase tx
-pub fn coinbase_tx(reward_addr: &Address) -> Result<Tx, Box<dyn Error>> {
-    // Coinbase txs will contain an arbitrary in, since there is no previous out
-    let mut rand_data = [0u8; 32];
-    rand::thread_rng().fill_bytes(&mut rand_data);
+/// Returns the block reward at the given height. The reward starts at INITIAL_BLOCK_REWARD and
+/// halves every `halving_interval` blocks, until it reaches zero and issuance stops.
+pub fn block_reward(height: u32) -> u32 {
+    let halvings = height / network_params().halving_interval;
+    INITIAL_BLOCK_REWARD.checked_shr(halvings).unwrap_or(0)
+}
 
-    // Create a random ephemeral pubkey and signature
+/// Create the coinbase tx. The miner is rewarded with the height's block reward plus the fees
+/// of all txs included in the block.
+///
+/// The coinbase input is derived from the block height, so coinbase txs are unique across
+/// heights while a block can still be reconstructed deterministically.
+pub fn coinbase_tx(reward_addr: &Address, fees: u32, height: u32) -> Result<Tx, Box<dyn Error>> {
+    // Coinbase txs will contain an arbitrary in, since there is no previous out. The ephemeral
+    // key and signed msg are both derived from the height. ECDSA signing is deterministic, so
+    // the same height always yields the same input
+    let seed: [u8; 32] = Sha256::new()
+        .chain_update(b"dcoin coinbase")
+        .chain_update(height.to_le_bytes())
+        .finalize()
+        .into();
     let secp = Secp256k1::new();
-    let secret_key = SecretKey::new(&mut rand::thread_rng());
-    let msg = Message::from_digest(rand_data);
+    let secret_key = SecretKey::from_slice(&seed)?;
+    let msg = Message::from_digest(Sha256::digest(seed).into());
     let signature = secp.sign_ecdsa(&msg, &secret_key);
 
     // Create the dummy in tx
@@ -76,7 +191,9 @@ pub fn coinbase_tx(reward_addr: &Address) -> Result<Tx, Box<dyn Error>> {
 
     // Create the tx out with the creator's pub key hash
     let tx_out = vec![TxOutput {
-        value: COINBASE_REWARD, // Reward for coinbase tx is static
+        value: block_reward(height)
+            .checked_add(fees)
+            .ok_or("[tx::coinbase_tx] ERROR: Reward overflow")?,
         pub_key_hash: *reward_addr.pub_key_hash(),
     }];
 
@@ -90,3 +207,147 @@ pub fn coinbase_tx(reward_addr: &Address) -> Result<Tx, Box<dyn Error>> {
     tx.id = tx.hash()?;
     Ok(tx)
 }
+
+#[cfg(test)]
+mod tests {
+    use core_lib::{
+        tx::{MAINNET_NETWORK_ID, TESTNET_NETWORK_ID},
+        wallet::Wallet,
+    };
+
+    use super::*;
+    use crate::{
+        blockchain::{error::ChainError, transaction::mempool::add_tx_to_mempool},
+        test_utils::{new_chain, pay, setup},
+    };
+
+    #[test]
+    fn verify_rejects_outputs_exceeding_inputs() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let tx = pay(&wallet, &Wallet::new().get_wallet_address(), 90, 0);
+        assert!(tx.verify().unwrap());
+
+        // A correctly signed tx paying out more than the genesis output it spends
+        let mut minted = tx.clone();
+        minted.outputs[0].value = 150;
+        wallet.sign_tx(&mut minted).unwrap();
+        assert!(!minted.verify().unwrap());
+    }
+
+    #[test]
+    fn dust_outputs_are_rejected() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let mut tx = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 0);
+        tx.outputs[1].value -= MIN_OUTPUT_VALUE;
+        tx.outputs.push(TxOutput {
+            value: MIN_OUTPUT_VALUE - 1,
+            pub_key_hash: [0u8; 20],
+        });
+        wallet.sign_tx(&mut tx).unwrap();
+
+        assert!(!tx.verify().unwrap());
+        match add_tx_to_mempool(&tx) {
+            Err(ChainError::InvalidTx(msg)) => assert!(msg.contains("dust")),
+            _ => panic!("expected tx with a dust output to be rejected"),
+        }
+    }
+    #[test]
+    fn coinbase_is_deterministic_per_height_and_address() {
+        let miner = Wallet::new().get_wallet_address();
+
+        let first = coinbase_tx(&miner, 5, 7).unwrap();
+        let second = coinbase_tx(&miner, 5, 7).unwrap();
+        assert_eq!(first.id, second.id);
+        assert_eq!(
+            bincode::serialize(&first).unwrap(),
+            bincode::serialize(&second).unwrap()
+        );
+
+        // Coinbase txs stay unique across heights, even for the same reward
+        let next_height = coinbase_tx(&miner, 5, 8).unwrap();
+        assert_ne!(first.id, next_height.id);
+        let other_miner = coinbase_tx(&Wallet::new().get_wallet_address(), 5, 7).unwrap();
+        assert_ne!(first.id, other_miner.id);
+    }
+
+    #[test]
+    fn tx_moving_no_value_is_rejected() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let tx = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 0);
+
+        // Correctly signed, but paying nothing to the recipient
+        let mut zero_value = tx.clone();
+        zero_value.outputs[0].value = 0;
+        wallet.sign_tx(&mut zero_value).unwrap();
+        assert!(!zero_value.verify().unwrap());
+
+        // Without outputs, the whole input would go to the miner as a fee
+        let mut no_outputs = tx;
+        no_outputs.outputs.clear();
+        wallet.sign_tx(&mut no_outputs).unwrap();
+        assert!(!no_outputs.verify().unwrap());
+    }
+
+    #[test]
+    fn tx_signed_for_another_network_fails_verification() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let mut tx = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 0);
+        assert!(tx.verify().unwrap());
+
+        // Re-sign the same tx for the network the node isn't running on
+        let other_network = if network_params().magic == MAINNET_NETWORK_ID {
+            TESTNET_NETWORK_ID
+        } else {
+            MAINNET_NETWORK_ID
+        };
+        let secp = Secp256k1::new();
+        let msg = Message::from_digest(tx.sig_hash(&other_network).unwrap());
+        for input in &mut tx.inputs {
+            input.signature = secp.sign_ecdsa(&msg, wallet.private_key());
+        }
+        assert!(!tx.verify().unwrap());
+    }
+
+    #[test]
+    fn block_reward_halves_until_zero() {
+        let interval = network_params().halving_interval;
+        let rewards: Vec<u32> = [
+            0,
+            interval - 1,
+            interval,
+            interval + 1,
+            2 * interval - 1,
+            2 * interval,
+        ]
+        .into_iter()
+        .map(block_reward)
+        .collect();
+        assert_eq!(rewards, vec![100, 100, 50, 50, 50, 25]);
+
+        // Issuance stops once the reward has been halved down to zero
+        assert_eq!(block_reward(6 * interval), 1);
+        assert_eq!(block_reward(7 * interval), 0);
+        assert_eq!(block_reward(u32::MAX), 0);
+
+        let miner = Wallet::new().get_wallet_address();
+        let coinbase = coinbase_tx(&miner, 0, 2 * interval).unwrap();
+        assert_eq!(coinbase.outputs[0].value, 25);
+    }
+
+    #[test]
+    fn tx_spending_same_output_twice_is_rejected() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let mut tx = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 0);
+
+        // Listing the genesis output twice would otherwise double its value
+        tx.inputs.push(tx.inputs[0]);
+        tx.outputs[0].value += 100;
+        wallet.sign_tx(&mut tx).unwrap();
+        assert!(!tx.verify().unwrap());
+    }
+}
@@ -1,112 +1,199 @@
-use std::{collections::HashMap, error::Error};
+use std::{cmp::Reverse, collections::HashMap, error::Error};
 
 use core_lib::tx::TxOutput;
+use log::{debug, error};
 use rocksdb::IteratorMode;
 
 use crate::{
-    blockchain::blocks::block::Block,
-    cli::db::{self, utxo_cf, ROCKS_DB},
+    blockchain::{blocks::block::Block, error::ChainError},
+    cli::db::{self, utxo_cf, ChainBatch, ROCKS_DB},
 };
 
 use super::mempool::mempool_contains_txo;
 
 pub type TxOutMap = HashMap<u32, TxOutput>;
 pub type UTXOSet = HashMap<[u8; 32], TxOutMap>;
+/// A utxo keyed by the outpoint (tx id, output index) that references it
+pub type OutpointUtxo = (([u8; 32], u32), TxOutput);
 
 /// Searches through all db entries with the UTXO prefix for utxos with outputs matching the given pub key hash.
 ///
 /// Note that returned utxos *may be in a pending tx within the mempool
-pub fn find_utxos_for_addr(pub_key_hash: &[u8; 20]) -> Vec<TxOutput> {
-    let mut utxos: Vec<TxOutput> = Vec::new();
-    let iter = ROCKS_DB.iterator_cf(utxo_cf(), IteratorMode::Start);
+pub fn find_utxos_for_addr(pub_key_hash: &[u8; 20]) -> Result<Vec<TxOutput>, ChainError> {
+    Ok(find_outpoints_for_addr(pub_key_hash)?
+        .into_iter()
+        .map(|(_, tx_out)| tx_out)
+        .collect())
+}
 
-    for res in iter {
-        match res {
-            Err(_) => {
-                panic!("[utxo::find_utxos_for_addr] ERROR: Failed to iterate through db")
-            }
-            Ok((_, val)) => {
-                let tx_out_map: HashMap<u32, TxOutput> = match bincode::deserialize(&val) {
-                    Ok(map) => map,
-                    Err(e) => {
-                        println!("Failed to deserialize TxOutMap: {:?}", e);
-                        continue;
-                    }
-                };
+/// Same as `find_utxos_for_addr`, but keeps the tx id and output index of each utxo so
+/// they can be referenced as tx inputs. Results are ordered by tx id, then output index.
+///
+/// Note that returned utxos *may be in a pending tx within the mempool. Corrupt utxo entries
+/// are logged and skipped, while failing to read the db is an error
+pub fn find_outpoints_for_addr(pub_key_hash: &[u8; 20]) -> Result<Vec<OutpointUtxo>, ChainError> {
+    Ok(find_outpoints_for_addrs(&[*pub_key_hash])?
+        .remove(pub_key_hash)
+        .unwrap_or_default())
+}
 
-                for (_, tx_out) in tx_out_map {
-                    if tx_out.is_locked_with_key(pub_key_hash) {
-                        utxos.push(tx_out);
-                    }
+/// Same as `find_outpoints_for_addr`, but finds the outpoints of several addresses in a single
+/// pass over the utxo set. Every given pub key hash has an entry, which is empty if the address
+/// has no utxos
+pub fn find_outpoints_for_addrs(
+    pub_key_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], Vec<OutpointUtxo>>, ChainError> {
+    let mut outpoints: HashMap<[u8; 20], Vec<OutpointUtxo>> = pub_key_hashes
+        .iter()
+        .map(|pub_key_hash| (*pub_key_hash, Vec::new()))
+        .collect();
+
+    for res in db::iter_utxos() {
+        let entry = res.map_err(|e| {
+            ChainError::Db(format!(
+                "[utxo::find_outpoints_for_addrs] ERROR: Failed to read utxos: {}",
+                e
+            ))
+        })?;
+        match entry {
+            Err(e) => {
+                error!("Skipping corrupt utxo: {}", e);
+                continue;
+            }
+            Ok((outpoint, tx_out)) => {
+                if let Some(addr_outpoints) = outpoints.get_mut(&tx_out.pub_key_hash) {
+                    addr_outpoints.push((outpoint, tx_out));
                 }
             }
         }
     }
-    utxos
+    Ok(outpoints)
 }
 
+/// A single spendable output, identified by its tx id and output index
+type UTXOCandidate = ([u8; 32], u32, TxOutput);
+
 /// Creates a hashmap of transaction ids to spendable utxo indexes by searching the db for utxos with spendable
-/// outputs that add to the target amount.
+/// outputs that add to the target amount. See `select_utxos` for how the outputs are chosen.
 ///
-/// Spendable utxos must not be present in the mempool.
-pub fn find_spendable_utxos(
+/// Spendable utxos must not be present in the mempool. Unlike address lookups, a corrupt utxo
+/// entry is an error here.
+pub fn find_spendable_utxos(pub_key_hash: &[u8; 20], amount: u32) -> Result<UTXOSet, ChainError> {
+    let mut candidates: Vec<UTXOCandidate> = Vec::new();
+
+    // Utxos are iterated in outpoint order, which keeps coin selection reproducible across calls
+    for res in db::iter_utxos() {
+        // A corrupt entry may hold funds of the address, so coin selection fails rather than
+        // skipping it and wrongly reporting insufficient funds
+        let ((tx_id, out_idx), tx_out) = res.and_then(|entry| entry).map_err(|e| {
+            ChainError::Db(format!(
+                "[utxo::find_spendable_utxos] ERROR: Failed to read utxos: {}",
+                e
+            ))
+        })?;
+        if tx_out.is_locked_with_key(pub_key_hash) && !mempool_contains_txo(tx_id, out_idx) {
+            candidates.push((tx_id, out_idx, tx_out));
+        }
+    }
+
+    // Not enough funds if total spendable is less than new tx value
+    let available: u64 = candidates
+        .iter()
+        .map(|(_, _, tx_out)| u64::from(tx_out.value))
+        .sum();
+    let selected = select_utxos(candidates, amount).ok_or(ChainError::InsufficientFunds {
+        available,
+        required: amount,
+    })?;
+
+    let mut utxo_map: UTXOSet = HashMap::new();
+    for (tx_id, out_idx, tx_out) in selected {
+        utxo_map.entry(tx_id).or_default().insert(out_idx, tx_out);
+    }
+    Ok(utxo_map)
+}
+
+/// Builds a utxo set from explicitly chosen outpoints, for coin control. Every outpoint must be an
+/// unspent output locked to the given pub key hash and not already spent in the mempool, and
+/// together they must cover the target amount.
+pub fn find_utxos_for_outpoints(
     pub_key_hash: &[u8; 20],
+    outpoints: &[([u8; 32], u32)],
     amount: u32,
-) -> Result<UTXOSet, Box<dyn Error>> {
+) -> Result<UTXOSet, ChainError> {
     let mut utxo_map: UTXOSet = HashMap::new();
-    let mut accumulated: u32 = 0;
-    let iter = ROCKS_DB.iterator_cf(utxo_cf(), IteratorMode::Start);
-
-    for res in iter {
-        match res {
-            Err(_) => {
-                return Err(
-                    "[utxo::find_spendable_utxos] ERROR: Failed to iterate through db".into(),
-                );
-            }
-            Ok((key, val)) => {
-                let tx_id: [u8; 32] = key.into_vec().try_into().map_err(|e| {
-                    format!(
-                        "[utxo::find_spendable_utxos] ERROR: Failed to unwrap key {:?}",
-                        e
-                    )
-                })?;
-                let txo_map: TxOutMap = bincode::deserialize(&val)?;
-                let mut new_txo_map: TxOutMap = HashMap::new();
-                for (out_idx, tx_out) in txo_map.iter() {
-                    // If we get a match and we have more room to accumulate, add the
-                    // index of the utxo to the map, using the tx id as the key
-                    if tx_out.is_locked_with_key(&pub_key_hash)
-                        && accumulated < amount
-                        && !mempool_contains_txo(tx_id, *out_idx)
-                    {
-                        accumulated += tx_out.value;
-
-                        new_txo_map.insert(*out_idx, tx_out.clone());
-                        // Stop iterating once we have enough funds
-                        if accumulated >= amount {
-                            break;
-                        }
-                    }
-                }
-                utxo_map.insert(tx_id, new_txo_map);
-            }
+    let mut available: u64 = 0;
+    for &(tx_id, out_idx) in outpoints {
+        let outpoint = format!("{}:{}", hex::encode(tx_id), out_idx);
+        let tx_out = db::get_utxo(&tx_id, out_idx)
+            .map_err(|e| {
+                ChainError::Db(format!(
+                    "[utxo::find_utxos_for_outpoints] ERROR: Failed to read utxo: {}",
+                    e
+                ))
+            })?
+            .ok_or_else(|| ChainError::NotFound(format!("utxo {}", outpoint)))?;
+        if !tx_out.is_locked_with_key(pub_key_hash) {
+            return Err(ChainError::InvalidTx(format!(
+                "utxo {} is not owned by the sender",
+                outpoint
+            )));
         }
-        // Stop iterating once we have enough funds
-        if accumulated >= amount {
-            break;
+        if mempool_contains_txo(tx_id, out_idx) {
+            return Err(ChainError::InvalidTx(format!(
+                "utxo {} is already spent by a pending tx",
+                outpoint
+            )));
+        }
+        if utxo_map
+            .entry(tx_id)
+            .or_default()
+            .insert(out_idx, tx_out)
+            .is_none()
+        {
+            available += u64::from(tx_out.value);
         }
-    }
-    // Not enough funds if total spendable is less than new tx value
-    if accumulated < amount {
-        return Err(
-            format!("[tx::new] ERROR: provided address does not have enough funds!!!",).into(),
-        );
     }
 
+    if available < u64::from(amount) {
+        return Err(ChainError::InsufficientFunds {
+            available,
+            required: amount,
+        });
+    }
     Ok(utxo_map)
 }
 
+/// Selects a set of utxos covering the target amount, aiming to keep both the number of
+/// inputs and the resulting change small.
+///
+/// The smallest single utxo that covers the amount is preferred, which picks an exact match
+/// when one exists. Otherwise utxos are taken largest first until the amount is reached.
+/// Returns `None` if the candidates cannot cover the amount.
+fn select_utxos(mut candidates: Vec<UTXOCandidate>, amount: u32) -> Option<Vec<UTXOCandidate>> {
+    if let Some(idx) = candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, _, tx_out))| tx_out.value >= amount)
+        .min_by_key(|(_, (_, _, tx_out))| tx_out.value)
+        .map(|(idx, _)| idx)
+    {
+        return Some(vec![candidates.swap_remove(idx)]);
+    }
+
+    candidates.sort_by_key(|(_, _, tx_out)| Reverse(tx_out.value));
+    let mut selected = Vec::new();
+    let mut accumulated: u64 = 0;
+    for candidate in candidates {
+        accumulated += u64::from(candidate.2.value);
+        selected.push(candidate);
+        if accumulated >= u64::from(amount) {
+            return Some(selected);
+        }
+    }
+    None
+}
+
 /// Builds a hashmap containing the UTXO set from the chain found in the database.
 fn get_utxos_from_chain() -> Result<UTXOSet, Box<dyn Error>> {
     let mut utxo_map: UTXOSet = HashMap::new();
@@ -123,6 +210,13 @@ fn get_utxos_from_chain() -> Result<UTXOSet, Box<dyn Error>> {
     })?;
 
     loop {
+        if current_block.is_pruned() {
+            return Err(format!(
+                "[utxo::get_utxos_from_chain] ERROR: Block at height {} has been pruned",
+                current_block.height
+            )
+            .into());
+        }
         for tx in &current_block.txs {
             // Loop through all tx outputs in the current block txs
             'outputs: for (out_idx, tx_out) in tx.outputs.iter().enumerate() {
@@ -138,8 +232,8 @@ fn get_utxos_from_chain() -> Result<UTXOSet, Box<dyn Error>> {
                 }
                 utxo_map
                     .entry(tx.id)
-                    .or_insert_with(HashMap::new) // If `tx_id` isn't found, insert an empty HashMap
-                    .insert(out_idx, tx_out.clone());
+                    .or_default() // If `tx_id` isn't found, insert an empty HashMap
+                    .insert(out_idx, *tx_out);
             }
 
             // Tx inputs spend outputs from previous txs. By adding the outs to the
@@ -149,7 +243,7 @@ fn get_utxos_from_chain() -> Result<UTXOSet, Box<dyn Error>> {
                 for tx_in in &tx.inputs {
                     spent_txo_map
                         .entry(tx_in.prev_tx_id)
-                        .or_insert_with(Vec::new)
+                        .or_default()
                         .push(tx_in.out);
                 }
             }
@@ -177,7 +271,7 @@ fn delete_all_utxos() -> Result<(), Box<dyn Error>> {
         let (key, _) =
             res.map_err(|_| "[utxo::delete_all_utxos] ERROR: Failed to iterate through db")?;
 
-        if let Err(e) = ROCKS_DB.delete(key) {
+        if let Err(e) = ROCKS_DB.delete_cf(utxo_cf(), key) {
             return Err(format!(
                 "[utxo::delete_all_utxos] ERROR: Failed to delete key: {}",
                 e
@@ -191,7 +285,14 @@ fn delete_all_utxos() -> Result<(), Box<dyn Error>> {
 
 /// Reindexes utxos in db. Deletes all existing and uses the chain from the db
 /// to rebuild all utxos in the db.
+///
+/// Pruned chains no longer hold the txs needed to rebuild the set, so the persisted set, which
+/// is kept up to date as blocks are committed, is left as is.
 pub fn reindex_utxos() -> Result<(), Box<dyn Error>> {
+    if db::get_pruned_height().is_some() {
+        debug!("Chain has been pruned - skipping utxo reindex");
+        return Ok(());
+    }
     delete_all_utxos()?;
     let utxos = get_utxos_from_chain()?;
 
@@ -206,12 +307,13 @@ pub fn reindex_utxos() -> Result<(), Box<dyn Error>> {
 }
 
 /// Update utxos with a new block
-pub fn update_utxos(block: &Block) -> Result<(), Box<dyn Error>> {
+/// Spends the utxos consumed by a block's txs and adds the outputs it creates
+pub fn update_utxos(block: &Block, batch: &mut ChainBatch) -> Result<(), Box<dyn Error>> {
     for tx in &block.txs {
         if !tx.is_coinbase() {
             for tx_in in &tx.inputs {
                 // Remove any outputs now spent by a given tx input
-                db::delete_utxo(&tx_in.prev_tx_id, tx_in.out)?;
+                batch.delete_utxo(&tx_in.prev_tx_id, tx_in.out)?;
             }
         }
 
@@ -220,32 +322,212 @@ pub fn update_utxos(block: &Block) -> Result<(), Box<dyn Error>> {
             let out_idx = out_idx
                 .try_into()
                 .expect("[utxo::update_utxos] ERROR: Index too large for u32");
-            db::put_utxo(&tx.id, out_idx, tx_out)?;
+            batch.put_utxo(&tx.id, out_idx, tx_out)?;
         }
     }
     Ok(())
 }
 
-// /// Fetch all utxos from the db. Does not reindex, simply builds a map from the existing utxos in the db.
-// pub fn get_all_utxos() -> Result<UTXOSet, Box<dyn Error>> {
-//     let mut utxo_map: UTXOSet = HashMap::new();
-//     let iter = ROCKS_DB.iterator_cf(utxo_cf(), IteratorMode::Start);
-//     for res in iter {
-//         match res {
-//             Err(_) => {
-//                 return Err("[db::get_all_utxos] ERROR: Failed to iterate through db".into());
-//             }
-//             Ok((key, val)) => {
-//                 let tx_id: [u8; 32] = key.into_vec().try_into().map_err(|e| {
-//                     format!(
-//                         "[utxo::find_spendable_utxos] ERROR: Failed to unwrap key {:?}",
-//                         e
-//                     )
-//                 })?;
-//                 let txo_map: TxOutMap = bincode::deserialize(&val)?;
-//                 utxo_map.insert(tx_id, txo_map);
-//             }
-//         }
-//     }
-//     Ok(utxo_map)
-// }
+/// Fetch all utxos from the db. Does not reindex, simply builds a map from the existing utxos in the db.
+pub fn get_all_utxos() -> Result<UTXOSet, Box<dyn Error>> {
+    let mut utxo_map: UTXOSet = HashMap::new();
+    for res in db::iter_utxos() {
+        // The full set must be complete, so corrupt entries are errors here
+        let ((tx_id, out_idx), tx_out) = res??;
+        utxo_map.entry(tx_id).or_default().insert(out_idx, tx_out);
+    }
+    Ok(utxo_map)
+}
+
+/// Applies a block to an in-memory utxo set, spending the outputs consumed by its txs and adding
+/// the outputs it creates
+pub fn apply_block_to_set(utxos: &mut UTXOSet, block: &Block) {
+    for tx in &block.txs {
+        if !tx.is_coinbase() {
+            for tx_in in &tx.inputs {
+                if let Some(txo_map) = utxos.get_mut(&tx_in.prev_tx_id) {
+                    txo_map.remove(&tx_in.out);
+                    // If no more outputs remain, remove the entire tx_id entry
+                    if txo_map.is_empty() {
+                        utxos.remove(&tx_in.prev_tx_id);
+                    }
+                }
+            }
+        }
+
+        let txo_map = utxos.entry(tx.id).or_default();
+        for (out_idx, tx_out) in tx.outputs.iter().enumerate() {
+            let out_idx = out_idx
+                .try_into()
+                .expect("[utxo::apply_block_to_set] ERROR: Index too large for u32");
+            txo_map.insert(out_idx, *tx_out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core_lib::{tx::Tx, wallet::Wallet};
+
+    use super::*;
+    use crate::{
+        blockchain::transaction::mempool::add_tx_to_mempool,
+        test_utils::{add_block, new_chain, setup},
+    };
+
+    /// Returns candidates with the given values, each in its own tx
+    fn candidates(values: &[u32]) -> Vec<UTXOCandidate> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| {
+                let tx_out = TxOutput {
+                    value,
+                    pub_key_hash: [0u8; 20],
+                };
+                ([i as u8; 32], 0, tx_out)
+            })
+            .collect()
+    }
+
+    fn selected_values(selected: Option<Vec<UTXOCandidate>>) -> Vec<u32> {
+        selected
+            .unwrap()
+            .iter()
+            .map(|(_, _, tx_out)| tx_out.value)
+            .collect()
+    }
+
+    #[test]
+    fn select_prefers_exact_match_over_many_small_utxos() {
+        let utxos = candidates(&[10, 10, 10, 10, 10, 80, 50]);
+        assert_eq!(selected_values(select_utxos(utxos, 50)), vec![50]);
+    }
+
+    #[test]
+    fn select_prefers_smallest_utxo_covering_amount() {
+        let utxos = candidates(&[120, 30, 70]);
+        assert_eq!(selected_values(select_utxos(utxos, 60)), vec![70]);
+    }
+
+    #[test]
+    fn select_takes_largest_utxos_first() {
+        let utxos = candidates(&[10, 40, 20, 30]);
+        assert_eq!(selected_values(select_utxos(utxos, 65)), vec![40, 30]);
+        assert!(select_utxos(candidates(&[10, 20]), 31).is_none());
+    }
+
+    #[test]
+    fn find_spendable_reports_insufficient_funds() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let pub_key_hash = *wallet.get_wallet_address().pub_key_hash();
+
+        assert!(find_spendable_utxos(&pub_key_hash, 100).is_ok());
+        match find_spendable_utxos(&pub_key_hash, 101) {
+            Err(ChainError::InsufficientFunds {
+                available,
+                required,
+            }) => assert_eq!((available, required), (100, 101)),
+            _ => panic!("expected insufficient funds"),
+        }
+        let empty = *Wallet::new().get_wallet_address().pub_key_hash();
+        assert!(matches!(
+            find_spendable_utxos(&empty, 1),
+            Err(ChainError::InsufficientFunds { available: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn find_spendable_selects_same_utxos_every_call() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let addr = wallet.get_wallet_address();
+        // Splits the genesis output into equal outputs, so any of them could cover the amount
+        let split = Tx::new_multi(
+            &wallet,
+            &[
+                (addr.clone(), 20),
+                (addr.clone(), 20),
+                (addr.clone(), 20),
+                (addr.clone(), 20),
+            ],
+            0,
+            find_spendable_utxos(addr.pub_key_hash(), 80).unwrap(),
+        )
+        .unwrap();
+        add_block(&Wallet::new().get_wallet_address(), vec![split]);
+
+        let first = find_spendable_utxos(addr.pub_key_hash(), 30).unwrap();
+        for _ in 0..10 {
+            assert_eq!(
+                find_spendable_utxos(addr.pub_key_hash(), 30).unwrap(),
+                first
+            );
+        }
+    }
+
+    #[test]
+    fn tx_spends_explicitly_chosen_outpoints() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let owner = wallet.get_wallet_address();
+        let utxos = find_spendable_utxos(owner.pub_key_hash(), 90).unwrap();
+        let recipients = [
+            (owner.clone(), 20),
+            (owner.clone(), 30),
+            (owner.clone(), 40),
+        ];
+        let split = Tx::new_multi(&wallet, &recipients, 0, utxos).unwrap();
+        add_block(&owner, vec![split.clone()]);
+
+        // Only the chosen outputs are spent, even though automatic selection could pick others
+        let chosen = [(split.id, 1), (split.id, 2)];
+        let utxos = find_utxos_for_outpoints(owner.pub_key_hash(), &chosen, 65).unwrap();
+        let to = Wallet::new().get_wallet_address();
+        let tx = Tx::new(&wallet, &to, 60, 5, utxos).unwrap();
+        let mut spent: Vec<([u8; 32], u32)> = tx
+            .inputs
+            .iter()
+            .map(|input| (input.prev_tx_id, input.out))
+            .collect();
+        spent.sort();
+        assert_eq!(spent, chosen);
+        add_tx_to_mempool(&tx).unwrap();
+
+        // Chosen outputs must cover the amount, belong to the sender and not be pending
+        assert!(matches!(
+            find_utxos_for_outpoints(owner.pub_key_hash(), &[(split.id, 0)], 65),
+            Err(ChainError::InsufficientFunds { .. })
+        ));
+        assert!(matches!(
+            find_utxos_for_outpoints(to.pub_key_hash(), &[(split.id, 0)], 20),
+            Err(ChainError::InvalidTx(_))
+        ));
+        assert!(matches!(
+            find_utxos_for_outpoints(owner.pub_key_hash(), &chosen, 65),
+            Err(ChainError::InvalidTx(_))
+        ));
+    }
+
+    #[test]
+    fn corrupt_utxo_entry_is_skipped_by_address_lookups() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let owner = wallet.get_wallet_address();
+        // An entry too short to hold a tx output
+        db::ROCKS_DB
+            .put_cf(db::utxo_cf(), db::to_utxo_db_key(&[3u8; 32], 0), b"corrupt")
+            .unwrap();
+
+        let utxos = find_utxos_for_addr(owner.pub_key_hash()).unwrap();
+        assert_eq!(utxos.iter().map(|tx_out| tx_out.value).sum::<u32>(), 100);
+        // The entry may hold funds of the address, so coins can't be selected without it
+        assert!(matches!(
+            find_spendable_utxos(owner.pub_key_hash(), 100),
+            Err(ChainError::Db(_))
+        ));
+        // The full utxo set can't be built without the entry, so it is an error there
+        assert!(get_all_utxos().is_err());
+    }
+}
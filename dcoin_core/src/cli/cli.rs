@@ -1,10 +1,31 @@
 use clap::{Parser, Subcommand};
 use colored::*;
+use core_lib::constants::SEED_API_NODE;
+use libp2p::Multiaddr;
+use log::LevelFilter;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
+use super::config::NodeConfig;
+use super::db::{set_data_dir, DEFAULT_DATA_DIR};
 use super::handlers::{
-    handle_clear_blockchain, handle_create_blockchain, handle_create_wallet, handle_get_balance,
-    handle_get_node_id, handle_get_wallets, handle_print_blockchain, handle_send_tx,
-    handle_start_node,
+    handle_clear_blockchain, handle_create_blockchain, handle_create_wallet, handle_decode_tx,
+    handle_export_wallet, handle_get_balance, handle_get_node_id, handle_get_wallets,
+    handle_import_wallet, handle_new_address, handle_next_block_preview, handle_print_blockchain,
+    handle_recover_wallet, handle_send_many, handle_send_tx, handle_show_mempool,
+    handle_start_node, handle_validate_chain, NodeOptions,
+};
+use crate::{
+    blockchain::{
+        params::{set_network_params, Checkpoint, Network, DEFAULT_MAX_TIME_DRIFT_SECS},
+        transaction::mempool::DEFAULT_MEMPOOL_TTL_SECS,
+    },
+    networking::p2p::peers::DEFAULT_MSG_RATE,
 };
 
 #[derive(Parser)]
@@ -14,6 +35,34 @@ use super::handlers::{
 pub struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// TOML config file with node settings. Values passed as flags take precedence
+    #[arg(long = "config", env = "DCOIN_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// Base directory for the node db and wallet store [default: ./data]
+    #[arg(long = "data-dir", env = "DCOIN_DATA_DIR", global = true)]
+    data_dir: Option<PathBuf>,
+
+    /// Network to run on. Nodes only exchange messages with peers on the same network
+    #[arg(long = "network", env = "DCOIN_NETWORK", global = true, value_enum, default_value_t = Network::Mainnet)]
+    network: Network,
+
+    /// Additional checkpoint in the format HEIGHT:HASH. Can be repeated
+    #[arg(long = "checkpoint", global = true)]
+    checkpoints: Vec<Checkpoint>,
+
+    /// Max seconds a block timestamp may be ahead of local time before the block is rejected
+    #[arg(long = "max-time-drift", global = true, default_value_t = DEFAULT_MAX_TIME_DRIFT_SECS)]
+    max_time_drift: u64,
+
+    /// Max level of log messages to print. RUST_LOG directives are applied on top for per-module filtering [default: info]
+    #[arg(long = "log-level", env = "DCOIN_LOG_LEVEL", global = true)]
+    log_level: Option<LevelFilter>,
+
+    /// Print command output as machine-readable JSON instead of formatted text
+    #[arg(long = "json", global = true)]
+    json: bool,
 }
 
 #[derive(Subcommand)]
@@ -33,11 +82,65 @@ enum Commands {
         reward_addr: Option<String>,
         #[arg(short = 'm', long = "mine")]
         mine: bool,
+        /// Discover peers on the local network using mDNS
+        #[arg(long = "mdns")]
+        mdns: bool,
+        /// P2P seed node multiaddr. Can be repeated, or set as a comma separated list in DCOIN_SEEDS
+        #[arg(long = "seed", env = "DCOIN_SEEDS", value_delimiter = ',')]
+        seeds: Vec<Multiaddr>,
+        /// Seconds a tx may stay in the mempool without being mined before it is dropped
+        #[arg(long = "mempool-ttl", default_value_t = DEFAULT_MEMPOOL_TTL_SECS)]
+        mempool_ttl: u64,
+        /// Discard the txs of blocks buried deeper than this many blocks, keeping only their
+        /// headers. Only blocks at or below the latest checkpoint are pruned
+        #[arg(long = "prune")]
+        prune: Option<u32>,
+        /// Max gossip messages per second accepted from a single peer. Excess messages are
+        /// dropped and count against the peer's score
+        #[arg(long = "max-msg-rate", default_value_t = DEFAULT_MSG_RATE, value_parser = clap::value_parser!(u32).range(1..))]
+        max_msg_rate: u32,
     },
 
     /// Creates a new wallet
     #[command(about = "Creates a new wallet")]
-    CreateWallet,
+    CreateWallet {
+        /// Human readable label for the wallet. Must be unique among local wallets
+        #[arg(short = 'l', long = "label")]
+        label: Option<String>,
+    },
+
+    /// Recovers a wallet from a mnemonic phrase
+    #[command(about = "Recovers a wallet from its mnemonic phrase and adds it to local storage")]
+    RecoverWallet {
+        #[arg(short = 'm', long = "mnemonic")]
+        mnemonic: String,
+    },
+
+    /// Imports a wallet from its keys
+    #[command(about = "Imports a wallet from its hex-encoded keys and adds it to local storage")]
+    ImportWallet {
+        #[arg(long = "pub-key")]
+        pub_key: String,
+        #[arg(long = "priv-key")]
+        priv_key: String,
+    },
+
+    /// Exports the keys of a local wallet
+    #[command(about = "Prints the keys of a wallet in local storage")]
+    ExportWallet {
+        #[arg(short = 'a', long = "address")]
+        address: String,
+    },
+
+    /// Derive a new receiving address
+    #[command(
+        about = "Derives the next receiving address of a local wallet and adds it to local storage"
+    )]
+    NewAddress {
+        /// Address of the wallet to derive from. Defaults to the first local wallet
+        #[arg(short = 'f', long = "from")]
+        from: Option<String>,
+    },
 
     /// Get existing wallets
     #[command(about = "Gets existing wallets from local storage")]
@@ -66,8 +169,40 @@ enum Commands {
     PrintBlockchain {
         #[arg(short = 't')]
         show_txs: bool,
+        /// Include the value and address spent by each tx input
+        #[arg(short = 'r', long = "resolve-inputs", requires = "show_txs")]
+        resolve_inputs: bool,
+    },
+
+    /// Print the txs in the mempool
+    #[command(about = "Prints the pending txs in the mempool along with their fees")]
+    ShowMempool,
+
+    /// Preview the txs the next mined block would include
+    #[command(
+        about = "Prints the mempool txs the next mined block would include, using the same selection as the miner"
+    )]
+    NextBlockPreview,
+
+    /// Decode a serialized tx
+    #[command(
+        about = "Decodes a tx given as hex-encoded bincode or as tx JSON, and prints its inputs and outputs"
+    )]
+    DecodeTx {
+        /// Hex-encoded bincode tx, as accepted by /tx/send-raw
+        #[arg(long = "hex", required_unless_present = "tx_json")]
+        hex: Option<String>,
+        /// Tx JSON, as accepted by /tx/send
+        #[arg(long = "tx-json", conflicts_with = "hex")]
+        tx_json: Option<String>,
     },
 
+    /// Re-verify the stored chain from genesis
+    #[command(
+        about = "Re-verifies the stored chain from genesis and reports the first inconsistency found"
+    )]
+    ValidateChain,
+
     /// Send transaction
     #[command(about = "Send a transaction given an destination address and value")]
     SendTx {
@@ -77,45 +212,378 @@ enum Commands {
         value: u32,
         #[arg(short = 'f', long = "from")]
         from: Option<String>,
+        /// Label of the local wallet to send from, instead of its address
+        #[arg(long = "from-label", conflicts_with = "from")]
+        from_label: Option<String>,
+        #[arg(long = "fee", default_value_t = 0)]
+        fee: u32,
+        /// REST API node the tx is sent to
+        #[arg(long = "api-seed", env = "DCOIN_API_SEED", default_value = SEED_API_NODE)]
+        api_seed: String,
+        /// Outpoints to spend, in the format TXID:INDEX. Can be repeated or comma separated.
+        /// Skips automatic coin selection, and must cover the value and fee
+        #[arg(long = "inputs", value_delimiter = ',', value_parser = parse_outpoint)]
+        inputs: Vec<([u8; 32], u32)>,
+    },
+
+    /// Sends a tx paying multiple recipients
+    #[command(about = "Sends a single tx paying multiple recipients")]
+    SendMany {
+        /// Recipient in the format ADDRESS:VALUE. Can be repeated
+        #[arg(short = 't', long = "to", required = true, value_parser = parse_recipient)]
+        recipients: Vec<(String, u32)>,
+        #[arg(short = 'f', long = "from")]
+        from: Option<String>,
+        #[arg(long = "fee", default_value_t = 0)]
+        fee: u32,
+        /// REST API node the tx is sent to
+        #[arg(long = "api-seed", env = "DCOIN_API_SEED", default_value = SEED_API_NODE)]
+        api_seed: String,
     },
 }
 
+/// Parses a recipient in the format ADDRESS:VALUE
+fn parse_recipient(s: &str) -> Result<(String, u32), String> {
+    let (address, value) = s
+        .split_once(':')
+        .ok_or("recipient must be in the format ADDRESS:VALUE")?;
+    let value = value
+        .parse::<u32>()
+        .map_err(|e| format!("invalid recipient value: {}", e))?;
+    Ok((address.to_string(), value))
+}
+
+/// Parses an outpoint in the format TXID:INDEX, with the tx id hex-encoded
+fn parse_outpoint(s: &str) -> Result<([u8; 32], u32), String> {
+    let (tx_id, out_idx) = s
+        .split_once(':')
+        .ok_or("input must be in the format TXID:INDEX")?;
+    let tx_id = hex::decode(tx_id)
+        .map_err(|e| format!("invalid input tx id: {}", e))?
+        .try_into()
+        .map_err(|_| "input tx id must be 32 bytes")?;
+    let out_idx = out_idx
+        .parse::<u32>()
+        .map_err(|e| format!("invalid input index: {}", e))?;
+    Ok((tx_id, out_idx))
+}
+
 impl Cli {
     pub async fn run() {
         let cli = Cli::parse();
+        CliUI::set_json_output(cli.json);
+
+        let config = match &cli.config {
+            Some(path) => NodeConfig::load(path).unwrap_or_else(|e| {
+                CliUI::print_error(&e.to_string());
+                std::process::exit(1);
+            }),
+            None => NodeConfig::default(),
+        };
+
+        logger(
+            cli.log_level
+                .or(config.log_level)
+                .unwrap_or(LevelFilter::Info),
+        )
+        .init();
+
+        // The data dir must be set before the db or wallet store are first accessed
+        let data_dir = cli
+            .data_dir
+            .clone()
+            .or(config.data_dir.clone())
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR));
+        if set_data_dir(data_dir).is_err() {
+            CliUI::print_error("data directory was already initialized");
+            std::process::exit(1);
+        }
+
+        // The network must be set before the chain or p2p network are first accessed
+        let mut params = cli.network.params();
+        params.checkpoints.extend(cli.checkpoints.iter().copied());
+        params.max_time_drift = cli.max_time_drift;
+        if set_network_params(params).is_err() {
+            CliUI::print_error("network was already initialized");
+            std::process::exit(1);
+        }
 
         match &cli.command {
             Commands::GetNodeId => handle_get_node_id(),
-            Commands::StartNode {
-                rest_api_port,
-                p2p_port,
-                reward_addr,
-                mine,
-            } => handle_start_node(rest_api_port, p2p_port, reward_addr, *mine).await,
-            Commands::CreateWallet => handle_create_wallet(),
+            Commands::StartNode { .. } => {
+                let opts = node_options(&cli.command, &config)
+                    .expect("[cli::run] ERROR: Command is not start-node");
+                handle_start_node(opts).await
+            }
+            Commands::CreateWallet { label } => handle_create_wallet(label),
+            Commands::RecoverWallet { mnemonic } => handle_recover_wallet(mnemonic),
+            Commands::ImportWallet { pub_key, priv_key } => handle_import_wallet(pub_key, priv_key),
+            Commands::ExportWallet { address } => handle_export_wallet(address),
+            Commands::NewAddress { from } => handle_new_address(from),
             Commands::GetWallets => handle_get_wallets(),
             Commands::CreateBlockchain { address } => handle_create_blockchain(address),
             Commands::ClearBlockchain => handle_clear_blockchain(),
-            Commands::PrintBlockchain { show_txs } => handle_print_blockchain(*show_txs),
+            Commands::PrintBlockchain {
+                show_txs,
+                resolve_inputs,
+            } => handle_print_blockchain(*show_txs, *resolve_inputs),
+            Commands::ShowMempool => handle_show_mempool(),
+            Commands::NextBlockPreview => handle_next_block_preview(),
+            Commands::DecodeTx { hex, tx_json } => handle_decode_tx(hex, tx_json),
+            Commands::ValidateChain => handle_validate_chain(),
             Commands::GetBalance { address } => handle_get_balance(address),
-            Commands::SendTx { to, value, from } => handle_send_tx(to, *value, from).await,
+            Commands::SendTx {
+                to,
+                value,
+                from,
+                from_label,
+                fee,
+                api_seed,
+                inputs,
+            } => handle_send_tx(to, *value, from, from_label, *fee, api_seed, inputs).await,
+            Commands::SendMany {
+                recipients,
+                from,
+                fee,
+                api_seed,
+            } => handle_send_many(recipients, from, *fee, api_seed).await,
         }
     }
 }
 
 pub struct CliUI {}
 
+/// Set when command output should be printed as JSON
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// CliUI formats all command output. In JSON mode, the human-readable helpers print nothing, and
+/// each command prints its result with `print_json` instead
 impl CliUI {
+    pub fn set_json_output(enabled: bool) {
+        JSON_OUTPUT.store(enabled, Ordering::Relaxed);
+    }
+    pub fn is_json_output() -> bool {
+        JSON_OUTPUT.load(Ordering::Relaxed)
+    }
     pub fn print_header(text: &str) {
+        if Self::is_json_output() {
+            return;
+        }
         println!("{}", text.bold().underline().green());
     }
     pub fn print_kv(label: &str, value: &str) {
+        if Self::is_json_output() {
+            return;
+        }
         println!("{}: {}", label.blue().bold(), value.cyan());
     }
     pub fn print_text(text: &str) {
+        if Self::is_json_output() {
+            return;
+        }
         println!("{}", text.white());
     }
+    /// Prints the result of a command. Only printed in JSON mode
+    pub fn print_json<T: Serialize>(value: &T) {
+        if !Self::is_json_output() {
+            return;
+        }
+        match serde_json::to_string(value) {
+            Ok(out) => println!("{}", out),
+            Err(e) => Self::print_error(&format!("failed to serialize output: {}", e)),
+        }
+    }
     pub fn print_error(text: &str) {
+        if Self::is_json_output() {
+            eprintln!("{}", json!({ "error": text }));
+            return;
+        }
         eprintln!("{}", text.red().bold());
     }
 }
+
+/// Returns the options of a start-node command, or None for any other command. Flags override
+/// the config file, and seeds from the file are only used if none were passed
+fn node_options(command: &Commands, config: &NodeConfig) -> Option<NodeOptions> {
+    let Commands::StartNode {
+        rest_api_port,
+        p2p_port,
+        reward_addr,
+        mine,
+        mdns,
+        seeds,
+        mempool_ttl,
+        prune,
+        max_msg_rate,
+    } = command
+    else {
+        return None;
+    };
+    let seeds = if seeds.is_empty() {
+        config.seeds.clone()
+    } else {
+        seeds.clone()
+    };
+    Some(NodeOptions {
+        rest_api_port: rest_api_port.or(config.rest_api_port),
+        p2p_port: p2p_port.or(config.p2p_port),
+        reward_address: reward_addr.clone().or(config.reward_addr.clone()),
+        mine: *mine || config.mine,
+        mdns: *mdns,
+        seeds,
+        mempool_ttl: Duration::from_secs(*mempool_ttl),
+        prune: prune.or(config.prune),
+        msg_rate: *max_msg_rate,
+    })
+}
+
+/// Returns a logger printing messages up to the given level, with RUST_LOG directives applied
+/// on top
+fn logger(level: LevelFilter) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).parse_default_env();
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    /// Parses a start-node command line, returning its seeds
+    fn parse_seeds(args: &[&str]) -> Result<Vec<Multiaddr>, clap::Error> {
+        let cli = Cli::try_parse_from(["dcoin-cli", "start-node"].iter().chain(args))?;
+        match cli.command {
+            Commands::StartNode { seeds, .. } => Ok(seeds),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn start_node_parses_seed_list() {
+        let seeds = parse_seeds(&[
+            "--seed",
+            "/ip4/127.0.0.1/tcp/4000",
+            "--seed",
+            "/ip4/10.0.0.2/tcp/4001,/dns4/seed.example.com/tcp/4000",
+        ])
+        .unwrap();
+
+        let expected: Vec<Multiaddr> = [
+            "/ip4/127.0.0.1/tcp/4000",
+            "/ip4/10.0.0.2/tcp/4001",
+            "/dns4/seed.example.com/tcp/4000",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        assert_eq!(seeds, expected);
+        assert!(parse_seeds(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn start_node_rejects_invalid_seed() {
+        let err = parse_seeds(&["--seed", "127.0.0.1:4000"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+        assert!(err.to_string().contains("127.0.0.1:4000"));
+    }
+
+    #[test]
+    fn start_node_flags_override_config_file() {
+        let config: NodeConfig = toml::from_str(
+            r#"
+            p2p_port = 4001
+            rest_api_port = 3001
+            seeds = ["/ip4/127.0.0.1/tcp/4002"]
+            reward_addr = "file-addr"
+            prune = 100
+            "#,
+        )
+        .unwrap();
+        let options = |args: &[&str]| {
+            let cli = Cli::try_parse_from(["dcoin-cli", "start-node"].iter().chain(args)).unwrap();
+            node_options(&cli.command, &config).unwrap()
+        };
+
+        // Without flags, every value comes from the file
+        let opts = options(&[]);
+        assert_eq!(opts.p2p_port, Some(4001));
+        assert_eq!(opts.rest_api_port, Some(3001));
+        assert_eq!(opts.seeds, config.seeds);
+        assert_eq!(opts.reward_address.as_deref(), Some("file-addr"));
+        assert_eq!(opts.prune, Some(100));
+        assert!(!opts.mine);
+
+        let opts = options(&[
+            "--p2p_port",
+            "5001",
+            "--seed",
+            "/ip4/10.0.0.2/tcp/5002",
+            "--reward_addr",
+            "flag-addr",
+            "--mine",
+        ]);
+        assert_eq!(opts.p2p_port, Some(5001));
+        assert_eq!(opts.rest_api_port, Some(3001));
+        assert_eq!(
+            opts.seeds,
+            vec!["/ip4/10.0.0.2/tcp/5002".parse::<Multiaddr>().unwrap()]
+        );
+        assert_eq!(opts.reward_address.as_deref(), Some("flag-addr"));
+        assert!(opts.mine);
+    }
+
+    /// Collects everything the logger writes
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logger_emits_at_configured_level() {
+        let cli = Cli::try_parse_from(["dcoin-cli", "--log-level", "warn", "get-node-id"]).unwrap();
+        let level = cli.log_level.unwrap();
+        assert_eq!(level, LevelFilter::Warn);
+
+        let buffer = LogBuffer::default();
+        logger(level)
+            .target(env_logger::Target::Pipe(Box::new(buffer.clone())))
+            .try_init()
+            .unwrap();
+        log::warn!("logger smoke test warning");
+        log::info!("logger smoke test info");
+        log::logger().flush();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("logger smoke test warning"));
+        assert!(!output.contains("logger smoke test info"));
+    }
+
+    #[test]
+    fn json_flag_applies_to_every_command() {
+        for args in [
+            ["dcoin-cli", "--json", "get-wallets"].as_slice(),
+            &["dcoin-cli", "get-wallets", "--json"],
+            &["dcoin-cli", "get-balance", "-a", "addr", "--json"],
+        ] {
+            assert!(Cli::try_parse_from(args).unwrap().json, "{:?}", args);
+        }
+        assert!(
+            !Cli::try_parse_from(["dcoin-cli", "get-wallets"])
+                .unwrap()
+                .json
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use libp2p::Multiaddr;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// NodeConfig holds node settings loaded from a TOML config file, e.g. dcoin.toml.
+/// Every field is optional, and values passed on the command line take precedence.
+///
+/// ```toml
+/// data_dir = "./data"
+/// log_level = "debug"
+/// p2p_port = 4001
+/// rest_api_port = 3000
+/// seeds = ["/ip4/127.0.0.1/tcp/4002"]
+/// mine = true
+/// reward_addr = "..."
+/// prune = 1000
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub data_dir: Option<PathBuf>,
+    pub log_level: Option<LevelFilter>,
+    pub p2p_port: Option<u16>,
+    pub rest_api_port: Option<u16>,
+    pub seeds: Vec<Multiaddr>,
+    pub mine: bool,
+    pub reward_addr: Option<String>,
+    pub prune: Option<u32>,
+}
+
+impl NodeConfig {
+    /// Reads and parses the config file at the given path
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            format!(
+                "[NodeConfig::load] ERROR: Failed to read config file {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        toml::from_str(&contents).map_err(|e| {
+            format!(
+                "[NodeConfig::load] ERROR: Failed to parse config file {}: {}",
+                path.display(),
+                e
+            )
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_complete_config() {
+        let config: NodeConfig = toml::from_str(
+            r#"
+            data_dir = "./data"
+            log_level = "debug"
+            p2p_port = 4001
+            rest_api_port = 3000
+            seeds = ["/ip4/127.0.0.1/tcp/4002", "/dns4/seed.example.com/tcp/4000"]
+            mine = true
+            reward_addr = "1BoatSLRHtKNngkdXEeobR76b53LETtpyT"
+            prune = 1000
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.data_dir, Some(PathBuf::from("./data")));
+        assert_eq!(config.log_level, Some(LevelFilter::Debug));
+        assert_eq!(config.p2p_port, Some(4001));
+        assert_eq!(config.rest_api_port, Some(3000));
+        assert_eq!(config.seeds.len(), 2);
+        assert_eq!(
+            config.seeds[1],
+            "/dns4/seed.example.com/tcp/4000".parse().unwrap()
+        );
+        assert!(config.mine);
+        assert_eq!(
+            config.reward_addr.as_deref(),
+            Some("1BoatSLRHtKNngkdXEeobR76b53LETtpyT")
+        );
+        assert_eq!(config.prune, Some(1000));
+    }
+
+    #[test]
+    fn load_rejects_unknown_settings() {
+        let path = std::env::temp_dir().join(format!("dcoin_config_{}.toml", std::process::id()));
+        fs::write(&path, "p2p_port = 4001\nminer = true\n").unwrap();
+
+        let err = NodeConfig::load(&path).unwrap_err().to_string();
+        let _ = fs::remove_file(&path);
+        assert!(err.contains("Failed to parse config file"), "{}", err);
+        // A missing file is an error too
+        let err = NodeConfig::load(&path).unwrap_err().to_string();
+        assert!(err.contains("Failed to read config file"), "{}", err);
+    }
+}
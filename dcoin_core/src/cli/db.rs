@@ -1,43 +1,226 @@
-use std::{collections::HashMap, error::Error, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use core_lib::tx::{Tx, TxOutput};
-use once_cell::sync::Lazy;
-use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, IteratorMode, Options, DB};
+use libp2p::{Multiaddr, PeerId};
+use log::{info, warn};
+use once_cell::sync::{Lazy, OnceCell};
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, Direction, ErrorKind, IteratorMode, Options, WriteBatch,
+    DB, DEFAULT_COLUMN_FAMILY_NAME,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::blockchain::{
-    blocks::block::{Block, OrphanBlocks},
-    transaction::{mempool::Mempool, utxo::TxOutMap},
+use crate::{
+    blockchain::{
+        blocks::block::{Block, OrphanBlocks},
+        transaction::{
+            mempool::{index_mempool_spends, Mempool, MempoolEntry, MempoolSpends, OrphanTxs},
+            utxo::TxOutMap,
+        },
+    },
+    cli::cli::CliUI,
 };
 
 /// LAST_HASH_KEY holds the key to discover the last block hash
 pub const LAST_HASH_KEY: &str = "lh";
 /// MEMPOOL_KEY holds the key to retrieve the mempool
 const MEMPOOL_KEY: &str = "mempool";
+/// Mempool spends key holds the index of outpoints spent by mempool txs. It is always written
+/// together with the mempool
+const MEMPOOL_SPENDS_KEY: &str = "mempool_spends";
 /// Orphan key is used to retrieve the orphaned block set
 const ORPHAN_KEY: &str = "orphan";
+/// Orphan order key is used to retrieve the orphan block hashes, ordered from least to most recently inserted
+const ORPHAN_ORDER_KEY: &str = "orphan_order";
+/// Orphan txs key is used to retrieve the txs waiting on unknown inputs
+const ORPHAN_TXS_KEY: &str = "orphan_txs";
+/// Peers key is used to retrieve the known peer addresses
+const PEERS_KEY: &str = "peers";
+/// Pruned height key holds the height of the highest block whose txs have been pruned
+const PRUNED_HEIGHT_KEY: &str = "pruned_height";
 
+/// Utxo CF holds each unspent output, keyed by its outpoint. See `to_utxo_db_key`
 const UTXO_CF: &str = "utxo";
 const BLOCK_CF: &str = "block";
+/// Work CF holds the cumulative chain work of each stored block, keyed by block hash
+const WORK_CF: &str = "work";
+/// Height CF holds the hash of each main chain block, keyed by its big-endian height
+const HEIGHT_CF: &str = "height";
+/// Tx CF holds the hash of the main chain block containing each unpruned tx, keyed by tx id
+const TX_CF: &str = "tx";
+/// Column families opened by this version. Missing ones are created when the db is opened
+const COLUMN_FAMILIES: [&str; 5] = [BLOCK_CF, UTXO_CF, WORK_CF, HEIGHT_CF, TX_CF];
+
+/// Schema version key holds the version of the db layout, so older dbs can be migrated on open
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+/// SCHEMA_VERSION is the db layout written by this version. Bump it along with a new entry in
+/// MIGRATIONS whenever the layout changes
+const SCHEMA_VERSION: u32 = 5;
+
+/// A migration backfills the data of a new schema version from the previous one
+type Migration = fn(&DB) -> Result<(), Box<dyn Error>>;
+/// Migrations by the schema version they upgrade to, in order. Dbs without a stored version
+/// predate versioning, and are at version 1, which stores blocks in the LegacyBlock layout
+const MIGRATIONS: [(u32, Migration); 4] = [
+    (2, migrate_utxo_keys),
+    (3, build_height_index),
+    (4, build_tx_index),
+    (5, drop_legacy_chain),
+];
+
+/// DEFAULT_DATA_DIR is the base directory for node data when none is configured
+pub const DEFAULT_DATA_DIR: &str = "./data";
+const DB_DIR: &str = "db";
+
+static DATA_DIR: OnceCell<PathBuf> = OnceCell::new();
 
-pub const DB_PATH: &str = "./data/db";
+/// Sets the base directory for the db and wallet store. Must be called before the db is first
+/// used, and can only be set once.
+pub fn set_data_dir(path: PathBuf) -> Result<(), PathBuf> {
+    DATA_DIR.set(path)
+}
+
+/// Returns the configured data directory, or DEFAULT_DATA_DIR if none was set
+pub fn data_dir() -> &'static Path {
+    DATA_DIR.get_or_init(|| PathBuf::from(DEFAULT_DATA_DIR))
+}
+
+#[derive(Debug, Error)]
+pub enum DbError {
+    #[error("database at {path} is locked by another process. Stop any other node using this data directory and try again")]
+    Locked { path: String },
+    #[error("database at {path} is corrupted and could not be repaired: {source}. Remove the directory to resync the chain from peers")]
+    Corrupted {
+        path: String,
+        source: rocksdb::Error,
+    },
+    #[error("failed to open database at {path}: {source}")]
+    Open {
+        path: String,
+        source: rocksdb::Error,
+    },
+    #[error("failed to migrate database at {path}: {reason}")]
+    Migration { path: String, reason: String },
+    #[error("database at {path} was written by a newer version of dcoin ({reason}). Upgrade dcoin to open it")]
+    NewerSchema { path: String, reason: String },
+}
 
 // Our db will hold 3 types of kv pairs - an "lh" / hash pair to store our last hash,
 // hash / block pairs to store and retrieve each block, and utxos
 pub static ROCKS_DB: Lazy<Arc<DB>> = Lazy::new(|| {
+    open_db().unwrap_or_else(|e| {
+        CliUI::print_error(&e.to_string());
+        std::process::exit(1);
+    })
+});
+
+/// Opens the db within the data directory. If the db is corrupted, a repair is attempted before opening it again.
+pub fn open_db() -> Result<Arc<DB>, DbError> {
+    open_db_at(&data_dir().join(DB_DIR))
+}
+
+/// Opens the db at the given path, see `open_db`
+fn open_db_at(db_path: &Path) -> Result<Arc<DB>, DbError> {
     let mut opts = Options::default();
     opts.create_if_missing(true);
     opts.create_missing_column_families(true);
 
-    let cf_descriptors = vec![
-        ColumnFamilyDescriptor::new(BLOCK_CF, Options::default()),
-        ColumnFamilyDescriptor::new(UTXO_CF, Options::default()),
-    ];
+    // RocksDB refuses to open a db without all of its column families, so column families added
+    // by a newer version are reported before trying
+    if let Ok(cfs) = DB::list_cf(&opts, db_path) {
+        if let Some(unknown) = cfs.iter().find(|cf| {
+            cf.as_str() != DEFAULT_COLUMN_FAMILY_NAME && !COLUMN_FAMILIES.contains(&cf.as_str())
+        }) {
+            return Err(DbError::NewerSchema {
+                path: db_path.display().to_string(),
+                reason: format!("unknown column family {}", unknown),
+            });
+        }
+    }
 
-    let db =
-        DB::open_cf_descriptors(&opts, DB_PATH, cf_descriptors).expect("Failed to open RocksDB");
+    let open = |opts: &Options| {
+        let cf_descriptors = COLUMN_FAMILIES
+            .iter()
+            .map(|cf| ColumnFamilyDescriptor::new(*cf, Options::default()));
+        DB::open_cf_descriptors(opts, db_path, cf_descriptors)
+    };
 
-    Arc::new(db) // Wrap DB in Arc to share it safely
-});
+    let db = match open(&opts) {
+        Ok(db) => db,
+        Err(e) if is_lock_error(&e) => {
+            return Err(DbError::Locked {
+                path: db_path.display().to_string(),
+            })
+        }
+        Err(e) if e.kind() == ErrorKind::Corruption => {
+            warn!("Database is corrupted, attempting repair...");
+            DB::repair(&opts, db_path)
+                .and_then(|_| open(&opts))
+                .map_err(|source| DbError::Corrupted {
+                    path: db_path.display().to_string(),
+                    source,
+                })?
+        }
+        Err(source) => {
+            return Err(DbError::Open {
+                path: db_path.display().to_string(),
+                source,
+            })
+        }
+    };
+
+    migrate_schema(&db, &db_path.display().to_string())?;
+
+    Ok(Arc::new(db)) // Wrap DB in Arc to share it safely
+}
+
+/// Brings the db up to SCHEMA_VERSION by running each migration past its stored version. The
+/// version is stored after every migration, so an interrupted upgrade resumes where it stopped
+fn migrate_schema(db: &DB, path: &str) -> Result<(), DbError> {
+    let migration_err = |reason: String| DbError::Migration {
+        path: path.to_string(),
+        reason,
+    };
+
+    let version = match db.get(SCHEMA_VERSION_KEY) {
+        Ok(Some(data)) => u32::from_le_bytes(
+            data.try_into()
+                .map_err(|_| migration_err("invalid schema version".to_string()))?,
+        ),
+        Ok(None) => 1,
+        Err(e) => return Err(migration_err(e.to_string())),
+    };
+    if version > SCHEMA_VERSION {
+        return Err(DbError::NewerSchema {
+            path: path.to_string(),
+            reason: format!(
+                "schema version {}, but at most {} is supported",
+                version, SCHEMA_VERSION
+            ),
+        });
+    }
+
+    for (target, migrate) in MIGRATIONS.iter().filter(|(target, _)| *target > version) {
+        migrate(db).map_err(|e| migration_err(e.to_string()))?;
+        db.put(SCHEMA_VERSION_KEY, target.to_le_bytes())
+            .map_err(|e| migration_err(e.to_string()))?;
+        info!("Migrated database to schema version {}", target);
+    }
+    Ok(())
+}
+
+/// RocksDB reports a held LOCK file as an IO error, so the message is the only way to detect it
+fn is_lock_error(e: &rocksdb::Error) -> bool {
+    e.kind() == ErrorKind::IOError && e.to_string().to_lowercase().contains("lock")
+}
 
 /*** UTXO DB handlers ***/
 pub fn utxo_cf() -> &'static ColumnFamily {
@@ -46,82 +229,138 @@ pub fn utxo_cf() -> &'static ColumnFamily {
         .expect("Column family not found")
 }
 
+/// Builds the db key of a utxo - the tx id followed by the big endian output index, so that
+/// the outputs of a tx are stored next to each other in output order
+pub fn to_utxo_db_key(tx_id: &[u8; 32], out_idx: u32) -> [u8; 36] {
+    let mut key = [0u8; 36];
+    key[..32].copy_from_slice(tx_id);
+    key[32..].copy_from_slice(&out_idx.to_be_bytes());
+    key
+}
+
+/// Parses a utxo db key back into its tx id and output index
+pub fn from_utxo_db_key(key: &[u8]) -> Result<([u8; 32], u32), Box<dyn Error>> {
+    if key.len() != 36 {
+        return Err(format!(
+            "[db::from_utxo_db_key] ERROR: Invalid utxo key length {}",
+            key.len()
+        )
+        .into());
+    }
+    let tx_id: [u8; 32] = key[..32].try_into()?;
+    let out_idx = u32::from_be_bytes(key[32..].try_into()?);
+    Ok((tx_id, out_idx))
+}
+
 /// Returns an option representing a utxo. the utxo will be deserialized if found.
 pub fn get_utxo(tx_id: &[u8; 32], out_idx: u32) -> Result<Option<TxOutput>, Box<dyn Error>> {
     let txo_data = ROCKS_DB
-        .get_cf(utxo_cf(), tx_id)
+        .get_cf(utxo_cf(), to_utxo_db_key(tx_id, out_idx))
         .map_err(|e| format!("[db::get_utxo] ERROR: Failed to read from DB {:?}", e))?;
 
     match txo_data {
         None => Ok(None),
-        Some(data) => {
-            let txo_map: TxOutMap = bincode::deserialize(&data)?;
-            Ok(txo_map.get(&out_idx).cloned())
-        }
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
     }
 }
 
-/// Returns a bool representing if a tx exists in the utxo set
+/// Returns an iterator over every utxo in the db, ordered by tx id, then output index. Failing
+/// to read the db is an error of the iterator, while an entry that can't be decoded is only an
+/// error of that entry, so callers can choose to skip it
+pub fn iter_utxos() -> impl Iterator<Item = Result<UtxoEntry, Box<dyn Error>>> {
+    ROCKS_DB
+        .iterator_cf(utxo_cf(), IteratorMode::Start)
+        .map(|res| {
+            let (key, val) = res.map_err(|e| {
+                format!(
+                    "[db::iter_utxos] ERROR: Failed to iterate through db {:?}",
+                    e
+                )
+            })?;
+            Ok(decode_utxo_entry(&key, &val))
+        })
+}
+
+/// A stored utxo keyed by its outpoint, or the error decoding it
+pub type UtxoEntry = Result<(([u8; 32], u32), TxOutput), Box<dyn Error>>;
+
+fn decode_utxo_entry(key: &[u8], val: &[u8]) -> UtxoEntry {
+    let outpoint = from_utxo_db_key(key)?;
+    let tx_out: TxOutput = bincode::deserialize(val).map_err(|e| {
+        format!(
+            "[db::decode_utxo_entry] ERROR: Failed to deserialize utxo {}:{} {:?}",
+            hex::encode(outpoint.0),
+            outpoint.1,
+            e
+        )
+    })?;
+    Ok((outpoint, tx_out))
+}
+
+/// Returns a bool representing if a tx has any unspent outputs in the utxo set
 pub fn utxo_set_contains_tx(tx_id: [u8; 32]) -> Result<bool, Box<dyn Error>> {
-    let txo_data = ROCKS_DB
-        .get_cf(utxo_cf(), tx_id)
-        .map_err(|e| format!("[db::get_utxo] ERROR: Failed to read from DB {:?}", e))?;
+    // Keys are prefixed with the tx id, so the first key at or after it tells if any output exists
+    let mut iter = ROCKS_DB.iterator_cf(utxo_cf(), IteratorMode::From(&tx_id, Direction::Forward));
 
-    match txo_data {
+    match iter.next() {
         None => Ok(false),
-        Some(_) => Ok(true),
+        Some(res) => {
+            let (key, _) = res.map_err(|e| {
+                format!(
+                    "[db::utxo_set_contains_tx] ERROR: Failed to read from DB {:?}",
+                    e
+                )
+            })?;
+            Ok(key.starts_with(&tx_id))
+        }
     }
 }
 
 pub fn put_utxo(tx_id: &[u8; 32], out_idx: u32, tx_out: &TxOutput) -> Result<(), Box<dyn Error>> {
-    // Try to get the existing TxOutMap for this transaction ID
-    let mut txo_map = match ROCKS_DB.get_cf(utxo_cf(), tx_id)? {
-        Some(data) => bincode::deserialize::<TxOutMap>(&data)?,
-        None => HashMap::new(), // If no existing map, create a new one
-    };
-
-    txo_map.insert(out_idx, tx_out.clone());
-
-    let serialized = bincode::serialize(&txo_map)
+    let serialized = bincode::serialize(tx_out)
         .map_err(|e| format!("[db::put_utxo] ERROR: Serialization failed {:?}", e))?;
-
     ROCKS_DB
-        .put_cf(utxo_cf(), tx_id, serialized)
+        .put_cf(utxo_cf(), to_utxo_db_key(tx_id, out_idx), serialized)
         .map_err(|e| format!("[db::put_utxo] ERROR: Failed to write to DB {:?}", e))?;
-
     Ok(())
 }
 
-pub fn delete_utxo(tx_id: &[u8; 32], out_idx: u32) -> Result<(), Box<dyn Error>> {
-    // Try to get the existing TxOutMap for this transaction ID
-    let mut txo_map = match ROCKS_DB.get_cf(utxo_cf(), tx_id)? {
-        Some(data) => bincode::deserialize::<TxOutMap>(&data)?,
-        None => return Ok(()), // No entry found, nothing to delete
-    };
+/// Utxos used to be stored as one TxOutMap per tx id. Rewrites any of those entries into a key
+/// per output, so dbs created before the change keep their utxo set
+fn migrate_utxo_keys(db: &DB) -> Result<(), Box<dyn Error>> {
+    let cf = db.cf_handle(UTXO_CF).ok_or("Column family not found")?;
+    let mut batch = WriteBatch::default();
+    let mut migrated = 0;
 
-    // Remove the specific UTXO if it exists
-    if txo_map.remove(&out_idx).is_some() {
-        if txo_map.is_empty() {
-            // If no more outputs remain, remove the entire tx_id entry
-            ROCKS_DB.delete_cf(utxo_cf(), tx_id).map_err(|e| {
-                format!("[db::delete_utxo] ERROR: Failed to delete from DB {:?}", e)
-            })?;
-        } else {
-            // Otherwise, update DB with the modified map
-            let serialized = bincode::serialize(&txo_map)
-                .map_err(|e| format!("[db::delete_utxo] ERROR: Serialization failed {:?}", e))?;
-
-            ROCKS_DB
-                .put_cf(utxo_cf(), tx_id, serialized)
-                .map_err(|e| format!("[db::delete_utxo] ERROR: Failed to update DB {:?}", e))?;
+    for res in db.iterator_cf(cf, IteratorMode::Start) {
+        let (key, val) = res?;
+        // Only the old entries are keyed by the bare tx id
+        let Ok(tx_id) = <[u8; 32]>::try_from(key.as_ref()) else {
+            continue;
+        };
+        let txo_map: TxOutMap = bincode::deserialize(&val)?;
+        for (out_idx, tx_out) in txo_map {
+            batch.put_cf(
+                cf,
+                to_utxo_db_key(&tx_id, out_idx),
+                bincode::serialize(&tx_out)?,
+            );
         }
+        batch.delete_cf(cf, key);
+        migrated += 1;
     }
 
+    if migrated > 0 {
+        db.write(batch)?;
+        info!("Migrated utxos of {} txs to per-output keys", migrated);
+    }
     Ok(())
 }
 
 pub fn delete_all_utxos() {
-    let _ = ROCKS_DB.delete_range_cf(utxo_cf(), b"", b"");
+    // Utxos are keyed by a 32 byte tx id and 4 byte output index, so a 37 byte end key bounds
+    // all of them. An empty range would delete nothing
+    let _ = ROCKS_DB.delete_range_cf(utxo_cf(), &[0u8; 32][..], &[0xffu8; 37][..]);
 }
 
 /*** Block DB handlers ***/
@@ -172,20 +411,424 @@ pub fn get_all_block_hashes() -> Result<Vec<[u8; 32]>, Box<dyn Error>> {
     Ok(block_hashes)
 }
 
-pub fn put_block(block_data: &Block) {
-    let serialized =
-        bincode::serialize(&block_data).expect("[db::put_block] ERROR: Serialization failed");
+pub fn delete_all_blocks() {
+    // Block hashes are 32 byte keys, so a 33 byte end key bounds all of them
+    let _ = ROCKS_DB.delete_range_cf(block_cf(), &[0u8; 32][..], &[0xffu8; 33][..]);
+}
+
+/// LegacyBlock is the block layout of schema versions before 5, as stored by dbs that predate
+/// versioning. Blocks had no extranonce, and their merkle root was computed from the txs
+#[derive(Serialize, Deserialize)]
+struct LegacyBlock {
+    txs: Vec<Tx>,
+    prev_hash: [u8; 32],
+    hash: [u8; 32],
+    nonce: u32,
+    height: u32,
+    timestamp: u64,
+}
+
+impl LegacyBlock {
+    /// Converts the block to the current layout, filling in its merkle root. Legacy blocks were
+    /// never pruned, so the root is always computed from their txs
+    fn into_block(self) -> Result<Block, Box<dyn Error>> {
+        let mut block = Block {
+            txs: self.txs,
+            prev_hash: self.prev_hash,
+            merkle_root: [0u8; 32],
+            hash: self.hash,
+            nonce: self.nonce,
+            extranonce: 0,
+            height: self.height,
+            timestamp: self.timestamp,
+        };
+        block.merkle_root = block.merkle_tree()?.root.hash;
+        Ok(block)
+    }
+}
+
+/// Decodes a block stored in the legacy layout
+fn decode_legacy_block(data: &[u8]) -> Result<Block, Box<dyn Error>> {
+    bincode::deserialize::<LegacyBlock>(data)?.into_block()
+}
+
+/// Blocks gained an extranonce and a stored merkle root after the legacy layout, and the block
+/// hash covers both. Legacy hashes can't be recomputed without mining every block again, so a
+/// legacy chain can't be carried over. Its chain state is dropped instead, and the node resyncs
+/// the chain from peers. Peers and banned peers are kept
+fn drop_legacy_chain(db: &DB) -> Result<(), Box<dyn Error>> {
+    let block_cf = db.cf_handle(BLOCK_CF).ok_or("Column family not found")?;
+    let had_chain = db.get(LAST_HASH_KEY)?.is_some()
+        || db
+            .iterator_cf(block_cf, IteratorMode::Start)
+            .next()
+            .is_some();
+
+    // Every column family holds chain state
+    let mut batch = WriteBatch::default();
+    for cf in COLUMN_FAMILIES {
+        let cf = db.cf_handle(cf).ok_or("Column family not found")?;
+        for res in db.iterator_cf(cf, IteratorMode::Start) {
+            batch.delete_cf(cf, res?.0);
+        }
+    }
+    for key in [
+        LAST_HASH_KEY,
+        PRUNED_HEIGHT_KEY,
+        ORPHAN_KEY,
+        ORPHAN_ORDER_KEY,
+        ORPHAN_TXS_KEY,
+        MEMPOOL_KEY,
+        MEMPOOL_SPENDS_KEY,
+    ] {
+        batch.delete(key);
+    }
+    db.write(batch)?;
+
+    if had_chain {
+        warn!("Dropped a chain stored in the legacy block layout. It will be resynced from peers");
+    }
+    Ok(())
+}
+
+/*** Chain work DB handlers ***/
+
+pub fn work_cf() -> &'static ColumnFamily {
     ROCKS_DB
-        .put_cf(block_cf(), block_data.hash, serialized)
-        .expect("[db::put_block] ERROR: Failed to write to DB");
+        .cf_handle(WORK_CF)
+        .expect("Column family not found")
 }
 
-pub fn delete_block(block_hash: &[u8; 32]) {
-    let _ = ROCKS_DB.delete_cf(block_cf(), block_hash);
+/// Returns the stored cumulative chain work of the given block, if any
+pub fn get_chain_work(block_hash: &[u8; 32]) -> Result<Option<u128>, Box<dyn Error>> {
+    let work_data = ROCKS_DB
+        .get_cf(work_cf(), block_hash)
+        .map_err(|e| format!("[db::get_chain_work] ERROR: Failed to read from DB {:?}", e))?;
+
+    match work_data {
+        Some(data) => {
+            let work: [u8; 16] = data.try_into().map_err(|e| {
+                format!(
+                    "[db::get_chain_work] ERROR: Failed to parse chain work {:?}",
+                    e
+                )
+            })?;
+            Ok(Some(u128::from_le_bytes(work)))
+        }
+        None => Ok(None),
+    }
 }
 
-pub fn delete_all_blocks() {
-    let _ = ROCKS_DB.delete_range_cf(block_cf(), b"", b"");
+pub fn delete_all_chain_work() {
+    // Block hashes are 32 byte keys, so a 33 byte end key bounds all of them
+    let _ = ROCKS_DB.delete_range_cf(work_cf(), &[0u8; 32][..], &[0xffu8; 33][..]);
+}
+
+/*** Height index DB handlers ***/
+
+pub fn height_cf() -> &'static ColumnFamily {
+    ROCKS_DB
+        .cf_handle(HEIGHT_CF)
+        .expect("Column family not found")
+}
+
+/// Returns the hash of the main chain block at the given height, if any
+pub fn get_block_hash_at_height(height: u32) -> Result<Option<[u8; 32]>, Box<dyn Error>> {
+    let hash_data = ROCKS_DB
+        .get_cf(height_cf(), height.to_be_bytes())
+        .map_err(|e| {
+            format!(
+                "[db::get_block_hash_at_height] ERROR: Failed to read from DB {:?}",
+                e
+            )
+        })?;
+
+    match hash_data {
+        Some(data) => {
+            let hash: [u8; 32] = data.try_into().map_err(|e| {
+                format!(
+                    "[db::get_block_hash_at_height] ERROR: Failed to parse block hash {:?}",
+                    e
+                )
+            })?;
+            Ok(Some(hash))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn delete_height_index() {
+    // Heights are 4 byte keys, so a 5 byte end key bounds all of them
+    let _ = ROCKS_DB.delete_range_cf(height_cf(), &[0u8; 4][..], &[0xffu8; 5][..]);
+}
+
+/// The height index was added after the chain was first stored. Builds it by walking back from
+/// the tip if the db holds a chain but no index yet
+fn build_height_index(db: &DB) -> Result<(), Box<dyn Error>> {
+    let height_cf = db.cf_handle(HEIGHT_CF).ok_or("Column family not found")?;
+    let block_cf = db.cf_handle(BLOCK_CF).ok_or("Column family not found")?;
+    if db
+        .iterator_cf(height_cf, IteratorMode::Start)
+        .next()
+        .is_some()
+    {
+        return Ok(());
+    }
+    let Some(last_hash) = db.get(LAST_HASH_KEY)? else {
+        return Ok(());
+    };
+
+    let mut batch = WriteBatch::default();
+    let mut block_hash: [u8; 32] = last_hash
+        .try_into()
+        .map_err(|_| "Failed to parse last hash")?;
+    loop {
+        let data = db
+            .get_cf(block_cf, block_hash)?
+            .ok_or_else(|| format!("Could not find block {}", hex::encode(block_hash)))?;
+        // Blocks are still in their legacy layout when this migration runs
+        let block = decode_legacy_block(&data)?;
+        batch.put_cf(height_cf, block.height.to_be_bytes(), block.hash);
+        if block.is_genesis() {
+            break;
+        }
+        block_hash = block.prev_hash;
+    }
+
+    let indexed = batch.len();
+    db.write(batch)?;
+    info!("Indexed {} main chain blocks by height", indexed);
+    Ok(())
+}
+
+/*** Tx index DB handlers ***/
+
+pub fn tx_cf() -> &'static ColumnFamily {
+    ROCKS_DB.cf_handle(TX_CF).expect("Column family not found")
+}
+
+/// Returns the hash of the main chain block containing the given tx, if any
+pub fn get_tx_block_hash(tx_id: &[u8; 32]) -> Result<Option<[u8; 32]>, Box<dyn Error>> {
+    let hash_data = ROCKS_DB.get_cf(tx_cf(), tx_id).map_err(|e| {
+        format!(
+            "[db::get_tx_block_hash] ERROR: Failed to read from DB {:?}",
+            e
+        )
+    })?;
+
+    match hash_data {
+        Some(data) => {
+            let hash: [u8; 32] = data.try_into().map_err(|e| {
+                format!(
+                    "[db::get_tx_block_hash] ERROR: Failed to parse block hash {:?}",
+                    e
+                )
+            })?;
+            Ok(Some(hash))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn delete_tx_index() {
+    // Tx ids are 32 byte keys, so a 33 byte end key bounds all of them
+    let _ = ROCKS_DB.delete_range_cf(tx_cf(), &[0u8; 32][..], &[0xffu8; 33][..]);
+}
+
+/// The tx index was added after the chain was first stored. Builds it by walking back from the
+/// tip to the pruned part of the chain if the db holds a chain but no index yet
+fn build_tx_index(db: &DB) -> Result<(), Box<dyn Error>> {
+    let tx_cf = db.cf_handle(TX_CF).ok_or("Column family not found")?;
+    let block_cf = db.cf_handle(BLOCK_CF).ok_or("Column family not found")?;
+    if db.iterator_cf(tx_cf, IteratorMode::Start).next().is_some() {
+        return Ok(());
+    }
+    let Some(last_hash) = db.get(LAST_HASH_KEY)? else {
+        return Ok(());
+    };
+
+    let mut batch = WriteBatch::default();
+    let mut block_hash: [u8; 32] = last_hash
+        .try_into()
+        .map_err(|_| "Failed to parse last hash")?;
+    loop {
+        let data = db
+            .get_cf(block_cf, block_hash)?
+            .ok_or_else(|| format!("Could not find block {}", hex::encode(block_hash)))?;
+        // Blocks are still in their legacy layout when this migration runs
+        let block = decode_legacy_block(&data)?;
+        if block.is_pruned() {
+            break;
+        }
+        for tx in &block.txs {
+            batch.put_cf(tx_cf, tx.id, block.hash);
+        }
+        if block.is_genesis() {
+            break;
+        }
+        block_hash = block.prev_hash;
+    }
+
+    let indexed = batch.len();
+    db.write(batch)?;
+    info!("Indexed {} main chain txs by id", indexed);
+    Ok(())
+}
+
+/*** Batched chain writes ***/
+
+/// ChainBatch groups writes to the chain state - blocks, utxos, the mempool and the last
+/// hash - so that they are committed atomically. Nothing is written until `commit` is called,
+/// so a failure part way through leaves the db untouched.
+pub struct ChainBatch {
+    batch: WriteBatch,
+    /// Pending utxo writes by outpoint, so that utxo reads within the batch see earlier writes.
+    /// A `None` marks a deleted utxo
+    utxos: HashMap<([u8; 32], u32), Option<TxOutput>>,
+    /// Txs to remove from the mempool. Applied to the latest mempool on commit, so txs added
+    /// while the batch was being built aren't lost
+    mempool_removals: Vec<[u8; 32]>,
+}
+
+impl ChainBatch {
+    pub fn new() -> Self {
+        ChainBatch {
+            batch: WriteBatch::default(),
+            utxos: HashMap::new(),
+            mempool_removals: Vec::new(),
+        }
+    }
+
+    /// Returns the utxo at the given outpoint, including any pending changes
+    pub fn get_utxo(
+        &self,
+        tx_id: &[u8; 32],
+        out_idx: u32,
+    ) -> Result<Option<TxOutput>, Box<dyn Error>> {
+        match self.utxos.get(&(*tx_id, out_idx)) {
+            Some(pending) => Ok(*pending),
+            None => get_utxo(tx_id, out_idx),
+        }
+    }
+
+    pub fn put_utxo(
+        &mut self,
+        tx_id: &[u8; 32],
+        out_idx: u32,
+        tx_out: &TxOutput,
+    ) -> Result<(), Box<dyn Error>> {
+        self.utxos.insert((*tx_id, out_idx), Some(*tx_out));
+        Ok(())
+    }
+
+    pub fn delete_utxo(&mut self, tx_id: &[u8; 32], out_idx: u32) -> Result<(), Box<dyn Error>> {
+        self.utxos.insert((*tx_id, out_idx), None);
+        Ok(())
+    }
+
+    pub fn put_block(&mut self, block: &Block) -> Result<(), Box<dyn Error>> {
+        let serialized = bincode::serialize(block).map_err(|e| {
+            format!(
+                "[db::ChainBatch::put_block] ERROR: Serialization failed {:?}",
+                e
+            )
+        })?;
+        self.batch.put_cf(block_cf(), block.hash, serialized);
+        Ok(())
+    }
+
+    /// Stores the cumulative chain work of a block. Work only depends on the block's ancestry,
+    /// so it is kept when the block is removed from the chain
+    pub fn put_chain_work(&mut self, block_hash: &[u8; 32], work: u128) {
+        self.batch.put_cf(work_cf(), block_hash, work.to_le_bytes());
+    }
+
+    pub fn delete_block(&mut self, block_hash: &[u8; 32]) {
+        self.batch.delete_cf(block_cf(), block_hash);
+    }
+
+    pub fn put_last_hash(&mut self, last_hash: &[u8; 32]) {
+        self.batch.put(LAST_HASH_KEY, last_hash);
+    }
+
+    /// Indexes the block with the given hash as the main chain block at the given height
+    pub fn put_height_index(&mut self, height: u32, block_hash: &[u8; 32]) {
+        self.batch
+            .put_cf(height_cf(), height.to_be_bytes(), block_hash);
+    }
+
+    pub fn delete_height_index(&mut self, height: u32) {
+        self.batch.delete_cf(height_cf(), height.to_be_bytes());
+    }
+
+    /// Indexes the txs of a main chain block by id, so they can be found without walking the chain
+    pub fn put_tx_index(&mut self, block: &Block) {
+        for tx in &block.txs {
+            self.batch.put_cf(tx_cf(), tx.id, block.hash);
+        }
+    }
+
+    pub fn delete_tx_index(&mut self, block: &Block) {
+        for tx in &block.txs {
+            self.batch.delete_cf(tx_cf(), tx.id);
+        }
+    }
+
+    pub fn put_pruned_height(&mut self, height: u32) {
+        self.batch.put(PRUNED_HEIGHT_KEY, height.to_le_bytes());
+    }
+
+    /// Removes the given txs from the mempool
+    pub fn remove_txs_from_mempool(&mut self, tx_ids: &[[u8; 32]]) -> Result<(), Box<dyn Error>> {
+        self.mempool_removals.extend_from_slice(tx_ids);
+        Ok(())
+    }
+
+    /// Atomically writes all batched changes to the db
+    pub fn commit(mut self) -> Result<(), Box<dyn Error>> {
+        for ((tx_id, out_idx), tx_out) in &self.utxos {
+            let key = to_utxo_db_key(tx_id, *out_idx);
+            match tx_out {
+                Some(tx_out) => {
+                    let serialized = bincode::serialize(tx_out).map_err(|e| {
+                        format!(
+                            "[db::ChainBatch::commit] ERROR: Serialization failed {:?}",
+                            e
+                        )
+                    })?;
+                    self.batch.put_cf(utxo_cf(), key, serialized);
+                }
+                None => self.batch.delete_cf(utxo_cf(), key),
+            }
+        }
+
+        // Hold the mempool lock from reading the mempool until the batch is written, so
+        // concurrent mempool writes can't be overwritten
+        let _mempool_guard = if self.mempool_removals.is_empty() {
+            None
+        } else {
+            let guard = lock_mempool();
+            let mut mempool = get_mempool();
+            for tx_id in &self.mempool_removals {
+                mempool.remove(tx_id);
+            }
+            put_mempool_to_batch(&mut self.batch, &mempool).map_err(|e| {
+                format!(
+                    "[db::ChainBatch::commit] ERROR: Failed to serialize mempool {:?}",
+                    e
+                )
+            })?;
+            Some(guard)
+        };
+
+        ROCKS_DB.write(self.batch).map_err(|e| {
+            format!(
+                "[db::ChainBatch::commit] ERROR: Failed to write to DB {:?}",
+                e
+            )
+        })?;
+        Ok(())
+    }
 }
 
 /*** Last Hash DB handlers ***/
@@ -200,7 +843,7 @@ pub fn blockchain_exists() -> bool {
 pub fn get_last_hash() -> Result<[u8; 32], Box<dyn Error>> {
     let last_hash: [u8; 32] = ROCKS_DB
         .get(LAST_HASH_KEY.as_bytes())?
-        .ok_or_else(|| "[db::get_last_hash] ERROR: No last hash found in the db")?
+        .ok_or("[db::get_last_hash] ERROR: No last hash found in the db")?
         .try_into()
         .map_err(|e| {
             format!(
@@ -212,57 +855,105 @@ pub fn get_last_hash() -> Result<[u8; 32], Box<dyn Error>> {
     Ok(last_hash)
 }
 
-pub fn put_last_hash(last_hash: &[u8; 32]) {
+pub fn delete_last_hash() {
+    let _ = ROCKS_DB.delete(LAST_HASH_KEY);
+}
+
+/*** Pruned Height DB handlers ***/
+
+/// Returns the height of the highest pruned block, or None if the chain has not been pruned
+pub fn get_pruned_height() -> Option<u32> {
     ROCKS_DB
-        .put(LAST_HASH_KEY, last_hash)
-        .expect("[db::put_last_hash] ERROR: Failed to write to DB");
+        .get(PRUNED_HEIGHT_KEY.as_bytes())
+        .unwrap_or(None)
+        .and_then(|data| data.try_into().ok())
+        .map(u32::from_le_bytes)
 }
 
-pub fn delete_last_hash() {
-    let _ = ROCKS_DB.delete(LAST_HASH_KEY);
+pub fn delete_pruned_height() {
+    let _ = ROCKS_DB.delete(PRUNED_HEIGHT_KEY);
 }
 
 /*** Mempool DB handlers ***/
+
+/// The mempool is stored as a single blob, so every change is a read-modify-write. This lock
+/// is held across each change so that concurrent writers can't overwrite each other's updates
+static MEMPOOL_LOCK: Mutex<()> = Mutex::new(());
+
+fn lock_mempool() -> MutexGuard<'static, ()> {
+    // The lock guards no data, so a panic while it was held can't leave anything inconsistent
+    MEMPOOL_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 pub fn get_mempool() -> Mempool {
     let mempool_data = ROCKS_DB.get(MEMPOOL_KEY.as_bytes()).unwrap();
     mempool_data
         .and_then(|data| bincode::deserialize(&data).ok()) // Try to deserialize
-        .unwrap_or_else(HashMap::new)
+        .unwrap_or_default()
 }
 
-pub fn put_mempool(tx: &Tx) {
-    let mut mempool = get_mempool();
-
-    // Insert each output of the transaction into the mempool UTXOSet
-    mempool.insert(tx.id, tx.clone());
-
-    let serialized =
-        bincode::serialize(&mempool).expect("[db::put_mempool] ERROR: Failed to serialize mempool");
+/// Returns the index of outpoints spent by mempool txs. Falls back to indexing the mempool if
+/// no index has been stored yet
+pub fn get_mempool_spends() -> MempoolSpends {
+    let spends_data = ROCKS_DB.get(MEMPOOL_SPENDS_KEY.as_bytes()).unwrap();
+    spends_data
+        .and_then(|data| bincode::deserialize(&data).ok()) // Try to deserialize
+        .unwrap_or_else(|| index_mempool_spends(&get_mempool()))
+}
 
-    ROCKS_DB
-        .put(MEMPOOL_KEY, serialized)
-        .expect("[db::put_mempool] ERROR: Failed to write to DB");
+/// Adds the mempool and its spends index to a write batch, so they are always written together
+fn put_mempool_to_batch(batch: &mut WriteBatch, mempool: &Mempool) -> bincode::Result<()> {
+    batch.put(MEMPOOL_KEY, bincode::serialize(mempool)?);
+    batch.put(
+        MEMPOOL_SPENDS_KEY,
+        bincode::serialize(&index_mempool_spends(mempool))?,
+    );
+    Ok(())
 }
 
-pub fn remove_txs_from_mempool(tx_ids: Vec<[u8; 32]>) {
+/// Applies a change to the mempool while holding the mempool lock. The change is given the
+/// current spends index to check conflicts against. The mempool is only written if the change
+/// succeeds, so checks made against it can't be invalidated by a concurrent write
+pub fn modify_mempool<T, E>(
+    f: impl FnOnce(&mut Mempool, &MempoolSpends) -> Result<T, E>,
+) -> Result<T, E> {
+    let _guard = lock_mempool();
     let mut mempool = get_mempool();
 
-    for tx_id in tx_ids {
-        mempool.remove(&tx_id);
-    }
+    let res = f(&mut mempool, &get_mempool_spends())?;
 
-    let serialized =
-        bincode::serialize(&mempool).expect("[db::put_mempool] ERROR: Failed to serialize mempool");
+    let mut batch = WriteBatch::default();
+    put_mempool_to_batch(&mut batch, &mempool)
+        .expect("[db::modify_mempool] ERROR: Failed to serialize mempool");
 
     ROCKS_DB
-        .put(MEMPOOL_KEY, serialized)
-        .expect("[db::remove_txs_from_mempool] ERROR: Failed to write to DB");
+        .write(batch)
+        .expect("[db::modify_mempool] ERROR: Failed to write to DB");
+    Ok(res)
+}
+
+pub fn put_mempool(tx: &Tx) {
+    let _ = modify_mempool(|mempool, _| {
+        mempool.insert(tx.id, MempoolEntry::new(tx.clone()));
+        Ok::<_, Infallible>(())
+    });
+}
+
+pub fn remove_txs_from_mempool(tx_ids: Vec<[u8; 32]>) {
+    let _ = modify_mempool(|mempool, _| {
+        for tx_id in tx_ids {
+            mempool.remove(&tx_id);
+        }
+        Ok::<_, Infallible>(())
+    });
 }
 
-/// Delete all mempool entries by deleting the mempool key
+/// Delete all mempool entries by deleting the mempool and spends index keys
 pub fn delete_mempool() {
-    // Delete the mempool key, effectively resetting the entire mempool. No error on failure
+    let _guard = lock_mempool();
+    // Delete the mempool keys, effectively resetting the entire mempool. No error on failure
     let _ = ROCKS_DB.delete(MEMPOOL_KEY);
+    let _ = ROCKS_DB.delete(MEMPOOL_SPENDS_KEY);
 }
 
 /*** Orphan DB handlers ***/
@@ -272,26 +963,72 @@ pub fn delete_mempool() {
 /// Ex. An orphan chain of 5 blocks that is 11 blocks behind the accepted chain will be discarded. Any less and it will be retained incase the chain completes
 pub const MAX_ORPHAN_CHAIN_AGE: u32 = 10;
 
+/// MAX_ORPHAN_BLOCKS is the max number of orphan blocks held by the node. Once exceeded, the least
+/// recently inserted orphans are evicted first
+pub const MAX_ORPHAN_BLOCKS: usize = 100;
+
 pub fn get_orphaned_blocks() -> OrphanBlocks {
     let block_data = ROCKS_DB.get(ORPHAN_KEY.as_bytes()).unwrap();
     block_data
         .and_then(|data| bincode::deserialize(&data).ok()) // Try to deserialize
-        .unwrap_or_else(HashMap::new)
+        .unwrap_or_default()
+}
+
+/// Returns the orphan block hashes ordered from least to most recently inserted. Hashes that are
+/// no longer in the orphan set are dropped, and any orphans missing from the stored order are
+/// treated as the oldest entries.
+fn get_orphan_order(block_map: &OrphanBlocks) -> Vec<[u8; 32]> {
+    let mut order: Vec<[u8; 32]> = ROCKS_DB
+        .get(ORPHAN_ORDER_KEY.as_bytes())
+        .unwrap()
+        .and_then(|data| bincode::deserialize(&data).ok())
+        .unwrap_or_default();
+    order.retain(|hash| block_map.contains_key(hash));
+
+    let untracked: Vec<[u8; 32]> = block_map
+        .keys()
+        .filter(|hash| !order.contains(hash))
+        .copied()
+        .collect();
+    order.splice(0..0, untracked);
+    order
+}
+
+fn put_orphan_blocks(block_map: &OrphanBlocks, order: &[[u8; 32]]) {
+    let serialized_blocks = bincode::serialize(block_map)
+        .expect("[db::put_orphan_blocks] ERROR: Failed to serialize orphan blocks");
+    let serialized_order = bincode::serialize(order)
+        .expect("[db::put_orphan_blocks] ERROR: Failed to serialize orphan order");
+
+    ROCKS_DB
+        .put(ORPHAN_KEY, serialized_blocks)
+        .expect("[db::put_orphan_blocks] ERROR: Failed to write to DB");
+    ROCKS_DB
+        .put(ORPHAN_ORDER_KEY, serialized_order)
+        .expect("[db::put_orphan_blocks] ERROR: Failed to write to DB");
 }
 
+/// Adds a block to the orphan set, marking it as the most recently used orphan. If the set
+/// grows beyond MAX_ORPHAN_BLOCKS, the least recently used orphans are evicted.
 pub fn put_orphan_block(block: &Block) {
-    // TODO: Put cap on map size, use LRU evictions
     let mut block_map = get_orphaned_blocks();
+    let mut order = get_orphan_order(&block_map);
 
-    // Insert each output of the transaction into the mempool UTXOSet
     block_map.insert(block.hash, block.clone());
+    order.retain(|hash| *hash != block.hash);
+    order.push(block.hash);
 
-    let serialized = bincode::serialize(&block_map)
-        .expect("[db::put_orphan_block] ERROR: Failed to serialize orphan blocks");
+    // Evict the oldest orphans until we are back under the cap
+    while block_map.len() > MAX_ORPHAN_BLOCKS {
+        let oldest = order.remove(0);
+        block_map.remove(&oldest);
+        info!(
+            "Orphan pool full, evicted orphan block {}",
+            hex::encode(oldest)
+        );
+    }
 
-    ROCKS_DB
-        .put(ORPHAN_KEY, serialized)
-        .expect("[db::put_orphan_block] ERROR: Failed to write to DB");
+    put_orphan_blocks(&block_map, &order);
 }
 
 pub fn remove_from_orphan_blocks(block_hashes: Vec<[u8; 32]>) {
@@ -301,15 +1038,428 @@ pub fn remove_from_orphan_blocks(block_hashes: Vec<[u8; 32]>) {
         block_map.remove(&hash);
     }
 
-    let serialized = bincode::serialize(&block_map)
-        .expect("[db::remove_from_orphan_blocks] ERROR: Failed to serialize mempool");
-
-    ROCKS_DB
-        .put(ORPHAN_KEY, serialized)
-        .expect("[db::remove_blocks_from_orphan_blocks] ERROR: Failed to write to DB");
+    let order = get_orphan_order(&block_map);
+    put_orphan_blocks(&block_map, &order);
 }
 
 pub fn delete_all_orphan_blocks() {
-    // Delete the orphan key, effectively resetting the orphan block storage. No error on failure
+    // Delete the orphan keys, effectively resetting the orphan block storage. No error on failure
     let _ = ROCKS_DB.delete(ORPHAN_KEY);
+    let _ = ROCKS_DB.delete(ORPHAN_ORDER_KEY);
+}
+
+/*** Orphan tx DB handlers ***/
+
+/// MAX_ORPHAN_TXS is the max number of orphan txs held by the node. Once exceeded, the oldest
+/// orphans are evicted first
+pub const MAX_ORPHAN_TXS: usize = 100;
+
+pub fn get_orphan_txs() -> OrphanTxs {
+    let tx_data = ROCKS_DB.get(ORPHAN_TXS_KEY.as_bytes()).unwrap();
+    tx_data
+        .and_then(|data| bincode::deserialize(&data).ok()) // Try to deserialize
+        .unwrap_or_default()
+}
+
+fn put_orphan_txs(orphans: &OrphanTxs) {
+    let serialized = bincode::serialize(orphans)
+        .expect("[db::put_orphan_txs] ERROR: Failed to serialize orphan txs");
+
+    ROCKS_DB
+        .put(ORPHAN_TXS_KEY, serialized)
+        .expect("[db::put_orphan_txs] ERROR: Failed to write to DB");
+}
+
+/// Adds a tx to the orphan tx pool. If the pool grows beyond MAX_ORPHAN_TXS, the oldest
+/// orphans are evicted.
+pub fn put_orphan_tx(tx: &Tx) {
+    // Orphan txs are only written alongside mempool changes, so they share the mempool lock
+    let _guard = lock_mempool();
+    let mut orphans = get_orphan_txs();
+    orphans.insert(tx.id, MempoolEntry::new(tx.clone()));
+
+    // Evict the oldest orphans until we are back under the cap
+    while orphans.len() > MAX_ORPHAN_TXS {
+        let Some(oldest) = orphans
+            .iter()
+            .min_by_key(|(_, entry)| entry.added_at)
+            .map(|(tx_id, _)| *tx_id)
+        else {
+            break;
+        };
+        orphans.remove(&oldest);
+        info!(
+            "Orphan tx pool full, evicted orphan tx {}",
+            hex::encode(oldest)
+        );
+    }
+
+    put_orphan_txs(&orphans);
+}
+
+pub fn remove_orphan_txs(tx_ids: &[[u8; 32]]) {
+    let _guard = lock_mempool();
+    let mut orphans = get_orphan_txs();
+    for tx_id in tx_ids {
+        orphans.remove(tx_id);
+    }
+    put_orphan_txs(&orphans);
+}
+
+pub fn delete_all_orphan_txs() {
+    let _guard = lock_mempool();
+    // Delete the orphan txs key, effectively resetting the orphan tx pool. No error on failure
+    let _ = ROCKS_DB.delete(ORPHAN_TXS_KEY);
+}
+
+/*** Peer DB handlers ***/
+
+/// MAX_STORED_PEERS is the max number of peer addresses kept in the db. The least recently seen are dropped first
+pub const MAX_STORED_PEERS: usize = 50;
+/// PEER_TTL_SECS is how long a peer address is kept after the peer was last seen
+pub const PEER_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize)]
+struct StoredPeer {
+    peer_id: Vec<u8>,
+    addr: Vec<u8>,
+    last_seen: u64,
+}
+
+fn get_stored_peers() -> Vec<StoredPeer> {
+    let peer_data = ROCKS_DB.get(PEERS_KEY.as_bytes()).unwrap();
+    peer_data
+        .and_then(|data| bincode::deserialize(&data).ok()) // Try to deserialize
+        .unwrap_or_default()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the stored peer addresses that have been seen within PEER_TTL_SECS, most recently seen first
+pub fn get_peers() -> Vec<(PeerId, Multiaddr)> {
+    let now = now_secs();
+    get_stored_peers()
+        .into_iter()
+        .filter(|peer| now.saturating_sub(peer.last_seen) <= PEER_TTL_SECS)
+        .filter_map(|peer| {
+            let peer_id = PeerId::from_bytes(&peer.peer_id).ok()?;
+            let addr = Multiaddr::try_from(peer.addr).ok()?;
+            Some((peer_id, addr))
+        })
+        .collect()
+}
+
+/// Stores or refreshes a peer address. Stale peers are dropped and the set is capped at MAX_STORED_PEERS.
+pub fn put_peer(peer_id: &PeerId, addr: &Multiaddr) {
+    let now = now_secs();
+    let peer_id_bytes = peer_id.to_bytes();
+
+    let mut peers = get_stored_peers();
+    peers.retain(|peer| {
+        peer.peer_id != peer_id_bytes && now.saturating_sub(peer.last_seen) <= PEER_TTL_SECS
+    });
+    peers.push(StoredPeer {
+        peer_id: peer_id_bytes,
+        addr: addr.to_vec(),
+        last_seen: now,
+    });
+
+    // Keep the most recently seen peers
+    peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+    peers.truncate(MAX_STORED_PEERS);
+
+    let serialized =
+        bincode::serialize(&peers).expect("[db::put_peer] ERROR: Failed to serialize peers");
+
+    ROCKS_DB
+        .put(PEERS_KEY, serialized)
+        .expect("[db::put_peer] ERROR: Failed to write to DB");
+}
+
+#[cfg(test)]
+mod tests {
+    use core_lib::wallet::Wallet;
+
+    use super::*;
+    use crate::{
+        blockchain::{
+            chain::validate_chain,
+            transaction::{tx::coinbase_tx, utxo::find_utxos_for_addr},
+        },
+        test_utils::{new_chain, setup},
+    };
+
+    /// Returns an unmined block in the legacy layout, paying its reward to the given wallet
+    fn legacy_block(wallet: &Wallet, prev_hash: [u8; 32], height: u32) -> LegacyBlock {
+        let tx = coinbase_tx(&wallet.get_wallet_address(), 0, height).unwrap();
+        LegacyBlock {
+            hash: [height as u8 + 1; 32],
+            txs: vec![tx],
+            prev_hash,
+            nonce: 0,
+            height,
+            timestamp: u64::from(height),
+        }
+    }
+
+    #[test]
+    fn migrates_db_without_schema_version() {
+        let _guard = setup();
+        let path = std::env::temp_dir().join(format!("dcoin_migration_{}", std::process::id()));
+        let _ = DB::destroy(&Options::default(), &path);
+
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let cf_descriptors = COLUMN_FAMILIES
+            .iter()
+            .map(|cf| ColumnFamilyDescriptor::new(*cf, Options::default()));
+        let db = DB::open_cf_descriptors(&opts, &path, cf_descriptors).unwrap();
+        let block_cf = db.cf_handle(BLOCK_CF).unwrap();
+
+        // Write a two block chain, an orphan and a utxo as stored before versioning
+        let wallet = Wallet::new();
+        let genesis = legacy_block(&wallet, [0u8; 32], 0);
+        let tip = legacy_block(&wallet, genesis.hash, 1);
+        let orphan = legacy_block(&wallet, [9u8; 32], 5);
+        let tip_tx = tip.txs[0].clone();
+        for block in [&genesis, &tip] {
+            db.put_cf(block_cf, block.hash, bincode::serialize(block).unwrap())
+                .unwrap();
+        }
+        db.put(LAST_HASH_KEY, tip.hash).unwrap();
+        let orphan_hash = orphan.hash;
+        let orphans = HashMap::from([(orphan_hash, orphan)]);
+        db.put(ORPHAN_KEY, bincode::serialize(&orphans).unwrap())
+            .unwrap();
+        let txo_map: TxOutMap = HashMap::from([(0, tip_tx.outputs[0])]);
+        db.put_cf(
+            db.cf_handle(UTXO_CF).unwrap(),
+            tip_tx.id,
+            bincode::serialize(&txo_map).unwrap(),
+        )
+        .unwrap();
+
+        db.put(PEERS_KEY, b"peers").unwrap();
+
+        migrate_schema(&db, &path.display().to_string()).unwrap();
+
+        assert_eq!(
+            db.get(SCHEMA_VERSION_KEY).unwrap().unwrap(),
+            SCHEMA_VERSION.to_le_bytes()
+        );
+        // Legacy block hashes can't be recomputed, so the chain is dropped to be resynced
+        for cf in COLUMN_FAMILIES {
+            let cf = db.cf_handle(cf).unwrap();
+            assert!(db.iterator_cf(cf, IteratorMode::Start).next().is_none());
+        }
+        assert!(db.get(LAST_HASH_KEY).unwrap().is_none());
+        assert!(db.get(ORPHAN_KEY).unwrap().is_none());
+        assert_eq!(db.get(PEERS_KEY).unwrap().unwrap(), b"peers");
+
+        drop(db);
+        let _ = DB::destroy(&Options::default(), &path);
+    }
+
+    #[test]
+    fn migrated_baseline_db_passes_chain_validation() {
+        let _guard = setup();
+        let wallet = Wallet::new();
+        let genesis = legacy_block(&wallet, [0u8; 32], 0);
+        let tip = legacy_block(&wallet, genesis.hash, 1);
+        let tip_tx = tip.txs[0].clone();
+        for block in [&genesis, &tip] {
+            ROCKS_DB
+                .put_cf(block_cf(), block.hash, bincode::serialize(block).unwrap())
+                .unwrap();
+        }
+        ROCKS_DB.put(LAST_HASH_KEY, tip.hash).unwrap();
+        let txo_map: TxOutMap = HashMap::from([(0, tip_tx.outputs[0])]);
+        ROCKS_DB
+            .put_cf(utxo_cf(), tip_tx.id, bincode::serialize(&txo_map).unwrap())
+            .unwrap();
+        ROCKS_DB.delete(SCHEMA_VERSION_KEY).unwrap();
+
+        migrate_schema(&ROCKS_DB, "test").unwrap();
+        assert!(
+            validate_chain().is_err(),
+            "the legacy chain should be dropped"
+        );
+
+        // Nothing of the legacy chain is left to conflict with the resynced one
+        new_chain();
+        assert!(validate_chain().unwrap().is_none());
+    }
+
+    /// Returns an empty orphan block whose hash is derived from the given index
+    fn orphan(i: usize) -> Block {
+        let mut hash = [0u8; 32];
+        hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        Block {
+            txs: vec![],
+            prev_hash: [0xff; 32],
+            merkle_root: [0u8; 32],
+            hash,
+            nonce: 0,
+            extranonce: 0,
+            height: 5,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn orphan_pool_evicts_oldest_past_cap() {
+        let _guard = setup();
+        for i in 0..MAX_ORPHAN_BLOCKS {
+            put_orphan_block(&orphan(i));
+        }
+        // Re-inserting the oldest orphan makes it the most recently used
+        put_orphan_block(&orphan(0));
+        for i in MAX_ORPHAN_BLOCKS..MAX_ORPHAN_BLOCKS + 3 {
+            put_orphan_block(&orphan(i));
+        }
+
+        let orphans = get_orphaned_blocks();
+        assert_eq!(orphans.len(), MAX_ORPHAN_BLOCKS);
+        assert!(orphans.contains_key(&orphan(0).hash));
+        for i in 1..=3 {
+            assert!(!orphans.contains_key(&orphan(i).hash));
+        }
+        for i in 4..MAX_ORPHAN_BLOCKS + 3 {
+            assert!(orphans.contains_key(&orphan(i).hash));
+        }
+    }
+
+    #[test]
+    fn open_db_rejects_corrupted_path() {
+        let path = std::env::temp_dir().join(format!("dcoin_corrupt_{}", std::process::id()));
+        // A file of garbage where the db directory should be
+        std::fs::write(&path, b"not a database").unwrap();
+
+        let res = open_db_at(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert!(matches!(
+            res,
+            Err(DbError::Open { .. } | DbError::Corrupted { .. })
+        ));
+    }
+
+    #[test]
+    fn open_db_reports_db_locked_by_another_instance() {
+        let path = std::env::temp_dir().join(format!("dcoin_locked_{}", std::process::id()));
+        let db = open_db_at(&path).unwrap();
+
+        match open_db_at(&path) {
+            Err(e @ DbError::Locked { .. }) => {
+                assert!(e.to_string().contains("Stop any other node"))
+            }
+            _ => panic!("expected the db to be reported as locked"),
+        }
+
+        drop(db);
+        let _ = DB::destroy(&Options::default(), &path);
+    }
+
+    #[test]
+    fn peers_round_trip_and_drop_stale_entries() {
+        let _guard = setup();
+        ROCKS_DB.delete(PEERS_KEY).unwrap();
+        let peer = PeerId::random();
+        let addr: Multiaddr = "/ip4/10.0.0.2/tcp/4000".parse().unwrap();
+        let new_addr: Multiaddr = "/ip4/10.0.0.3/tcp/4001".parse().unwrap();
+
+        // A peer last seen before the ttl is kept out of the results, and dropped on the next put
+        let stale = StoredPeer {
+            peer_id: PeerId::random().to_bytes(),
+            addr: addr.to_vec(),
+            last_seen: now_secs() - PEER_TTL_SECS - 1,
+        };
+        ROCKS_DB
+            .put(PEERS_KEY, bincode::serialize(&vec![stale]).unwrap())
+            .unwrap();
+        assert!(get_peers().is_empty());
+
+        put_peer(&peer, &addr);
+        assert_eq!(get_peers(), vec![(peer, addr)]);
+        assert_eq!(get_stored_peers().len(), 1);
+
+        // Storing a known peer again replaces its address
+        put_peer(&peer, &new_addr);
+        assert_eq!(get_peers(), vec![(peer, new_addr)]);
+
+        for _ in 0..MAX_STORED_PEERS {
+            put_peer(
+                &PeerId::random(),
+                &"/ip4/10.0.0.4/tcp/4000".parse().unwrap(),
+            );
+        }
+        assert_eq!(get_peers().len(), MAX_STORED_PEERS);
+        ROCKS_DB.delete(PEERS_KEY).unwrap();
+    }
+
+    #[test]
+    fn dbs_in_different_dirs_do_not_collide() {
+        let base = std::env::temp_dir().join(format!("dcoin_dirs_{}", std::process::id()));
+        let (path_a, path_b) = (base.join("a").join(DB_DIR), base.join("b").join(DB_DIR));
+        std::fs::create_dir_all(base.join("a")).unwrap();
+        std::fs::create_dir_all(base.join("b")).unwrap();
+        let db_a = open_db_at(&path_a).unwrap();
+        let db_b = open_db_at(&path_b).unwrap();
+
+        db_a.put(LAST_HASH_KEY, [1u8; 32]).unwrap();
+        db_b.put(LAST_HASH_KEY, [2u8; 32]).unwrap();
+
+        assert_eq!(db_a.get(LAST_HASH_KEY).unwrap().unwrap(), [1u8; 32]);
+        assert_eq!(db_b.get(LAST_HASH_KEY).unwrap().unwrap(), [2u8; 32]);
+
+        drop((db_a, db_b));
+        for path in [path_a, path_b] {
+            let _ = DB::destroy(&Options::default(), &path);
+        }
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn utxos_are_stored_per_output() {
+        let _guard = setup();
+        let tx_out = |value| TxOutput {
+            value,
+            pub_key_hash: [1u8; 20],
+        };
+        let (first, second) = ([1u8; 32], [2u8; 32]);
+        for (tx_id, out_idx, value) in [(first, 0, 10), (first, 1, 11), (second, 3, 12)] {
+            put_utxo(&tx_id, out_idx, &tx_out(value)).unwrap();
+        }
+
+        assert_eq!(get_utxo(&first, 1).unwrap().unwrap().value, 11);
+        assert_eq!(get_utxo(&second, 3).unwrap().unwrap().value, 12);
+        assert!(get_utxo(&second, 0).unwrap().is_none());
+        assert_eq!(
+            from_utxo_db_key(&to_utxo_db_key(&second, 3)).unwrap(),
+            (second, 3)
+        );
+
+        // Deleting one output leaves the other outputs of its tx in place
+        let mut batch = ChainBatch::new();
+        batch.delete_utxo(&first, 0).unwrap();
+        batch.commit().unwrap();
+        assert!(get_utxo(&first, 0).unwrap().is_none());
+        assert_eq!(get_utxo(&first, 1).unwrap().unwrap().value, 11);
+        assert!(utxo_set_contains_tx(first).unwrap());
+
+        let stored: Vec<_> = iter_utxos()
+            .map(|res| {
+                let (outpoint, tx_out) = res.unwrap().unwrap();
+                (outpoint, tx_out.value)
+            })
+            .collect();
+        assert_eq!(stored, vec![((first, 1), 11), ((second, 3), 12)]);
+        assert_eq!(find_utxos_for_addr(&[1u8; 20]).unwrap().len(), 2);
+    }
 }
@@ -1,19 +1,32 @@
 use core_lib::{
     address::Address,
-    constants::SEED_API_NODE,
     req_types::{convert_json_to_utxoset, GetUTXORes, TxJson},
     tx::Tx,
     wallet::Wallet,
 };
+use libp2p::Multiaddr;
 use reqwest::Client;
+use serde_json::json;
+use std::time::Duration;
 use tokio::sync::mpsc;
 
 use crate::{
     blockchain::{
-        chain::{clear_blockchain, create_blockchain, get_blockchain_json},
-        transaction::utxo::{find_utxos_for_addr, reindex_utxos, UTXOSet},
+        blocks::block::select_next_block_txs,
+        chain::{
+            clear_blockchain, create_blockchain, get_blockchain_json, get_chain_height,
+            set_prune_depth, validate_chain,
+        },
+        transaction::{
+            mempool::start_mempool_expiry,
+            tx::{block_reward, get_tx_fee},
+            utxo::{find_utxos_for_addr, find_utxos_for_outpoints, reindex_utxos, UTXOSet},
+        },
+    },
+    cli::{
+        cli::CliUI,
+        db::{get_last_hash, get_mempool},
     },
-    cli::cli::CliUI,
     mining::miner::start_miner,
     networking::{node::Node, p2p::network::start_p2p_network, server::rest_api::start_rest_api},
     wallets::wallet::WalletStore,
@@ -23,42 +36,157 @@ pub fn handle_get_node_id() {
     CliUI::print_header("Get Node ID");
     let node = Node::get_or_create_keys();
     CliUI::print_kv("Node ID", &node.get_peer_id().to_string());
+    CliUI::print_json(&json!({ "node_id": node.get_peer_id().to_string() }));
 }
 
-pub async fn handle_start_node(
-    rest_api_port: &Option<u16>,
-    p2p_port: &Option<u16>,
-    reward_address: &Option<String>,
-    mine: bool,
-) {
+/// NodeOptions are the settings a node is started with, resolved from CLI flags and the config file
+pub struct NodeOptions {
+    pub rest_api_port: Option<u16>,
+    pub p2p_port: Option<u16>,
+    pub reward_address: Option<String>,
+    pub mine: bool,
+    pub mdns: bool,
+    pub seeds: Vec<Multiaddr>,
+    pub mempool_ttl: Duration,
+    pub prune: Option<u32>,
+    pub msg_rate: u32,
+}
+
+pub async fn handle_start_node(opts: NodeOptions) {
+    // Pruning must be enabled before any blocks are committed
+    if let Some(depth) = opts.prune {
+        if set_prune_depth(depth).is_err() {
+            CliUI::print_error("prune depth was already initialized");
+            std::process::exit(1);
+        }
+    }
+
+    // Rebuild the utxo set once on startup. From here on it is kept up to date
+    // incrementally as blocks are committed.
+    if get_last_hash().is_ok() {
+        unwrap_or_exit(reindex_utxos(), "failed to reindex utxos");
+    }
+
     // Create a channel to pass messages from the server to the p2p network
     let (tx, rx) = mpsc::channel(32);
 
     // Spawn the P2P network task
-    let p2p_port = p2p_port.unwrap_or(4001);
-    tokio::spawn(start_p2p_network(rx, p2p_port));
+    let p2p_port = opts.p2p_port.unwrap_or(4001);
+    tokio::spawn(start_p2p_network(
+        rx,
+        p2p_port,
+        opts.mdns,
+        opts.seeds,
+        opts.msg_rate,
+    ));
+
+    tokio::spawn(start_mempool_expiry(opts.mempool_ttl));
 
     // Start the miner if requested on startup
-    if mine {
-        tokio::spawn(start_miner(tx.clone(), reward_address.clone()));
+    if opts.mine {
+        tokio::spawn(start_miner(tx.clone(), opts.reward_address));
     }
 
     // Start the HTTP server
-    start_rest_api(tx, *rest_api_port).await;
+    start_rest_api(tx, opts.rest_api_port).await;
 }
 
-pub fn handle_create_wallet() {
+pub fn handle_create_wallet(label: &Option<String>) {
     CliUI::print_header("Create Wallet");
 
     let mut wallet_store = unwrap_or_exit(
         WalletStore::init_wallet_store(),
         "failed to initialize wallet store",
     );
-    let addr = unwrap_or_exit(
-        wallet_store.add_wallet(),
+    let (addr, phrase) = unwrap_or_exit(
+        wallet_store.add_mnemonic_wallet(label.clone()),
         "failed to add wallet to wallet store",
     );
     CliUI::print_kv("New wallet address", addr.get_full_address().as_str());
+    if let Some(label) = label {
+        CliUI::print_kv("Label", label);
+    }
+    CliUI::print_kv("Mnemonic", &phrase);
+    CliUI::print_text("Store the mnemonic somewhere safe - it can be used to recover this wallet");
+    CliUI::print_json(&json!({
+        "address": addr.get_full_address(),
+        "label": label,
+        "mnemonic": phrase,
+    }));
+}
+
+pub fn handle_recover_wallet(mnemonic: &str) {
+    CliUI::print_header("Recover Wallet");
+
+    let mut wallet_store = unwrap_or_exit(
+        WalletStore::init_wallet_store(),
+        "failed to initialize wallet store",
+    );
+    let addr = unwrap_or_exit(
+        wallet_store.recover_wallet(mnemonic),
+        "failed to recover wallet",
+    );
+    CliUI::print_kv("Recovered wallet address", addr.get_full_address().as_str());
+    CliUI::print_json(&json!({ "address": addr.get_full_address() }));
+}
+
+pub fn handle_import_wallet(pub_key: &str, priv_key: &str) {
+    CliUI::print_header("Import Wallet");
+
+    let mut wallet_store = unwrap_or_exit(
+        WalletStore::init_wallet_store(),
+        "failed to initialize wallet store",
+    );
+    let addr = unwrap_or_exit(
+        wallet_store.import_wallet(pub_key, priv_key),
+        "failed to import wallet",
+    );
+    CliUI::print_kv("Imported wallet address", addr.get_full_address().as_str());
+    CliUI::print_json(&json!({ "address": addr.get_full_address() }));
+}
+
+pub fn handle_export_wallet(address: &str) {
+    CliUI::print_header("Export Wallet");
+
+    let wallet_store = unwrap_or_exit(
+        WalletStore::init_wallet_store(),
+        "failed to initialize wallet store",
+    );
+    let addr = unwrap_or_exit(Address::new_from_str(address), "invalid wallet address");
+    let wallet = unwrap_or_exit(
+        wallet_store.get_local_wallet(&addr),
+        "no local wallet found for address",
+    );
+    CliUI::print_kv("Public key", &wallet.pub_key().to_string());
+    CliUI::print_kv(
+        "Private key",
+        &wallet.private_key().display_secret().to_string(),
+    );
+    CliUI::print_text("Never share the private key - anyone with it can spend this wallet's funds");
+    CliUI::print_json(&json!({
+        "pub_key": wallet.pub_key().to_string(),
+        "priv_key": wallet.private_key().display_secret().to_string(),
+    }));
+}
+
+pub fn handle_new_address(from: &Option<String>) {
+    CliUI::print_header("New Address");
+
+    let mut wallet_store = unwrap_or_exit(
+        WalletStore::init_wallet_store(),
+        "failed to initialize wallet store",
+    );
+    let (addr, root, index) = unwrap_or_exit(
+        wallet_store.derive_next_address(from.as_deref()),
+        "failed to derive new address",
+    );
+    CliUI::print_kv("New address", addr.get_full_address().as_str());
+    CliUI::print_kv("Derived from", &format!("{} (index {})", root, index));
+    CliUI::print_json(&json!({
+        "address": addr.get_full_address(),
+        "derived_from": root,
+        "index": index,
+    }));
 }
 
 pub fn handle_get_wallets() {
@@ -71,9 +199,29 @@ pub fn handle_get_wallets() {
     if wallet_store.wallets.is_empty() {
         CliUI::print_text("No wallets found! Try creating a new wallet");
     }
-    for (addr, _) in wallet_store.wallets {
-        CliUI::print_kv("Wallet address", addr.as_str());
+    for (addr, record) in &wallet_store.wallets {
+        match &record.label {
+            Some(label) => CliUI::print_kv("Wallet address", &format!("{} ({})", addr, label)),
+            None => CliUI::print_kv("Wallet address", addr),
+        }
     }
+    CliUI::print_json(&wallets_json(&wallet_store));
+}
+
+/// Returns the JSON output of GetWallets - an entry per stored wallet
+fn wallets_json(wallet_store: &WalletStore) -> Vec<serde_json::Value> {
+    wallet_store
+        .wallets
+        .iter()
+        .map(|(addr, record)| {
+            json!({
+                "address": addr,
+                "label": record.label,
+                "derived_from": record.derived_from.as_ref().map(|(root, _)| root),
+                "index": record.derived_from.as_ref().map(|(_, index)| index),
+            })
+        })
+        .collect()
 }
 
 pub fn handle_create_blockchain(req_addr: &Option<String>) {
@@ -107,27 +255,190 @@ pub fn handle_create_blockchain(req_addr: &Option<String>) {
         "Mining rewards sent to",
         address.get_full_address().as_str(),
     );
+    CliUI::print_json(&json!({ "reward_address": address.get_full_address() }));
 }
 
 pub fn handle_clear_blockchain() {
     CliUI::print_header("Clear Blockchain");
     clear_blockchain();
     CliUI::print_text("Blockchain data removed successfully");
+    CliUI::print_json(&json!({ "cleared": true }));
 }
 
-pub fn handle_print_blockchain(show_txs: bool) {
+pub fn handle_print_blockchain(show_txs: bool, resolve_inputs: bool) {
     CliUI::print_header("Print Blockchain");
-    let printable_chain = unwrap_or_exit(get_blockchain_json(show_txs), "failed to get blockchain");
-    CliUI::print_text(&format!(
-        "{}",
-        unwrap_or_exit(
-            serde_json::to_string_pretty(&printable_chain),
-            "failed to print blockchain"
-        )
+    let printable_chain = unwrap_or_exit(
+        get_blockchain_json(show_txs, resolve_inputs),
+        "failed to get blockchain",
+    );
+    CliUI::print_text(&unwrap_or_exit(
+        serde_json::to_string_pretty(&printable_chain),
+        "failed to print blockchain",
     ));
+    CliUI::print_json(&printable_chain);
+}
+
+pub fn handle_show_mempool() {
+    CliUI::print_header("Show Mempool");
+    let mut mempool: Vec<Tx> = get_mempool().into_values().map(|entry| entry.tx).collect();
+    mempool.sort_by_key(|tx| tx.id);
+
+    let mut txs = Vec::with_capacity(mempool.len());
+    let mut total_fees: u64 = 0;
+    for tx in &mempool {
+        // Fees of txs spending the outputs of other pending txs can't be resolved yet
+        let fee = get_tx_fee(tx).ok();
+        total_fees += u64::from(fee.unwrap_or(0));
+        CliUI::print_kv(
+            &hex::encode(tx.id),
+            &format!(
+                "fee {}, {} bytes",
+                fee.map_or("unknown".to_string(), |f| f.to_string()),
+                tx.size()
+            ),
+        );
+        txs.push(json!({ "id": hex::encode(tx.id), "fee": fee, "size": tx.size() }));
+    }
+
+    if mempool.is_empty() {
+        CliUI::print_text("Mempool is empty");
+    }
+    CliUI::print_kv("Txs", &mempool.len().to_string());
+    CliUI::print_kv("Total fees", &total_fees.to_string());
+    CliUI::print_json(&json!({ "txs": txs, "total_fees": total_fees }));
+}
+
+pub fn handle_next_block_preview() {
+    CliUI::print_header("Next Block Preview");
+    let height = unwrap_or_exit(get_chain_height(), "failed to get chain height") + 1;
+
+    // The coinbase size doesn't depend on the reward address, so any address reserves the
+    // same space as the miner's
+    let reward_addr = Wallet::new().get_wallet_address();
+    let (txs, fees) = unwrap_or_exit(
+        select_next_block_txs(&reward_addr, height),
+        "failed to select block txs",
+    );
+    let size: usize = txs.iter().map(Tx::size).sum();
+    let pending = get_mempool().len();
+    let reward = u64::from(block_reward(height)) + u64::from(fees);
+
+    for tx in &txs {
+        CliUI::print_kv(
+            &hex::encode(tx.id),
+            &format!("fee {}, {} bytes", get_tx_fee(tx).unwrap_or(0), tx.size()),
+        );
+    }
+    CliUI::print_kv("Height", &height.to_string());
+    CliUI::print_kv("Included txs", &format!("{} of {}", txs.len(), pending));
+    CliUI::print_kv("Tx bytes", &size.to_string());
+    CliUI::print_kv("Fees", &fees.to_string());
+    CliUI::print_kv("Coinbase reward", &reward.to_string());
+    CliUI::print_json(&json!({
+        "height": height,
+        "txs": txs.iter().map(|tx| hex::encode(tx.id)).collect::<Vec<_>>(),
+        "pending_txs": pending,
+        "size": size,
+        "fees": fees,
+        "reward": reward,
+    }));
 }
 
-pub fn handle_get_balance(req_addr: &String) {
+pub fn handle_decode_tx(raw: &Option<String>, tx_json: &Option<String>) {
+    CliUI::print_header("Decode Tx");
+    let tx = unwrap_or_exit(decode_tx(raw, tx_json), "failed to decode tx");
+
+    CliUI::print_kv("Id", &hex::encode(tx.id));
+    CliUI::print_kv("Coinbase", &tx.is_coinbase().to_string());
+    CliUI::print_kv("Size", &format!("{} bytes", tx.size()));
+    for (i, input) in tx.inputs.iter().enumerate() {
+        let outpoint = format!("{}:{}", hex::encode(input.prev_tx_id), input.out);
+        CliUI::print_kv(&format!("Input {}", i), &outpoint);
+        CliUI::print_kv("  Pub key", &input.pub_key.to_string());
+    }
+    for (i, output) in tx.outputs.iter().enumerate() {
+        let address = Address::new_from_pub_key_hash(output.pub_key_hash).get_full_address();
+        CliUI::print_kv(
+            &format!("Output {}", i),
+            &format!("{} to {}", output.value, address),
+        );
+    }
+    CliUI::print_json(&decoded_tx_json(&tx));
+}
+
+/// Decodes a tx given as hex-encoded bincode or, if no hex is given, as tx JSON
+fn decode_tx(raw: &Option<String>, tx_json: &Option<String>) -> Result<Tx, String> {
+    match (raw, tx_json) {
+        (Some(raw), _) => {
+            let bytes = hex::decode(raw).map_err(|e| format!("tx is not valid hex: {}", e))?;
+            bincode::deserialize(&bytes).map_err(|e| format!("invalid raw tx: {}", e))
+        }
+        (None, Some(tx_json)) => {
+            let tx_json: TxJson = serde_json::from_str(tx_json)
+                .map_err(|e| format!("tx is not valid tx JSON: {}", e))?;
+            tx_json.to_tx().map_err(|e| e.to_string())
+        }
+        (None, None) => Err("either a hex or JSON tx is required".to_string()),
+    }
+}
+
+/// Returns the JSON output of DecodeTx
+fn decoded_tx_json(tx: &Tx) -> serde_json::Value {
+    let inputs: Vec<serde_json::Value> = tx
+        .inputs
+        .iter()
+        .map(|input| {
+            json!({
+                "prev_tx_id": hex::encode(input.prev_tx_id),
+                "out": input.out,
+                "pub_key": input.pub_key.to_string(),
+            })
+        })
+        .collect();
+    // Outputs only hold the pub key hash, which is enough to rebuild the address
+    let outputs: Vec<serde_json::Value> = tx
+        .outputs
+        .iter()
+        .map(|output| {
+            json!({
+                "value": output.value,
+                "pub_key_hash": hex::encode(output.pub_key_hash),
+                "address": Address::new_from_pub_key_hash(output.pub_key_hash).get_full_address(),
+            })
+        })
+        .collect();
+
+    json!({
+        "id": hex::encode(tx.id),
+        "is_coinbase": tx.is_coinbase(),
+        "size": tx.size(),
+        "inputs": inputs,
+        "outputs": outputs,
+    })
+}
+
+pub fn handle_validate_chain() {
+    CliUI::print_header("Validate Chain");
+    match unwrap_or_exit(validate_chain(), "failed to validate chain") {
+        None => {
+            CliUI::print_text("Chain is valid");
+            CliUI::print_json(&json!({ "valid": true }));
+        }
+        Some(fault) => {
+            CliUI::print_kv("Height", &fault.height.to_string());
+            CliUI::print_kv("Block hash", &hex::encode(fault.hash));
+            CliUI::print_json(&json!({
+                "valid": false,
+                "height": fault.height,
+                "hash": hex::encode(fault.hash),
+                "reason": fault.reason,
+            }));
+            exit_with_error(&format!("chain is invalid: {}", fault.reason), None);
+        }
+    }
+}
+
+pub fn handle_get_balance(req_addr: &str) {
     CliUI::print_header("Get Balance");
     // TODO: Refactor to be an API call
     let address = unwrap_or_exit(
@@ -136,7 +447,10 @@ pub fn handle_get_balance(req_addr: &String) {
     );
     unwrap_or_exit(reindex_utxos(), "failed to reindex utxos");
 
-    let utxos = find_utxos_for_addr(address.pub_key_hash());
+    let utxos = unwrap_or_exit(
+        find_utxos_for_addr(address.pub_key_hash()),
+        "failed to find utxos",
+    );
 
     let mut balance = 0;
 
@@ -146,10 +460,62 @@ pub fn handle_get_balance(req_addr: &String) {
 
     CliUI::print_kv("Address", req_addr);
     CliUI::print_kv("Balance", &format!("{}", balance));
+    CliUI::print_json(&balance_json(req_addr, balance));
+}
+
+/// Returns the JSON output of GetBalance
+fn balance_json(address: &str, balance: u32) -> serde_json::Value {
+    json!({ "address": address, "balance": balance })
 }
 
-pub async fn handle_send_tx(to: &String, value: u32, from: &Option<String>) {
+pub async fn handle_send_tx(
+    to: &str,
+    value: u32,
+    from: &Option<String>,
+    from_label: &Option<String>,
+    fee: u32,
+    api_seed: &str,
+    inputs: &[([u8; 32], u32)],
+) {
     CliUI::print_header("Send Transaction");
+
+    // A label is resolved to the address of the local wallet it belongs to
+    let from = match from_label {
+        Some(label) => {
+            let wallet_store = unwrap_or_exit(
+                WalletStore::init_wallet_store(),
+                "failed to initialize wallet store",
+            );
+            Some(unwrap_or_exit(
+                wallet_store.find_by_label(label),
+                "failed to resolve from label",
+            ))
+        }
+        None => from.clone(),
+    };
+    send_to_recipients(&[(to.to_string(), value)], &from, fee, api_seed, inputs).await;
+}
+
+pub async fn handle_send_many(
+    recipients: &[(String, u32)],
+    from: &Option<String>,
+    fee: u32,
+    api_seed: &str,
+) {
+    CliUI::print_header("Send Many");
+    send_to_recipients(recipients, from, fee, api_seed, &[]).await;
+}
+
+/// Builds a single tx paying all of the given recipients from a local wallet, and sends it to
+/// the given REST API node. The tx spends the given outpoints if any, otherwise its inputs are
+/// selected by the node
+async fn send_to_recipients(
+    recipients: &[(String, u32)],
+    from: &Option<String>,
+    fee: u32,
+    api_seed: &str,
+    inputs: &[([u8; 32], u32)],
+) {
     let client = Client::new();
 
     let wallet_store = WalletStore::init_wallet_store()
@@ -157,19 +523,19 @@ pub async fn handle_send_tx(to: &String, value: u32, from: &Option<String>) {
     let from_wallet: &Wallet;
     match from {
         Some(addr) => {
-            from_wallet = wallet_store.wallets.get(addr).expect(
+            from_wallet = &wallet_store.wallets.get(addr).expect(
                 "[handlers::handle_send_tx] ERROR: No local wallet found for given from address",
-            );
+            ).wallet;
         }
         None => {
             let first_wallet = wallet_store.wallets.iter().next();
             CliUI::print_text("From wallet not provided, using first local wallet");
             match first_wallet {
-                Some((_, wallet)) => {
-                    from_wallet = wallet;
+                Some((_, record)) => {
+                    from_wallet = &record.wallet;
                     CliUI::print_kv(
                         "First local wallet",
-                        &format!("{}", from_wallet.get_wallet_address().get_full_address()),
+                        &from_wallet.get_wallet_address().get_full_address(),
                     );
                 }
                 None => exit_with_error("No local wallets found", None),
@@ -179,58 +545,78 @@ pub async fn handle_send_tx(to: &String, value: u32, from: &Option<String>) {
 
     let from_address = from_wallet.get_wallet_address();
 
+    // Inputs must cover both the values sent and the fee paid to the miner
+    let amount = recipients
+        .iter()
+        .try_fold(fee, |total, (_, value)| total.checked_add(*value));
+    let amount = match amount {
+        Some(a) => a,
+        None => exit_with_error("values and fee are too large", None),
+    };
+
     let url = format!(
         "{}/utxo?address={}&amount={}",
-        SEED_API_NODE,
+        api_seed,
         from_address.get_full_address(),
-        value
+        amount
     );
 
     let utxos: UTXOSet;
 
-    match client.get(url).send().await {
-        Ok(response) => {
-            if response.status().is_success() {
-                match response.json::<GetUTXORes>().await {
-                    Ok(data) => match convert_json_to_utxoset(&data.utxos) {
-                        Ok(set) => {
-                            utxos = set;
-                        }
+    if !inputs.is_empty() {
+        // Explicitly chosen inputs are resolved from the local utxo set
+        utxos = unwrap_or_exit(
+            find_utxos_for_outpoints(from_address.pub_key_hash(), inputs, amount),
+            "invalid inputs",
+        );
+    } else {
+        match client.get(url).send().await {
+            Ok(response) => {
+                if response.status().is_success() {
+                    match response.json::<GetUTXORes>().await {
+                        Ok(data) => match convert_json_to_utxoset(&data.utxos) {
+                            Ok(set) => {
+                                utxos = set;
+                            }
+                            Err(e) => {
+                                exit_with_error("failed to convert UTXO JSON to UTXOSet", Some(&e));
+                            }
+                        },
                         Err(e) => {
-                            exit_with_error("failed to convert UTXO JSON to UTXOSet", Some(&e));
+                            exit_with_error("failed to parse UTXO response", Some(&e));
                         }
-                    },
-                    Err(e) => {
-                        exit_with_error("failed to parse UTXO response", Some(&e));
                     }
+                } else {
+                    let status = response.status();
+                    let error_text = response.text().await.unwrap_or_default();
+                    let err = format!("status code: {}, response body: {}", status, error_text);
+                    exit_with_error("failed to fetch UTXOs from node", Some(&err));
                 }
-            } else {
-                let status = response.status();
-                let error_text = response.text().await.unwrap_or_default();
-                let err = format!("status code: {}, response body: {}", status, error_text);
-                exit_with_error("failed to fetch UTXOs from node", Some(&err));
             }
-        }
-        Err(e) => {
-            exit_with_error("failed to connect to node", Some(&e));
+            Err(e) => {
+                exit_with_error("failed to connect to node", Some(&e));
+            }
         }
     }
 
-    let to_address = match Address::new_from_str(to.as_str()) {
-        Ok(a) => a,
-        Err(e) => {
-            exit_with_error("invalid destination address", Some(&e));
+    let mut to_addresses: Vec<(Address, u32)> = Vec::with_capacity(recipients.len());
+    for (to, value) in recipients {
+        match Address::new_from_str(to.as_str()) {
+            Ok(a) => to_addresses.push((a, *value)),
+            Err(e) => {
+                exit_with_error("invalid destination address", Some(&e));
+            }
         }
-    };
+    }
 
-    let tx = match Tx::new(from_wallet, &to_address, value, utxos) {
+    let tx = match Tx::new_multi(from_wallet, &to_addresses, fee, utxos) {
         Ok(tx) => tx,
         Err(e) => {
             exit_with_error("failed to create tx", Some(&e));
         }
     };
 
-    let url = format!("{}/tx/send", SEED_API_NODE);
+    let url = format!("{}/tx/send", api_seed);
 
     let tx_json = match TxJson::from_tx(&tx) {
         Ok(tx) => tx,
@@ -243,6 +629,7 @@ pub async fn handle_send_tx(to: &String, value: u32, from: &Option<String>) {
         Ok(resp) => {
             if resp.status().is_success() {
                 CliUI::print_text("Transaction successfully sent to node");
+                CliUI::print_json(&json!({ "tx_id": hex::encode(tx.id) }));
             } else {
                 let status = resp.status();
                 let error_text = resp.text().await.unwrap_or_default();
@@ -258,7 +645,7 @@ pub async fn handle_send_tx(to: &String, value: u32, from: &Option<String>) {
 
 fn unwrap_or_exit<T, E: std::fmt::Debug>(res: Result<T, E>, msg: &str) -> T {
     res.unwrap_or_else(|e| {
-        CliUI::print_error(&format!("{}: {:?}", msg, e).as_str());
+        CliUI::print_error(&format!("{}: {:?}", msg, e));
         std::process::exit(1);
     })
 }
@@ -271,3 +658,119 @@ fn exit_with_error(msg: &str, err: Option<&dyn std::fmt::Debug>) -> ! {
 
     std::process::exit(1);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use core_lib::tx::TxOutput;
+
+    use super::*;
+    use crate::wallets::wallet::WalletRecord;
+
+    #[test]
+    fn balance_json_has_address_and_balance() {
+        let address = Wallet::new().get_wallet_address().get_full_address();
+        assert_eq!(
+            balance_json(&address, 42),
+            json!({ "address": address, "balance": 42 })
+        );
+    }
+
+    #[test]
+    fn wallets_json_lists_each_wallet() {
+        let root = Wallet::new().get_wallet_address().get_full_address();
+        let child = Wallet::new().get_wallet_address().get_full_address();
+        let wallet_store = WalletStore {
+            wallets: HashMap::from([
+                (
+                    root.clone(),
+                    WalletRecord {
+                        wallet: Wallet::new(),
+                        label: Some("savings".to_string()),
+                        derived_from: None,
+                    },
+                ),
+                (
+                    child.clone(),
+                    WalletRecord {
+                        wallet: Wallet::new(),
+                        label: None,
+                        derived_from: Some((root.clone(), 3)),
+                    },
+                ),
+            ]),
+        };
+
+        let mut wallets = wallets_json(&wallet_store);
+        wallets.sort_by_key(|wallet| wallet["address"] != json!(root));
+        assert_eq!(
+            wallets,
+            vec![
+                json!({ "address": root, "label": "savings", "derived_from": null, "index": null }),
+                json!({ "address": child, "label": null, "derived_from": root, "index": 3 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_tx_reports_inputs_and_outputs() {
+        let from = Wallet::new();
+        let to = Wallet::new().get_wallet_address();
+        let tx_out = TxOutput {
+            value: 100,
+            pub_key_hash: *from.get_wallet_address().pub_key_hash(),
+        };
+        let utxos = HashMap::from([([1u8; 32], HashMap::from([(0, tx_out)]))]);
+        let tx = Tx::new(&from, &to, 60, 5, utxos).unwrap();
+
+        // Both encodings decode to the same tx
+        let raw = Some(hex::encode(bincode::serialize(&tx).unwrap()));
+        let tx_json = Some(serde_json::to_string(&TxJson::from_tx(&tx).unwrap()).unwrap());
+        let from_raw = decoded_tx_json(&decode_tx(&raw, &None).unwrap());
+        assert_eq!(
+            from_raw,
+            decoded_tx_json(&decode_tx(&None, &tx_json).unwrap())
+        );
+
+        assert_eq!(
+            from_raw,
+            json!({
+                "id": hex::encode(tx.id),
+                "is_coinbase": false,
+                "size": tx.size(),
+                "inputs": [{
+                    "prev_tx_id": hex::encode([1u8; 32]),
+                    "out": 0,
+                    "pub_key": from.pub_key().to_string(),
+                }],
+                "outputs": [
+                    {
+                        "value": 60,
+                        "pub_key_hash": hex::encode(to.pub_key_hash()),
+                        "address": to.get_full_address(),
+                    },
+                    {
+                        "value": 35,
+                        "pub_key_hash": hex::encode(from.get_wallet_address().pub_key_hash()),
+                        "address": from.get_wallet_address().get_full_address(),
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn decode_tx_rejects_malformed_input() {
+        assert!(decode_tx(&Some("zz".to_string()), &None)
+            .unwrap_err()
+            .contains("not valid hex"));
+        assert!(decode_tx(&Some("00".to_string()), &None)
+            .unwrap_err()
+            .contains("invalid raw tx"));
+        assert!(decode_tx(&None, &Some("{}".to_string()))
+            .unwrap_err()
+            .contains("not valid tx JSON"));
+        assert!(decode_tx(&None, &None).is_err());
+    }
+}
@@ -1,5 +1,4 @@
 use cli::cli::Cli;
-use env_logger::Env;
 
 mod blockchain {
     pub mod blocks {
@@ -8,11 +7,14 @@ mod blockchain {
     }
     pub mod merkle;
     pub mod transaction {
+        pub mod fee;
         pub mod mempool;
         pub mod tx;
         pub mod utxo;
     }
     pub mod chain;
+    pub mod error;
+    pub mod params;
 }
 mod wallets {
     pub mod wallet;
@@ -22,6 +24,8 @@ mod networking {
     pub mod p2p {
         pub mod handlers;
         pub mod network;
+        pub mod peers;
+        pub mod sync;
     }
     pub mod server {
         pub mod handlers;
@@ -29,16 +33,19 @@ mod networking {
     }
 }
 mod cli {
+    #[allow(clippy::module_inception)]
     pub mod cli;
+    pub mod config;
     pub mod db;
     pub mod handlers;
 }
 mod mining {
     pub mod miner;
 }
+#[cfg(test)]
+mod test_utils;
 
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     Cli::run().await;
 }
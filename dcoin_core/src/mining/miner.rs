@@ -4,43 +4,72 @@ use std::{
 };
 
 use crate::{
-    blockchain::{blocks::block::Block, transaction::utxo::update_utxos},
+    blockchain::{
+        blocks::block::{get_target_difficulty, Block},
+        chain::{commit_mined_block, subscribe_new_blocks, CommitStatus},
+    },
     cli::db,
     networking::p2p::network::{NewInventory, P2Prx},
     wallets::wallet::WalletStore,
 };
 use core_lib::wallet::Wallet;
-use tokio::{sync::mpsc::Sender, time};
+use log::{error, info};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc::Sender},
+    time,
+};
 
 static MINING_LOCK: AtomicBool = AtomicBool::new(false);
+/// Set to abort the block currently being mined
+pub(crate) static CANCEL_MINING: AtomicBool = AtomicBool::new(false);
+
+/// Signals the miner to abort the block in progress, if any. The miner then restarts
+/// with a fresh block built on the new chain tip.
+fn cancel_mining() {
+    if MINING_LOCK.load(Ordering::SeqCst) {
+        CANCEL_MINING.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Spawns a task abandoning the block being mined whenever a new block is committed, since the
+/// block then builds on a stale tip
+fn spawn_tip_listener() {
+    let mut new_blocks = subscribe_new_blocks();
+    tokio::spawn(async move {
+        // Lagging behind still means the tip changed, so only a closed channel stops the listener
+        while let Ok(_) | Err(RecvError::Lagged(_)) = new_blocks.recv().await {
+            cancel_mining();
+        }
+    });
+}
 
 pub async fn start_miner(p2p: Sender<P2Prx>, reward_address: Option<String>) {
     let wallet_store = if let Ok(w) = WalletStore::init_wallet_store() {
         w
     } else {
-        println!("[miner::handle_mine] ERROR: Failed to initialize wallet store");
+        error!("[miner::handle_mine] Failed to initialize wallet store");
         return;
     };
 
     let reward_wallet = match reward_address {
         Some(addr) => match wallet_store.wallets.get(&addr) {
-            Some(wallet) => wallet.clone(),
+            Some(record) => record.wallet,
             None => {
-                println!(
-                        "[miner::handle_mine] ERROR: Mining failed - no local wallet found for given from address"
+                error!(
+                        "[miner::handle_mine] Mining failed - no local wallet found for given from address"
                     );
                 return;
             }
         },
         None => {
-            println!("Wallet address not provided for mining, using first local wallet instead");
-            match wallet_store.wallets.values().next() {
+            info!("Wallet address not provided for mining, using first local wallet instead");
+            match wallet_store.wallets.values().next().map(|r| &r.wallet) {
                 Some(wallet) => {
-                    println!(
+                    info!(
                         "First local wallet: {}",
                         wallet.get_wallet_address().get_full_address()
                     );
-                    wallet.clone()
+                    *wallet
                 }
                 None => {
                     panic!("[miner::handle_mine] ERROR: No local wallets found");
@@ -49,6 +78,8 @@ pub async fn start_miner(p2p: Sender<P2Prx>, reward_address: Option<String>) {
         }
     };
 
+    spawn_tip_listener();
+
     // Trigger mining every 10 seconds for now
     // TODO: implement mining based on mempool size or time
     let mut interval = time::interval(Duration::from_secs(10));
@@ -60,7 +91,7 @@ pub async fn start_miner(p2p: Sender<P2Prx>, reward_address: Option<String>) {
             let mine_p2p = p2p.clone();
 
             tokio::spawn(async move {
-                handle_mine(mine_p2p, reward_wallet.clone()).await;
+                handle_mine(mine_p2p, reward_wallet).await;
                 // Release the lock when done
                 MINING_LOCK.store(false, Ordering::SeqCst);
             });
@@ -69,46 +100,181 @@ pub async fn start_miner(p2p: Sender<P2Prx>, reward_address: Option<String>) {
 }
 
 pub async fn handle_mine(p2p: Sender<P2Prx>, reward_wallet: Wallet) {
-    // Fail fast if there are no txs in the mempool
-    let mempool = db::get_mempool();
-    if mempool.len() == 0 {
-        return;
-    }
-
-    println!("Miner: Txs found in mempool. Starting mining routine...");
-    let mut new_block = match Block::new(&reward_wallet.get_wallet_address()) {
-        Ok(b) => b,
-        Err(e) => {
-            println!(
-                "[miner::handle_mine] ERROR: Failed to create block: {:?}",
-                e
-            );
+    let new_block = loop {
+        // Fail fast if there are no txs in the mempool
+        let mempool = db::get_mempool();
+        if mempool.is_empty() {
             return;
         }
-    };
 
-    if let Err(e) = new_block.mine() {
-        println!("[miner::handle_mine] ERROR: Failed to mine block: {:?}", e);
-        return;
-    }
+        // Reset before building the block so a cancel for an earlier block isn't carried over
+        CANCEL_MINING.store(false, Ordering::SeqCst);
 
-    if let Err(e) = update_utxos(&new_block) {
-        println!(
-            "[miner::handle_mine] ERROR: Failed to update utxos: {:?}",
-            e
-        );
-        return;
+        info!("Miner: Txs found in mempool. Starting mining routine...");
+        let new_block = match Block::new(&reward_wallet.get_wallet_address()) {
+            Ok(b) => b,
+            Err(e) => {
+                error!("[miner::handle_mine] Failed to create block: {:?}", e);
+                return;
+            }
+        };
+
+        match mine_block(new_block, get_target_difficulty()).await {
+            // The tip can change after the last cancellation check, so a mined block is only
+            // kept if it still extends the tip
+            Ok(mined_block) => match commit_mined_block(&mined_block) {
+                Ok(Some(CommitStatus::Committed)) => break mined_block,
+                Ok(Some(status)) => {
+                    error!(
+                        "[miner::handle_mine] Mined block was not committed: {:?}",
+                        status
+                    );
+                    return;
+                }
+                Ok(None) => {
+                    info!("Miner: Chain tip changed while mining, discarding stale block...");
+                }
+                Err(e) => {
+                    error!("[miner::handle_mine] Failed to store mined block: {:?}", e);
+                    return;
+                }
+            },
+            // A competing block was committed, so restart on the new tip with the updated mempool
+            Err(_) if CANCEL_MINING.load(Ordering::SeqCst) => {
+                info!("Miner: Competing block received, restarting mining routine...");
+            }
+            Err(e) => {
+                error!("[miner::handle_mine] Failed to mine block: {:?}", e);
+                return;
+            }
+        }
     };
-    db::delete_mempool();
 
     if let Err(e) = p2p
         .send(P2Prx::BroadcastNewInv(NewInventory::Block(new_block.hash)))
         .await
     {
-        println!(
-            "[miner::handle_mine] ERROR: Failed to send msg to p2p server: {:?}",
+        error!(
+            "[miner::handle_mine] Failed to send msg to p2p server: {:?}",
             e
         );
-        return;
+    }
+}
+
+/// Mines the given block to the target on the blocking thread pool. The PoW loop never yields,
+/// so running it on the async runtime would starve the REST API and p2p tasks sharing its
+/// worker threads.
+pub(crate) async fn mine_block(mut block: Block, target: [u8; 32]) -> Result<Block, String> {
+    tokio::task::spawn_blocking(move || {
+        block
+            .mine(&target, &CANCEL_MINING)
+            .map(|_| block)
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| format!("[miner::mine_block] ERROR: Mining task failed: {}", e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use core_lib::tx::Tx;
+    use std::collections::HashMap;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        blockchain::{
+            chain::{get_last_block, notify_new_block},
+            transaction::mempool::{add_orphan_tx, add_tx_to_mempool},
+        },
+        test_utils::{add_block, new_chain, next_block, pay, setup},
     };
+
+    #[test]
+    fn stored_mined_block_records_chain_work() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let block = next_block(&miner, vec![]);
+
+        assert_eq!(
+            commit_mined_block(&block).unwrap(),
+            Some(CommitStatus::Committed)
+        );
+
+        assert_eq!(db::get_last_hash().unwrap(), block.hash);
+        let genesis_work = db::get_chain_work(&block.prev_hash).unwrap().unwrap();
+        assert_eq!(
+            db::get_chain_work(&block.hash).unwrap(),
+            Some(genesis_work + block.work())
+        );
+    }
+
+    #[test]
+    fn stale_block_is_discarded_when_tip_changes() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let stale = next_block(&miner, vec![]);
+
+        // A competing block arrives while the stale block is being mined
+        let competing = add_block(&Wallet::new().get_wallet_address(), vec![]);
+
+        assert_eq!(commit_mined_block(&stale).unwrap(), None);
+        assert_eq!(db::get_last_hash().unwrap(), competing.hash);
+        assert!(db::get_block(&stale.hash).unwrap().is_none());
+    }
+
+    #[test]
+    fn tip_change_interrupts_mining() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let mut block = next_block(&miner, vec![]);
+        let competing = next_block(&Wallet::new().get_wallet_address(), vec![]);
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            spawn_tip_listener();
+            MINING_LOCK.store(true, Ordering::SeqCst);
+            CANCEL_MINING.store(false, Ordering::SeqCst);
+
+            // Simulates a competing block being committed while the miner is busy
+            notify_new_block(&competing);
+            for _ in 0..100 {
+                if CANCEL_MINING.load(Ordering::SeqCst) {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+
+        let res = block.mine(&get_target_difficulty(), &CANCEL_MINING);
+        MINING_LOCK.store(false, Ordering::SeqCst);
+        CANCEL_MINING.store(false, Ordering::SeqCst);
+        assert!(res.unwrap_err().to_string().contains("Mining cancelled"));
+    }
+
+    #[test]
+    fn mined_block_promotes_orphan_txs_spending_it() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let to = Wallet::new();
+        let parent = pay(&wallet, &to.get_wallet_address(), 50, 0);
+        let parent_out = HashMap::from([(parent.id, HashMap::from([(0, parent.outputs[0])]))]);
+        let orphan = Tx::new(&to, &wallet.get_wallet_address(), 40, 0, parent_out).unwrap();
+        add_tx_to_mempool(&parent).unwrap();
+        add_orphan_tx(&orphan);
+
+        // The parent is mined by the local miner, rather than received from a peer
+        let (p2p_tx, mut p2p_rx) = mpsc::channel(8);
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            handle_mine(p2p_tx, wallet).await;
+            assert!(p2p_rx.recv().await.is_some());
+        });
+
+        let tip = get_last_block().unwrap();
+        assert_eq!(tip.height, 1);
+        assert!(tip.txs.iter().any(|tx| tx.id == parent.id));
+        let mempool = db::get_mempool();
+        assert!(!mempool.contains_key(&parent.id));
+        assert!(mempool.contains_key(&orphan.id));
+        assert!(db::get_orphan_txs().is_empty());
+    }
 }
@@ -3,27 +3,46 @@ use libp2p::{
     futures::StreamExt,
     gossipsub::{self, IdentTopic, Message},
     kad::{self, store::MemoryStore},
-    noise,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, Multiaddr, PeerId, SwarmBuilder,
+    mdns, noise,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, Swarm, SwarmBuilder,
 };
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::{error::Error, str::FromStr};
-use tokio::sync::mpsc;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot};
 
 use crate::{
     blockchain::{
-        blocks::block::{get_blocks_since_height, Block},
-        chain::{clear_blockchain, commit_block, get_last_block},
+        blocks::block::{
+            get_block_hashes_from_height, get_blocks_in_range, get_headers_in_range, Block,
+            BlockHeader, MAX_BLOCK_BYTES, MAX_BLOCK_TX_BYTES,
+        },
+        chain::{clear_blockchain, commit_block, get_chain_height, get_last_block, CommitStatus},
+        params::network_params,
         transaction::{
             mempool::{
-                add_tx_to_mempool, get_tx_from_mempool, mempool_contains_tx, mempool_contains_txo,
+                add_orphan_tx, add_tx_to_mempool, get_tx_from_mempool, has_missing_inputs,
+                mempool_contains_tx, mempool_contains_txo,
             },
             tx::TxVerify,
         },
     },
-    cli::db::{get_block, utxo_set_contains_tx},
-    networking::node::Node,
+    cli::db::{
+        get_block, get_orphan_txs, get_orphaned_blocks, get_peers, put_peer, utxo_set_contains_tx,
+    },
+    networking::{
+        node::Node,
+        p2p::{
+            peers::{Misbehaviour, PeerScores, RateLimiter, ReconnectBackoff},
+            sync::{has_more_work, HeaderStatus, HeaderSync, InitialSync, SyncStep},
+        },
+    },
 };
 
 // Inventory enum matching your existing type
@@ -39,17 +58,126 @@ pub enum Inventory {
     Block(Block),
 }
 
+/// GetBlocksRange requests the main chain blocks between two heights, inclusive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetBlocksRange {
+    pub from_height: u32,
+    pub to_height: u32,
+}
+
+/// BlocksRange answers a GetBlocksRange with consecutive blocks, ordered from lowest to highest.
+/// Responses are size limited, so fewer blocks than requested may be sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlocksRange {
+    pub blocks: Vec<Block>,
+    /// The last height that was requested, so the requester knows if more blocks remain
+    pub to_height: u32,
+}
+
+/// GetHeaders requests the headers of the main chain blocks between two heights, inclusive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetHeaders {
+    pub from_height: u32,
+    pub to_height: u32,
+}
+
+/// Headers answers a GetHeaders with consecutive headers, ordered from lowest to highest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Headers {
+    pub headers: Vec<BlockHeader>,
+    /// The last height that will be sent, capped at the sender's chain tip, so the requester
+    /// knows if more headers remain
+    pub to_height: u32,
+}
+
+/// ChainSync answers a chainsync request with the hashes of the main chain blocks from
+/// `from_height` up to the sender's tip, ordered from highest to lowest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainSync {
+    pub block_hashes: Vec<[u8; 32]>,
+    /// The height of the lowest hash. The requester's tip may move before the response
+    /// arrives, so it can't be recomputed from the requester's chain
+    pub from_height: u32,
+}
+
+/// Max number of headers sent in a single Headers response
+const MAX_HEADERS_LEN: u32 = 2000;
+
+/// Max serialized size of a BlocksRange response. Kept below the max gossipsub message size
+const MAX_BLOCKS_RANGE_BYTES: usize = 8 * 1024 * 1024;
+/// Max number of blocks sent in a single BlocksRange response
+const MAX_BLOCKS_RANGE_LEN: u32 = 500;
+
+/// How long an inventory announcement is remembered before it can trigger another request
+const SEEN_INV_TTL: Duration = Duration::from_secs(60);
+/// Max number of remembered inventory announcements. The oldest are evicted first
+const MAX_SEEN_INV: usize = 10_000;
+
+/// Time-bounded LRU set of recently seen inventory announcements. Used to drop duplicate
+/// announcements from the mesh before they trigger another inventory request.
+struct SeenInventory {
+    seen: HashSet<NewInventory>,
+    order: VecDeque<(NewInventory, Instant)>,
+}
+
+impl SeenInventory {
+    fn new() -> Self {
+        Self {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records the inventory as seen. Returns false if it was already seen within the window
+    fn insert(&mut self, inv: &NewInventory) -> bool {
+        // Entries are kept in insertion order, so expired entries are always at the front
+        while let Some((oldest, seen_at)) = self.order.front() {
+            if seen_at.elapsed() < SEEN_INV_TTL && self.order.len() < MAX_SEEN_INV {
+                break;
+            }
+            self.seen.remove(oldest);
+            self.order.pop_front();
+        }
+
+        if !self.seen.insert(inv.clone()) {
+            return false;
+        }
+        self.order.push_back((inv.clone(), Instant::now()));
+        true
+    }
+}
+
 pub enum P2Prx {
     BroadcastNewInv(NewInventory),
-    HealthCheck(),
+    /// Requests the current state of the p2p network
+    HealthCheck(oneshot::Sender<P2pHealth>),
+    /// Requests the currently connected peers
+    GetPeers(oneshot::Sender<Vec<PeerInfo>>),
+}
+
+/// P2pHealth describes the live state of the swarm, for health checks
+#[derive(Debug, Clone, Serialize)]
+pub struct P2pHealth {
+    pub peer_count: usize,
+    pub listen_addrs: Vec<String>,
+}
+
+/// PeerInfo describes a connected peer along with the addresses it is connected on
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub addrs: Vec<String>,
 }
 
 pub async fn start_p2p_network(
     mut rx: mpsc::Receiver<P2Prx>,
     port: u16,
+    mdns: bool,
+    seeds: Vec<Multiaddr>,
+    msg_rate: u32,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let node = Node::get_or_create_keys();
-    println!("Local peer id: {}", node.get_peer_id());
+    info!("Local peer id: {}", node.get_peer_id());
 
     let p2p_addr: Multiaddr = format!("/ip4/0.0.0.0/tcp/{}", port).parse().unwrap();
 
@@ -62,23 +190,33 @@ pub async fn start_p2p_network(
             yamux::Config::default,
         )
         .unwrap()
-        .with_behaviour(|_| BlockchainBehaviour::create())
+        .with_behaviour(|_| BlockchainBehaviour::create(mdns))
         .unwrap()
         .build();
 
+    // Ignore any messages from peers banned in previous sessions
+    let mut peer_scores = PeerScores::load();
+    let mut seen_inventory = SeenInventory::new();
+    let mut rate_limiter = RateLimiter::new(msg_rate);
+    // Addresses of each open connection, grouped by peer
+    let mut connected_peers: HashMap<PeerId, HashSet<Multiaddr>> = HashMap::new();
+    for peer in peer_scores.banned_peers() {
+        swarm.behaviour_mut().gossipsub.blacklist_peer(peer);
+    }
+
     // Listen on a specific port
     swarm.listen_on(p2p_addr.clone()).unwrap();
 
-    // Get bootstrap nodes
-    let bootstrap_nodes = get_seed_nodes();
+    // Connect to peers known from previous sessions and the seeds. Dials are retried with
+    // backoff while the node has too few peers, so a node started before its seeds isn't isolated
+    let bootstrap_nodes = get_seed_nodes(seeds);
+    dial_bootstrap_peers(&mut swarm, &bootstrap_nodes, &peer_scores, &connected_peers);
+    let mut reconnect = ReconnectBackoff::new();
 
-    // Connect to each bootstrap node. Successful dial actions create a "connection established" event, at which point they're added to kademlia
-    for node_addr in bootstrap_nodes {
-        match swarm.dial(node_addr.clone()) {
-            Ok(_) => println!("Dialed bootstrap node: {}", node_addr),
-            Err(e) => println!("Failed to dial bootstrap node {}: {}", node_addr, e),
-        }
-    }
+    // Catch up with the network before relying on new inventory announcements
+    let local_height = || get_last_block().map(|b| b.height).unwrap_or(0);
+    let mut initial_sync = InitialSync::new(local_height());
+    let mut header_sync = HeaderSync::new();
 
     // Main event loop
     loop {
@@ -86,22 +224,24 @@ pub async fn start_p2p_network(
             // Handle network events
             event = swarm.select_next_some() => {
                 match event {
-                    SwarmEvent::Behaviour(BlockchainBehaviourEvent::Gossipsub(
-                        gossipsub::Event::Subscribed { peer_id: _, topic } ))=> {
-                        if topic.as_str() == CHAIN_SYNC_REQ_TOPIC {
-                            if let Err(e) = swarm.behaviour_mut().publish_chainsync_req() {
-                                println!("Failed to publish chain sync request: {}", e);
-                            }
-                        }
-                    }
                     // Handle gossipsub messages (original functionality)
                     SwarmEvent::Behaviour(BlockchainBehaviourEvent::Gossipsub(
                         gossipsub::Event::Message { message, .. }
                     )) => {
                         let topic_str = message.topic.to_string();
+                        let source = message.source;
 
+                        // Topics are namespaced by network, so anything outside ours is ignored
+                        let Some(topic_str) = strip_network_prefix(&topic_str) else {
+                            continue;
+                        };
+
+                        // Messages from peers over the rate limit are dropped before any work is done for them
+                        let res = if source.is_some_and(|peer| !rate_limiter.allow(peer)) {
+                            debug!("Dropping message from rate limited peer: {:?}", source);
+                            Err(Misbehaviour::RateLimited)
                         // --- HANDLERS FOR ALL DIRECT MSGS --- //
-                        if topic_str.starts_with("direct:") {
+                        } else if topic_str.starts_with("direct:") {
                             let parts: Vec<&str> = topic_str.split(':').collect();
 
                             if parts.len() < 3 {
@@ -110,7 +250,7 @@ pub async fn start_p2p_network(
                                 let target_peer_id = parts[1];
 
                                 // Check if this message is meant for us
-                                if PeerId::from_str(target_peer_id)? == node.get_peer_id().clone() {
+                                if PeerId::from_str(target_peer_id)? == *node.get_peer_id() {
                                     match parts[2] {
                                         INV_REQ_TOPIC => {
                                             swarm.behaviour_mut().handle_inventory_req(message)
@@ -119,69 +259,161 @@ pub async fn start_p2p_network(
                                             swarm.behaviour_mut().handle_inventory_res(message)
                                         }
                                         CHAIN_SYNC_RES_TOPIC => {
-                                            swarm.behaviour_mut().handle_chainsync_res(message)
+                                            swarm.behaviour_mut().handle_chainsync_res(message, &mut initial_sync)
+                                        }
+                                        GET_HEADERS_TOPIC => {
+                                            swarm.behaviour_mut().handle_get_headers(message)
+                                        }
+                                        HEADERS_TOPIC => {
+                                            swarm.behaviour_mut().handle_headers(message, &mut header_sync)
+                                        }
+                                        GET_BLOCKS_RANGE_TOPIC => {
+                                            swarm.behaviour_mut().handle_get_blocks_range(message)
+                                        }
+                                        BLOCKS_RANGE_TOPIC => {
+                                            swarm.behaviour_mut().handle_blocks_range(message)
                                         }
-                                        _ => {}
+                                        _ => Ok(())
                                     }
+                                } else {
+                                    Ok(())
                                 }
                         } else {
                             // ----- HANDLERS FOR GOSSIP MSGS ----- //
-                            match topic_str.as_str() {
+                            match topic_str {
                                 NEW_INV_TOPIC => {
-                                    swarm.behaviour_mut().handle_new_inventory(message);
+                                    swarm.behaviour_mut().handle_new_inventory(message, &mut seen_inventory)
                                 }
                                 CHAIN_SYNC_REQ_TOPIC => {
-                                    swarm.behaviour_mut().handle_chainsync_req(message);
+                                    swarm.behaviour_mut().handle_chainsync_req(message)
                                 }
-                                _ => {}
+                                _ => Ok(())
+                        }
+                    };
+
+                    // Penalize the sender of any invalid data, banning them once their score is too low
+                    if let (Err(misbehaviour), Some(peer)) = (res, source) {
+                        if peer_scores.penalize(peer, misbehaviour) {
+                            warn!("Banning misbehaving peer: {}", peer);
+                            swarm.behaviour_mut().gossipsub.blacklist_peer(&peer);
+                            let _ = swarm.disconnect_peer_id(peer);
                         }
                     }
                 }
 
                     // Handle Kademlia events
-                    SwarmEvent::Behaviour(BlockchainBehaviourEvent::Kademlia(event)) => {
+                    SwarmEvent::Behaviour(BlockchainBehaviourEvent::Kademlia(
+                        kad::Event::RoutingUpdated { peer, addresses, .. },
+                    )) => {
+                        debug!("Kademlia routing updated for peer: {}", peer);
+                        put_peer(&peer, addresses.first());
+                        // Bootstrap Kademlia on new connections
+                        match swarm.behaviour_mut().kademlia.bootstrap() {
+                            Ok(_) => {
+                                debug!("Bootstrapped Kademlia DHT");
+                            },
+                            Err(e) => warn!("Failed to bootstrap Kademlia DHT: {}", e),
+                        }
+                    }
+
+                    // Handle mDNS events - peers found on the local network are added to kademlia and gossipsub
+                    SwarmEvent::Behaviour(BlockchainBehaviourEvent::Mdns(event)) => {
                         match event {
-                            kad::Event::RoutingUpdated { peer, .. } => {
-                                println!("Kademlia routing updated for peer: {}", peer);
-                                // Bootstrap Kademlia on new connections
-                                match swarm.behaviour_mut().kademlia.bootstrap() {
-                                    Ok(_) => {
-                                        println!("Bootstrapped Kademlia DHT");
-                                    },
-                                    Err(e) => println!("Failed to bootstrap Kademlia DHT: {}", e),
+                            mdns::Event::Discovered(peers) => {
+                                swarm.behaviour_mut().add_discovered_peers(peers, &peer_scores);
+                            }
+                            mdns::Event::Expired(peers) => {
+                                for (peer_id, _) in peers {
+                                    swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
                                 }
                             }
-                            _ => {}
                         }
                     }
 
                     // Listen address events (original functionality)
                     SwarmEvent::NewListenAddr { address, .. } => {
-                        println!("Listening on {}", address);
+                        info!("Listening on {}", address);
                     }
 
                     // Connection established events - add peer to Kademlia
                     SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
-                        println!("Connected to peer: {}", peer_id);
+                        if peer_scores.is_banned(&peer_id) {
+                            warn!("Disconnecting banned peer: {}", peer_id);
+                            let _ = swarm.disconnect_peer_id(peer_id);
+                            continue;
+                        }
+                        info!("Connected to peer: {}", peer_id);
+                        connected_peers
+                            .entry(peer_id)
+                            .or_default()
+                            .insert(endpoint.get_remote_address().clone());
 
                         // Add connected peer to Kademlia routing table
                         swarm.behaviour_mut().kademlia.add_address(&peer_id, endpoint.get_remote_address().clone());
+
+                        // Only outbound addresses are known to be dialable, so only those are persisted
+                        if endpoint.is_dialer() {
+                            put_peer(&peer_id, endpoint.get_remote_address());
+                        }
+                    }
+
+                    SwarmEvent::ConnectionClosed { peer_id, endpoint, num_established, .. } => {
+                        if num_established == 0 {
+                            info!("Disconnected from peer: {}", peer_id);
+                            connected_peers.remove(&peer_id);
+                        } else if let Some(addrs) = connected_peers.get_mut(&peer_id) {
+                            addrs.remove(endpoint.get_remote_address());
+                        }
                     }
                     _ => {}
                 }
             }
 
+            // Re-dial the seeds and known peers while the node has too few peers
+            _ = tokio::time::sleep_until(reconnect.next_attempt().into()) => {
+                reconnect.attempt(connected_peers.len(), Instant::now(), || {
+                    debug!("Connected to {} peers, re-dialing bootstrap peers", connected_peers.len());
+                    dial_bootstrap_peers(&mut swarm, &bootstrap_nodes, &peer_scores, &connected_peers);
+                });
+            }
+
+            // Keep requesting chainsync until the chain stops growing
+            _ = tokio::time::sleep_until(initial_sync.next_check().into()), if !initial_sync.is_done() => {
+                let step = initial_sync.check(local_height(), connected_peers.len(), Instant::now());
+                if step == SyncStep::Request {
+                    if let Err(e) = swarm.behaviour_mut().publish_chainsync_req() {
+                        warn!("Failed to publish chain sync request: {}", e);
+                    }
+                }
+            }
+
             // ----- HANDLERS FOR LOCAL BROADCAST REQUESTS ----- //
             Some(message) = rx.recv() => {
                 match message {
                     P2Prx::BroadcastNewInv(inv) => {
                         // Publish inventory to gossipsub topic (original functionality)
                         if let Err(e) = swarm.behaviour_mut().publish_new_inventory(&inv) {
-                            println!("Failed to broadcast inventory: {}", e);
+                            warn!("Failed to broadcast inventory: {}", e);
                         }
                     }
-                    P2Prx::HealthCheck() => {
-                        println!("P2P Channel received health check")
+                    P2Prx::HealthCheck(reply) => {
+                        debug!("P2P Channel received health check");
+                        let health = P2pHealth {
+                            peer_count: connected_peers.len(),
+                            listen_addrs: swarm.listeners().map(|addr| addr.to_string()).collect(),
+                        };
+                        let _ = reply.send(health);
+                    }
+                    P2Prx::GetPeers(reply) => {
+                        let peers = connected_peers
+                            .iter()
+                            .map(|(peer_id, addrs)| PeerInfo {
+                                peer_id: peer_id.to_string(),
+                                addrs: addrs.iter().map(|addr| addr.to_string()).collect(),
+                            })
+                            .collect();
+                        // The requester may have gone away, in which case there's no one to reply to
+                        let _ = reply.send(peers);
                     }
                 }
             }
@@ -194,10 +426,11 @@ pub async fn start_p2p_network(
 struct BlockchainBehaviour {
     gossipsub: gossipsub::Behaviour,
     kademlia: kad::Behaviour<MemoryStore>,
+    mdns: Toggle<mdns::tokio::Behaviour>,
 }
 
 impl BlockchainBehaviour {
-    fn create() -> Self {
+    fn create(enable_mdns: bool) -> Self {
         let node = Node::get_or_create_keys();
         let peer_id = *node.get_peer_id();
 
@@ -226,9 +459,28 @@ impl BlockchainBehaviour {
         let store = MemoryStore::new(peer_id);
         let kademlia = kad::Behaviour::new(peer_id, store);
 
+        // Configure mDNS for local peer discovery, if enabled
+        let mdns = enable_mdns.then(|| {
+            mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id)
+                .expect("[network::blockchain_behavior] ERROR: invalid mdns behavior")
+        });
+
         Self {
             gossipsub: gossipsub_behaviour,
             kademlia,
+            mdns: Toggle::from(mdns),
+        }
+    }
+
+    /// Adds peers found on the local network to kademlia and gossipsub, skipping banned peers
+    fn add_discovered_peers(&mut self, peers: Vec<(PeerId, Multiaddr)>, peer_scores: &PeerScores) {
+        for (peer_id, addr) in peers {
+            if peer_scores.is_banned(&peer_id) {
+                continue;
+            }
+            info!("mDNS discovered peer: {} at {}", peer_id, addr);
+            self.kademlia.add_address(&peer_id, addr);
+            self.gossipsub.add_explicit_peer(&peer_id);
         }
     }
 
@@ -244,7 +496,7 @@ impl BlockchainBehaviour {
         self.gossipsub
             .publish(GossipTopic::NewInv.to_ident_topic(), serialized_inv)?;
 
-        println!("Broadcasted inventory message to network!");
+        debug!("Broadcasted inventory message to network!");
         Ok(())
     }
 
@@ -254,7 +506,7 @@ impl BlockchainBehaviour {
         let height = match get_last_block() {
             Ok(b) => b.height,
             Err(_) => {
-                println!("Failed to find latest block - refreshing blockchain");
+                warn!("Failed to find latest block - refreshing blockchain");
                 clear_blockchain();
                 0
             }
@@ -266,33 +518,42 @@ impl BlockchainBehaviour {
         self.gossipsub
             .publish(GossipTopic::ChainSyncReq.to_ident_topic(), serialized)?;
 
-        println!("Broadcasted chainsync message to network!");
+        debug!("Broadcasted chainsync message to network!");
         Ok(())
     }
 
-    fn handle_new_inventory(&mut self, message: Message) {
-        println!("Received inventory message from network");
+    fn handle_new_inventory(
+        &mut self,
+        message: Message,
+        seen_inventory: &mut SeenInventory,
+    ) -> Result<(), Misbehaviour> {
+        debug!("Received inventory message from network");
         let requesting_peer = if let Some(peer) = message.source {
             peer
         } else {
-            println!("[network::handle_new_inventory] ERROR: Received message without a source.");
-            return;
+            warn!("[network::handle_new_inventory] Received message without a source.");
+            return Ok(());
         };
 
         match serde_json::from_slice::<NewInventory>(&message.data) {
+            Ok(inv) if !seen_inventory.insert(&inv) => {
+                debug!("Inventory was recently seen - ignoring duplicate announcement");
+            }
             Ok(inv) => match inv {
                 NewInventory::Transaction(tx_id) => {
-                    if !mempool_contains_tx(tx_id) && !utxo_set_contains_tx(tx_id).unwrap_or(false)
+                    if !mempool_contains_tx(tx_id)
+                        && !get_orphan_txs().contains_key(&tx_id)
+                        && !utxo_set_contains_tx(tx_id).unwrap_or(false)
                     {
                         match self.gossipsub.publish(
                                 GossipTopic::InvReq(requesting_peer).to_ident_topic(),
                                 message.data,
                             ) {
-                               Err(e) =>  println!(
-                                    "[network::handle_new_inventory] ERROR: Failed to publish inventory request: {:?}",
+                               Err(e) =>  error!(
+                                    "[network::handle_new_inventory] Failed to publish inventory request: {:?}",
                                     e
                                 ),
-                                Ok(_)=> println!(
+                                Ok(_)=> debug!(
                                     "Tx not found in chain - requesting tx from sender...",
                                 ),
                             }
@@ -304,35 +565,35 @@ impl BlockchainBehaviour {
                             GossipTopic::InvReq(requesting_peer).to_ident_topic(),
                             message.data,
                         ) {
-                           Err(e) =>  println!(
-                                "[network::handle_new_inventory] ERROR: Failed to publish inventory request: {:?}",
+                           Err(e) =>  error!(
+                                "[network::handle_new_inventory] Failed to publish inventory request: {:?}",
                                 e
                             ),
-                            Ok(_)=> println!(
+                            Ok(_)=> debug!(
                                 "Block not found in chain - requesting block from sender...",
                             ),
                         }
                     }
                     Ok(Some(_)) => {}
-                    Err(e) => println!("{}", e),
+                    Err(e) => error!("{}", e),
                 },
             },
             Err(e) => {
-                println!("Failed to deserialize inventory data: {}", e);
+                warn!("Failed to deserialize inventory data: {}", e);
+                return Err(Misbehaviour::MalformedMessage);
             }
         }
+        Ok(())
     }
 
     // Handle received inventory message
-    fn handle_inventory_req(&mut self, message: Message) {
+    fn handle_inventory_req(&mut self, message: Message) -> Result<(), Misbehaviour> {
         let requesting_peer = if let Some(peer) = message.source {
-            println!("Received inventory request from peer: {:?}", peer);
+            debug!("Received inventory request from peer: {:?}", peer);
             peer
         } else {
-            println!(
-                "[network::handle_inventory_req] ERROR: Received message from an unknown peer."
-            );
-            return;
+            warn!("[network::handle_inventory_req] Received message from an unknown peer.");
+            return Ok(());
         };
 
         match serde_json::from_slice::<NewInventory>(&message.data) {
@@ -342,27 +603,25 @@ impl BlockchainBehaviour {
                         let tx = if let Some(tx) = get_tx_from_mempool(tx_id) {
                             tx
                         } else {
-                            println!(
-                                "[network::handle_inventory_req] ERROR: tx not found in mempool."
-                            );
-                            return;
+                            warn!("[network::handle_inventory_req] tx not found in mempool.");
+                            return Ok(());
                         };
                         let inventory = Inventory::Transaction(tx);
                         let serialized_tx = if let Ok(bytes) = serde_json::to_vec(&inventory) {
                             bytes
                         } else {
-                            println!("[network::handle_inventory_req] ERROR: failed to serialize inventory");
-                            return;
+                            error!("[network::handle_inventory_req] failed to serialize inventory");
+                            return Ok(());
                         };
                         match self.gossipsub.publish(
                             GossipTopic::InvRes(requesting_peer).to_ident_topic(),
                             serialized_tx,
                         ) {
-                            Err(e) => println!(
-                                "[network::handle_inventory_req] ERROR: Failed to publish inventory req: {:?}",
+                            Err(e) => error!(
+                                "[network::handle_inventory_req] Failed to publish inventory req: {:?}",
                                 e
                             ),
-                            Ok(_)=> println!("Sending tx record to peer: {:?}", requesting_peer),
+                            Ok(_)=> debug!("Sending tx record to peer: {:?}", requesting_peer),
                         }
                     }
                     NewInventory::Block(block_hash) => {
@@ -372,182 +631,645 @@ impl BlockchainBehaviour {
                         let block = if let Ok(Some(b)) = get_block(&block_hash) {
                             b
                         } else {
-                            println!(
-                                "[network::handle_inventory_req] ERROR: block not found in local chain."
+                            warn!(
+                                "[network::handle_inventory_req] block not found in local chain."
                             );
-                            return;
+                            return Ok(());
                         };
+                        // Pruned blocks can't be verified by the requester without their txs
+                        if block.is_pruned() {
+                            warn!(
+                                "[network::handle_inventory_req] requested block has been pruned."
+                            );
+                            return Ok(());
+                        }
                         let inventory = Inventory::Block(block);
                         let serialized_block = if let Ok(bytes) = serde_json::to_vec(&inventory) {
                             bytes
                         } else {
-                            println!("[network::handle_inventory_req] ERROR: failed to serialize inventory");
-                            return;
+                            error!("[network::handle_inventory_req] failed to serialize inventory");
+                            return Ok(());
                         };
                         match self.gossipsub.publish(
                             GossipTopic::InvRes(requesting_peer).to_ident_topic(),
                             serialized_block,
                         ) {
-                            Err(e) => println!(
-                                "[network::handle_inventory_req] ERROR: Failed to publish inventory req: {:?}",
+                            Err(e) => error!(
+                                "[network::handle_inventory_req] Failed to publish inventory req: {:?}",
                                 e
                             ),
-                            Ok(_)=> println!("Sending block record to peer: {:?}", requesting_peer),
+                            Ok(_)=> debug!("Sending block record to peer: {:?}", requesting_peer),
                         }
                     }
                 }
             }
             Err(e) => {
-                println!("Failed to deserialize inventory data: {}", e);
+                warn!("Failed to deserialize inventory data: {}", e);
+                return Err(Misbehaviour::MalformedMessage);
             }
         }
+        Ok(())
     }
 
-    fn handle_inventory_res(&mut self, message: Message) {
-        println!("Inventory record successfully retrieved");
+    fn handle_inventory_res(&mut self, message: Message) -> Result<(), Misbehaviour> {
+        debug!("Inventory record successfully retrieved");
         match serde_json::from_slice::<Inventory>(&message.data) {
             Ok(inv) => {
                 match inv {
                     Inventory::Transaction(tx) => {
+                        // A tx larger than a block can never be mined
+                        if tx.size() > MAX_BLOCK_TX_BYTES {
+                            warn!("[network::handle_inventory_res] Dropping oversized tx");
+                            return Err(Misbehaviour::InvalidTx);
+                        }
+
+                        // Txs spending unknown outputs may depend on a tx we haven't seen yet.
+                        // They are held as orphans until a block provides the missing inputs
+                        if !tx.is_coinbase() {
+                            match has_missing_inputs(&tx) {
+                                Ok(true) => {
+                                    add_orphan_tx(&tx);
+                                    info!("Tx spends unknown outputs and was added to the orphan tx pool");
+                                    return Ok(());
+                                }
+                                Ok(false) => {}
+                                Err(e) => {
+                                    error!("[network::handle_inventory_res] failed to check tx inputs: {:?}", e);
+                                    return Ok(());
+                                }
+                            }
+                        }
+
                         match tx.verify() {
                             Ok(v) => {
                                 if !v {
-                                    println!(
-                                        "[network::handle_inventory_res] ERROR: Transaction verification failed!"
+                                    warn!(
+                                        "[network::handle_inventory_res] Transaction verification failed!"
                                     );
-                                    return;
+                                    return Err(Misbehaviour::InvalidTx);
                                 }
                             }
                             Err(e) => {
-                                println!("[network::handle_inventory_res] ERROR: Cannot mine block - {:?}", e);
-                                return;
+                                error!(
+                                    "[network::handle_inventory_res] Cannot mine block - {:?}",
+                                    e
+                                );
+                                return Ok(());
                             }
                         };
 
                         // Ensure no txs are double spent
                         for tx_input in &tx.inputs {
                             if mempool_contains_txo(tx_input.prev_tx_id, tx_input.out) {
-                                println!("[network::handle_inventory_res] ERROR: tx contains outputs spent in mempool");
-                                return;
+                                warn!("[network::handle_inventory_res] tx contains outputs spent in mempool");
+                                return Ok(());
                             }
                         }
 
                         match add_tx_to_mempool(&tx) {
-                            Err(e) => println!("[network::handle_inventory_res] ERROR: failed to add transaction to mempool: {:?}", e),
-                            Ok(_)=>println!("Tx was successfully committed to the mempool")
+                            Err(e) => error!("[network::handle_inventory_res] failed to add transaction to mempool: {:?}", e),
+                            Ok(_)=>info!("Tx was successfully committed to the mempool")
+                        }
+                    }
+                    Inventory::Block(block) => {
+                        // Oversized blocks can never be valid, so drop them before verifying
+                        if block.size() > MAX_BLOCK_BYTES {
+                            warn!("[network::handle_inventory_res] Dropping oversized block");
+                            return Err(Misbehaviour::InvalidBlock);
+                        }
+
+                        // Blocks we already have are discarded too, but aren't the peer's fault
+                        let known = matches!(get_block(&block.hash), Ok(Some(_)));
+                        match commit_block(&block) {
+                            Ok(CommitStatus::Rejected) if !known => {
+                                return Err(Misbehaviour::InvalidBlock)
+                            }
+                            // Ask the sender for the missing parent so the orphan can be connected
+                            Ok(CommitStatus::Orphaned) => {
+                                if let (Some(peer), Ok(None)) =
+                                    (message.source, get_block(&block.prev_hash))
+                                {
+                                    self.request_block(peer, block.prev_hash);
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(e) => error!(
+                                "[network::handle_inventory_res] failed to commit block: {:?}",
+                                e
+                            ),
                         }
                     }
-                    Inventory::Block(block) => match commit_block(&block) {
-                        Ok(_) => {}
-                        Err(e) => println!(
-                            "[network::handle_inventory_res] ERROR: failed to commit block: {:?}",
-                            e
-                        ),
-                    },
                 }
             }
             Err(e) => {
-                println!("Failed to deserialize inventory data: {}", e);
+                warn!("Failed to deserialize inventory data: {}", e);
+                return Err(Misbehaviour::MalformedMessage);
+            }
+        }
+        Ok(())
+    }
+
+    /// Requests a block from the given peer
+    fn request_block(&mut self, peer: PeerId, block_hash: [u8; 32]) {
+        let serialized = match serde_json::to_vec(&NewInventory::Block(block_hash)) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!(
+                    "[network::request_block] failed to serialize inventory: {:?}",
+                    e
+                );
+                return;
             }
+        };
+        match self
+            .gossipsub
+            .publish(GossipTopic::InvReq(peer).to_ident_topic(), serialized)
+        {
+            Err(e) => error!(
+                "[network::request_block] Failed to publish inventory request: {:?}",
+                e
+            ),
+            Ok(_) => debug!(
+                "Requesting block {} from peer: {:?}",
+                hex::encode(block_hash),
+                peer
+            ),
         }
     }
 
-    fn handle_chainsync_req(&mut self, message: Message) {
+    fn handle_chainsync_req(&mut self, message: Message) -> Result<(), Misbehaviour> {
         let requesting_peer = if let Some(peer) = message.source {
-            println!("Received chainsync request from peer: {:?}", peer);
+            debug!("Received chainsync request from peer: {:?}", peer);
             peer
         } else {
-            println!(
-                "[network::handle_chainsync_req] ERROR: Received message from an unknown peer."
-            );
-            return;
+            warn!("[network::handle_chainsync_req] Received message from an unknown peer.");
+            return Ok(());
         };
 
         let height = match serde_json::from_slice::<u32>(&message.data) {
             Ok(h) => h,
             Err(e) => {
-                println!("Failed to deserialize height data: {}", e);
-                return;
+                warn!("Failed to deserialize height data: {}", e);
+                return Err(Misbehaviour::MalformedMessage);
             }
         };
 
-        let blocks = match get_blocks_since_height(height) {
+        // The requesting node's last height is excluded, unless it has no chain yet
+        let from_height = if height == 0 {
+            0
+        } else {
+            height.saturating_add(1)
+        };
+        // Only hashes are sent, so pruned nodes can answer from their height index
+        let block_hashes = match get_block_hashes_from_height(from_height) {
             Ok(h) => h,
             Err(e) => {
-                println!("Failed to handle chainsync request: {}", e);
-                return;
+                warn!("Failed to handle chainsync request: {}", e);
+                return Ok(());
             }
         };
 
-        let block_hashes: Vec<[u8; 32]> = blocks.iter().map(|b| b.hash).collect();
-        let payload = if let Ok(bytes) = serde_json::to_vec(&block_hashes) {
+        let res = ChainSync {
+            block_hashes,
+            from_height,
+        };
+        let payload = if let Ok(bytes) = serde_json::to_vec(&res) {
             bytes
         } else {
-            println!("[network::handle_chainsync_req] ERROR: failed to serialize block hashes");
-            return;
+            error!("[network::handle_chainsync_req] failed to serialize block hashes");
+            return Ok(());
         };
         match self.gossipsub.publish(
             GossipTopic::ChainSyncRes(requesting_peer).to_ident_topic(),
             payload,
         ) {
-            Err(e) => println!(
-                "[network::handle_chainsync_req] ERROR: Failed to publish chainsync res: {:?}",
+            Err(e) => error!(
+                "[network::handle_chainsync_req] Failed to publish chainsync res: {:?}",
                 e
             ),
-            Ok(_) => println!(
+            Ok(_) => debug!(
                 "Sending chainsync block hashes to peer: {:?}",
                 requesting_peer
             ),
         }
+        Ok(())
     }
 
-    fn handle_chainsync_res(&mut self, message: Message) {
+    fn handle_chainsync_res(
+        &mut self,
+        message: Message,
+        initial_sync: &mut InitialSync,
+    ) -> Result<(), Misbehaviour> {
         let requesting_peer = if let Some(peer) = message.source {
-            println!("Received chainsync response from peer: {:?}", peer);
+            debug!("Received chainsync response from peer: {:?}", peer);
             peer
         } else {
-            println!(
-                "[network::handle_chainsync_res] ERROR: Received message from an unknown peer."
-            );
-            return;
-        };
-
-        match serde_json::from_slice::<Vec<[u8; 32]>>(&message.data) {
-            Ok(block_hashes) => {
-                for block_hash in block_hashes {
-                    let inventory = NewInventory::Block(block_hash);
-                    let serialized_bh = if let Ok(bytes) = serde_json::to_vec(&inventory) {
-                        bytes
-                    } else {
-                        println!(
-                            "[network::handle_chainsync_res] ERROR: failed to serialize inventory"
-                        );
-                        return;
-                    };
-                    match self.gossipsub.publish(
-                        GossipTopic::InvReq(requesting_peer).to_ident_topic(),
-                        serialized_bh,
-                    ) {
-                       Err(e) =>  println!(
-                            "[network::handle_new_inventory] ERROR: Failed to publish new inventory: {:?}",
-                            e
-                        ),
-                        Ok(_)=> println!(
-                            "Requesting blocks from sender...",
-                        ),
+            warn!("[network::handle_chainsync_res] Received message from an unknown peer.");
+            return Ok(());
+        };
+
+        let ChainSync {
+            block_hashes,
+            from_height,
+        } = match serde_json::from_slice::<ChainSync>(&message.data) {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("Failed to deserialize blockhash data: {}", e);
+                return Err(Misbehaviour::MalformedMessage);
+            }
+        };
+        if block_hashes.is_empty() {
+            return Ok(());
+        }
+
+        // Hashes are sent from the peer's tip down to from_height
+        let peer_height = from_height.saturating_add(block_hashes.len() as u32 - 1);
+        initial_sync.observe_peer_height(peer_height);
+
+        let missing_heights = missing_block_heights(&block_hashes, from_height);
+        debug!(
+            "Chainsync announced {} blocks, {} of which are missing. Best known height is {}",
+            block_hashes.len(),
+            missing_heights.len(),
+            initial_sync.best_known_height()
+        );
+
+        // Download the headers of the missing blocks first, so their proof of work and linkage
+        // are checked before any blocks are requested
+        for (from, to) in height_runs(&missing_heights) {
+            self.request_headers(requesting_peer, from, to);
+        }
+        Ok(())
+    }
+
+    /// Requests the headers between the given heights from the given peer
+    fn request_headers(&mut self, peer: PeerId, from_height: u32, to_height: u32) {
+        let req = GetHeaders {
+            from_height,
+            to_height,
+        };
+        let serialized = match serde_json::to_vec(&req) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!(
+                    "[network::request_headers] failed to serialize request: {:?}",
+                    e
+                );
+                return;
+            }
+        };
+        match self
+            .gossipsub
+            .publish(GossipTopic::GetHeaders(peer).to_ident_topic(), serialized)
+        {
+            Err(e) => error!(
+                "[network::request_headers] Failed to publish headers request: {:?}",
+                e
+            ),
+            Ok(_) => debug!(
+                "Requesting headers {} to {} from peer: {:?}",
+                from_height, to_height, peer
+            ),
+        }
+    }
+
+    fn handle_get_headers(&mut self, message: Message) -> Result<(), Misbehaviour> {
+        let requesting_peer = if let Some(peer) = message.source {
+            debug!("Received headers request from peer: {:?}", peer);
+            peer
+        } else {
+            warn!("[network::handle_get_headers] Received message from an unknown peer.");
+            return Ok(());
+        };
+
+        let req = match serde_json::from_slice::<GetHeaders>(&message.data) {
+            Ok(req) if req.from_height <= req.to_height => req,
+            Ok(_) => return Err(Misbehaviour::MalformedMessage),
+            Err(e) => {
+                warn!("Failed to deserialize headers request: {}", e);
+                return Err(Misbehaviour::MalformedMessage);
+            }
+        };
+
+        let (headers, tip_height) = match get_headers_in_range(
+            req.from_height,
+            req.to_height
+                .min(req.from_height.saturating_add(MAX_HEADERS_LEN - 1)),
+        )
+        .and_then(|headers| Ok((headers, get_chain_height()?)))
+        {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("Failed to handle headers request: {}", e);
+                return Ok(());
+            }
+        };
+
+        let res = Headers {
+            headers,
+            to_height: req.to_height.min(tip_height),
+        };
+        let payload = if let Ok(bytes) = serde_json::to_vec(&res) {
+            bytes
+        } else {
+            error!("[network::handle_get_headers] failed to serialize headers");
+            return Ok(());
+        };
+        match self.gossipsub.publish(
+            GossipTopic::Headers(requesting_peer).to_ident_topic(),
+            payload,
+        ) {
+            Err(e) => error!(
+                "[network::handle_get_headers] Failed to publish headers: {:?}",
+                e
+            ),
+            Ok(_) => debug!(
+                "Sending {} headers to peer: {:?}",
+                res.headers.len(),
+                requesting_peer
+            ),
+        }
+        Ok(())
+    }
+
+    /// Validates a batch of headers and keeps requesting more until the peer's header chain is
+    /// complete. Its blocks are then requested if it has more work than the local chain
+    fn handle_headers(
+        &mut self,
+        message: Message,
+        header_sync: &mut HeaderSync,
+    ) -> Result<(), Misbehaviour> {
+        let Some(peer) = message.source else {
+            warn!("[network::handle_headers] Received message from an unknown peer.");
+            return Ok(());
+        };
+
+        let res = match serde_json::from_slice::<Headers>(&message.data) {
+            Ok(res) if res.headers.len() <= MAX_HEADERS_LEN as usize => res,
+            Ok(_) => return Err(Misbehaviour::MalformedMessage),
+            Err(e) => {
+                warn!("Failed to deserialize headers: {}", e);
+                return Err(Misbehaviour::MalformedMessage);
+            }
+        };
+        let Some(last) = res.headers.last() else {
+            return Ok(());
+        };
+        debug!(
+            "Received {} headers from peer: {:?}",
+            res.headers.len(),
+            peer
+        );
+
+        match header_sync.connect(&res.headers) {
+            Ok(HeaderStatus::Connected) => {}
+            // The headers fork below our chain's blocks. They'll be found via orphan handling
+            Ok(HeaderStatus::UnknownParent) => {
+                debug!("Dropping headers with an unknown parent");
+                return Ok(());
+            }
+            Ok(HeaderStatus::Invalid) => return Err(Misbehaviour::InvalidBlock),
+            Err(e) => {
+                warn!("Failed to validate headers: {}", e);
+                return Ok(());
+            }
+        }
+
+        // Responses are length limited, so keep requesting until every header is received
+        if last.height < res.to_height {
+            self.request_headers(peer, last.height + 1, res.to_height);
+            return Ok(());
+        }
+
+        let chain = header_sync.take_chain(last.hash);
+        match has_more_work(&chain) {
+            Ok(true) => {
+                info!(
+                    "Validated {} headers up to height {}, downloading blocks",
+                    chain.len(),
+                    last.height
+                );
+                self.request_blocks_range(peer, chain[0].height, last.height);
+            }
+            Ok(false) => debug!("Header chain has no more work than ours, skipping its blocks"),
+            Err(e) => warn!("Failed to compare header chain work: {}", e),
+        }
+        Ok(())
+    }
+
+    /// Requests the blocks between the given heights from the given peer
+    fn request_blocks_range(&mut self, peer: PeerId, from_height: u32, to_height: u32) {
+        let req = GetBlocksRange {
+            from_height,
+            to_height,
+        };
+        let serialized = match serde_json::to_vec(&req) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!(
+                    "[network::request_blocks_range] failed to serialize request: {:?}",
+                    e
+                );
+                return;
+            }
+        };
+        match self.gossipsub.publish(
+            GossipTopic::GetBlocksRange(peer).to_ident_topic(),
+            serialized,
+        ) {
+            Err(e) => error!(
+                "[network::request_blocks_range] Failed to publish blocks range request: {:?}",
+                e
+            ),
+            Ok(_) => debug!(
+                "Requesting blocks {} to {} from peer: {:?}",
+                from_height, to_height, peer
+            ),
+        }
+    }
+
+    fn handle_get_blocks_range(&mut self, message: Message) -> Result<(), Misbehaviour> {
+        let requesting_peer = if let Some(peer) = message.source {
+            debug!("Received blocks range request from peer: {:?}", peer);
+            peer
+        } else {
+            warn!("[network::handle_get_blocks_range] Received message from an unknown peer.");
+            return Ok(());
+        };
+
+        let req = match serde_json::from_slice::<GetBlocksRange>(&message.data) {
+            Ok(req) if req.from_height <= req.to_height => req,
+            Ok(_) => return Err(Misbehaviour::MalformedMessage),
+            Err(e) => {
+                warn!("Failed to deserialize blocks range request: {}", e);
+                return Err(Misbehaviour::MalformedMessage);
+            }
+        };
+
+        let last_height = req
+            .to_height
+            .min(req.from_height.saturating_add(MAX_BLOCKS_RANGE_LEN - 1));
+        let blocks = match get_blocks_in_range(req.from_height, last_height) {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                warn!("Failed to handle blocks range request: {}", e);
+                return Ok(());
+            }
+        };
+
+        // Send as many blocks as fit in a single message. At least one block is always sent so
+        // the requester can make progress
+        let mut range = BlocksRange {
+            blocks: Vec::new(),
+            to_height: req.to_height,
+        };
+        let mut size = 0;
+        for block in blocks {
+            let block_size = serde_json::to_vec(&block).map(|b| b.len()).unwrap_or(0);
+            if !range.blocks.is_empty() && size + block_size > MAX_BLOCKS_RANGE_BYTES {
+                break;
+            }
+            size += block_size;
+            range.blocks.push(block);
+        }
+
+        let payload = if let Ok(bytes) = serde_json::to_vec(&range) {
+            bytes
+        } else {
+            error!("[network::handle_get_blocks_range] failed to serialize blocks range");
+            return Ok(());
+        };
+        match self.gossipsub.publish(
+            GossipTopic::BlocksRange(requesting_peer).to_ident_topic(),
+            payload,
+        ) {
+            Err(e) => error!(
+                "[network::handle_get_blocks_range] Failed to publish blocks range: {:?}",
+                e
+            ),
+            Ok(_) => debug!(
+                "Sending {} blocks to peer: {:?}",
+                range.blocks.len(),
+                requesting_peer
+            ),
+        }
+        Ok(())
+    }
+
+    fn handle_blocks_range(&mut self, message: Message) -> Result<(), Misbehaviour> {
+        let range = match serde_json::from_slice::<BlocksRange>(&message.data) {
+            Ok(range) => range,
+            Err(e) => {
+                warn!("Failed to deserialize blocks range: {}", e);
+                return Err(Misbehaviour::MalformedMessage);
+            }
+        };
+        debug!("Received {} blocks in range", range.blocks.len());
+
+        let mut last_height = None;
+        for block in &range.blocks {
+            if block.size() > MAX_BLOCK_BYTES {
+                warn!("[network::handle_blocks_range] Dropping oversized block");
+                return Err(Misbehaviour::InvalidBlock);
+            }
+
+            // Blocks we already have are discarded too, but aren't the peer's fault
+            let known = matches!(get_block(&block.hash), Ok(Some(_)));
+            match commit_block(block) {
+                Ok(CommitStatus::Rejected) if !known => return Err(Misbehaviour::InvalidBlock),
+                // The range forks from our chain below its first block. Ask the sender for
+                // the missing parent so the orphans can be connected
+                Ok(CommitStatus::Orphaned) => {
+                    if let (Some(peer), Ok(None)) = (message.source, get_block(&block.prev_hash)) {
+                        self.request_block(peer, block.prev_hash);
                     }
                 }
+                Ok(_) => {}
+                Err(e) => {
+                    error!(
+                        "[network::handle_blocks_range] failed to commit block: {:?}",
+                        e
+                    );
+                    return Ok(());
+                }
             }
-            Err(e) => {
-                println!("Failed to deserialize blockhash data: {}", e);
+            last_height = Some(block.height);
+        }
+
+        // Responses are size limited, so keep requesting until the whole range is received
+        if let (Some(peer), Some(last_height)) = (message.source, last_height) {
+            if last_height < range.to_height {
+                self.request_blocks_range(peer, last_height + 1, range.to_height);
             }
         }
+        Ok(())
+    }
+}
+
+/// Returns the ascending heights of the announced blocks we don't already hold, either on a
+/// chain or as orphans. Hashes run from the peer's tip down to the given height
+fn missing_block_heights(block_hashes: &[[u8; 32]], from_height: u32) -> Vec<u32> {
+    let orphans = get_orphaned_blocks();
+    block_hashes
+        .iter()
+        .rev()
+        .enumerate()
+        .filter(|(_, hash)| !orphans.contains_key(*hash) && !matches!(get_block(hash), Ok(Some(_))))
+        .map(|(i, _)| from_height.saturating_add(i as u32))
+        .collect()
+}
+
+/// Groups ascending heights into runs of consecutive heights, returned as inclusive ranges
+fn height_runs(heights: &[u32]) -> Vec<(u32, u32)> {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for &height in heights {
+        match runs.last_mut() {
+            Some((_, to)) if to.checked_add(1) == Some(height) => *to = height,
+            _ => runs.push((height, height)),
+        }
+    }
+    runs
+}
+
+/// Dials peers known from previous sessions, followed by the seed nodes. Banned peers and
+/// addresses with an open connection are skipped. Successful dials create a "connection
+/// established" event, at which point the peer is added to kademlia
+fn dial_bootstrap_peers(
+    swarm: &mut Swarm<BlockchainBehaviour>,
+    seeds: &[Multiaddr],
+    peer_scores: &PeerScores,
+    connected_peers: &HashMap<PeerId, HashSet<Multiaddr>>,
+) {
+    let is_connected =
+        |addr: &Multiaddr| connected_peers.values().any(|addrs| addrs.contains(addr));
+
+    for (peer_id, addr) in get_peers() {
+        if peer_scores.is_banned(&peer_id) || connected_peers.contains_key(&peer_id) {
+            continue;
+        }
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .add_address(&peer_id, addr.clone());
+        if let Err(e) = swarm.dial(addr.clone()) {
+            warn!("Failed to dial known peer {}: {}", addr, e);
+        }
+    }
+
+    for node_addr in seeds.iter().filter(|addr| !is_connected(addr)) {
+        match swarm.dial(node_addr.clone()) {
+            Ok(_) => info!("Dialed bootstrap node: {}", node_addr),
+            Err(e) => warn!("Failed to dial bootstrap node {}: {}", node_addr, e),
+        }
     }
 }
 
-// Once deployed, introduce seed nodes (same as before)
+// Default seed nodes, used when none are provided on startup
 const SEED_P2P_NODES: [&str; 2] = ["/ip4/127.0.0.1/tcp/4000", "/ip4/127.0.0.1/tcp/4001"];
-fn get_seed_nodes() -> Vec<Multiaddr> {
+fn get_seed_nodes(seeds: Vec<Multiaddr>) -> Vec<Multiaddr> {
+    if !seeds.is_empty() {
+        return seeds;
+    }
     SEED_P2P_NODES
         .iter()
         .map(|addr| addr.parse().expect("Invalid Multiaddr"))
@@ -560,6 +1282,10 @@ const INV_REQ_TOPIC: &str = "inv_req";
 const INV_RES_TOPIC: &str = "inv_res";
 const CHAIN_SYNC_REQ_TOPIC: &str = "chain_sync_req";
 const CHAIN_SYNC_RES_TOPIC: &str = "chain_sync_res";
+const GET_BLOCKS_RANGE_TOPIC: &str = "get_blocks_range";
+const BLOCKS_RANGE_TOPIC: &str = "blocks_range";
+const GET_HEADERS_TOPIC: &str = "get_headers";
+const HEADERS_TOPIC: &str = "headers";
 
 #[derive(Debug, Clone)]
 pub enum GossipTopic {
@@ -568,34 +1294,434 @@ pub enum GossipTopic {
     InvRes(PeerId),
     ChainSyncReq,
     ChainSyncRes(PeerId),
+    GetBlocksRange(PeerId),
+    BlocksRange(PeerId),
+    GetHeaders(PeerId),
+    Headers(PeerId),
 }
 
 impl GossipTopic {
-    /// Returns the corresponding `IdentTopic`
+    /// Returns the corresponding `IdentTopic`, namespaced by the configured network
     pub fn to_ident_topic(&self) -> IdentTopic {
-        match self {
-            GossipTopic::NewInv => IdentTopic::new(format!("{}", NEW_INV_TOPIC)),
-            GossipTopic::InvReq(peer_id) => {
-                IdentTopic::new(format!("direct:{}:{}", peer_id, INV_REQ_TOPIC))
+        let topic = match self {
+            GossipTopic::NewInv => NEW_INV_TOPIC.to_string(),
+            GossipTopic::InvReq(peer_id) => format!("direct:{}:{}", peer_id, INV_REQ_TOPIC),
+            GossipTopic::InvRes(peer_id) => format!("direct:{}:{}", peer_id, INV_RES_TOPIC),
+            GossipTopic::ChainSyncReq => CHAIN_SYNC_REQ_TOPIC.to_string(),
+            GossipTopic::ChainSyncRes(peer_id) => {
+                format!("direct:{}:{}", peer_id, CHAIN_SYNC_RES_TOPIC)
             }
-            GossipTopic::InvRes(peer_id) => {
-                IdentTopic::new(format!("direct:{}:{}", peer_id, INV_RES_TOPIC))
+            GossipTopic::GetBlocksRange(peer_id) => {
+                format!("direct:{}:{}", peer_id, GET_BLOCKS_RANGE_TOPIC)
             }
-            GossipTopic::ChainSyncReq => IdentTopic::new(format!("{}", CHAIN_SYNC_REQ_TOPIC)),
-            GossipTopic::ChainSyncRes(peer_id) => {
-                IdentTopic::new(format!("direct:{}:{}", peer_id, CHAIN_SYNC_RES_TOPIC))
+            GossipTopic::BlocksRange(peer_id) => {
+                format!("direct:{}:{}", peer_id, BLOCKS_RANGE_TOPIC)
             }
-        }
+            GossipTopic::GetHeaders(peer_id) => {
+                format!("direct:{}:{}", peer_id, GET_HEADERS_TOPIC)
+            }
+            GossipTopic::Headers(peer_id) => format!("direct:{}:{}", peer_id, HEADERS_TOPIC),
+        };
+        IdentTopic::new(format!("{}/{}", network_params().magic_hex(), topic))
     }
 }
 
+/// Strips the network prefix from the given topic. Returns None if the topic belongs to
+/// another network.
+fn strip_network_prefix(topic: &str) -> Option<&str> {
+    topic
+        .strip_prefix(&network_params().magic_hex())?
+        .strip_prefix('/')
+}
+
 /// Returns all topics relevant to the given peer
 fn get_all_topics(peer_id: &PeerId) -> Vec<IdentTopic> {
     vec![
         GossipTopic::NewInv.to_ident_topic(),
-        GossipTopic::InvReq(peer_id.clone()).to_ident_topic(),
-        GossipTopic::InvRes(peer_id.clone()).to_ident_topic(),
+        GossipTopic::InvReq(*peer_id).to_ident_topic(),
+        GossipTopic::InvRes(*peer_id).to_ident_topic(),
         GossipTopic::ChainSyncReq.to_ident_topic(),
-        GossipTopic::ChainSyncRes(peer_id.clone()).to_ident_topic(),
+        GossipTopic::ChainSyncRes(*peer_id).to_ident_topic(),
+        GossipTopic::GetBlocksRange(*peer_id).to_ident_topic(),
+        GossipTopic::BlocksRange(*peer_id).to_ident_topic(),
+        GossipTopic::GetHeaders(*peer_id).to_ident_topic(),
+        GossipTopic::Headers(*peer_id).to_ident_topic(),
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use core_lib::wallet::Wallet;
+
+    use super::*;
+    use crate::{
+        blockchain::params::Network,
+        test_utils::{block_on, new_chain, next_block, pay, setup, start_node},
+    };
+
+    /// Runs the future to completion on a new runtime. Tests hold the db guard outside of it,
+    /// since a std mutex guard must not be held across an await
+    fn run<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    /// Returns a bare gossipsub peer dialing the given address
+    fn gossip_peer(addr: Multiaddr) -> Swarm<gossipsub::Behaviour> {
+        let mut peer = SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )
+            .unwrap()
+            .with_behaviour(|key| {
+                gossipsub::Behaviour::new(
+                    gossipsub::MessageAuthenticity::Signed(key.clone()),
+                    gossipsub::Config::default(),
+                )
+                .unwrap()
+            })
+            .unwrap()
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+        peer.dial(addr).unwrap();
+        peer
+    }
+
+    #[test]
+    fn height_runs_groups_consecutive_heights() {
+        assert_eq!(height_runs(&[]), vec![]);
+        assert_eq!(height_runs(&[4]), vec![(4, 4)]);
+        assert_eq!(
+            height_runs(&[1, 2, 3, 7, 9, 10]),
+            vec![(1, 3), (7, 7), (9, 10)]
+        );
+        assert_eq!(
+            height_runs(&[u32::MAX - 1, u32::MAX]),
+            vec![(u32::MAX - 1, u32::MAX)]
+        );
+    }
+
+    #[test]
+    fn chainsync_requests_only_missing_blocks() {
+        let _guard = setup();
+        new_chain();
+        let miner = Wallet::new().get_wallet_address();
+        let mut chain = vec![get_last_block().unwrap()];
+        for _ in 0..6 {
+            chain.push(block_on(chain.last().unwrap(), &miner, vec![]));
+        }
+
+        // Every other block was already received out of order, so is held as an orphan
+        for block in chain.iter().skip(2).step_by(2) {
+            assert!(matches!(
+                commit_block(block).unwrap(),
+                CommitStatus::Orphaned
+            ));
+        }
+
+        // The peer announces its chain from the tip down to genesis
+        let hashes: Vec<[u8; 32]> = chain.iter().rev().map(|block| block.hash).collect();
+        let missing = missing_block_heights(&hashes, 0);
+        assert_eq!(missing, vec![1, 3, 5]);
+        assert_eq!(height_runs(&missing), vec![(1, 1), (3, 3), (5, 5)]);
+    }
+
+    #[test]
+    fn chainsync_response_heights_come_from_the_request() {
+        let _guard = setup();
+        new_chain();
+        let miner = Wallet::new().get_wallet_address();
+        let mut chain = vec![get_last_block().unwrap()];
+        for _ in 0..6 {
+            chain.push(block_on(chain.last().unwrap(), &miner, vec![]));
+        }
+        let mut behaviour = BlockchainBehaviour::create(false);
+        let mut initial_sync = InitialSync::new(0);
+
+        // The response answers a request sent from genesis, but the tip has moved since
+        for block in &chain[1..4] {
+            assert_eq!(commit_block(block).unwrap(), CommitStatus::Committed);
+        }
+        let peer = PeerId::random();
+        let res = ChainSync {
+            block_hashes: chain.iter().rev().map(|block| block.hash).collect(),
+            from_height: 0,
+        };
+        let message = Message {
+            source: Some(peer),
+            data: serde_json::to_vec(&res).unwrap(),
+            sequence_number: None,
+            topic: GossipTopic::ChainSyncRes(peer).to_ident_topic().hash(),
+        };
+
+        behaviour
+            .handle_chainsync_res(message, &mut initial_sync)
+            .unwrap();
+        assert_eq!(initial_sync.best_known_height(), 6);
+        assert_eq!(missing_block_heights(&res.block_hashes, 0), vec![4, 5, 6]);
+    }
+
+    /// Returns an inventory response carrying the given inventory, as sent by the given peer
+    fn inv_res(peer: PeerId, inv: &Inventory) -> Message {
+        Message {
+            source: Some(peer),
+            data: serde_json::to_vec(inv).unwrap(),
+            sequence_number: None,
+            topic: GossipTopic::InvRes(peer).to_ident_topic().hash(),
+        }
+    }
+
+    #[test]
+    fn oversized_inventory_is_dropped() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let tip = get_last_block().unwrap().hash;
+        let mut behaviour = BlockchainBehaviour::create(false);
+        let peer = PeerId::random();
+
+        // Padded with outputs until it can't fit in a block. It spends an unknown output, so
+        // would otherwise be held as an orphan
+        let mut tx = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 0);
+        tx.inputs[0].prev_tx_id = [7u8; 32];
+        let padding = tx.outputs[0];
+        tx.outputs.extend(vec![padding; MAX_BLOCK_BYTES / 24]);
+        assert!(tx.size() > MAX_BLOCK_BYTES);
+        let res =
+            behaviour.handle_inventory_res(inv_res(peer, &Inventory::Transaction(tx.clone())));
+        assert!(matches!(res, Err(Misbehaviour::InvalidTx)));
+        assert!(get_orphan_txs().is_empty());
+
+        let mut block = next_block(&wallet.get_wallet_address(), vec![]);
+        block.txs.push(tx);
+        let res = behaviour.handle_inventory_res(inv_res(peer, &Inventory::Block(block.clone())));
+        assert!(matches!(res, Err(Misbehaviour::InvalidBlock)));
+        assert!(get_block(&block.hash).unwrap().is_none());
+        assert!(!get_orphaned_blocks().contains_key(&block.hash));
+        assert_eq!(get_last_block().unwrap().hash, tip);
+    }
+
+    #[test]
+    fn seen_inventory_drops_duplicates_within_window() {
+        let mut seen = SeenInventory::new();
+        let tx = NewInventory::Transaction([1u8; 32]);
+        let block = NewInventory::Block([1u8; 32]);
+
+        // Only the first announcement should go on to request the inventory
+        let requests = [&tx, &tx, &block, &tx, &block]
+            .into_iter()
+            .filter(|inv| seen.insert(inv))
+            .count();
+        assert_eq!(requests, 2);
+
+        // Once the window has passed, the same inventory is requested again
+        seen.order[0].1 = Instant::now() - SEEN_INV_TTL;
+        assert!(seen.insert(&tx));
+        assert!(!seen.insert(&block));
+    }
+
+    #[test]
+    fn discovered_peers_are_added_to_routing_table() {
+        let _guard = setup();
+        let mut behaviour = BlockchainBehaviour::create(false);
+        let mut peer_scores = PeerScores::load();
+        let peer = PeerId::random();
+        let banned = PeerId::random();
+        while !peer_scores.penalize(banned, Misbehaviour::InvalidBlock) {}
+
+        let addr: Multiaddr = "/ip4/192.168.1.20/tcp/4000".parse().unwrap();
+        behaviour.add_discovered_peers(vec![(peer, addr.clone()), (banned, addr)], &peer_scores);
+
+        let routed: Vec<PeerId> = behaviour
+            .kademlia
+            .kbuckets()
+            .flat_map(|bucket| {
+                bucket
+                    .iter()
+                    .map(|entry| *entry.node.key.preimage())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(routed, vec![peer]);
+    }
+
+    #[test]
+    fn orphan_block_triggers_parent_request() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let parent = next_block(&miner, vec![]);
+        let orphan = block_on(&parent, &Wallet::new().get_wallet_address(), vec![]);
+
+        let requested = run(async {
+            let (_p2p, node_addr) = start_node().await;
+            let node_id = *Node::get_or_create_keys().get_peer_id();
+            let mut peer = gossip_peer(node_addr);
+            let peer_id = *peer.local_peer_id();
+            peer.behaviour_mut()
+                .subscribe(&GossipTopic::InvReq(peer_id).to_ident_topic())
+                .unwrap();
+
+            // Sends the orphan as an inventory response once the node can receive it
+            let res_topic = GossipTopic::InvRes(node_id).to_ident_topic();
+            let data = serde_json::to_vec(&Inventory::Block(orphan)).unwrap();
+            let wait_for_request = async {
+                loop {
+                    match peer.select_next_some().await {
+                        SwarmEvent::Behaviour(gossipsub::Event::Subscribed { peer_id, topic })
+                            if peer_id == node_id && topic == res_topic.hash() =>
+                        {
+                            peer.behaviour_mut()
+                                .publish(res_topic.clone(), data.clone())
+                                .unwrap();
+                        }
+                        SwarmEvent::Behaviour(gossipsub::Event::Message { message, .. }) => {
+                            break serde_json::from_slice::<NewInventory>(&message.data).unwrap();
+                        }
+                        _ => {}
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(20), wait_for_request)
+                .await
+                .expect("node did not request the orphan's parent")
+        });
+
+        assert_eq!(requested, NewInventory::Block(parent.hash));
+    }
+
+    #[test]
+    fn inventory_from_another_network_is_ignored() {
+        let _guard = setup();
+        new_chain();
+        let foreign_tx = [1u8; 32];
+        let local_tx = [2u8; 32];
+        // Tests run on testnet, so the peer announces one tx as a mainnet node would
+        let foreign_topic = IdentTopic::new(format!(
+            "{}/{}",
+            Network::Mainnet.params().magic_hex(),
+            NEW_INV_TOPIC
+        ));
+        assert_eq!(strip_network_prefix(&foreign_topic.to_string()), None);
+
+        let requested = run(async {
+            let (_p2p, node_addr) = start_node().await;
+            let node_id = *Node::get_or_create_keys().get_peer_id();
+            let mut peer = gossip_peer(node_addr);
+            let peer_id = *peer.local_peer_id();
+            peer.behaviour_mut()
+                .subscribe(&GossipTopic::InvReq(peer_id).to_ident_topic())
+                .unwrap();
+
+            let new_inv_topic = GossipTopic::NewInv.to_ident_topic();
+            let wait_for_request = async {
+                loop {
+                    match peer.select_next_some().await {
+                        SwarmEvent::Behaviour(gossipsub::Event::Subscribed { peer_id, topic })
+                            if peer_id == node_id && topic == new_inv_topic.hash() =>
+                        {
+                            // The foreign announcement goes first, so it would be requested
+                            // first if the node accepted it
+                            for (topic, tx_id) in
+                                [(&foreign_topic, foreign_tx), (&new_inv_topic, local_tx)]
+                            {
+                                let data =
+                                    serde_json::to_vec(&NewInventory::Transaction(tx_id)).unwrap();
+                                let _ = peer.behaviour_mut().publish(topic.clone(), data);
+                            }
+                        }
+                        SwarmEvent::Behaviour(gossipsub::Event::Message { message, .. }) => {
+                            break serde_json::from_slice::<NewInventory>(&message.data).unwrap();
+                        }
+                        _ => {}
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(20), wait_for_request)
+                .await
+                .expect("node did not request the announced tx")
+        });
+
+        assert_eq!(requested, NewInventory::Transaction(local_tx));
+    }
+
+    #[test]
+    fn syncs_blocks_range_in_single_exchange() {
+        let _guard = setup();
+        new_chain();
+        let genesis = get_last_block().unwrap();
+        let miner = Wallet::new().get_wallet_address();
+        let mut blocks: Vec<Block> = Vec::new();
+        for _ in 0..20 {
+            let parent = blocks.last().unwrap_or(&genesis);
+            blocks.push(block_on(parent, &miner, vec![]));
+        }
+        let tip = blocks.last().unwrap().hash;
+
+        let served = run(async {
+            let (_p2p, node_addr) = start_node().await;
+            let node_id = *Node::get_or_create_keys().get_peer_id();
+            let mut peer = gossip_peer(node_addr);
+            let peer_id = *peer.local_peer_id();
+            peer.behaviour_mut()
+                .subscribe(&GossipTopic::BlocksRange(peer_id).to_ident_topic())
+                .unwrap();
+
+            let range_topic = GossipTopic::BlocksRange(node_id).to_ident_topic();
+            let range = BlocksRange {
+                blocks: blocks.clone(),
+                to_height: 20,
+            };
+            let data = serde_json::to_vec(&range).unwrap();
+            let mut poll = tokio::time::interval(Duration::from_millis(50));
+            let mut requested = false;
+            let exchange = async {
+                loop {
+                    tokio::select! {
+                        event = peer.select_next_some() => match event {
+                            // Sends the whole range once the node can receive it
+                            SwarmEvent::Behaviour(gossipsub::Event::Subscribed { peer_id, topic })
+                                if peer_id == node_id && topic == range_topic.hash() =>
+                            {
+                                peer.behaviour_mut()
+                                    .publish(range_topic.clone(), data.clone())
+                                    .unwrap();
+                            }
+                            SwarmEvent::Behaviour(gossipsub::Event::Message { message, .. }) => {
+                                break serde_json::from_slice::<BlocksRange>(&message.data)
+                                    .unwrap();
+                            }
+                            _ => {}
+                        },
+                        // Once the node has synced the range, ask it to serve the range back
+                        _ = poll.tick(), if !requested => {
+                            if get_last_block().unwrap().hash == tip {
+                                let req = GetBlocksRange {
+                                    from_height: 1,
+                                    to_height: 20,
+                                };
+                                peer.behaviour_mut()
+                                    .publish(
+                                        GossipTopic::GetBlocksRange(node_id).to_ident_topic(),
+                                        serde_json::to_vec(&req).unwrap(),
+                                    )
+                                    .unwrap();
+                                requested = true;
+                            }
+                        }
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(30), exchange)
+                .await
+                .expect("node did not sync and serve the blocks range")
+        });
+
+        assert_eq!(get_last_block().unwrap().hash, tip);
+        let served: Vec<[u8; 32]> = served.blocks.iter().map(|block| block.hash).collect();
+        let expected: Vec<[u8; 32]> = blocks.iter().map(|block| block.hash).collect();
+        assert_eq!(served, expected);
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+use log::{error, warn};
+
+use crate::cli::db::ROCKS_DB;
+
+pub const BANNED_PEERS_KEY: &str = "banned_peers";
+
+/// Peers whose score drops to or below this threshold are banned
+const BAN_THRESHOLD: i32 = -100;
+
+/// DEFAULT_MSG_RATE is the default max number of gossip messages per second accepted from a peer
+pub const DEFAULT_MSG_RATE: u32 = 50;
+/// Max number of peers tracked by the rate limiter before idle buckets are dropped
+const MAX_RATE_LIMITED_PEERS: usize = 1000;
+
+/// MIN_CONNECTED_PEERS is the number of peers below which the node keeps re-dialing its seeds
+/// and known peers
+pub const MIN_CONNECTED_PEERS: usize = 3;
+/// Delay before the first reconnection attempt, and after the node has enough peers again
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Reconnection attempts back off up to this delay
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Misbehaviour is reported by the p2p handlers when a peer sends data that fails validation
+#[derive(Debug, Clone, Copy)]
+pub enum Misbehaviour {
+    MalformedMessage,
+    InvalidTx,
+    InvalidBlock,
+    RateLimited,
+}
+
+impl Misbehaviour {
+    fn penalty(&self) -> i32 {
+        match self {
+            Misbehaviour::MalformedMessage => 10,
+            Misbehaviour::InvalidTx => 25,
+            Misbehaviour::InvalidBlock => 25,
+            // Penalized for every dropped message, so only sustained spam leads to a ban
+            Misbehaviour::RateLimited => 1,
+        }
+    }
+}
+
+/// Tracks a score for each peer, along with the list of banned peers. All peers start with a
+/// score of 0. Scores are kept in memory, while the ban list is persisted across restarts.
+pub struct PeerScores {
+    scores: HashMap<PeerId, i32>,
+    banned: HashSet<PeerId>,
+}
+
+impl PeerScores {
+    /// Loads the persisted ban list from the db
+    pub fn load() -> Self {
+        let banned = match ROCKS_DB.get(BANNED_PEERS_KEY) {
+            Ok(Some(data)) => bincode::deserialize::<Vec<Vec<u8>>>(&data)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|bytes| PeerId::from_bytes(bytes).ok())
+                .collect(),
+            _ => HashSet::new(),
+        };
+
+        Self {
+            scores: HashMap::new(),
+            banned,
+        }
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.banned.contains(peer)
+    }
+
+    pub fn banned_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.banned.iter()
+    }
+
+    /// Decrements the peer's score for the given misbehaviour. Returns true if the peer
+    /// was banned as a result.
+    pub fn penalize(&mut self, peer: PeerId, misbehaviour: Misbehaviour) -> bool {
+        if self.is_banned(&peer) {
+            return false;
+        }
+
+        let score = self.scores.entry(peer).or_insert(0);
+        *score -= misbehaviour.penalty();
+        warn!(
+            "Peer {} penalized for {:?}, score is now {}",
+            peer, misbehaviour, score
+        );
+        if *score > BAN_THRESHOLD {
+            return false;
+        }
+
+        self.scores.remove(&peer);
+        self.banned.insert(peer);
+        self.save();
+        true
+    }
+
+    fn save(&self) {
+        let banned: Vec<Vec<u8>> = self.banned.iter().map(|peer| peer.to_bytes()).collect();
+        match bincode::serialize(&banned) {
+            Ok(serialized) => {
+                if let Err(e) = ROCKS_DB.put(BANNED_PEERS_KEY, serialized) {
+                    error!("[peers::save] Failed to persist banned peers: {:?}", e);
+                }
+            }
+            Err(e) => error!("[peers::save] Failed to serialize banned peers: {:?}", e),
+        }
+    }
+}
+
+/// Token bucket for a single peer. Tokens refill continuously at the rate limit, and the bucket
+/// holds at most one second's worth of messages
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Limits the rate of gossip messages accepted from each peer, so a single peer can't force the
+/// node into endless db lookups by spamming announcements or requests.
+pub struct RateLimiter {
+    msgs_per_sec: f64,
+    buckets: HashMap<PeerId, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(msgs_per_sec: u32) -> Self {
+        Self {
+            msgs_per_sec: f64::from(msgs_per_sec),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token for a message from the given peer. Returns false if the peer has exceeded
+    /// the rate limit, in which case the message should be dropped
+    pub fn allow(&mut self, peer: PeerId) -> bool {
+        let now = Instant::now();
+        let capacity = self.msgs_per_sec;
+
+        // Buckets idle for a second are full again, and are no different to a new bucket
+        if self.buckets.len() >= MAX_RATE_LIMITED_PEERS {
+            self.buckets
+                .retain(|_, bucket| now.duration_since(bucket.last_refill).as_secs_f64() < 1.0);
+        }
+
+        let bucket = self.buckets.entry(peer).or_insert(TokenBucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * capacity).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// Schedules reconnection attempts with exponential backoff. Each failed attempt doubles the
+/// delay until the next one, up to MAX_RECONNECT_DELAY, so unreachable seeds aren't hammered.
+pub struct ReconnectBackoff {
+    delay: Duration,
+    next_attempt: Instant,
+}
+
+impl ReconnectBackoff {
+    pub fn new() -> Self {
+        Self {
+            delay: MIN_RECONNECT_DELAY,
+            next_attempt: Instant::now() + MIN_RECONNECT_DELAY,
+        }
+    }
+
+    /// Returns when the next reconnection attempt is due
+    pub fn next_attempt(&self) -> Instant {
+        self.next_attempt
+    }
+
+    /// Runs a due reconnection attempt, calling `dial` if the node has fewer than
+    /// MIN_CONNECTED_PEERS. Each dial doubles the delay until the next attempt, while having
+    /// enough peers resets it, so a later disconnect is retried quickly. Returns true if it dialed
+    pub fn attempt(&mut self, peer_count: usize, now: Instant, dial: impl FnOnce()) -> bool {
+        if peer_count >= MIN_CONNECTED_PEERS {
+            self.delay = MIN_RECONNECT_DELAY;
+            self.next_attempt = now + MIN_RECONNECT_DELAY;
+            return false;
+        }
+
+        dial();
+        self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+        self.next_attempt = now + self.delay;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup;
+
+    #[test]
+    fn peer_sending_invalid_txs_is_banned() {
+        let _guard = setup();
+        let mut scores = PeerScores::load();
+        let bad_peer = PeerId::random();
+        let good_peer = PeerId::random();
+
+        scores.penalize(good_peer, Misbehaviour::MalformedMessage);
+        for _ in 0..3 {
+            assert!(!scores.penalize(bad_peer, Misbehaviour::InvalidTx));
+        }
+        assert!(!scores.is_banned(&bad_peer));
+        assert!(scores.penalize(bad_peer, Misbehaviour::InvalidTx));
+        assert!(scores.is_banned(&bad_peer));
+        assert!(!scores.is_banned(&good_peer));
+        // Once banned, further misbehaviour is ignored
+        assert!(!scores.penalize(bad_peer, Misbehaviour::InvalidBlock));
+
+        // The ban list survives a restart
+        let reloaded = PeerScores::load();
+        assert!(reloaded.is_banned(&bad_peer));
+        assert_eq!(reloaded.banned_peers().count(), 1);
+    }
+
+    /// Handles a message from the peer the same way the network loop does, returning true if
+    /// it was accepted
+    fn receive(limiter: &mut RateLimiter, scores: &mut PeerScores, peer: PeerId) -> bool {
+        let allowed = limiter.allow(peer);
+        if !allowed {
+            scores.penalize(peer, Misbehaviour::RateLimited);
+        }
+        allowed
+    }
+
+    #[test]
+    fn rate_limiter_drops_bursts_beyond_limit() {
+        let _guard = setup();
+        let mut limiter = RateLimiter::new(5);
+        let mut scores = PeerScores::load();
+        let spammer = PeerId::random();
+        let well_behaved = PeerId::random();
+
+        let accepted = (0..20)
+            .filter(|_| receive(&mut limiter, &mut scores, spammer))
+            .count();
+        assert_eq!(accepted, 5);
+        assert!(receive(&mut limiter, &mut scores, well_behaved));
+
+        // Dropped messages count against the spammer's score, leading to a ban if sustained
+        assert!(!scores.is_banned(&spammer));
+        while !scores.is_banned(&spammer) {
+            receive(&mut limiter, &mut scores, spammer);
+        }
+        assert!(!scores.is_banned(&well_behaved));
+    }
+
+    #[test]
+    fn rate_limiter_refills_over_time() {
+        let mut limiter = RateLimiter::new(5);
+        let peer = PeerId::random();
+        while limiter.allow(peer) {}
+
+        // Half a second later, half the bucket has refilled
+        let bucket = limiter.buckets.get_mut(&peer).unwrap();
+        bucket.last_refill -= Duration::from_millis(500);
+        let accepted = (0..5).filter(|_| limiter.allow(peer)).count();
+        assert_eq!(accepted, 2);
+    }
+
+    #[test]
+    fn failed_dials_are_retried_with_backoff() {
+        let mut reconnect = ReconnectBackoff::new();
+        let mut dials = 0;
+
+        // Dials that leave the node without peers are retried after doubling delays
+        let mut delays = Vec::new();
+        for _ in 0..10 {
+            let due = reconnect.next_attempt();
+            assert!(reconnect.attempt(0, due, || dials += 1));
+            delays.push(reconnect.next_attempt() - due);
+        }
+        assert_eq!(dials, 10);
+        assert_eq!(delays[..4], [10, 20, 40, 80].map(Duration::from_secs));
+        assert_eq!(delays[9], MAX_RECONNECT_DELAY);
+
+        // Once enough peers are connected, nothing is dialed and the backoff starts over
+        let due = reconnect.next_attempt();
+        assert!(!reconnect.attempt(MIN_CONNECTED_PEERS, due, || dials += 1));
+        assert_eq!(dials, 10);
+        assert_eq!(reconnect.next_attempt(), due + MIN_RECONNECT_DELAY);
+        assert!(reconnect.attempt(0, reconnect.next_attempt(), || dials += 1));
+        assert_eq!(dials, 11);
+    }
+}
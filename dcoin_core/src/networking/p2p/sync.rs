@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{
+    blockchain::{
+        blocks::block::BlockHeader,
+        chain::{chain_work, get_last_block},
+        params::network_params,
+    },
+    cli::db::get_block,
+    networking::p2p::peers::MIN_CONNECTED_PEERS,
+};
+
+/// Delay between chainsync requests while the node is syncing
+const SYNC_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Sync is finished once the chain height hasn't changed for this long
+const SYNC_QUIET_PERIOD: Duration = Duration::from_secs(30);
+/// How long to wait for MIN_CONNECTED_PEERS before syncing from whichever peers are connected
+const SYNC_PEER_WAIT: Duration = Duration::from_secs(30);
+
+/// Max number of validated headers held while their chains are being downloaded. Once reached,
+/// all of them are dropped and requested again by a later sync
+const MAX_PENDING_HEADERS: usize = 100_000;
+
+/// InitialSync drives the chain sync run at startup. Chainsync requests are sent once the node
+/// has enough peers, and repeated until no new blocks have arrived for SYNC_QUIET_PERIOD
+pub struct InitialSync {
+    started_at: Instant,
+    next_check: Instant,
+    // Time the local height last changed, set once the first request is sent
+    last_progress: Option<Instant>,
+    local_height: u32,
+    best_known_height: u32,
+    done: bool,
+}
+
+/// SyncStep is what the node should do when a sync check is due
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncStep {
+    /// Not enough peers are connected yet
+    Wait,
+    /// Broadcast a chainsync request
+    Request,
+    /// The chain has stopped growing, so the sync is over
+    Done,
+}
+
+impl InitialSync {
+    pub fn new(local_height: u32) -> Self {
+        let now = Instant::now();
+        Self {
+            started_at: now,
+            next_check: now,
+            last_progress: None,
+            local_height,
+            best_known_height: local_height,
+            done: false,
+        }
+    }
+
+    /// Returns when the next sync check is due
+    pub fn next_check(&self) -> Instant {
+        self.next_check
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Returns the highest chain height announced by a peer, or the local height if higher
+    pub fn best_known_height(&self) -> u32 {
+        self.best_known_height
+    }
+
+    /// Records the chain height announced by a peer's chainsync response
+    pub fn observe_peer_height(&mut self, height: u32) {
+        self.best_known_height = self.best_known_height.max(height);
+    }
+
+    /// Decides the next sync step given the local chain height and number of connected peers,
+    /// and schedules the check after it
+    pub fn check(&mut self, local_height: u32, peer_count: usize, now: Instant) -> SyncStep {
+        self.next_check = now + SYNC_RETRY_INTERVAL;
+
+        let enough_peers = peer_count >= MIN_CONNECTED_PEERS
+            || (peer_count > 0 && now.duration_since(self.started_at) >= SYNC_PEER_WAIT);
+        if !enough_peers {
+            return SyncStep::Wait;
+        }
+
+        if local_height != self.local_height {
+            self.local_height = local_height;
+            self.last_progress = Some(now);
+        }
+        self.best_known_height = self.best_known_height.max(local_height);
+
+        match self.last_progress {
+            Some(last) if now.duration_since(last) >= SYNC_QUIET_PERIOD => {
+                self.done = true;
+                info!(
+                    "Chain sync finished at height {} (best known height {})",
+                    self.local_height, self.best_known_height
+                );
+                SyncStep::Done
+            }
+            _ => {
+                self.last_progress.get_or_insert(now);
+                info!(
+                    "Syncing chain: height {} of best known height {}, {} peers",
+                    self.local_height, self.best_known_height, peer_count
+                );
+                SyncStep::Request
+            }
+        }
+    }
+}
+
+/// HeaderStatus is the result of connecting a batch of headers to the known chain
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderStatus {
+    /// Every header was valid and has been added to the pending headers
+    Connected,
+    /// The first header's parent is unknown, so the batch can't be validated
+    UnknownParent,
+    /// A header failed its proof of work or doesn't follow its parent
+    Invalid,
+}
+
+/// HeaderSync holds the headers validated during a headers-first sync. Headers are downloaded
+/// in batches, each linking to the last, and their blocks are only requested once the whole
+/// header chain is in and proves more work than the local chain
+pub struct HeaderSync {
+    pending: HashMap<[u8; 32], BlockHeader>,
+}
+
+impl HeaderSync {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Validates a batch of consecutive headers, ordered from lowest to highest, and adds them to
+    /// the pending headers. The first header must extend a stored block or a pending header
+    pub fn connect(&mut self, headers: &[BlockHeader]) -> Result<HeaderStatus, Box<dyn Error>> {
+        let Some(first) = headers.first() else {
+            return Ok(HeaderStatus::Connected);
+        };
+
+        let mut parent = match self.pending.get(&first.prev_hash) {
+            Some(header) => Some(header.clone()),
+            None => get_block(&first.prev_hash)?.map(|block| block.header()),
+        };
+        if parent.is_none() && first.height != 0 {
+            return Ok(HeaderStatus::UnknownParent);
+        }
+
+        for header in headers {
+            let follows_parent = match &parent {
+                Some(parent) => header.extends(parent),
+                // Only a genesis header may have no parent
+                None => header.height == 0 && header.prev_hash == [0u8; 32],
+            };
+            if !follows_parent
+                || !header.verify_pow()
+                || header.is_too_far_in_future()
+                || !network_params().matches_checkpoint(header.height, &header.hash)
+            {
+                return Ok(HeaderStatus::Invalid);
+            }
+            parent = Some(header.clone());
+        }
+
+        if self.pending.len() + headers.len() > MAX_PENDING_HEADERS {
+            warn!("Too many pending headers, dropping them until the next sync");
+            self.pending.clear();
+        }
+        for header in headers {
+            self.pending.insert(header.hash, header.clone());
+        }
+        Ok(HeaderStatus::Connected)
+    }
+
+    /// Removes the pending header chain ending at the given hash, returning it ordered from lowest
+    /// to highest. The first header's parent is a stored block, or it is the genesis header
+    pub fn take_chain(&mut self, tip: [u8; 32]) -> Vec<BlockHeader> {
+        let mut chain = Vec::new();
+        let mut hash = tip;
+        while let Some(header) = self.pending.remove(&hash) {
+            hash = header.prev_hash;
+            chain.push(header);
+        }
+        chain.reverse();
+        chain
+    }
+}
+
+/// Returns true if the chain ending with the given headers, ordered from lowest to highest, has
+/// more cumulative work than the local chain
+pub fn has_more_work(headers: &[BlockHeader]) -> Result<bool, Box<dyn Error>> {
+    let Some(first) = headers.first() else {
+        return Ok(false);
+    };
+    let parent_work = match get_block(&first.prev_hash)? {
+        Some(parent) => chain_work(&parent)?,
+        None => 0,
+    };
+    let work = headers.iter().fold(parent_work, |work, header| {
+        work.saturating_add(header.work())
+    });
+
+    let local_work = match get_last_block() {
+        Ok(tip) => chain_work(&tip)?,
+        // A node with no chain yet has no work to compare against
+        Err(_) => 0,
+    };
+    Ok(work > local_work)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_waits_for_peers() {
+        let mut sync = InitialSync::new(0);
+        let start = sync.started_at;
+
+        assert_eq!(sync.check(0, 0, start), SyncStep::Wait);
+        assert_eq!(sync.check(0, 1, start), SyncStep::Wait);
+        assert_eq!(sync.next_check(), start + SYNC_RETRY_INTERVAL);
+        // A single peer is enough once the node has waited long enough for more
+        assert_eq!(sync.check(0, 1, start + SYNC_PEER_WAIT), SyncStep::Request);
+        // No peers at all is never enough
+        let mut sync = InitialSync::new(0);
+        assert_eq!(
+            sync.check(0, 0, sync.started_at + SYNC_PEER_WAIT),
+            SyncStep::Wait
+        );
+    }
+
+    #[test]
+    fn check_finishes_once_height_stops_changing() {
+        let mut sync = InitialSync::new(5);
+        let start = sync.started_at;
+
+        assert_eq!(sync.check(5, MIN_CONNECTED_PEERS, start), SyncStep::Request);
+        // New blocks push back the end of the sync
+        let progress = start + SYNC_QUIET_PERIOD / 2;
+        assert_eq!(
+            sync.check(8, MIN_CONNECTED_PEERS, progress),
+            SyncStep::Request
+        );
+        assert_eq!(
+            sync.check(8, MIN_CONNECTED_PEERS, start + SYNC_QUIET_PERIOD),
+            SyncStep::Request
+        );
+        assert!(!sync.is_done());
+
+        assert_eq!(
+            sync.check(8, MIN_CONNECTED_PEERS, progress + SYNC_QUIET_PERIOD),
+            SyncStep::Done
+        );
+        assert!(sync.is_done());
+    }
+}
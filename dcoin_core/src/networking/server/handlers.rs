@@ -1,27 +1,52 @@
 use crate::{
     blockchain::{
-        chain::get_blockchain_json,
+        blocks::block::{get_target_difficulty, Block},
+        chain::{
+            get_block_by_height, get_block_with_tx, get_chain_height, get_chain_page,
+            subscribe_new_blocks, BlockJson, ChainPage,
+        },
+        error::ChainError,
+        params::network_params,
         transaction::{
-            mempool::add_tx_to_mempool,
-            tx::TxVerify,
-            utxo::{find_spendable_utxos, find_utxos_for_addr, reindex_utxos},
+            fee::{
+                estimate_fee, FeeEstimate, DEFAULT_FEE_ESTIMATE_BLOCKS, MAX_FEE_ESTIMATE_BLOCKS,
+            },
+            mempool::{add_tx_to_mempool, Mempool, MempoolSpends},
+            tx::{get_tx_fee, TxVerify},
+            utxo::{find_outpoints_for_addr, find_outpoints_for_addrs, find_spendable_utxos},
         },
     },
-    networking::p2p::network::{NewInventory, P2Prx},
+    cli::db::{get_block, get_last_hash, get_mempool, get_mempool_spends},
+    networking::{
+        node::Node,
+        p2p::network::{NewInventory, P2Prx},
+    },
 };
 
 use axum::{
     extract::{Path, Query, State},
     http::StatusCode,
-    response::{IntoResponse, Json, Response},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json, Response,
+    },
 };
 use core_lib::{
-    address::Address,
-    req_types::{convert_utxoset_to_json, GetUTXORes, TxJson, UTXOSetJson},
+    address::{hash_pub_key, Address},
+    req_types::{
+        convert_utxoset_to_json, GetAllUTXORes, GetBalanceRes, GetBalancesRes, GetUTXORes,
+        OutpointJson, SendRawTxReq, TxFeeRes, TxJson, UTXOSetJson, VerifyMessageReq,
+        VerifyMessageRes,
+    },
+    tx::{Tx, TxOutput},
+    wallet::verify_message,
 };
+use log::debug;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::sync::mpsc::Sender;
+use std::{convert::Infallible, error::Error};
+use tokio::sync::{mpsc::Sender, oneshot};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
 
 pub async fn handle_root() -> Result<Json<serde_json::Value>, StatusCode> {
     Ok(Json(json!({
@@ -33,24 +58,38 @@ pub async fn handle_root() -> Result<Json<serde_json::Value>, StatusCode> {
 pub async fn handle_health_check(
     tx: State<Sender<P2Prx>>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    println!("Received health check request...");
-    println!("HTTP Channel sending msg to p2p server...");
-    tx.send(P2Prx::HealthCheck())
+    debug!("Received health check request...");
+    debug!("HTTP Channel sending msg to p2p server...");
+    let (reply_tx, reply_rx) = oneshot::channel();
+    tx.send(P2Prx::HealthCheck(reply_tx))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let p2p = reply_rx
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    // A node without peers can still serve the api, but can't relay txs or follow the chain
+    let (status, msg) = if p2p.peer_count == 0 {
+        ("degraded", "Service is degraded - no connected peers")
+    } else {
+        ("healthy", "Service is healthy")
+    };
+
     Ok(Json(json!({
-        "msg": "Service is healthy",
+        "msg": msg,
+        "status": status,
         "categories": {
-            "p2p": "healthy",
+            "p2p": status,
             "api": "healthy"
         },
+        "peer_count": p2p.peer_count,
+        "listen_addrs": p2p.listen_addrs,
     })))
 }
 
 pub async fn handle_get_wallet_balance(
     Path(addr): Path<String>,
-) -> Result<Json<serde_json::Value>, ErrorResponse> {
+) -> Result<Json<GetBalanceRes>, ErrorResponse> {
     let wallet_addr: Address = match Address::new_from_str(&addr) {
         Ok(addr) => addr,
         Err(e) => {
@@ -61,26 +100,188 @@ pub async fn handle_get_wallet_balance(
         }
     };
 
-    // TODO: remove reindexing - shouldn't be required for running nodes
-    reindex_utxos().map_err(|e| ErrorResponse {
+    let pub_key_hash = *wallet_addr.pub_key_hash();
+    let outpoints = scan_utxos(move || find_outpoints_for_addr(&pub_key_hash)).await?;
+    let mempool = get_mempool();
+
+    Ok(Json(address_balance(
+        addr,
+        &pub_key_hash,
+        &outpoints,
+        &mempool,
+        &get_mempool_spends(),
+    )))
+}
+
+/// MAX_BALANCE_ADDRESSES is the max number of addresses accepted by a single /balances request
+const MAX_BALANCE_ADDRESSES: usize = 1000;
+
+/// Returns the balances of several addresses, found in a single pass over the utxo set
+pub async fn handle_get_balances(
+    Json(addrs): Json<Vec<String>>,
+) -> Result<Json<GetBalancesRes>, ErrorResponse> {
+    if addrs.len() > MAX_BALANCE_ADDRESSES {
+        return Err(ErrorResponse {
+            code: StatusCode::BAD_REQUEST.as_u16(),
+            error: format!(
+                "at most {} addresses can be requested at once",
+                MAX_BALANCE_ADDRESSES
+            ),
+        });
+    }
+
+    let mut pub_key_hashes = Vec::with_capacity(addrs.len());
+    for addr in &addrs {
+        let wallet_addr = Address::new_from_str(addr).map_err(|e| ErrorResponse {
+            code: StatusCode::BAD_REQUEST.as_u16(),
+            error: format!("invalid address {}: {}", addr, e),
+        })?;
+        pub_key_hashes.push(*wallet_addr.pub_key_hash());
+    }
+
+    let scan_hashes = pub_key_hashes.clone();
+    let outpoints = scan_utxos(move || find_outpoints_for_addrs(&scan_hashes)).await?;
+    let mempool = get_mempool();
+    let pending_spends = get_mempool_spends();
+
+    let balances = addrs
+        .into_iter()
+        .zip(&pub_key_hashes)
+        .map(|(addr, pub_key_hash)| {
+            let addr_outpoints = outpoints.get(pub_key_hash).map_or(&[][..], Vec::as_slice);
+            let balance = address_balance(
+                addr.clone(),
+                pub_key_hash,
+                addr_outpoints,
+                &mempool,
+                &pending_spends,
+            );
+            (addr, balance)
+        })
+        .collect();
+    Ok(Json(balances))
+}
+
+/// Runs a utxo scan on tokio's blocking thread pool. Scans iterate the whole utxo set, so
+/// running them on an async worker would stall other requests until they finish
+pub(crate) async fn scan_utxos<T, F>(scan: F) -> Result<T, ErrorResponse>
+where
+    F: FnOnce() -> Result<T, ChainError> + Send + 'static,
+    T: Send + 'static,
+{
+    let res = tokio::task::spawn_blocking(scan)
+        .await
+        .map_err(|e| ErrorResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            error: format!("utxo scan failed: {}", e),
+        })?;
+    Ok(res?)
+}
+
+/// Builds the balance of an address from its confirmed outpoints and the txs pending in the mempool
+fn address_balance(
+    address: String,
+    pub_key_hash: &[u8; 20],
+    outpoints: &[(([u8; 32], u32), TxOutput)],
+    mempool: &Mempool,
+    pending_spends: &MempoolSpends,
+) -> GetBalanceRes {
+    let mut confirmed: u64 = 0;
+    let mut pending_outgoing: u64 = 0;
+    for (outpoint, utxo) in outpoints {
+        confirmed += u64::from(utxo.value);
+        if pending_spends.contains_key(outpoint) {
+            pending_outgoing += u64::from(utxo.value);
+        }
+    }
+
+    // Outputs paying this address from pending txs, including change from its own txs
+    let pending_incoming: u64 = mempool
+        .values()
+        .flat_map(|entry| entry.tx.outputs.iter())
+        .filter(|output| output.is_locked_with_key(pub_key_hash))
+        .map(|output| u64::from(output.value))
+        .sum();
+
+    GetBalanceRes {
+        address,
+        confirmed,
+        pending_outgoing,
+        pending_incoming,
+    }
+}
+
+/// Returns the peers the node is currently connected to
+pub async fn handle_get_peers(
+    p2p: State<Sender<P2Prx>>,
+) -> Result<Json<serde_json::Value>, ErrorResponse> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    p2p.send(P2Prx::GetPeers(reply_tx))
+        .await
+        .map_err(|e| ErrorResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            error: format!("failed to send msg to p2p server: {}", e),
+        })?;
+    let peers = reply_rx.await.map_err(|e| ErrorResponse {
         code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-        error: e.to_string(),
+        error: format!("p2p server did not respond: {}", e),
     })?;
 
-    let utxos = find_utxos_for_addr(wallet_addr.pub_key_hash());
+    Ok(Json(json!({
+        "count": peers.len(),
+        "peers": peers,
+    })))
+}
 
-    let mut balance = 0;
+/// Returns a summary of the node - the chain tip, mempool size, PoW difficulty and peer id
+pub async fn handle_get_info() -> Result<Json<serde_json::Value>, ErrorResponse> {
+    let internal_err = |e: Box<dyn Error>| ErrorResponse {
+        code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+        error: e.to_string(),
+    };
+    let height = get_chain_height().map_err(internal_err)?;
+    let tip_hash = get_last_hash().map_err(internal_err)?;
 
-    for utxo in utxos {
-        balance += utxo.value;
-    }
+    Ok(Json(json!({
+        "height": height,
+        "tip_hash": hex::encode(tip_hash),
+        "mempool_size": get_mempool().len(),
+        "difficulty": network_params().difficulty,
+        "network": network_params().network,
+        "peer_id": Node::get_or_create_keys().get_peer_id().to_string(),
+    })))
+}
 
+/// Returns the current PoW difficulty, along with the target block hashes must be below
+pub async fn handle_get_difficulty() -> Result<Json<serde_json::Value>, ErrorResponse> {
     Ok(Json(json!({
-        "address": addr,
-        "balance": balance
+        "difficulty": network_params().difficulty,
+        "target": hex::encode(get_target_difficulty()),
     })))
 }
 
+#[derive(Deserialize)]
+pub struct FeeEstimateQuery {
+    blocks: Option<u32>,
+}
+/// Suggests fees per byte based on the fee rates paid by txs in the last `blocks` blocks
+pub async fn handle_get_fee_estimate(
+    Query(params): Query<FeeEstimateQuery>,
+) -> Result<Json<FeeEstimate>, ErrorResponse> {
+    let blocks = params
+        .blocks
+        .unwrap_or(DEFAULT_FEE_ESTIMATE_BLOCKS)
+        .clamp(1, MAX_FEE_ESTIMATE_BLOCKS);
+
+    match estimate_fee(blocks) {
+        Ok(estimate) => Ok(Json(estimate)),
+        Err(e) => Err(ErrorResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            error: e.to_string(),
+        }),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct UTXOQuery {
     address: String,
@@ -99,19 +300,42 @@ pub async fn handle_get_spendable_utxos(
         }
     };
 
-    let spendable_utxos = match find_spendable_utxos(wallet_addr.pub_key_hash(), params.amount) {
-        Ok(map) => map,
+    let pub_key_hash = *wallet_addr.pub_key_hash();
+    let spendable_utxos =
+        scan_utxos(move || find_spendable_utxos(&pub_key_hash, params.amount)).await?;
+
+    let utxos: UTXOSetJson = convert_utxoset_to_json(&spendable_utxos);
+    Ok(Json(GetUTXORes {
+        address: wallet_addr.get_full_address(),
+        utxos,
+    }))
+}
+
+pub async fn handle_get_all_utxos(
+    Path(addr): Path<String>,
+) -> Result<Json<GetAllUTXORes>, ErrorResponse> {
+    let wallet_addr: Address = match Address::new_from_str(&addr) {
+        Ok(addr) => addr,
         Err(e) => {
             return Err(ErrorResponse {
-                // Add check for not enough funds, should be bad request
-                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                code: StatusCode::BAD_REQUEST.as_u16(),
                 error: e.to_string(),
-            });
+            })
         }
     };
 
-    let utxos: UTXOSetJson = convert_utxoset_to_json(&spendable_utxos);
-    Ok(Json(GetUTXORes {
+    let pub_key_hash = *wallet_addr.pub_key_hash();
+    let utxos = scan_utxos(move || find_outpoints_for_addr(&pub_key_hash))
+        .await?
+        .into_iter()
+        .map(|((tx_id, out_idx), tx_out)| OutpointJson {
+            tx_id: hex::encode(tx_id),
+            out_idx,
+            value: tx_out.value,
+        })
+        .collect();
+
+    Ok(Json(GetAllUTXORes {
         address: wallet_addr.get_full_address(),
         utxos,
     }))
@@ -120,12 +344,95 @@ pub async fn handle_get_spendable_utxos(
 #[derive(Deserialize)]
 pub struct ChainQuery {
     show_txs: Option<bool>,
+    // Look up the value and address spent by each tx input
+    resolve_inputs: Option<bool>,
+}
+
+/// DEFAULT_CHAIN_PAGE_LIMIT is the number of blocks returned by /chain when no limit is given
+const DEFAULT_CHAIN_PAGE_LIMIT: usize = 50;
+/// MAX_CHAIN_PAGE_LIMIT is the max number of blocks returned by a single /chain request
+const MAX_CHAIN_PAGE_LIMIT: usize = 500;
+
+#[derive(Deserialize)]
+pub struct ChainPageQuery {
+    show_txs: Option<bool>,
+    resolve_inputs: Option<bool>,
+    from_height: Option<u32>,
+    limit: Option<usize>,
 }
+/// Returns a page of main chain blocks, walking back from `from_height` or the last block.
+/// The response includes the height to request the next page from
 pub async fn handle_get_chain(
+    Query(params): Query<ChainPageQuery>,
+) -> Result<Json<ChainPage>, ErrorResponse> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_CHAIN_PAGE_LIMIT)
+        .clamp(1, MAX_CHAIN_PAGE_LIMIT);
+
+    match get_chain_page(
+        params.from_height,
+        limit,
+        params.show_txs.unwrap_or(false),
+        params.resolve_inputs.unwrap_or(false),
+    ) {
+        Ok(Some(page)) => Ok(Json(page)),
+        Ok(None) => Err(ChainError::NotFound(format!(
+            "block at height {}",
+            params.from_height.unwrap_or_default()
+        ))
+        .into()),
+        Err(e) => Err(ErrorResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            error: e.to_string(),
+        }),
+    }
+}
+
+/// Builds the JSON served for a single block, with its confirmations and, if requested, the
+/// values and addresses spent by its tx inputs
+fn block_json(block: &Block, params: &ChainQuery) -> Result<BlockJson, Box<dyn Error>> {
+    let json =
+        BlockJson::from_block(block, params.show_txs.unwrap_or(true)).with_confirmations(block)?;
+    if params.resolve_inputs.unwrap_or(false) {
+        return json.with_resolved_inputs(block);
+    }
+    Ok(json)
+}
+
+pub async fn handle_get_block(
+    Path(hash): Path<String>,
     Query(params): Query<ChainQuery>,
-) -> Result<Json<serde_json::Value>, ErrorResponse> {
-    match get_blockchain_json(params.show_txs.unwrap_or(false)) {
-        Ok(blocks) => Ok(Json(json!(blocks))),
+) -> Result<Json<BlockJson>, ErrorResponse> {
+    let block_hash = decode_hash(&hash)?;
+
+    match get_block(&block_hash) {
+        Ok(Some(block)) => block_json(&block, &params)
+            .map(Json)
+            .map_err(|e| ErrorResponse {
+                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                error: e.to_string(),
+            }),
+        Ok(None) => Err(ChainError::NotFound(format!("block {}", hash)).into()),
+        Err(e) => Err(ErrorResponse {
+            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            error: e.to_string(),
+        }),
+    }
+}
+
+pub async fn handle_get_block_by_height(
+    Path(height): Path<u32>,
+    Query(params): Query<ChainQuery>,
+) -> Result<Json<BlockJson>, ErrorResponse> {
+    match get_block_by_height(height) {
+        Ok(Some(block)) => block_json(&block, &params)
+            .map(Json)
+            .map_err(|e| ErrorResponse {
+                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                error: e.to_string(),
+            }),
+        Ok(None) => Err(ChainError::NotFound(format!("block at height {}", height)).into()),
         Err(e) => Err(ErrorResponse {
             code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
             error: e.to_string(),
@@ -133,6 +440,38 @@ pub async fn handle_get_chain(
     }
 }
 
+#[derive(Deserialize)]
+pub struct MempoolQuery {
+    full: Option<bool>,
+}
+/// Returns the ids of all pending txs in the mempool, or the full txs if requested
+pub async fn handle_get_mempool(
+    Query(params): Query<MempoolQuery>,
+) -> Result<Json<serde_json::Value>, ErrorResponse> {
+    let mempool = get_mempool();
+
+    if params.full.unwrap_or(false) {
+        let txs = mempool
+            .values()
+            .map(|entry| TxJson::from_tx(&entry.tx))
+            .collect::<Result<Vec<TxJson>, _>>()
+            .map_err(|e| ErrorResponse {
+                code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                error: e.to_string(),
+            })?;
+        Ok(Json(json!(txs)))
+    } else {
+        let tx_ids: Vec<String> = mempool.keys().map(hex::encode).collect();
+        Ok(Json(json!(tx_ids)))
+    }
+}
+
+pub async fn handle_get_mempool_count() -> Result<Json<serde_json::Value>, ErrorResponse> {
+    Ok(Json(json!({
+        "count": get_mempool().len()
+    })))
+}
+
 pub async fn handle_send_tx(
     p2p: State<Sender<P2Prx>>,
     Json(payload): Json<TxJson>,
@@ -141,25 +480,58 @@ pub async fn handle_send_tx(
         code: StatusCode::BAD_REQUEST.as_u16(),
         error: e.to_string(),
     })?;
+    submit_tx(p2p, tx).await
+}
 
-    //TODO: deprecate all reindex utxos
-    reindex_utxos().map_err(|e| ErrorResponse {
-        code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+/// Same as `handle_send_tx`, but takes the tx in its raw hex-encoded bincode form
+pub async fn handle_send_raw_tx(
+    p2p: State<Sender<P2Prx>>,
+    Json(payload): Json<SendRawTxReq>,
+) -> Result<Json<serde_json::Value>, ErrorResponse> {
+    let tx = payload.to_tx().map_err(|e| ErrorResponse {
+        code: StatusCode::BAD_REQUEST.as_u16(),
         error: e.to_string(),
     })?;
+    submit_tx(p2p, tx).await
+}
 
-    tx.verify().map_err(|e| ErrorResponse {
+/// Returns the fee a tx would pay, without verifying or broadcasting it. Every input must
+/// spend an existing utxo
+pub async fn handle_get_tx_fee(
+    Json(payload): Json<TxJson>,
+) -> Result<Json<TxFeeRes>, ErrorResponse> {
+    let tx = payload.to_tx().map_err(|e| ErrorResponse {
         code: StatusCode::BAD_REQUEST.as_u16(),
         error: e.to_string(),
     })?;
+    let fee = get_tx_fee(&tx).map_err(|e| ErrorResponse {
+        code: StatusCode::BAD_REQUEST.as_u16(),
+        error: e.to_string(),
+    })?;
+
+    Ok(Json(TxFeeRes {
+        fee,
+        fee_rate: f64::from(fee) / tx.size() as f64,
+        size: tx.size(),
+    }))
+}
 
-    add_tx_to_mempool(&tx).map_err(|e| ErrorResponse {
+/// Verifies a tx received over http, adds it to the mempool and announces it to peers
+async fn submit_tx(
+    p2p: State<Sender<P2Prx>>,
+    tx: Tx,
+) -> Result<Json<serde_json::Value>, ErrorResponse> {
+    let valid = tx.verify().map_err(|e| ErrorResponse {
         code: StatusCode::BAD_REQUEST.as_u16(),
         error: e.to_string(),
     })?;
+    if !valid {
+        return Err(ChainError::InvalidTx("tx failed verification".to_string()).into());
+    }
 
-    let _ = p2p
-        .send(P2Prx::BroadcastNewInv(NewInventory::Transaction(tx.id)))
+    add_tx_to_mempool(&tx)?;
+
+    p2p.send(P2Prx::BroadcastNewInv(NewInventory::Transaction(tx.id)))
         .await
         .map_err(|e| ErrorResponse {
             code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
@@ -178,6 +550,133 @@ pub async fn handle_send_tx(
     })))
 }
 
+/// Checks a signed message proving ownership of an address. The proof is valid if the pub key
+/// hashes to the address and signed the message
+pub async fn handle_verify_message(
+    Json(payload): Json<VerifyMessageReq>,
+) -> Result<Json<VerifyMessageRes>, ErrorResponse> {
+    let (address, pub_key, signature) = payload.decode().map_err(|e| ErrorResponse {
+        code: StatusCode::BAD_REQUEST.as_u16(),
+        error: e.to_string(),
+    })?;
+
+    let valid = hash_pub_key(&pub_key) == *address.pub_key_hash()
+        && verify_message(&pub_key, payload.message.as_bytes(), &signature);
+    Ok(Json(VerifyMessageRes { valid }))
+}
+
+/// Returns the status of a tx - pending if it's in the mempool, confirmed if it's been
+/// included in a block, or unknown otherwise
+pub async fn handle_get_tx_status(
+    Path(tx_id): Path<String>,
+) -> Result<Json<serde_json::Value>, ErrorResponse> {
+    let tx_id = decode_hash(&tx_id)?;
+
+    if get_mempool().contains_key(&tx_id) {
+        return Ok(Json(json!({
+            "tx_id": hex::encode(tx_id),
+            "status": "pending",
+        })));
+    }
+
+    let internal_err = |e: Box<dyn std::error::Error>| ErrorResponse {
+        code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+        error: e.to_string(),
+    };
+
+    match get_block_with_tx(tx_id).map_err(internal_err)? {
+        Some(block) => {
+            let chain_height = get_chain_height().map_err(internal_err)?;
+            Ok(Json(json!({
+                "tx_id": hex::encode(tx_id),
+                "status": "confirmed",
+                "block_hash": hex::encode(block.hash),
+                "block_height": block.height,
+                // A reorg may drop the tip below the block, so the depth saturates
+                "confirmations": (chain_height + 1).saturating_sub(block.height),
+            })))
+        }
+        None => Ok(Json(json!({
+            "tx_id": hex::encode(tx_id),
+            "status": "unknown",
+        }))),
+    }
+}
+
+/// Returns a Merkle proof that a tx is included in a block of the chain, allowing
+/// light clients to verify the tx against the block's Merkle root
+pub async fn handle_get_tx_proof(
+    Path(tx_id): Path<String>,
+) -> Result<Json<serde_json::Value>, ErrorResponse> {
+    let tx_id = decode_hash(&tx_id)?;
+
+    let internal_err = |e: Box<dyn std::error::Error>| ErrorResponse {
+        code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+        error: e.to_string(),
+    };
+
+    let block = get_block_with_tx(tx_id)
+        .map_err(internal_err)?
+        .ok_or_else(|| ChainError::NotFound(format!("tx {} in chain", hex::encode(tx_id))))?;
+
+    // Merkle leaves are built from the tx hashes, which include the tx signatures
+    let tx_hash = block
+        .txs
+        .iter()
+        .find(|tx| tx.id == tx_id)
+        .map(|tx| tx.hash())
+        .transpose()
+        .map_err(internal_err)?
+        .ok_or_else(|| ErrorResponse {
+            code: StatusCode::NOT_FOUND.as_u16(),
+            error: "[handlers::handle_get_tx_proof] ERROR: Tx not found in block".to_string(),
+        })?;
+
+    let tree = block.merkle_tree().map_err(internal_err)?;
+    let proof = tree.generate_proof(&tx_hash).ok_or_else(|| ErrorResponse {
+        code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+        error: "[handlers::handle_get_tx_proof] ERROR: Failed to generate proof".to_string(),
+    })?;
+
+    Ok(Json(json!({
+        "tx_id": hex::encode(tx_id),
+        "tx_hash": hex::encode(tx_hash),
+        "block_hash": hex::encode(block.hash),
+        "block_height": block.height,
+        "merkle_root": hex::encode(block.merkle_root),
+        "proof": proof
+            .iter()
+            .map(|(is_left, hash)| json!({ "is_left": is_left, "hash": hex::encode(hash) }))
+            .collect::<Vec<_>>(),
+    })))
+}
+
+/// Streams a server-sent event for every new block added to the chain
+pub async fn handle_block_events() -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = BroadcastStream::new(subscribe_new_blocks()).filter_map(|res| match res {
+        Ok(block_event) => Event::default()
+            .event("block")
+            .json_data(block_event)
+            .ok()
+            .map(Ok),
+        // Subscribers that lag behind skip the missed events rather than closing the stream
+        Err(_) => None,
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Decodes a hex-encoded 32 byte hash from a request
+fn decode_hash(hash: &str) -> Result<[u8; 32], ErrorResponse> {
+    hex::decode(hash)
+        .ok()
+        .and_then(|h| h.try_into().ok())
+        .ok_or_else(|| ErrorResponse {
+            code: StatusCode::BAD_REQUEST.as_u16(),
+            error: format!("[handlers::decode_hash] ERROR: Invalid hash {}", hash),
+        })
+}
+
 #[derive(Serialize, Debug)]
 pub struct ErrorResponse {
     pub error: String,
@@ -185,7 +684,45 @@ pub struct ErrorResponse {
 }
 impl IntoResponse for ErrorResponse {
     fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
         let json_body = Json(json!(self));
-        (StatusCode::INTERNAL_SERVER_ERROR, json_body).into_response()
+        (status, json_body).into_response()
+    }
+}
+
+impl From<ChainError> for ErrorResponse {
+    fn from(e: ChainError) -> Self {
+        let code = match e {
+            ChainError::InsufficientFunds { .. } | ChainError::InvalidTx(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ChainError::NotFound(_) => StatusCode::NOT_FOUND,
+            ChainError::Db(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ErrorResponse {
+            code: code.as_u16(),
+            error: e.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_errors_map_to_status_codes() {
+        let code = |e: ChainError| ErrorResponse::from(e).code;
+
+        assert_eq!(
+            code(ChainError::InsufficientFunds {
+                available: 10,
+                required: 20
+            }),
+            400
+        );
+        assert_eq!(code(ChainError::InvalidTx("bad".to_string())), 400);
+        assert_eq!(code(ChainError::NotFound("block".to_string())), 404);
+        assert_eq!(code(ChainError::Db("closed".to_string())), 500);
     }
 }
@@ -3,14 +3,19 @@ use axum::{
     routing::{get, post},
     Router,
 };
+use log::info;
 use tokio::{net::TcpListener, sync::mpsc::Sender};
 use tower_http::cors::{Any, CorsLayer};
 
 use crate::networking::p2p::network::P2Prx;
 
 use super::handlers::{
-    handle_get_chain, handle_get_spendable_utxos, handle_get_wallet_balance, handle_health_check,
-    handle_root, handle_send_tx,
+    handle_block_events, handle_get_all_utxos, handle_get_balances, handle_get_block,
+    handle_get_block_by_height, handle_get_chain, handle_get_difficulty, handle_get_fee_estimate,
+    handle_get_info, handle_get_mempool, handle_get_mempool_count, handle_get_peers,
+    handle_get_spendable_utxos, handle_get_tx_fee, handle_get_tx_proof, handle_get_tx_status,
+    handle_get_wallet_balance, handle_health_check, handle_root, handle_send_raw_tx,
+    handle_send_tx, handle_verify_message,
 };
 
 pub async fn start_rest_api(tx: Sender<P2Prx>, port: Option<u16>) {
@@ -19,7 +24,7 @@ pub async fn start_rest_api(tx: Sender<P2Prx>, port: Option<u16>) {
     let addr = format!("0.0.0.0:{}", port);
     let router = create_router(tx.clone());
     let listener = TcpListener::bind(&addr).await.unwrap();
-    println!("REST API listening on port {port}");
+    info!("REST API listening on port {port}");
     axum::serve(listener, router.into_make_service())
         .await
         .unwrap();
@@ -34,10 +39,804 @@ fn create_router(p2p: Sender<P2Prx>) -> Router {
     Router::new()
         .route("/", get(handle_root))
         .route("/health", get(handle_health_check))
+        .route("/info", get(handle_get_info))
+        .route("/difficulty", get(handle_get_difficulty))
+        .route("/fee/estimate", get(handle_get_fee_estimate))
+        .route("/peers", get(handle_get_peers))
         .route("/wallet/balance/{addr}", get(handle_get_wallet_balance))
+        .route("/balances", post(handle_get_balances))
         .route("/utxo", get(handle_get_spendable_utxos))
+        .route("/utxo/all/{addr}", get(handle_get_all_utxos))
         .route("/chain", get(handle_get_chain))
+        .route("/block/{hash}", get(handle_get_block))
+        .route("/block/height/{height}", get(handle_get_block_by_height))
+        .route("/mempool", get(handle_get_mempool))
+        .route("/mempool/count", get(handle_get_mempool_count))
+        .route("/events/blocks", get(handle_block_events))
         .route("/tx/send", post(handle_send_tx))
+        .route("/tx/send-raw", post(handle_send_raw_tx))
+        .route("/tx/fee", post(handle_get_tx_fee))
+        .route("/tx/{id}", get(handle_get_tx_status))
+        .route("/tx/{id}/proof", get(handle_get_tx_proof))
+        .route("/verify-message", post(handle_verify_message))
         .with_state(p2p)
         .layer(cors)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, sync::atomic::Ordering, time::Duration};
+
+    use core_lib::{
+        req_types::{
+            GetBalanceRes, GetBalancesRes, SendRawTxReq, TxFeeRes, TxJson, VerifyMessageReq,
+        },
+        tx::Tx,
+        wallet::Wallet,
+    };
+    use libp2p::{futures::StreamExt, noise, swarm::dummy, tcp, yamux, Multiaddr, SwarmBuilder};
+    use serde::Serialize;
+    use serde_json::Value;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        blockchain::{
+            blocks::block::get_target_difficulty,
+            chain::get_last_block,
+            params::network_params,
+            transaction::{
+                mempool::add_tx_to_mempool,
+                tx::block_reward,
+                utxo::{find_outpoints_for_addr, find_spendable_utxos},
+            },
+        },
+        cli::db::{self, get_mempool},
+        mining::miner::{mine_block, CANCEL_MINING},
+        networking::server::handlers::scan_utxos,
+        test_utils::{add_block, new_chain, next_block, pay, setup, start_node},
+    };
+
+    /// Runs the future to completion on a new runtime. Tests hold the db guard outside of it,
+    /// since a std mutex guard must not be held across an await
+    fn run<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    /// Serves the router on a random local port, returning its base url
+    async fn serve(p2p: Sender<P2Prx>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, create_router(p2p).into_make_service())
+                .await
+                .unwrap()
+        });
+        format!("http://{}", addr)
+    }
+
+    /// Sends a GET request, returning the response status and JSON body
+    async fn get(url: String) -> (u16, Value) {
+        let res = reqwest::get(url).await.unwrap();
+        (res.status().as_u16(), res.json().await.unwrap())
+    }
+
+    /// Sends a POST request with a JSON body, returning the response status and JSON body
+    async fn post<T: Serialize>(url: String, body: &T) -> (u16, Value) {
+        let res = reqwest::Client::new()
+            .post(url)
+            .json(body)
+            .send()
+            .await
+            .unwrap();
+        (res.status().as_u16(), res.json().await.unwrap())
+    }
+
+    /// Requests spendable utxos of the given amount, returning the status and body
+    async fn get_utxos(url: &str, address: &str, amount: u32) -> (u16, Value) {
+        get(format!(
+            "{}/utxo?address={}&amount={}",
+            url, address, amount
+        ))
+        .await
+    }
+
+    /// Connects a bare peer to the node at the given address, returning its peer id
+    fn connect_peer(node_addr: Multiaddr) -> String {
+        let mut peer = SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )
+            .unwrap()
+            .with_behaviour(|_| dummy::Behaviour)
+            .unwrap()
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+        let peer_id = peer.local_peer_id().to_string();
+        peer.dial(node_addr).unwrap();
+        tokio::spawn(async move {
+            loop {
+                peer.select_next_some().await;
+            }
+        });
+        peer_id
+    }
+
+    #[test]
+    fn info_reports_chain_tip() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        for _ in 0..3 {
+            add_block(&miner, vec![]);
+        }
+        let tip = get_last_block().unwrap();
+
+        let info: Value = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            reqwest::get(format!("{}/info", url))
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap()
+        });
+
+        assert_eq!(info["height"], 3);
+        assert_eq!(info["tip_hash"], hex::encode(tip.hash));
+    }
+
+    #[test]
+    fn peers_lists_connected_peer() {
+        let _guard = setup();
+        new_chain();
+
+        run(async {
+            let (p2p_tx, node_addr) = start_node().await;
+
+            let peer_id = connect_peer(node_addr);
+
+            // The connection is registered by the p2p event loop, so poll until it shows up
+            let url = serve(p2p_tx).await;
+            for _ in 0..100 {
+                let peers: Value = reqwest::get(format!("{}/peers", url))
+                    .await
+                    .unwrap()
+                    .json()
+                    .await
+                    .unwrap();
+                let listed = peers["peers"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|p| p["peer_id"] == peer_id.as_str());
+                if listed {
+                    assert_eq!(peers["count"], 1);
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            panic!("connected peer was not listed by /peers");
+        });
+    }
+
+    #[test]
+    fn health_check_is_degraded_without_peers() {
+        let _guard = setup();
+        new_chain();
+
+        run(async {
+            let (p2p_tx, node_addr) = start_node().await;
+            let url = serve(p2p_tx).await;
+            let (status, health) = get(format!("{}/health", url)).await;
+            assert_eq!(status, 200);
+            assert_eq!(health["status"], "degraded");
+            assert_eq!(health["categories"]["p2p"], "degraded");
+            assert_eq!(health["peer_count"], 0);
+            assert!(health["listen_addrs"]
+                .as_array()
+                .unwrap()
+                .contains(&Value::from(node_addr.to_string())));
+
+            // The connection is registered by the p2p event loop, so poll until it counts
+            connect_peer(node_addr);
+            for _ in 0..100 {
+                let (_, health) = get(format!("{}/health", url)).await;
+                if health["peer_count"] == 1 {
+                    assert_eq!(health["status"], "healthy");
+                    assert_eq!(health["categories"]["p2p"], "healthy");
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            panic!("connected peer was not counted by /health");
+        });
+    }
+
+    #[test]
+    fn health_check_responds_during_slow_balance_scan() {
+        let _guard = setup();
+        let pub_key_hash = *new_chain().get_wallet_address().pub_key_hash();
+
+        // A single worker thread, so a scan run on it would hold up every other request
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (answered_after, outpoints) = runtime.block_on(async {
+            let (p2p_tx, _) = start_node().await;
+            let url = serve(p2p_tx).await;
+            let start = std::time::Instant::now();
+            // The balance handlers' scan, slowed down to stand in for a large utxo set
+            let scan = tokio::spawn(scan_utxos(move || {
+                std::thread::sleep(Duration::from_millis(1500));
+                find_outpoints_for_addr(&pub_key_hash)
+            }));
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            // Answered well before the 1.5s scan completes
+            let (status, _) = get(format!("{}/health", url)).await;
+            assert_eq!(status, 200);
+            (start.elapsed(), scan.await.unwrap().unwrap())
+        });
+
+        assert!(
+            answered_after < Duration::from_millis(1000),
+            "health check answered after {:?}",
+            answered_after
+        );
+        assert_eq!(
+            outpoints
+                .iter()
+                .map(|(_, tx_out)| tx_out.value)
+                .sum::<u32>(),
+            100
+        );
+    }
+
+    #[test]
+    fn tx_fee_is_resolved_from_spent_outputs() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let tx = pay(&wallet, &Wallet::new().get_wallet_address(), 50, 7);
+        let mut unknown_input = TxJson::from_tx(&tx).unwrap();
+        unknown_input.inputs[0].prev_tx_id = hex::encode([7u8; 32]);
+
+        let (fee, missing) = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            let fee: TxFeeRes = reqwest::Client::new()
+                .post(format!("{}/tx/fee", url))
+                .json(&TxJson::from_tx(&tx).unwrap())
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            (fee, post(format!("{}/tx/fee", url), &unknown_input).await)
+        });
+
+        assert_eq!(fee.fee, 7);
+        assert_eq!(fee.size, tx.size());
+        assert_eq!(fee.fee_rate, 7.0 / tx.size() as f64);
+        assert_eq!(missing.0, 400);
+        // The fee is computed without submitting the tx
+        assert!(get_mempool().is_empty());
+    }
+
+    #[test]
+    fn utxo_insufficient_funds_is_bad_request() {
+        let _guard = setup();
+        let address = new_chain().get_wallet_address().get_full_address();
+
+        let (found, missing) = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            (
+                get_utxos(&url, &address, 50).await,
+                get_utxos(&url, &address, 1000).await,
+            )
+        });
+
+        assert_eq!(found.0, 200);
+        assert_eq!(missing.0, 400);
+        assert!(missing.1["error"]
+            .as_str()
+            .unwrap()
+            .contains("insufficient funds"));
+    }
+
+    #[test]
+    fn utxo_db_failure_is_internal_error() {
+        let _guard = setup();
+        let address = new_chain().get_wallet_address().get_full_address();
+        // An entry too short to hold a tx output
+        db::ROCKS_DB
+            .put_cf(db::utxo_cf(), db::to_utxo_db_key(&[3u8; 32], 0), b"corrupt")
+            .unwrap();
+
+        let (status, _) = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            get_utxos(&url, &address, 50).await
+        });
+
+        assert_eq!(status, 500);
+    }
+
+    #[test]
+    fn block_by_hash_and_height() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let block = add_block(&miner, vec![]);
+        add_block(&miner, vec![]);
+
+        let (by_hash, by_height, unknown_hash, unknown_height) = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            (
+                get(format!("{}/block/{}", url, hex::encode(block.hash))).await,
+                get(format!("{}/block/height/1", url)).await,
+                get(format!("{}/block/{}", url, hex::encode([7u8; 32]))).await,
+                get(format!("{}/block/height/5", url)).await,
+            )
+        });
+
+        assert_eq!(by_hash.0, 200);
+        assert_eq!(by_hash.1["hash"], hex::encode(block.hash));
+        assert_eq!(by_height.0, 200);
+        assert_eq!(by_height.1["hash"], hex::encode(block.hash));
+        assert_eq!(by_height.1["height"], 1);
+        assert_eq!(unknown_hash.0, 404);
+        assert_eq!(unknown_height.0, 404);
+    }
+
+    #[test]
+    fn chain_pages_walk_back_with_cursor() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let blocks: Vec<_> = (0..5).map(|_| add_block(&miner, vec![])).collect();
+
+        let (window, next, last, unknown) = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            let window = get(format!("{}/chain?from_height=4&limit=2", url)).await;
+            let cursor = window.1["next_from_height"].as_u64().unwrap();
+            let next = get(format!("{}/chain?from_height={}&limit=2", url, cursor)).await;
+            let last = get(format!("{}/chain?from_height=1&limit=5", url)).await;
+            let unknown = get(format!("{}/chain?from_height=9", url)).await;
+            (window, next, last, unknown)
+        });
+        let heights = |page: &Value| -> Vec<u64> {
+            page["blocks"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|block| block["height"].as_u64().unwrap())
+                .collect()
+        };
+
+        // A window in the middle of the chain, highest block first
+        assert_eq!(window.0, 200);
+        assert_eq!(heights(&window.1), vec![4, 3]);
+        assert_eq!(window.1["blocks"][0]["hash"], hex::encode(blocks[3].hash));
+        assert_eq!(window.1["next_from_height"], 2);
+        assert_eq!(heights(&next.1), vec![2, 1]);
+        assert_eq!(next.1["next_from_height"], 0);
+        // The page ending at genesis has no cursor, even if it holds fewer blocks than the limit
+        assert_eq!(heights(&last.1), vec![1, 0]);
+        assert!(last.1["next_from_height"].is_null());
+        assert_eq!(unknown.0, 404);
+    }
+
+    #[test]
+    fn sent_tx_appears_in_mempool() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let tx = pay(&wallet, &Wallet::new().get_wallet_address(), 40, 5);
+        let tx_id = hex::encode(tx.id);
+
+        let (sent, ids, full, count) = run(async {
+            // The receiver is kept alive so the broadcast of the new tx can be queued
+            let (p2p_tx, _p2p_rx) = mpsc::channel(8);
+            let url = serve(p2p_tx).await;
+            let before = get(format!("{}/mempool/count", url)).await;
+            assert_eq!(before.1["count"], 0);
+            (
+                post(format!("{}/tx/send", url), &TxJson::from_tx(&tx).unwrap()).await,
+                get(format!("{}/mempool", url)).await,
+                get(format!("{}/mempool?full=true", url)).await,
+                get(format!("{}/mempool/count", url)).await,
+            )
+        });
+
+        assert_eq!(sent.0, 200);
+        assert_eq!(ids.1, Value::from(vec![tx_id.clone()]));
+        assert_eq!(full.1[0]["id"], tx_id);
+        assert_eq!(full.1[0]["outputs"][0]["value"], 40);
+        assert_eq!(count.1["count"], 1);
+    }
+
+    #[test]
+    fn tx_status_is_pending_confirmed_or_unknown() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+        let to = Wallet::new().get_wallet_address();
+        let confirmed = pay(&wallet, &to, 30, 5);
+        let block = add_block(&miner, vec![confirmed.clone()]);
+        add_block(&miner, vec![]);
+        let pending = pay(&wallet, &to, 30, 5);
+        add_tx_to_mempool(&pending).unwrap();
+
+        let (pending_res, confirmed_res, unknown_res) = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            (
+                get(format!("{}/tx/{}", url, hex::encode(pending.id))).await,
+                get(format!("{}/tx/{}", url, hex::encode(confirmed.id))).await,
+                get(format!("{}/tx/{}", url, hex::encode([7u8; 32]))).await,
+            )
+        });
+
+        assert_eq!(pending_res.1["status"], "pending");
+        assert_eq!(confirmed_res.1["status"], "confirmed");
+        assert_eq!(confirmed_res.1["block_hash"], hex::encode(block.hash));
+        assert_eq!(confirmed_res.1["block_height"], 1);
+        assert_eq!(confirmed_res.1["confirmations"], 2);
+        assert_eq!(unknown_res.0, 200);
+        assert_eq!(unknown_res.1["status"], "unknown");
+    }
+
+    /// Reads the block events stream until the next event arrives, returning its data
+    async fn next_block_event(res: &mut reqwest::Response) -> Value {
+        let mut buf = String::new();
+        loop {
+            let chunk = tokio::time::timeout(Duration::from_secs(10), res.chunk())
+                .await
+                .expect("no block event received")
+                .unwrap()
+                .expect("block event stream closed");
+            buf.push_str(std::str::from_utf8(&chunk).unwrap());
+            if let Some(data) = buf
+                .lines()
+                .find_map(|line| line.strip_prefix("data:"))
+                .filter(|_| buf.contains("\n\n"))
+            {
+                return serde_json::from_str(data.trim()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn block_events_reach_every_subscriber() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+
+        run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            // Each response is returned once its handler has subscribed
+            let mut first = reqwest::get(format!("{}/events/blocks", url))
+                .await
+                .unwrap();
+            let mut second = reqwest::get(format!("{}/events/blocks", url))
+                .await
+                .unwrap();
+            assert_eq!(first.status(), 200);
+
+            let block = add_block(&miner, vec![]);
+
+            for res in [&mut first, &mut second] {
+                let event = next_block_event(res).await;
+                assert_eq!(event["height"], 1);
+                assert_eq!(event["hash"], hex::encode(block.hash));
+            }
+        });
+    }
+
+    #[test]
+    fn balance_tracks_utxos_across_blocks() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+        let other = Wallet::new().get_wallet_address();
+        add_block(&miner, vec![pay(&wallet, &other, 30, 5)]);
+        add_block(&miner, vec![]);
+        add_block(&miner, vec![]);
+
+        let (mined, paid) = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            (
+                get(format!(
+                    "{}/wallet/balance/{}",
+                    url,
+                    miner.get_full_address()
+                ))
+                .await,
+                get(format!(
+                    "{}/wallet/balance/{}",
+                    url,
+                    other.get_full_address()
+                ))
+                .await,
+            )
+        });
+
+        // The genesis output less the payment, plus every block reward and the fee
+        let rewards: u32 = (1..=3).map(block_reward).sum();
+        assert_eq!(mined.1["confirmed"], 100 - 35 + 5 + rewards);
+        assert_eq!(paid.1["confirmed"], 30);
+        assert_eq!(paid.1["pending_incoming"], 0);
+    }
+
+    #[test]
+    fn balance_response_parses_as_wasm_balance() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let addr = wallet.get_wallet_address().get_full_address();
+
+        // The wasm get_balance binding parses the response into GetBalanceRes
+        let balance: GetBalanceRes = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            reqwest::get(format!("{}/wallet/balance/{}", url, addr))
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap()
+        });
+
+        assert_eq!(balance.address, addr);
+        assert_eq!(balance.confirmed, 100);
+        assert_eq!(balance.pending_outgoing, 0);
+        assert_eq!(balance.pending_incoming, 0);
+    }
+
+    #[test]
+    fn balances_of_several_addresses_in_one_request() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+        let (a, b) = (Wallet::new(), Wallet::new());
+        let utxos = find_spendable_utxos(miner.pub_key_hash(), 50).unwrap();
+        let fund = Tx::new_multi(
+            &wallet,
+            &[(a.get_wallet_address(), 20), (b.get_wallet_address(), 30)],
+            0,
+            utxos,
+        )
+        .unwrap();
+        add_block(&Wallet::new().get_wallet_address(), vec![fund]);
+        add_tx_to_mempool(&pay(&a, &b.get_wallet_address(), 10, 0)).unwrap();
+
+        let addrs: Vec<String> = [&miner, &a.get_wallet_address(), &b.get_wallet_address()]
+            .iter()
+            .map(|addr| addr.get_full_address())
+            .collect();
+        let (balances, invalid) = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            let balances: GetBalancesRes = reqwest::Client::new()
+                .post(format!("{}/balances", url))
+                .json(&addrs)
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            let invalid = post(format!("{}/balances", url), &vec!["not-an-address"]).await;
+            (balances, invalid)
+        });
+
+        let balance = |addr: &String| {
+            let res = &balances[addr];
+            assert_eq!(&res.address, addr);
+            (res.confirmed, res.pending_outgoing, res.pending_incoming)
+        };
+        assert_eq!(balances.len(), 3);
+        assert_eq!(balance(&addrs[0]), (50, 0, 0));
+        // The pending tx spends a's output, returning change of 10
+        assert_eq!(balance(&addrs[1]), (20, 20, 10));
+        assert_eq!(balance(&addrs[2]), (30, 0, 10));
+        assert_eq!(invalid.0, 400);
+    }
+
+    #[test]
+    fn all_utxos_lists_every_outpoint() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let miner = wallet.get_wallet_address();
+        let other = Wallet::new().get_wallet_address();
+        let utxos = find_spendable_utxos(miner.pub_key_hash(), 55).unwrap();
+        let split = Tx::new_multi(
+            &wallet,
+            &[(other.clone(), 20), (other.clone(), 30)],
+            5,
+            utxos,
+        )
+        .unwrap();
+        add_block(&miner, vec![split.clone()]);
+        let single = pay(&wallet, &other, 15, 0);
+        add_block(&miner, vec![single.clone()]);
+
+        let (status, res) = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            get(format!("{}/utxo/all/{}", url, other.get_full_address())).await
+        });
+
+        assert_eq!(status, 200);
+        assert_eq!(res["address"], other.get_full_address());
+        let mut outpoints: Vec<(String, u64, u64)> = res["utxos"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|utxo| {
+                (
+                    utxo["tx_id"].as_str().unwrap().to_string(),
+                    utxo["out_idx"].as_u64().unwrap(),
+                    utxo["value"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        outpoints.sort();
+        let mut expected = vec![
+            (hex::encode(split.id), 0, 20),
+            (hex::encode(split.id), 1, 30),
+            (hex::encode(single.id), 0, 15),
+        ];
+        expected.sort();
+        assert_eq!(outpoints, expected);
+    }
+
+    #[test]
+    fn difficulty_reports_current_target() {
+        let _guard = setup();
+        new_chain();
+
+        let (status, res) = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            get(format!("{}/difficulty", url)).await
+        });
+
+        assert_eq!(status, 200);
+        assert_eq!(res["difficulty"], network_params().difficulty);
+        assert_eq!(res["target"], hex::encode(get_target_difficulty()));
+    }
+
+    #[test]
+    fn balance_splits_confirmed_and_pending() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let address = wallet.get_wallet_address();
+        let other = Wallet::new().get_wallet_address();
+        add_tx_to_mempool(&pay(&wallet, &other, 30, 5)).unwrap();
+
+        let (sender, recipient) = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            (
+                get(format!(
+                    "{}/wallet/balance/{}",
+                    url,
+                    address.get_full_address()
+                ))
+                .await,
+                get(format!(
+                    "{}/wallet/balance/{}",
+                    url,
+                    other.get_full_address()
+                ))
+                .await,
+            )
+        });
+
+        // The genesis output is locked by the pending tx, which returns 65 in change
+        assert_eq!(sender.1["confirmed"], 100);
+        assert_eq!(sender.1["pending_outgoing"], 100);
+        assert_eq!(sender.1["pending_incoming"], 65);
+        assert_eq!(recipient.1["confirmed"], 0);
+        assert_eq!(recipient.1["pending_outgoing"], 0);
+        assert_eq!(recipient.1["pending_incoming"], 30);
+    }
+
+    #[test]
+    fn health_responds_while_mining() {
+        let _guard = setup();
+        let miner = new_chain().get_wallet_address();
+        let block = next_block(&miner, vec![]);
+        CANCEL_MINING.store(false, Ordering::SeqCst);
+
+        // The runtime has a single worker thread, so mining on it would stall the api
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let res = runtime.block_on(async {
+                let (p2p, _) = start_node().await;
+                let url = serve(p2p).await;
+                // No hash meets an all zero target, so mining runs until cancelled
+                let mining = tokio::spawn(mine_block(block, [0u8; 32]));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+
+                let (status, body) = get(format!("{}/health", url)).await;
+                let still_mining = !mining.is_finished();
+                CANCEL_MINING.store(true, Ordering::SeqCst);
+                let mined = mining.await.unwrap();
+                (status, body, still_mining, mined.is_ok())
+            });
+            done_tx.send(res).unwrap();
+        });
+
+        let res = done_rx.recv_timeout(Duration::from_secs(30));
+        CANCEL_MINING.store(false, Ordering::SeqCst);
+        let (status, body, still_mining, mined) = res.expect("api did not respond while mining");
+        assert_eq!(status, 200);
+        assert_eq!(body["categories"]["api"], "healthy");
+        assert!(still_mining);
+        assert!(!mined);
+    }
+
+    #[test]
+    fn verify_message_checks_signer_owns_address() {
+        let wallet = Wallet::new();
+        let other = Wallet::new();
+        let message = "I own this address";
+        let proof = |signer: &Wallet| VerifyMessageReq {
+            address: wallet.get_wallet_address().get_full_address(),
+            message: message.to_string(),
+            pub_key: wallet.pub_key().to_string(),
+            signature: signer.sign_message(message.as_bytes()).to_string(),
+        };
+        // The other wallet's pub key and signature match each other, but not the address
+        let foreign_key = VerifyMessageReq {
+            pub_key: other.pub_key().to_string(),
+            ..proof(&other)
+        };
+
+        let results = run(async {
+            let url = serve(mpsc::channel(1).0).await;
+            let mut results = Vec::new();
+            for req in [proof(&wallet), proof(&other), foreign_key] {
+                results.push(post(format!("{}/verify-message", url), &req).await);
+            }
+            results
+        });
+
+        let expected: Vec<(u16, Value)> = [true, false, false]
+            .into_iter()
+            .map(|valid| (200, serde_json::json!({ "valid": valid })))
+            .collect();
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn raw_tx_enters_mempool_and_malformed_hex_is_rejected() {
+        let _guard = setup();
+        let wallet = new_chain();
+        let tx = pay(&wallet, &Wallet::new().get_wallet_address(), 40, 5);
+        let raw = SendRawTxReq {
+            raw: hex::encode(bincode::serialize(&tx).unwrap()),
+        };
+        // Valid hex, but too short to hold a tx
+        let truncated = SendRawTxReq {
+            raw: raw.raw[..20].to_string(),
+        };
+
+        let (sent, malformed, truncated) = run(async {
+            let (p2p_tx, _p2p_rx) = mpsc::channel(8);
+            let url = serve(p2p_tx).await;
+            let send = |req| post(format!("{}/tx/send-raw", url), req);
+            (
+                send(&raw).await,
+                send(&SendRawTxReq {
+                    raw: "not hex".to_string(),
+                })
+                .await,
+                send(&truncated).await,
+            )
+        });
+
+        assert_eq!(sent.0, 200);
+        assert!(get_mempool().contains_key(&tx.id));
+        assert_eq!(malformed.0, 400);
+        assert_eq!(truncated.0, 400);
+    }
+}
@@ -0,0 +1,142 @@
+//! Helpers for tests that read or write the chain state in the db
+
+use std::{
+    sync::{atomic::AtomicBool, Mutex, MutexGuard, Once},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use core_lib::{address::Address, tx::Tx, wallet::Wallet};
+use libp2p::Multiaddr;
+use tokio::sync::{
+    mpsc::{self, Sender},
+    oneshot,
+};
+
+use crate::{
+    blockchain::{
+        blocks::block::{get_target_difficulty, Block},
+        chain::{clear_blockchain, commit_block, create_blockchain, get_last_block, CommitStatus},
+        params::{set_network_params, Network},
+        transaction::{
+            tx::{coinbase_tx, get_total_fees},
+            utxo::find_spendable_utxos,
+        },
+    },
+    cli::db::{set_data_dir, ROCKS_DB},
+    networking::p2p::{
+        network::{start_p2p_network, P2Prx},
+        peers::{BANNED_PEERS_KEY, DEFAULT_MSG_RATE},
+    },
+};
+
+/// Tests share a single db, so those using it are run one at a time
+static DB_LOCK: Mutex<()> = Mutex::new(());
+static INIT: Once = Once::new();
+
+/// Points the db at a temp dir and selects testnet, whose low difficulty keeps mining fast.
+/// Returns a guard to hold for the rest of the test, after clearing any chain and banned peers
+/// left behind by an earlier test
+pub fn setup() -> MutexGuard<'static, ()> {
+    INIT.call_once(|| {
+        let dir = std::env::temp_dir().join(format!("dcoin_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = set_data_dir(dir);
+        let _ = set_network_params(Network::Testnet.params());
+    });
+    // A failed test poisons the lock, but the db is cleared below regardless
+    let guard = DB_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    clear_blockchain();
+    // The ban list is stored apart from the chain, so it isn't cleared with it
+    ROCKS_DB.delete(BANNED_PEERS_KEY).unwrap();
+    guard
+}
+
+/// Creates a new chain whose genesis block pays a new wallet, and returns the wallet
+pub fn new_chain() -> Wallet {
+    let wallet = Wallet::new();
+    create_blockchain(&wallet.get_wallet_address()).unwrap();
+    wallet
+}
+
+/// Returns a mined block extending the given parent, paying the reward and fees of the given
+/// txs to the miner. The block is not committed
+pub fn block_on(parent: &Block, miner: &Address, txs: Vec<Tx>) -> Block {
+    let height = parent.height + 1;
+    let fees = get_total_fees(&txs).unwrap();
+    let mut all_txs = vec![coinbase_tx(miner, fees, height).unwrap()];
+    all_txs.extend(txs);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut block = Block {
+        txs: all_txs,
+        prev_hash: parent.hash,
+        merkle_root: [0u8; 32],
+        hash: [0u8; 32],
+        nonce: 0,
+        extranonce: 0,
+        height,
+        timestamp: now.max(parent.timestamp + 1),
+    };
+    remine(&mut block);
+    block
+}
+
+/// Returns a mined block extending the chain tip. The block is not committed
+pub fn next_block(miner: &Address, txs: Vec<Tx>) -> Block {
+    block_on(&get_last_block().unwrap(), miner, txs)
+}
+
+/// Mines a block with the given txs on the chain tip and commits it
+pub fn add_block(miner: &Address, txs: Vec<Tx>) -> Block {
+    let block = next_block(miner, txs);
+    assert!(matches!(
+        commit_block(&block).unwrap(),
+        CommitStatus::Committed
+    ));
+    block
+}
+
+/// Recomputes the block's merkle root and mines it again, after its fields were changed
+pub fn remine(block: &mut Block) {
+    block.merkle_root = block.merkle_tree().unwrap().root.hash;
+    block
+        .mine(&get_target_difficulty(), &AtomicBool::new(false))
+        .unwrap();
+}
+
+/// Returns a tx paying the given value from the wallet's confirmed utxos
+pub fn pay(from: &Wallet, to: &Address, value: u32, fee: u32) -> Tx {
+    let utxos =
+        find_spendable_utxos(from.get_wallet_address().pub_key_hash(), value + fee).unwrap();
+    Tx::new(from, to, value, fee, utxos).unwrap()
+}
+
+/// Starts a p2p node listening on a random local port, returning its channel and local address
+pub async fn start_node() -> (Sender<P2Prx>, Multiaddr) {
+    let (p2p_tx, p2p_rx) = mpsc::channel(8);
+    tokio::spawn(start_p2p_network(
+        p2p_rx,
+        0,
+        false,
+        vec![],
+        DEFAULT_MSG_RATE,
+    ));
+
+    // The node picks its own port, so wait until it is listening to find it
+    loop {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        p2p_tx.send(P2Prx::HealthCheck(reply_tx)).await.unwrap();
+        let health = reply_rx.await.unwrap();
+        if let Some(addr) = health
+            .listen_addrs
+            .iter()
+            .find(|addr| addr.starts_with("/ip4/127.0.0.1/"))
+        {
+            return (p2p_tx, addr.parse().unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
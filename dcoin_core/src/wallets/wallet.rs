@@ -3,22 +3,55 @@ use std::{
     error::Error,
     fs::{self, File, OpenOptions},
     io::{Read, Write},
-    path::Path,
+    path::PathBuf,
 };
 
 use core_lib::{address::Address, wallet::Wallet};
 use serde::{Deserialize, Serialize};
 
-const WALLET_PATH: &str = "./data/wallet_store.data";
+use crate::cli::db::data_dir;
+
+const WALLET_FILE: &str = "wallet_store.data";
+
+fn wallet_path() -> PathBuf {
+    data_dir().join(WALLET_FILE)
+}
+
+/// WalletRecord is a stored wallet along with its optional human readable label
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalletRecord {
+    pub wallet: Wallet,
+    pub label: Option<String>,
+    /// Address of the root wallet this wallet was derived from, and its child index
+    pub derived_from: Option<(String, u32)>,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WalletStore {
-    pub wallets: HashMap<String, Wallet>,
+    pub wallets: HashMap<String, WalletRecord>,
+}
+
+/// LabelledWalletStore is the wallet file format used before wallets could be derived
+#[derive(Deserialize)]
+struct LabelledWalletStore {
+    wallets: HashMap<String, LabelledWalletRecord>,
+}
+
+#[derive(Deserialize)]
+struct LabelledWalletRecord {
+    wallet: Wallet,
+    label: Option<String>,
+}
+
+/// LegacyWalletStore is the wallet file format used before wallets could be labelled
+#[derive(Deserialize)]
+struct LegacyWalletStore {
+    wallets: HashMap<String, Wallet>,
 }
 
 impl WalletStore {
     pub fn save_to_file(&self) -> Result<(), Box<dyn Error>> {
-        let path = Path::new(WALLET_PATH);
+        let path = wallet_path();
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
@@ -31,7 +64,7 @@ impl WalletStore {
 
     /// Get or create an existing wallet store
     pub fn init_wallet_store() -> Result<WalletStore, String> {
-        if Path::new(WALLET_PATH).exists() {
+        if wallet_path().exists() {
             Self::load_from_file().map_err(|e| {
                 format!(
                     "[WalletStore::load_from_file] ERROR: Could not load wallet file: {}",
@@ -47,22 +80,158 @@ impl WalletStore {
 
     fn load_from_file() -> Result<Self, Box<dyn Error>> {
         // Load file
-        let mut file = OpenOptions::new().read(true).open(WALLET_PATH)?;
+        let mut file = OpenOptions::new().read(true).open(wallet_path())?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
 
-        // Cast to wallets object
-        let wallets: WalletStore = bincode::deserialize(&buffer)?;
-        Ok(wallets)
+        // Cast to wallets object, falling back to the formats of older wallet files
+        if let Ok(wallets) = bincode::deserialize::<WalletStore>(&buffer) {
+            return Ok(wallets);
+        }
+        if let Ok(labelled) = bincode::deserialize::<LabelledWalletStore>(&buffer) {
+            return Ok(WalletStore {
+                wallets: labelled
+                    .wallets
+                    .into_iter()
+                    .map(|(addr, record)| {
+                        let record = WalletRecord {
+                            wallet: record.wallet,
+                            label: record.label,
+                            derived_from: None,
+                        };
+                        (addr, record)
+                    })
+                    .collect(),
+            });
+        }
+        let legacy: LegacyWalletStore = bincode::deserialize(&buffer)?;
+        Ok(WalletStore {
+            wallets: legacy
+                .wallets
+                .into_iter()
+                .map(|(addr, wallet)| {
+                    let record = WalletRecord {
+                        wallet,
+                        label: None,
+                        derived_from: None,
+                    };
+                    (addr, record)
+                })
+                .collect(),
+        })
     }
 
     pub fn add_wallet(&mut self) -> Result<Address, String> {
-        let new_wallet = Wallet::new();
-        let address = new_wallet.get_wallet_address();
-        self.wallets.insert(address.get_full_address(), new_wallet);
+        self.insert_wallet(Wallet::new(), None)
+    }
+
+    /// Adds a new wallet generated from a mnemonic, with an optional label. The mnemonic
+    /// phrase is returned so it can be backed up by the user
+    pub fn add_mnemonic_wallet(
+        &mut self,
+        label: Option<String>,
+    ) -> Result<(Address, String), String> {
+        if let Some(label) = &label {
+            if self.find_by_label(label).is_ok() {
+                return Err(format!(
+                    "[wallet::add_mnemonic_wallet] ERROR: A wallet with label '{}' already exists",
+                    label
+                ));
+            }
+        }
+        let (new_wallet, phrase) = Wallet::generate_mnemonic();
+        let address = self.insert_wallet(new_wallet, label)?;
+        Ok((address, phrase))
+    }
+
+    /// Recovers a wallet from a mnemonic phrase and adds it to the store
+    pub fn recover_wallet(&mut self, phrase: &str) -> Result<Address, String> {
+        let wallet = Wallet::from_mnemonic(phrase).map_err(|e| e.to_string())?;
+        self.insert_wallet(wallet, None)
+    }
+
+    /// Imports an existing wallet from its hex-encoded keys and adds it to the store
+    pub fn import_wallet(&mut self, pub_key: &str, priv_key: &str) -> Result<Address, String> {
+        let wallet = Wallet::from_keys(pub_key.to_string(), priv_key.to_string())
+            .map_err(|e| e.to_string())?;
+        self.insert_wallet(wallet, None)
+    }
+
+    /// Derives the next receiving address of a root wallet and adds it to the store. If the given
+    /// address belongs to a derived wallet, its root is used. Without an address, the first
+    /// local root wallet is used. Returns the new address with its root address and child index
+    pub fn derive_next_address(
+        &mut self,
+        from: Option<&str>,
+    ) -> Result<(Address, String, u32), String> {
+        let root_addr = match from {
+            Some(addr) => {
+                let record = self.wallets.get(addr).ok_or_else(|| {
+                    format!(
+                        "[wallet::derive_next_address] ERROR: Wallet not found for address: {}",
+                        addr
+                    )
+                })?;
+                match &record.derived_from {
+                    Some((root, _)) => root.clone(),
+                    None => addr.to_string(),
+                }
+            }
+            None => self
+                .wallets
+                .iter()
+                .find(|(_, record)| record.derived_from.is_none())
+                .map(|(addr, _)| addr.clone())
+                .ok_or("[wallet::derive_next_address] ERROR: No local root wallets found")?,
+        };
+        let root = self
+            .wallets
+            .get(&root_addr)
+            .map(|record| record.wallet)
+            .ok_or_else(|| {
+                format!(
+                    "[wallet::derive_next_address] ERROR: Root wallet not found: {}",
+                    root_addr
+                )
+            })?;
+
+        // Children are derived in order, so the next index follows the highest one stored
+        let index = self
+            .wallets
+            .values()
+            .filter_map(|record| record.derived_from.as_ref())
+            .filter(|(root, _)| *root == root_addr)
+            .map(|(_, index)| index + 1)
+            .max()
+            .unwrap_or(0);
+        let child = root.derive_child(index).map_err(|e| e.to_string())?;
+
+        let address = self.insert_record(WalletRecord {
+            wallet: child,
+            label: None,
+            derived_from: Some((root_addr.clone(), index)),
+        })?;
+        Ok((address, root_addr, index))
+    }
+
+    fn insert_wallet(
+        &mut self,
+        new_wallet: Wallet,
+        label: Option<String>,
+    ) -> Result<Address, String> {
+        self.insert_record(WalletRecord {
+            wallet: new_wallet,
+            label,
+            derived_from: None,
+        })
+    }
+
+    fn insert_record(&mut self, record: WalletRecord) -> Result<Address, String> {
+        let address = record.wallet.get_wallet_address();
+        self.wallets.insert(address.get_full_address(), record);
         self.save_to_file().map_err(|e| {
             format!(
-                "[wallet::add_wallet] ERROR: Failed to save new wallet: {}",
+                "[wallet::insert_record] ERROR: Failed to save new wallet: {}",
                 e
             )
         })?;
@@ -70,12 +239,180 @@ impl WalletStore {
         Ok(address)
     }
 
-    // pub fn get_local_wallet(&self, addr: &Address) -> Result<&Wallet, String> {
-    //     self.wallets.get(&addr.get_full_address()).ok_or_else(|| {
-    //         format!(
-    //             "[wallet::get_local_wallet] ERROR: Wallet not found for address: {}",
-    //             addr.get_full_address()
-    //         )
-    //     })
-    // }
+    pub fn get_local_wallet(&self, addr: &Address) -> Result<&Wallet, String> {
+        self.wallets
+            .get(&addr.get_full_address())
+            .map(|record| &record.wallet)
+            .ok_or_else(|| {
+                format!(
+                    "[wallet::get_local_wallet] ERROR: Wallet not found for address: {}",
+                    addr.get_full_address()
+                )
+            })
+    }
+
+    /// Returns the address of the local wallet with the given label
+    pub fn find_by_label(&self, label: &str) -> Result<String, String> {
+        self.wallets
+            .iter()
+            .find(|(_, record)| record.label.as_deref() == Some(label))
+            .map(|(addr, _)| addr.clone())
+            .ok_or_else(|| {
+                format!(
+                    "[wallet::find_by_label] ERROR: No wallet found with label: {}",
+                    label
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blockchain::transaction::utxo::{find_outpoints_for_addrs, find_spendable_utxos},
+        test_utils::{add_block, new_chain, pay, setup},
+    };
+    use core_lib::tx::Tx;
+
+    /// Returns the hex-encoded pub and private keys of the wallet, as printed by ExportWallet
+    fn keys(wallet: &Wallet) -> (String, String) {
+        (
+            wallet.pub_key().to_string(),
+            wallet.private_key().display_secret().to_string(),
+        )
+    }
+
+    #[test]
+    fn imported_keys_give_wallet_address() {
+        // The store is saved to the data dir set up for db tests
+        let _guard = setup();
+        let wallet = Wallet::new();
+        let (pub_key, priv_key) = keys(&wallet);
+
+        let mut store = WalletStore::init_wallet_store().unwrap();
+        let addr = store.import_wallet(&pub_key, &priv_key).unwrap();
+        assert_eq!(
+            addr.get_full_address(),
+            wallet.get_wallet_address().get_full_address()
+        );
+
+        // Exporting the stored wallet gives back the imported keys
+        let store = WalletStore::init_wallet_store().unwrap();
+        assert_eq!(
+            keys(store.get_local_wallet(&addr).unwrap()),
+            (pub_key, priv_key)
+        );
+    }
+
+    #[test]
+    fn import_rejects_invalid_or_mismatched_keys() {
+        let _guard = setup();
+        let (pub_key, priv_key) = keys(&Wallet::new());
+        let (other_pub_key, _) = keys(&Wallet::new());
+        let mut store = WalletStore::init_wallet_store().unwrap();
+
+        for (pub_key, priv_key) in [
+            (&other_pub_key, &priv_key),
+            (&pub_key, &"zz".repeat(32)),
+            (&pub_key[..10].to_string(), &priv_key),
+        ] {
+            assert!(store.import_wallet(pub_key, priv_key).is_err());
+        }
+        assert!(!store
+            .wallets
+            .values()
+            .any(|record| keys(&record.wallet).1 == priv_key));
+    }
+
+    #[test]
+    fn labeled_wallet_can_send_by_label() {
+        let _guard = setup();
+        let funder = new_chain();
+        let mut store = WalletStore::init_wallet_store().unwrap();
+        let (addr, _) = store
+            .add_mnemonic_wallet(Some("savings".to_string()))
+            .unwrap();
+        assert!(store
+            .add_mnemonic_wallet(Some("savings".to_string()))
+            .is_err());
+
+        // The label is kept in the saved store and resolves to the wallet's address
+        let store = WalletStore::init_wallet_store().unwrap();
+        let from = store.find_by_label("savings").unwrap();
+        assert_eq!(from, addr.get_full_address());
+        assert_eq!(store.wallets[&from].label.as_deref(), Some("savings"));
+        assert!(store.find_by_label("checking").is_err());
+
+        // The resolved wallet spends the coins sent to its address
+        add_block(
+            &funder.get_wallet_address(),
+            vec![pay(&funder, &addr, 50, 0)],
+        );
+        let wallet = &store.wallets[&from].wallet;
+        let to = Wallet::new().get_wallet_address();
+        let utxos = find_spendable_utxos(addr.pub_key_hash(), 20).unwrap();
+        let tx = Tx::new(wallet, &to, 20, 0, utxos).unwrap();
+        add_block(&funder.get_wallet_address(), vec![tx]);
+        assert!(find_spendable_utxos(to.pub_key_hash(), 20).is_ok());
+    }
+
+    #[test]
+    fn derived_addresses_hold_spendable_funds_of_one_wallet() {
+        let _guard = setup();
+        let funder = new_chain();
+        let mut store = WalletStore::init_wallet_store().unwrap();
+        let (root, _) = store.add_mnemonic_wallet(None).unwrap();
+        let root = root.get_full_address();
+
+        // Children are derived from the root key in order
+        let mut addrs = vec![root.clone()];
+        for expected_index in 0..2 {
+            let (child, from, index) = store.derive_next_address(Some(&root)).unwrap();
+            assert_eq!((from, index), (root.clone(), expected_index));
+            let expected = store.wallets[&root].wallet.derive_child(index).unwrap();
+            assert_eq!(
+                child.get_full_address(),
+                expected.get_wallet_address().get_full_address()
+            );
+            addrs.push(child.get_full_address());
+        }
+        // Deriving from a child continues the root's sequence
+        let (_, from, index) = store.derive_next_address(Some(&addrs[1])).unwrap();
+        assert_eq!((from, index), (root.clone(), 2));
+
+        // Funds sent to each address add up to the wallet's balance
+        let store = WalletStore::init_wallet_store().unwrap();
+        let wallets: Vec<Wallet> = addrs
+            .iter()
+            .map(|addr| store.wallets[addr].wallet)
+            .collect();
+        let recipients: Vec<(Address, u32)> = wallets
+            .iter()
+            .zip([10, 20, 30])
+            .map(|(wallet, value)| (wallet.get_wallet_address(), value))
+            .collect();
+        let utxos = find_spendable_utxos(funder.get_wallet_address().pub_key_hash(), 60).unwrap();
+        let fund = Tx::new_multi(&funder, &recipients, 0, utxos).unwrap();
+        add_block(&funder.get_wallet_address(), vec![fund]);
+        let pub_key_hashes: Vec<[u8; 20]> = wallets
+            .iter()
+            .map(|wallet| *wallet.get_wallet_address().pub_key_hash())
+            .collect();
+        let balance: u32 = find_outpoints_for_addrs(&pub_key_hashes)
+            .unwrap()
+            .values()
+            .flatten()
+            .map(|(_, tx_out)| tx_out.value)
+            .sum();
+        assert_eq!(balance, 60);
+
+        // A derived address spends with its stored key
+        let child = &wallets[2];
+        let to = Wallet::new().get_wallet_address();
+        let utxos = find_spendable_utxos(child.get_wallet_address().pub_key_hash(), 30).unwrap();
+        let tx = Tx::new(child, &to, 30, 0, utxos).unwrap();
+        add_block(&funder.get_wallet_address(), vec![tx]);
+        assert!(find_spendable_utxos(to.pub_key_hash(), 30).is_ok());
+    }
 }
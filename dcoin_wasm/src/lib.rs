@@ -1,7 +1,7 @@
 use core_lib::{
     address::Address,
     constants::SEED_API_NODE,
-    req_types::{GetUTXORes, TxJson, convert_json_to_utxoset},
+    req_types::{GetBalanceRes, GetUTXORes, TxJson, convert_json_to_utxoset},
     tx::{Tx, UTXOSet},
     wallet::Wallet,
 };
@@ -16,6 +16,7 @@ pub struct JsWallet {
 #[wasm_bindgen]
 impl JsWallet {
     #[wasm_bindgen(constructor)]
+    #[allow(clippy::new_without_default)]
     pub fn new() -> JsWallet {
         JsWallet {
             inner: Wallet::new(),
@@ -37,6 +38,38 @@ impl JsWallet {
         self.inner.private_key().display_secret().to_string()
     }
 
+    /// Signs an unsigned tx skeleton given as TxJson, returning the signed TxJson. Input
+    /// signatures may be left empty, but pub keys must be valid and are replaced by this wallet's.
+    #[wasm_bindgen]
+    pub fn sign_tx(&self, tx_json: JsValue) -> Result<JsValue, JsValue> {
+        let tx_json: TxJson = serde_wasm_bindgen::from_value(tx_json).map_err(|e| {
+            JsValue::from_str(&format!("[wallet::sign_tx] ERROR: Invalid tx JSON: {e}"))
+        })?;
+        let mut tx = tx_json.to_tx().map_err(|e| {
+            JsValue::from_str(&format!("[wallet::sign_tx] ERROR: Failed to parse tx: {e}"))
+        })?;
+        self.inner.sign_tx(&mut tx).map_err(|e| {
+            JsValue::from_str(&format!("[wallet::sign_tx] ERROR: Failed to sign tx: {e}"))
+        })?;
+        let signed = TxJson::from_tx(&tx).map_err(|e| {
+            JsValue::from_str(&format!(
+                "[wallet::sign_tx] ERROR: Failed to serialize tx: {e}"
+            ))
+        })?;
+        serde_wasm_bindgen::to_value(&signed).map_err(|e| {
+            JsValue::from_str(&format!(
+                "[wallet::sign_tx] ERROR: Failed to convert tx: {e}"
+            ))
+        })
+    }
+
+    /// Signs a message to prove ownership of this wallet's address, returning the hex-encoded
+    /// DER signature accepted by the node's /verify-message endpoint
+    #[wasm_bindgen]
+    pub fn sign_message(&self, message: &str) -> String {
+        self.inner.sign_message(message.as_bytes()).to_string()
+    }
+
     #[wasm_bindgen]
     pub fn from_keys(pub_key: String, priv_key: String) -> Result<JsWallet, JsValue> {
         match Wallet::from_keys(pub_key, priv_key) {
@@ -48,13 +81,74 @@ impl JsWallet {
     }
 }
 
+/// Fetches the confirmed balance of an address from the node
 #[wasm_bindgen]
-pub async fn send_tx(to: &str, from_wallet: &JsWallet, value: u32) -> Result<JsValue, JsValue> {
+pub async fn get_balance(address: &str) -> Result<u32, JsValue> {
+    // Catch invalid addresses before making a request
+    if let Err(e) = Address::new_from_str(address) {
+        return Err(JsValue::from_str(&format!(
+            "[wasm::get_balance] ERROR: Invalid address: {}",
+            e
+        )));
+    }
+
+    let url = format!("{}/wallet/balance/{}", SEED_API_NODE, address);
+
+    let response = match Client::new().get(url).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            return Err(JsValue::from_str(&format!(
+                "[wasm::get_balance] ERROR: Failed to connect to node: {}",
+                e
+            )));
+        }
+    };
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(JsValue::from_str(&format!(
+            "[wasm::get_balance] ERROR: Failed to fetch balance from node: {} - {}",
+            status, error_text
+        )));
+    }
+
+    let data = match response.json::<GetBalanceRes>().await {
+        Ok(data) => data,
+        Err(e) => {
+            return Err(JsValue::from_str(&format!(
+                "[wasm::get_balance] ERROR: Failed to parse balance response: {}",
+                e
+            )));
+        }
+    };
+
+    confirmed_balance(&data).map_err(|e| JsValue::from_str(&e))
+}
+
+/// Returns the confirmed balance of a balance response, as the u32 used for tx values
+fn confirmed_balance(res: &GetBalanceRes) -> Result<u32, String> {
+    u32::try_from(res.confirmed)
+        .map_err(|_| "[wasm::get_balance] ERROR: Balance is too large to represent".to_string())
+}
+
+#[wasm_bindgen]
+pub async fn send_tx(
+    to: &str,
+    from_wallet: &JsWallet,
+    value: u32,
+    fee: u32,
+) -> Result<JsValue, JsValue> {
     let from_address = from_wallet.get_wallet_address();
 
+    // Inputs must cover both the value sent and the fee paid to the miner
+    let amount = value
+        .checked_add(fee)
+        .ok_or_else(|| JsValue::from_str("[wasm::send_tx] ERROR: Value and fee are too large"))?;
+
     let url = format!(
         "{}/utxo?address={}&amount={}",
-        SEED_API_NODE, from_address, value
+        SEED_API_NODE, from_address, amount
     );
 
     let utxos: UTXOSet;
@@ -109,7 +203,7 @@ pub async fn send_tx(to: &str, from_wallet: &JsWallet, value: u32) -> Result<JsV
         }
     };
 
-    let tx = match Tx::new(&from_wallet.inner, &to_address, value, utxos) {
+    let tx = match Tx::new(&from_wallet.inner, &to_address, value, fee, utxos) {
         Ok(tx) => tx,
         Err(e) => {
             return Err(JsValue::from_str(&format!(
@@ -134,21 +228,48 @@ pub async fn send_tx(to: &str, from_wallet: &JsWallet, value: u32) -> Result<JsV
     match client.post(&url).json(&tx_json).send().await {
         Ok(resp) => {
             if resp.status().is_success() {
-                return Ok(JsValue::from_str("Transaction successfully sent to node"));
+                Ok(JsValue::from_str("Transaction successfully sent to node"))
             } else {
                 let status = resp.status();
                 let error_text = resp.text().await.unwrap_or_default();
-                return Err(JsValue::from_str(&format!(
+                Err(JsValue::from_str(&format!(
                     "[wasm::send_tx] ERROR: Failed to send transaction: {} - {}",
                     status, error_text
-                )));
+                )))
             }
         }
-        Err(e) => {
-            return Err(JsValue::from_str(&format!(
-                "[wasm::send_tx] ERROR: Error sending request: {}",
-                e
-            )));
+        Err(e) => Err(JsValue::from_str(&format!(
+            "[wasm::send_tx] ERROR: Error sending request: {}",
+            e
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balance_res(confirmed: u64) -> GetBalanceRes {
+        GetBalanceRes {
+            address: JsWallet::new().get_wallet_address(),
+            confirmed,
+            pending_outgoing: 20,
+            pending_incoming: 30,
         }
     }
+
+    #[test]
+    fn balance_is_the_confirmed_value() {
+        assert_eq!(confirmed_balance(&balance_res(150)), Ok(150));
+        assert_eq!(
+            confirmed_balance(&balance_res(u64::from(u32::MAX))),
+            Ok(u32::MAX)
+        );
+    }
+
+    #[test]
+    fn balance_too_large_for_tx_values_is_an_error() {
+        let err = confirmed_balance(&balance_res(u64::from(u32::MAX) + 1)).unwrap_err();
+        assert!(err.contains("too large"), "{}", err);
+    }
 }